@@ -0,0 +1,211 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use chrono::{FixedOffset, TimeZone};
+use log::{debug, warn};
+use rusqlite::Connection;
+
+use crate::{
+    collisions::CollisionPolicy,
+    db::{
+        self, add_to_table, clear_failures, record_checksums, record_chunks, record_provenance,
+        set_images_as_archived, ProvenanceEntry,
+        TableType::{Camera, Disk},
+    },
+    error,
+    failures::FailureKind,
+    geotag::{self, Track},
+    hash::{self, HashAlgorithm},
+    images::{archive_image, ArchivedCopy, CopyOptions, ExifDetails, ImageAdv, ImageBasic},
+    layout::Layout,
+};
+
+/// Copies camera images into the target and records the copies
+pub struct Archiver<'a> {
+    pub target_dir: &'a Path,
+    pub layout: &'a Layout,
+    pub algorithm: HashAlgorithm,
+    /// Size of the chunks checksummed separately, if the database stores
+    /// chunk checksums
+    pub chunk_size: Option<u64>,
+    /// Track that images without GPS tags are geotagged from
+    pub track: Option<&'a Track>,
+    /// Also write the positions from the track into XMP sidecars
+    pub write_sidecars: bool,
+    /// Checksums of the camera images when they were indexed. A copy on
+    /// another source is only archived instead if it has the same one.
+    pub checksums: Option<&'a HashMap<String, Vec<u8>>>,
+    /// Attributes the copies keep and whether they are flushed to disk
+    pub copy_options: CopyOptions<'a>,
+}
+
+impl<'a> Archiver<'a> {
+    /// Archive into `target_dir` with the hash algorithm and chunk size of
+    /// the database
+    pub fn new(conn: &Connection, target_dir: &'a Path, layout: &'a Layout) -> error::Result<Self> {
+        Ok(Archiver {
+            target_dir,
+            layout,
+            algorithm: db::get_hash_algorithm(conn)?,
+            chunk_size: db::get_chunk_size(conn)?,
+            track: None,
+            write_sidecars: false,
+            checksums: None,
+            copy_options: CopyOptions::default(),
+        })
+    }
+
+    /// Copy `image` from the first of `sources`, or from another source
+    /// holding the same file if the first can't be read, and geotag it from
+    /// the track
+    pub fn archive(&self, image: &ImageAdv, sources: &[&Path]) -> error::Result<ArchivedCopy> {
+        let mut copy = self.copy(image, sources)?;
+        if image.exif.position.is_none() {
+            copy.position = self.locate(image, &copy);
+        }
+        if let (Some(position), true) = (copy.position, self.write_sidecars) {
+            // The copy itself is fine, so it is still recorded
+            let path = self.target_dir.join(&copy.path);
+            if let Err(err) = geotag::write_sidecar(&path, position) {
+                warn!("{:#}", err);
+            }
+        }
+
+        Ok(copy)
+    }
+
+    /// Position of the track at the time `image` was taken
+    fn locate(&self, image: &ImageAdv, copy: &ArchivedCopy) -> Option<geotag::Position> {
+        let offset = FixedOffset::east_opt(copy.utc_offset?)?;
+        let taken = offset.from_local_datetime(&image.date).single()?;
+        self.track?.locate(taken.to_utc())
+    }
+
+    fn copy(&self, image: &ImageAdv, sources: &[&Path]) -> error::Result<ArchivedCopy> {
+        let err = match self.archive_from(image, sources[0]) {
+            Ok(copy) => return Ok(copy),
+            Err(err) => err,
+        };
+        if FailureKind::classify(&err) != FailureKind::IoError {
+            return Err(err.into());
+        }
+
+        let Some(expected) = self
+            .checksums
+            .and_then(|checksums| checksums.get(&image.basic.path))
+        else {
+            return Err(err.into());
+        };
+        for source in &sources[1..] {
+            let path = source.join(&image.basic.path);
+            let same_size = fs::metadata(&path).is_ok_and(|meta| meta.len() == image.basic.size);
+            if !same_size {
+                continue;
+            }
+            let same = hash::hash_file_chunked(&path, self.algorithm, None)
+                .is_ok_and(|(checksum, _)| checksum == *expected);
+            if !same {
+                debug!(
+                    "{} on {} differs from the indexed image",
+                    image.basic.path,
+                    source.display()
+                );
+                continue;
+            }
+            debug!(
+                "Copying {} from {} instead: {:#}",
+                image.basic.path,
+                source.display(),
+                err
+            );
+            if let Ok(copy) = self.archive_from(image, source) {
+                return Ok(copy);
+            }
+        }
+
+        Err(err.into())
+    }
+
+    fn archive_from(&self, image: &ImageAdv, source: &Path) -> anyhow::Result<ArchivedCopy> {
+        archive_image(
+            image,
+            source,
+            self.target_dir,
+            self.layout,
+            self.algorithm,
+            self.chunk_size,
+            self.copy_options,
+        )
+    }
+
+    /// Whether a failed copy is a name collision that `--on-collision skip`
+    /// leaves on the card without recording it
+    pub fn skips_collision(&self, kind: FailureKind) -> bool {
+        self.layout.on_collision == CollisionPolicy::Skip && kind == FailureKind::Collision
+    }
+
+    /// Mark freshly archived camera images of `card` as saved, record where
+    /// they came from, and index the copies with their checksums
+    pub fn record(
+        &self,
+        trans: &Connection,
+        card: &str,
+        card_id: Option<&str>,
+        success: &[(ImageAdv, ArchivedCopy)],
+    ) -> error::Result<()> {
+        set_images_as_archived(trans, card, success.iter().map(|(i, _)| i))?;
+        record_provenance(
+            trans,
+            card_id,
+            success.iter().map(|(image, copy)| ProvenanceEntry {
+                image,
+                disk_path: &copy.path,
+                checksum: Some(&copy.checksum),
+                utc_offset: copy.utc_offset,
+                dst_policy: Some(self.layout.dst_policy),
+            }),
+        )?;
+        clear_failures(trans, Camera, success.iter().map(|(i, _)| &i.basic))?;
+
+        // Index the new copies right away, so their checksums are recorded
+        let copies = success
+            .iter()
+            .map(|(image, copy)| ImageAdv {
+                basic: ImageBasic {
+                    path: copy.path.clone(),
+                    size: image.basic.size,
+                },
+                date: image.date,
+                rating: image.rating,
+                date_source: image.date_source.clone(),
+                camera: image.camera.clone(),
+                utc_offset: image.utc_offset,
+                exif: ExifDetails {
+                    position: image.exif.position.or(copy.position),
+                    ..image.exif.clone()
+                },
+            })
+            .collect::<Vec<_>>();
+        add_to_table(trans, Disk, "", &copies)?;
+        record_checksums(
+            trans,
+            Disk,
+            "",
+            success
+                .iter()
+                .map(|(_, copy)| (copy.path.as_str(), copy.checksum.as_slice())),
+        )?;
+        if let Some(chunk_size) = self.chunk_size {
+            for (image, copy) in success {
+                record_chunks(
+                    trans,
+                    &copy.path,
+                    chunk_size,
+                    image.basic.size,
+                    &copy.chunks,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
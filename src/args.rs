@@ -1,29 +1,444 @@
 use anyhow::bail;
-use std::{env, ffi::OsStr, path::PathBuf};
+use chrono::NaiveDate;
+use std::{
+    env,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{load_config, Config};
+use rawdb::{
+    collisions::CollisionPolicy,
+    cull::CullPolicy,
+    dates::{DateFallback, DateMapping},
+    db::TableType,
+    dump::DumpFormat,
+    duplicates::DuplicatePolicy,
+    export::Selection,
+    geotag::Track,
+    hash::HashAlgorithm,
+    images::Preserve,
+    layout::{DstPolicy, Template, TimeZonePolicy},
+    output::OutputFormat,
+};
 
 const HELP_STRING: &str = "\
 rawdb - A simple image archiver
-usage: rawdb [-options] [source_dir]
-    [--target <target_dir>] # The directory place archived images
+usage: rawdb [-options] archive [--guided] <source_dir...>
+                                # Archive each source_dir in turn, --guided shows the plan and
+                                # asks first, then verifies the copies (also available as import).
+                                # A source_dir of mtp://[device][/folder] reads the DCIM folder, or
+                                # the given one, of a phone over MTP (needs the ptp feature)
+       rawdb [-options] scan [source_dir...]
+                                # Index the target and source_dir and list what would be archived
+       rawdb [-options] status  # Summarize the database, pending failures and runs in progress,
+                                # fails if copies weren't verified within verify_every_months
+       rawdb [-options] stats   # Count the images and bytes on disk and on camera, with the dates
+                                # they cover and a breakdown by extension
+       rawdb archives list      # Show the archives registered in the config
+       rawdb [-options] db info [--json]
+                                # Describe the database without upgrading it
+       rawdb [-options] tether <session_dir>
+                                # Archive files as tethering software writes them into session_dir
+       rawdb [-options] watch <mount_dir>
+                                # Archive every card mounted under mount_dir (like /media/$USER)
+                                # as it appears, until stopped
+       rawdb [-options] index   # Refresh the index of the target without archiving
+       rawdb [-options] import-manifest <manifest.csv>
+                                # Index the target files listed as path,size,date in a manifest,
+                                # without reading them
+       rawdb [-options] verify [--mirror <mirror_dir>]
+                                # Check that archived images, or their copies in a mirror, still
+                                # have their recorded size and checksum, and list unknown files
+       rawdb [-options] locate <pattern>
+                                # Show which card and folder matching images came from
+       rawdb [-options] query [<name pattern>] [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>]
+                        [--match <pattern>] [--in <target|source>] [--json]
+                                # List indexed images of the target, or of the last source, by
+                                # name, capture date and path, as a table or JSON lines
+       rawdb [-options] repair --mirror <mirror_dir>
+                                # Restore corrupt archived images from a mirror
+       rawdb [-options] collisions [--fix]
+                                # List archived images in different folders sharing a name,
+                                # --fix renames all but the first to <name>-<n>
+       rawdb [-options] orphans [--adopt]
+                                # List files in the target that aren't indexed, --adopt reads and
+                                # indexes them
+       rawdb [-options] install-service [--dry-run]
+                                # Write a udev rule and a systemd unit that mount and archive the
+                                # cards of the config into the target as soon as they are inserted,
+                                # as root, --dry-run only prints them
+       rawdb [-options] prune [--dry-run]
+                                # Forget indexed files of the target that no longer exist, such as
+                                # after reorganizing it by hand, --dry-run only lists them
+       rawdb [-options] dedupe [--dry-run]
+                                # Replace archived images identical to another one with hard links
+                                # to it, after reading both again
+       rawdb [-options] inventory [--out <file.csv|file.pdf>]
+                                # Summarize archived files per volume (CSV without --out)
+       rawdb [-options] export --staging <dir> [--from <YYYY-MM-DD>] [--to <YYYY-MM-DD>]
+                        [--match <pattern>] [--disc-size <gb>]
+                                # Fill disc-sized staging folders with images not exported
+                                # before, each with a checksum manifest (25 GB discs by default)
+       rawdb [-options] export --format <csv|json> [--table <disk|camera>] [--out <file>]
+                                # Write every column of the disk (default) or camera image table,
+                                # CSV or a JSON object per line, to stdout or <file>
+       rawdb [-options] report [--month <YYYY-MM>] [--out <file>]
+                                # Write an HTML report of a month's archiving (default this month)
+Options only accepted by some commands are rejected by the others
+    [--target <target_dir>] # The directory place archived images. Given again, archive also
+                            # copies and verifies each image into the other ones as mirrors
     [--db <database_file>]  # The location to store the image database
+    [--archive <name>]      # Use the database and target registered as <name> in the config
+    [--config <file>]       # Config file to use instead of ~/.config/rawdb/config.toml, which
+                            # may set target, db, ignore_extensions, include_extensions,
+                            # ignore_dirs, exclude, cards, [defaults] for options and
+                            # [reporter] for a webhook or email address archive runs are
+                            # reported to
+    [--ignore-ext <ext>]    # Never index files with this extension, such as lrv, besides the
+                            # ignore_extensions of the config and sidecars (repeatable)
+    [--include-ext <ext>]   # Only index files with this extension (repeatable), instead of the
+                            # include_extensions of the config or every extension
+    [--ignore-dir <glob>]   # Skip folders with a matching name and everything below them (repeatable,
+                            # besides the ignore_dirs of the config and the trash and thumbnail
+                            # folders of darktable, digiKam, Synology and the OS)
+    [--min-size <bytes>]    # Skip files smaller than this with a warning, such as the empty stubs
+                            # a camera leaves when its battery dies
+    [--follow-symlinks]     # Follow symbolic links to files and folders while scanning, skipping
+                            # links back to a parent folder. Links are skipped by default.
+    [--no-follow-symlinks]  # Skip symbolic links, even if follow_symlinks is set in the config
+    [--include-hidden]      # Index hidden files, such as .DS_Store and the ._ AppleDouble files
+                            # macOS writes onto cards, which are ignored by default
+    [--exclude <glob>]      # Never index files or folders whose name or path below the scanned
+                            # directory matches, such as _rejected or exports/* (repeatable,
+                            # besides the exclude list of the config)
+    [--ffprobe <path>]      # ffprobe binary reading videos other than MP4 and QuickTime, instead
+                            # of the one on the PATH (or RAWDB_FFPROBE)
+    [--force-folder <name>] # Archive every image into this folder instead of by date
+    [--layout <template>]   # Folders images are archived into, from {year}, {month}, {day},
+                            # {date} and {camera}, such as {year}/{year}-{month}/{date}
+                            # (default {date})
+    [--on-collision <policy>]
+                            # Images whose name is taken by a different file in their folder:
+                            # error (default) fails them, skip leaves them on the card, suffix
+                            # archives them as <name>-2 and so on
+    [--card-folder <name>]  # Only archive images from this card folder, such as 105CANON (repeatable)
+    [--pick-card-folder]    # List the card folders with pending images and choose which to archive
+    [--source <dir>]        # Another source_dir, such as the second card slot (repeatable). Images
+                            # that can't be read from one source are copied from another one
+    [--auto]                # Also archive every mounted card whose volume label or UUID is one of
+                            # the cards of the config, doing nothing if none is (Linux only)
+    [--camera]              # Also archive the camera connected over PTP, for cameras that don't
+                            # mount as a card (needs the ptp feature and gphoto2)
+    [--camera-port <port>]  # Port of the camera to archive when several are connected, such as
+                            # usb:001,004 (implies --camera)
+    [--camera-staging <dir>]
+                            # Folder the files of cameras, and of phones read from a source_dir
+                            # like mtp://[device][/folder], are downloaded into before they are
+                            # archived, kept to only download new files next time (default
+                            # rawdb-camera in the temporary folder)
+    [--files-from <list>]   # Archive only the files listed in <list> (- for stdin), relative to the
+                            # only source_dir
+    [--dates-from <file>]   # Date files without usable metadata from a CSV (name or glob,date) or
+                            # JSON ({\"name or glob\": \"date\"}) mapping, dates as YYYY-MM-DD
+    [--date-fallback <source>]
+                            # Date files without usable metadata the mapping doesn't cover: none
+                            # (default) leaves them on the card, mtime uses their modification
+                            # time and records the date as low confidence
+    [--gpx <file.gpx>]      # Geotag archived images without GPS tags from the track points of
+                            # this GPX file (repeatable), recording the position in the database
+    [--gpx-sidecars]        # Also write the positions from --gpx into XMP sidecars of the copies,
+                            # unless they already have one
+    [--duplicates <policy>] # Files found twice under the same name and size: keep-first (default)
+                            # indexes the first path, keep-all every copy, abort stops the run,
+                            # ask lets you choose for each
+    [--hash <algorithm>]    # Checksum algorithm for a new database: blake3 (default) or sha256
+    [--dst-policy <policy>] # Placement of times repeated or skipped by daylight saving: earliest
+                            # (default), latest, or utc to assign every folder by UTC date
+    [--timezone <zone>]     # Zone camera clocks are set to, for images that don't record their
+                            # offset and to show video times in: local (default), utc or +09:00
+    [--cull <policy>]       # Skip images marked in camera: keep (default) archives everything,
+                            # rejected skips images marked for deletion, unrated also rating 0
+    [--skip-paired-jpegs]   # Only archive the RAW file of RAW+JPEG pairs and brackets
+    [--burst-folders]       # Archive each burst, three or more frames with consecutive file
+                            # numbers taken within seconds, into its own burst-<first> subfolder
+    [--eject]               # Flush, unmount and power down each card once the run finished
+                            # without errors
+    [--tag-card]            # Write an id to the root of each card without one, so the card is
+                            # recognized wherever it is mounted (cards are told apart by their
+                            # path otherwise)
+    [--move]                # Delete each image from the card once its copy, and its mirror
+                            # copies, are verified, flushed to disk and recorded as saved
+                            # (implies --fsync)
+    [--chunk-size <mib>]    # Also checksum archived images in 4-16 MiB chunks (0 to disable)
     [-c | --clean]          # Clear the image database
     [-d | --dry-run]        # Index but don't archive
+    [--output <format>]     # text (default) only logs, json also writes the dry run plan or the
+                            # results of each source, with duplicates, as a JSON line to stdout
     [-l | --leave]          # Do not remove temp tables
+    [--fail-on-access-errors]
+                            # Abort when a folder can't be read, instead of skipping it
+    [--strict]              # Stop the run at the first file that can't be indexed or archived,
+                            # instead of finishing with exit code 2, implies
+                            # --fail-on-access-errors
+    [--full-scan]           # Read every folder of the target instead of only changed ones
+    [-r | --retry-failed]   # Retry files that already failed too many times
+    [-b | --background]     # Run with lowered CPU and IO priority
+    [--notify]              # Show a desktop notification with the number of archived images and
+                            # failures when the run finishes, or why it failed
+    [-j | --jobs <n>]       # Threads reading new files while indexing (default: one per CPU)
+                            # and copying images while archiving (default: 1)
+    [--snapshot]            # Snapshot the target with the snapshot_command from the config
+                            # after a successful archive or index run
+    [--paranoid]            # Never delete or overwrite anything, bad copies are moved to quarantine
+    [--fsync]               # Flush each copy and its folder to disk before marking it as saved
+    [--force]               # Archive even if the images don't fit in the free space of the target
+    [--preserve <attrs>]    # Attributes copies keep from the original: mtime (default), mode
+                            # for its permissions, both as mtime,mode, or none
+    [--retention-days <n>]  # Age after which archived card files may be removed (default 14)
+Exit codes
+    0                       # Everything went through
+    1                       # The run failed
+    2                       # The run finished, but some files couldn't be indexed or archived
 ";
 
+pub enum Command {
+    Archive {
+        /// Archived one after another, empty to only index the target
+        source_dirs: Vec<PathBuf>,
+        target_dir: PathBuf,
+    },
+    Status,
+    Stats,
+    Archives,
+    DbInfo {
+        json: bool,
+    },
+    Index {
+        target_dir: PathBuf,
+    },
+    ImportManifest {
+        manifest: PathBuf,
+        target_dir: PathBuf,
+    },
+    Tether {
+        session_dir: PathBuf,
+        target_dir: PathBuf,
+    },
+    Watch {
+        mount_dir: PathBuf,
+        target_dir: PathBuf,
+    },
+    Verify {
+        target_dir: PathBuf,
+        mirror_dir: Option<PathBuf>,
+    },
+    Repair {
+        target_dir: PathBuf,
+        mirror_dir: PathBuf,
+    },
+    Locate {
+        pattern: String,
+    },
+    Query {
+        table: TableType,
+        /// Glob the file name has to match
+        name: Option<String>,
+        selection: Selection,
+        json: bool,
+    },
+    Report {
+        month: Option<String>,
+        out: Option<PathBuf>,
+    },
+    Collisions {
+        target_dir: PathBuf,
+        fix: bool,
+    },
+    Dedupe {
+        target_dir: PathBuf,
+    },
+    Prune {
+        target_dir: PathBuf,
+    },
+    InstallService {
+        target_dir: PathBuf,
+    },
+    Orphans {
+        target_dir: PathBuf,
+        adopt: bool,
+    },
+    Inventory {
+        target_dir: PathBuf,
+        out: Option<PathBuf>,
+    },
+    Dump {
+        table: TableType,
+        format: DumpFormat,
+        out: Option<PathBuf>,
+    },
+    Export {
+        target_dir: PathBuf,
+        staging_dir: PathBuf,
+        selection: Selection,
+        /// Capacity of each disc in bytes
+        disc_size: u64,
+    },
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Archive { .. } => "archive",
+            Command::Status => "status",
+            Command::Stats => "stats",
+            Command::Archives => "archives",
+            Command::DbInfo { .. } => "db info",
+            Command::Index { .. } => "index",
+            Command::ImportManifest { .. } => "import-manifest",
+            Command::Tether { .. } => "tether",
+            Command::Watch { .. } => "watch",
+            Command::Verify { .. } => "verify",
+            Command::Repair { .. } => "repair",
+            Command::Locate { .. } => "locate",
+            Command::Query { .. } => "query",
+            Command::Report { .. } => "report",
+            Command::Collisions { .. } => "collisions",
+            Command::Dedupe { .. } => "dedupe",
+            Command::Prune { .. } => "prune",
+            Command::InstallService { .. } => "install-service",
+            Command::Orphans { .. } => "orphans",
+            Command::Inventory { .. } => "inventory",
+            Command::Dump { .. } | Command::Export { .. } => "export",
+        }
+    }
+
+    pub fn target_dir(&self) -> Option<&Path> {
+        match self {
+            Command::Archive { target_dir, .. }
+            | Command::Index { target_dir }
+            | Command::ImportManifest { target_dir, .. }
+            | Command::Tether { target_dir, .. }
+            | Command::Watch { target_dir, .. }
+            | Command::Verify { target_dir, .. }
+            | Command::Repair { target_dir, .. }
+            | Command::Collisions { target_dir, .. }
+            | Command::Dedupe { target_dir }
+            | Command::Prune { target_dir }
+            | Command::InstallService { target_dir }
+            | Command::Orphans { target_dir, .. }
+            | Command::Inventory { target_dir, .. }
+            | Command::Export { target_dir, .. } => Some(target_dir),
+            Command::Status
+            | Command::Stats
+            | Command::Archives
+            | Command::DbInfo { .. }
+            | Command::Locate { .. }
+            | Command::Query { .. }
+            | Command::Dump { .. }
+            | Command::Report { .. } => None,
+        }
+    }
+}
+
 pub struct AppArgs {
-    pub source_dir: Option<PathBuf>,
-    pub target_dir: PathBuf,
-    pub database_path: PathBuf,
+    pub command: Command,
+    pub config: Config,
+    /// The config file given with `--config`
+    pub config_path: Option<PathBuf>,
+    /// Only `None` for commands that don't use a database
+    pub database_path: Option<PathBuf>,
+    pub force_folder: Option<String>,
+    pub layout: Template,
+    pub on_collision: CollisionPolicy,
+    pub files_from: Option<PathBuf>,
+    /// Port of the camera to archive over PTP, `Some` with `None` for the only
+    /// one connected
+    pub camera: Option<Option<String>>,
+    /// Folder files are downloaded from the camera into
+    pub camera_staging: Option<PathBuf>,
+    /// Whether the mounted cards registered in the config are archived
+    pub auto: bool,
+    pub card_folders: Vec<String>,
+    pub pick_card_folder: bool,
+    pub dates: Option<DateMapping>,
+    pub date_fallback: DateFallback,
+    pub track: Option<Track>,
+    pub gpx_sidecars: bool,
+    /// ffprobe binary to use instead of the one on the `PATH`. Commands
+    /// reading new files replace it with the one that runs, if any.
+    pub ffprobe: Option<PathBuf>,
+    /// Extensions of files that are never indexed, from the config and the
+    /// command line
+    pub ignore_extensions: Vec<String>,
+    /// Extensions files need to have to be indexed, any if empty
+    pub include_extensions: Vec<String>,
+    /// Files smaller than this many bytes are skipped
+    pub min_size: u64,
+    /// Whether scans follow symbolic links
+    pub follow_symlinks: bool,
+    /// Whether files whose name starts with a dot are indexed
+    pub include_hidden: bool,
+    /// Globs of folder names that are skipped, from the config and the
+    /// command line
+    pub ignore_dirs: Vec<String>,
+    /// Globs of files and folders that are never indexed, from the config
+    /// and the command line
+    pub exclude: Vec<String>,
+    pub duplicates: DuplicatePolicy,
+    pub hash: Option<HashAlgorithm>,
+    pub chunk_size: Option<u64>,
+    pub dst_policy: Option<DstPolicy>,
+    pub timezone: TimeZonePolicy,
+    pub cull: CullPolicy,
+    pub skip_paired_jpegs: bool,
+    pub burst_folders: bool,
+    pub move_files: bool,
+    pub eject: bool,
+    pub tag_card: bool,
     pub clean: bool,
     pub dry: bool,
+    pub output: OutputFormat,
     pub leave: bool,
+    pub retry_failed: bool,
+    pub full_scan: bool,
+    pub fail_on_access_errors: bool,
+    /// Whether files that fail make the whole run fail
+    pub strict: bool,
+    pub background: bool,
+    pub notify: bool,
+    pub guided: bool,
+    pub snapshot: bool,
+    pub paranoid: bool,
+    pub fsync: bool,
+    pub force: bool,
+    pub preserve: Preserve,
+    pub retention_days: u64,
+    /// Threads hashing and reading the metadata of new files, and copying
+    /// images into the archive
+    pub jobs: Option<usize>,
+    /// Targets given after the first, which archived images are copied to
+    pub mirrors: Vec<PathBuf>,
+}
+
+/// `target` or `source`, the tables of `--in`
+fn parse_table(s: &str) -> anyhow::Result<TableType> {
+    match s {
+        "target" => Ok(TableType::Disk),
+        "source" => Ok(TableType::Camera),
+        _ => bail!("Unknown table {:?} (Expected target or source)", s),
+    }
 }
 
 fn parse_path(os_str: &OsStr) -> Result<PathBuf, &'static str> {
     Ok(PathBuf::from(os_str))
 }
 
+/// Every remaining free argument, as paths
+fn free_paths(pargs: &mut pico_args::Arguments) -> Vec<PathBuf> {
+    std::iter::from_fn(|| pargs.opt_free_from_os_str(parse_path).unwrap()).collect()
+}
+
 pub fn parse_args() -> anyhow::Result<AppArgs> {
     let mut pargs = pico_args::Arguments::from_env();
 
@@ -32,23 +447,562 @@ pub fn parse_args() -> anyhow::Result<AppArgs> {
         std::process::exit(0);
     }
 
-    let target_dir = pargs
-        .opt_value_from_os_str("--target", parse_path)
-        .unwrap()
-        .or_else(|| env::var_os("RAWDB_TARGET").map(PathBuf::from))
-        .ok_or_else(|| anyhow::anyhow!("--target or RAWDB_TARGET must be set"))?;
+    let config_path = pargs.opt_value_from_os_str("--config", parse_path).unwrap();
+    let config = load_config(config_path.as_deref())?;
+
+    let mut mirrors = pargs.values_from_os_str("--target", parse_path).unwrap();
+    let target_dir = (!mirrors.is_empty()).then(|| mirrors.remove(0));
+    let database_path = pargs.opt_value_from_os_str("--db", parse_path).unwrap();
+
+    // Selecting an archive by name replaces both paths, so a database can't
+    // accidentally be paired with the target of another archive
+    let archive: Option<String> = pargs.opt_value_from_str("--archive")?;
+    let (target_dir, database_path) = match archive {
+        Some(name) => {
+            if target_dir.is_some() || database_path.is_some() {
+                bail!("--archive cannot be combined with --target or --db");
+            }
+            let Some(entry) = config.archives.get(&name) else {
+                bail!(
+                    "Unknown archive {:?} (Expected one of {:?})",
+                    name,
+                    config.archives.keys().collect::<Vec<_>>()
+                );
+            };
+            (Some(entry.target.clone()), Some(entry.db.clone()))
+        }
+        None => (
+            target_dir
+                .or_else(|| env::var_os("RAWDB_TARGET").map(PathBuf::from))
+                .or_else(|| config.target.clone()),
+            database_path
+                .or_else(|| env::var_os("RAWDB_DB").map(PathBuf::from))
+                .or_else(|| config.db.clone()),
+        ),
+    };
+
+    let force_folder: Option<String> = pargs.opt_value_from_str("--force-folder")?;
+    if let Some(folder) = &force_folder {
+        if folder.is_empty() || folder == "." || folder == ".." || folder.contains(['/', '\\']) {
+            bail!("--force-folder must be a single folder name: {:?}", folder);
+        }
+    }
 
-    let database_path = pargs
-        .opt_value_from_os_str("--db", parse_path)
+    let files_from = pargs
+        .opt_value_from_os_str("--files-from", parse_path)
+        .unwrap();
+
+    let card_folders: Vec<String> = pargs.values_from_str("--card-folder")?;
+    let pick_card_folder = pargs.contains("--pick-card-folder");
+    if pick_card_folder && !card_folders.is_empty() {
+        bail!("--pick-card-folder cannot be combined with --card-folder");
+    }
+
+    let dates = pargs
+        .opt_value_from_os_str("--dates-from", parse_path)
         .unwrap()
-        .or_else(|| env::var_os("RAWDB_DB").map(PathBuf::from))
-        .ok_or_else(|| anyhow::anyhow!("--db or RAWDB_DB must be set"))?;
+        .map(|path| DateMapping::load(&path))
+        .transpose()?;
+    let date_fallback: Option<DateFallback> = pargs.opt_value_from_str("--date-fallback")?;
+    let gpx_files = pargs.values_from_os_str("--gpx", parse_path).unwrap();
+    let gpx_sidecars = pargs.contains("--gpx-sidecars");
+    if gpx_sidecars && gpx_files.is_empty() {
+        bail!("--gpx-sidecars requires --gpx");
+    }
+    let duplicates: Option<DuplicatePolicy> = pargs.opt_value_from_str("--duplicates")?;
+    let ignore_ext: Vec<String> = pargs.values_from_str("--ignore-ext")?;
+    let include_ext: Vec<String> = pargs.values_from_str("--include-ext")?;
+    let ignore_dirs: Vec<String> = pargs.values_from_str("--ignore-dir")?;
+    let min_size: Option<u64> = pargs.opt_value_from_str("--min-size")?;
+    let follow_symlinks = pargs.contains("--follow-symlinks");
+    let no_follow_symlinks = pargs.contains("--no-follow-symlinks");
+    let include_hidden = pargs.contains("--include-hidden");
+    if follow_symlinks && no_follow_symlinks {
+        bail!("--follow-symlinks cannot be combined with --no-follow-symlinks");
+    }
+    let exclude: Vec<String> = pargs.values_from_str("--exclude")?;
+    let ffprobe = pargs
+        .opt_value_from_os_str("--ffprobe", parse_path)
+        .unwrap();
+    let ffprobe_given = ffprobe.is_some();
+    let ffprobe = ffprobe
+        .or_else(|| env::var_os("RAWDB_FFPROBE").map(PathBuf::from))
+        .or_else(|| config.ffprobe.clone());
+
+    let mirror_dir = pargs.opt_value_from_os_str("--mirror", parse_path).unwrap();
+    let sources = pargs.values_from_os_str("--source", parse_path).unwrap();
+    let camera_port: Option<String> = pargs.opt_value_from_str("--camera-port")?;
+    let camera = (pargs.contains("--camera") || camera_port.is_some()).then_some(camera_port);
+    let camera_staging = pargs
+        .opt_value_from_os_str("--camera-staging", parse_path)
+        .unwrap();
+    let auto = pargs.contains("--auto");
+    if auto && config.cards.is_empty() {
+        bail!("--auto requires the labels or UUIDs of cards in the config");
+    }
+
+    let month = pargs.opt_value_from_str("--month")?;
+    let staging_dir = pargs
+        .opt_value_from_os_str("--staging", parse_path)
+        .unwrap();
+    let parse_date = |date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d");
+    let from = pargs.opt_value_from_fn("--from", parse_date)?;
+    let to = pargs.opt_value_from_fn("--to", parse_date)?;
+    let pattern = pargs.opt_value_from_str("--match")?;
+    let table = pargs.opt_value_from_fn("--in", parse_table)?;
+    let disc_size: Option<u64> = pargs.opt_value_from_str("--disc-size")?;
+    let format: Option<DumpFormat> = pargs.opt_value_from_str("--format")?;
+    let dump_table: Option<TableType> = pargs.opt_value_from_str("--table")?;
+    let out = pargs.opt_value_from_os_str("--out", parse_path).unwrap();
+
+    // Options given on the command line win over the defaults of the config
+    let defaults = &config.defaults;
+    let hash = pargs.opt_value_from_str("--hash")?.or(defaults.hash);
+    let dst_policy = pargs
+        .opt_value_from_str("--dst-policy")?
+        .or(defaults.dst_policy);
+    let timezone: Option<TimeZonePolicy> = pargs.opt_value_from_str("--timezone")?;
+    let cull: Option<CullPolicy> = pargs.opt_value_from_str("--cull")?;
+    let layout: Option<Template> = pargs.opt_value_from_str("--layout")?;
+    let chunk_size: Option<u64> = pargs
+        .opt_value_from_str("--chunk-size")?
+        .or(defaults.chunk_size);
+    if let Some(chunk_size) = chunk_size {
+        if chunk_size != 0 && !(4..=16).contains(&chunk_size) {
+            bail!("--chunk-size must be between 4 and 16 MiB, or 0");
+        }
+    }
 
     let clean = pargs.contains(["-c", "--clean"]);
-    let dry = pargs.contains(["-d", "--dry-run"]);
+    let mut dry = pargs.contains(["-d", "--dry-run"]);
+    let output: Option<OutputFormat> = pargs.opt_value_from_str("--output")?;
     let leave = pargs.contains(["-l", "--leave"]);
+    let retry_failed = pargs.contains(["-r", "--retry-failed"]);
+    let full_scan = pargs.contains("--full-scan");
+    let fail_on_access_errors = pargs.contains("--fail-on-access-errors");
+    let strict = pargs.contains("--strict");
+    let background = pargs.contains(["-b", "--background"]);
+    let notify = pargs.contains("--notify");
+    let guided = pargs.contains("--guided");
+    let skip_paired_jpegs = pargs.contains("--skip-paired-jpegs");
+    let burst_folders = pargs.contains("--burst-folders");
+    let move_files = pargs.contains("--move");
+    let eject = pargs.contains("--eject");
+    let tag_card = pargs.contains("--tag-card");
+    let snapshot = pargs.contains("--snapshot");
+    if (snapshot || defaults.snapshot) && config.snapshot_command.is_none() {
+        bail!("--snapshot requires snapshot_command to be set in the config");
+    }
+    let paranoid = pargs.contains("--paranoid") || defaults.paranoid;
+    let fsync = pargs.contains("--fsync");
+    let force = pargs.contains("--force");
+    let preserve: Option<Preserve> = pargs.opt_value_from_str("--preserve")?;
+    let on_collision: Option<CollisionPolicy> = pargs.opt_value_from_str("--on-collision")?;
+    let fix = pargs.contains("--fix");
+    let adopt = pargs.contains("--adopt");
+    let json = pargs.contains("--json");
+    if paranoid && clean {
+        bail!("--clean cannot be used with --paranoid");
+    }
+    if paranoid && move_files {
+        bail!("--move cannot be used with --paranoid");
+    }
+    let retention_days: Option<u64> = pargs.opt_value_from_str("--retention-days")?;
+    let jobs: Option<usize> = pargs.opt_value_from_str(["-j", "--jobs"])?;
+    if jobs == Some(0) || defaults.jobs == Some(0) {
+        bail!("--jobs must be at least 1");
+    }
+
+    // Which commands each command specific option applies to
+    let specific: &[(&str, bool, &[&str])] = &[
+        (
+            "--force-folder",
+            force_folder.is_some(),
+            &["archive", "tether", "watch"],
+        ),
+        (
+            "--layout",
+            layout.is_some(),
+            &["archive", "tether", "watch"],
+        ),
+        (
+            "--on-collision",
+            on_collision.is_some(),
+            &["archive", "tether", "watch"],
+        ),
+        (
+            "--timezone",
+            timezone.is_some(),
+            &["archive", "index", "tether", "watch"],
+        ),
+        ("--source", !sources.is_empty(), &["archive"]),
+        ("--camera", camera.is_some(), &["archive"]),
+        ("--auto", auto, &["archive"]),
+        ("--eject", eject, &["archive", "watch"]),
+        ("--tag-card", tag_card, &["archive", "watch"]),
+        ("--camera-staging", camera_staging.is_some(), &["archive"]),
+        ("--files-from", files_from.is_some(), &["archive"]),
+        (
+            "--card-folder",
+            !card_folders.is_empty(),
+            &["archive", "watch"],
+        ),
+        ("--pick-card-folder", pick_card_folder, &["archive"]),
+        (
+            "--dates-from",
+            dates.is_some(),
+            &["archive", "index", "watch", "orphans"],
+        ),
+        (
+            "--date-fallback",
+            date_fallback.is_some(),
+            &["archive", "index", "watch", "orphans"],
+        ),
+        (
+            "--gpx",
+            !gpx_files.is_empty(),
+            &["archive", "tether", "watch"],
+        ),
+        (
+            "--ffprobe",
+            ffprobe_given,
+            &["archive", "index", "tether", "watch", "orphans"],
+        ),
+        (
+            "--ignore-ext",
+            !ignore_ext.is_empty(),
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--include-ext",
+            !include_ext.is_empty(),
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--min-size",
+            min_size.is_some(),
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--follow-symlinks",
+            follow_symlinks,
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--no-follow-symlinks",
+            no_follow_symlinks,
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--include-hidden",
+            include_hidden,
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--ignore-dir",
+            !ignore_dirs.is_empty(),
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--exclude",
+            !exclude.is_empty(),
+            &["archive", "index", "tether", "watch", "orphans", "verify"],
+        ),
+        (
+            "--duplicates",
+            duplicates.is_some(),
+            &["archive", "index", "watch"],
+        ),
+        ("--cull", cull.is_some(), &["archive", "watch"]),
+        (
+            "--skip-paired-jpegs",
+            skip_paired_jpegs,
+            &["archive", "watch"],
+        ),
+        ("--burst-folders", burst_folders, &["archive", "watch"]),
+        ("--move", move_files, &["archive", "watch"]),
+        ("--fsync", fsync, &["archive", "tether", "watch"]),
+        ("--force", force, &["archive", "watch"]),
+        (
+            "--preserve",
+            preserve.is_some(),
+            &["archive", "tether", "watch"],
+        ),
+        ("--guided", guided, &["archive"]),
+        (
+            "--dry-run",
+            dry,
+            &[
+                "archive",
+                "index",
+                "export",
+                "dedupe",
+                "prune",
+                "install-service",
+            ],
+        ),
+        ("--output", output.is_some(), &["archive", "watch"]),
+        ("--full-scan", full_scan, &["archive", "index", "watch"]),
+        (
+            "--fail-on-access-errors",
+            fail_on_access_errors,
+            &["archive", "index", "watch"],
+        ),
+        (
+            "--strict",
+            strict,
+            &["archive", "index", "watch", "orphans"],
+        ),
+        (
+            "--retry-failed",
+            retry_failed,
+            &["archive", "index", "watch"],
+        ),
+        ("--background", background, &["archive", "index"]),
+        ("--notify", notify, &["archive", "watch"]),
+        ("--snapshot", snapshot, &["archive", "index", "watch"]),
+        (
+            "--retention-days",
+            retention_days.is_some(),
+            &["archive", "watch"],
+        ),
+        ("--jobs", jobs.is_some(), &["archive", "index", "watch"]),
+        ("--mirror", mirror_dir.is_some(), &["verify", "repair"]),
+        ("--month", month.is_some(), &["report"]),
+        ("--out", out.is_some(), &["report", "inventory", "export"]),
+        ("--staging", staging_dir.is_some(), &["export"]),
+        ("--from", from.is_some(), &["export", "query"]),
+        ("--to", to.is_some(), &["export", "query"]),
+        ("--match", pattern.is_some(), &["export", "query"]),
+        ("--in", table.is_some(), &["query"]),
+        ("--disc-size", disc_size.is_some(), &["export"]),
+        ("--format", format.is_some(), &["export"]),
+        ("--table", dump_table.is_some(), &["export"]),
+        ("--fix", fix, &["collisions"]),
+        ("--adopt", adopt, &["orphans"]),
+        ("--json", json, &["db info", "query"]),
+    ];
+
+    let name = pargs.opt_free_from_str::<String>()?;
+    let target_dir =
+        || target_dir.ok_or_else(|| anyhow::anyhow!("--target or RAWDB_TARGET must be set"));
+    let command = match name.as_deref() {
+        Some("status") => Command::Status,
+        Some("stats") => Command::Stats,
+        Some("archives") => {
+            match pargs.opt_free_from_str::<String>()?.as_deref() {
+                None | Some("list") => {}
+                Some(other) => bail!("Unknown archives command {:?}", other),
+            }
+            Command::Archives
+        }
+        Some("tether") => Command::Tether {
+            session_dir: pargs
+                .opt_free_from_os_str(parse_path)
+                .unwrap()
+                .ok_or_else(|| anyhow::anyhow!("tether requires a session_dir"))?,
+            target_dir: target_dir()?,
+        },
+        Some("watch") => Command::Watch {
+            mount_dir: pargs
+                .opt_free_from_os_str(parse_path)
+                .unwrap()
+                .ok_or_else(|| anyhow::anyhow!("watch requires a mount_dir"))?,
+            target_dir: target_dir()?,
+        },
+        Some(name @ ("archive" | "import")) => {
+            let source_dirs = [free_paths(&mut pargs), sources].concat();
+            if source_dirs.is_empty() && camera.is_none() && !auto {
+                bail!("{} requires a source_dir", name);
+            }
+            Command::Archive {
+                source_dirs,
+                target_dir: target_dir()?,
+            }
+        }
+        Some("scan") => {
+            if dry {
+                bail!("scan never archives, it cannot be used with --dry-run");
+            }
+            dry = true;
+            Command::Archive {
+                source_dirs: [free_paths(&mut pargs), sources].concat(),
+                target_dir: target_dir()?,
+            }
+        }
+        Some("index") => Command::Index {
+            target_dir: target_dir()?,
+        },
+        Some("import-manifest") => Command::ImportManifest {
+            manifest: pargs
+                .opt_free_from_os_str(parse_path)
+                .unwrap()
+                .ok_or_else(|| anyhow::anyhow!("import-manifest requires a manifest"))?,
+            target_dir: target_dir()?,
+        },
+        Some("db") => match pargs.opt_free_from_str::<String>()?.as_deref() {
+            Some("info") => Command::DbInfo { json },
+            Some(other) => bail!("Unknown db command {:?}", other),
+            None => bail!("db requires a command, such as info"),
+        },
+        Some("verify") => Command::Verify {
+            target_dir: target_dir()?,
+            mirror_dir,
+        },
+        Some("repair") => Command::Repair {
+            target_dir: target_dir()?,
+            mirror_dir: mirror_dir
+                .ok_or_else(|| anyhow::anyhow!("repair requires --mirror <mirror_dir>"))?,
+        },
+        Some("locate") => Command::Locate {
+            pattern: pargs
+                .opt_free_from_str()?
+                .ok_or_else(|| anyhow::anyhow!("locate requires a file name pattern"))?,
+        },
+        Some("query") => Command::Query {
+            table: table.unwrap_or(TableType::Disk),
+            name: pargs.opt_free_from_str()?,
+            selection: Selection { from, to, pattern },
+            json,
+        },
+        Some("report") => Command::Report { month, out },
+        Some("inventory") => Command::Inventory {
+            target_dir: target_dir()?,
+            out,
+        },
+        Some("export") => match format {
+            Some(format) => {
+                // The options of disc exports don't apply to the tables
+                for (option, given) in [
+                    ("--staging", staging_dir.is_some()),
+                    ("--disc-size", disc_size.is_some()),
+                    ("--from", from.is_some()),
+                    ("--to", to.is_some()),
+                    ("--match", pattern.is_some()),
+                ] {
+                    if given {
+                        bail!("{} cannot be used with --format", option);
+                    }
+                }
+                Command::Dump {
+                    table: dump_table.unwrap_or(TableType::Disk),
+                    format,
+                    out,
+                }
+            }
+            None => {
+                if dump_table.is_some() || out.is_some() {
+                    bail!("export requires --format to write a table");
+                }
+                Command::Export {
+                    target_dir: target_dir()?,
+                    staging_dir: staging_dir
+                        .ok_or_else(|| anyhow::anyhow!("export requires --staging <dir>"))?,
+                    selection: Selection { from, to, pattern },
+                    disc_size: disc_size.unwrap_or(25) * 1_000_000_000,
+                }
+            }
+        },
+        Some("collisions") => Command::Collisions {
+            target_dir: target_dir()?,
+            fix,
+        },
+        Some("dedupe") => Command::Dedupe {
+            target_dir: target_dir()?,
+        },
+        Some("install-service") => {
+            if config.cards.is_empty() {
+                bail!("install-service requires the labels or UUIDs of cards in the config");
+            }
+            Command::InstallService {
+                target_dir: target_dir()?,
+            }
+        }
+        Some("prune") => Command::Prune {
+            target_dir: target_dir()?,
+        },
+        Some("orphans") => Command::Orphans {
+            target_dir: target_dir()?,
+            adopt,
+        },
+        Some(other) => bail!("Unknown command {:?} (See --help)", other),
+        None => bail!("A command is required, such as archive (See --help)"),
+    };
+
+    for (option, given, commands) in specific {
+        if *given && !commands.contains(&command.name()) {
+            bail!("{} cannot be used with {}", option, command.name());
+        }
+    }
+
+    let has_source =
+        matches!(&command, Command::Archive { source_dirs, .. } if !source_dirs.is_empty());
+    if files_from.is_some() && !has_source {
+        bail!("--files-from requires a source_dir");
+    }
+    // The list names the files of one source, the others may not hold them
+    let more_sources =
+        matches!(&command, Command::Archive { source_dirs, .. } if source_dirs.len() > 1);
+    if files_from.is_some() && (more_sources || camera.is_some() || auto) {
+        bail!("--files-from can only be used with a single source_dir");
+    }
+    if guided && !has_source {
+        bail!("--guided requires a source_dir to import");
+    }
+    if !mirrors.is_empty() && !matches!(command.name(), "archive" | "watch") {
+        bail!("Only archive and watch can use more than one --target");
+    }
+    if guided && dry {
+        bail!("--guided already shows the plan, it cannot be used with --dry-run");
+    }
+
+    let database_path = match command {
+        Command::Archives => None,
+        _ => Some(database_path.ok_or_else(|| anyhow::anyhow!("--db or RAWDB_DB must be set"))?),
+    };
+
+    let cull = cull.or(defaults.cull).unwrap_or_default();
+    let timezone = timezone.or(defaults.timezone).unwrap_or_default();
+    let date_fallback = date_fallback.or(defaults.date_fallback).unwrap_or_default();
+    let duplicates = duplicates.or(defaults.duplicates).unwrap_or_default();
+    if duplicates == DuplicatePolicy::Ask && matches!(command, Command::Watch { .. }) {
+        bail!("--duplicates ask cannot be used with watch");
+    }
+    let layout = layout
+        .or_else(|| defaults.layout.clone())
+        .unwrap_or_default();
+    let on_collision = on_collision.or(defaults.on_collision).unwrap_or_default();
+    let skip_paired_jpegs = skip_paired_jpegs || defaults.skip_paired_jpegs;
+    let burst_folders = burst_folders || defaults.burst_folders;
+    let full_scan = full_scan || defaults.full_scan;
+    // Originals are only deleted once their copies survive a power loss
+    let fsync = fsync || defaults.fsync || move_files;
+    let preserve = preserve.or(defaults.preserve).unwrap_or_default();
+    let strict = strict || defaults.strict;
+    let fail_on_access_errors = fail_on_access_errors || defaults.fail_on_access_errors || strict;
+    let background = background || defaults.background;
+    let notify = notify || defaults.notify;
+    let snapshot = snapshot || defaults.snapshot;
+    let retention_days = retention_days.or(defaults.retention_days).unwrap_or(14);
+    let jobs = jobs.or(defaults.jobs);
+
+    // Ignored extensions add up, included ones narrow down what is indexed
+    let ignore_extensions = [config.ignore_extensions.clone(), ignore_ext].concat();
+    let include_extensions = if include_ext.is_empty() {
+        config.include_extensions.clone()
+    } else {
+        include_ext
+    };
+    let min_size = min_size.or(config.defaults.min_size).unwrap_or(0);
+    let follow_symlinks =
+        follow_symlinks || (config.defaults.follow_symlinks && !no_follow_symlinks);
+    let include_hidden = include_hidden || config.defaults.include_hidden;
+    let ignore_dirs = [config.ignore_dirs.clone(), ignore_dirs].concat();
+    let exclude = [config.exclude.clone(), exclude].concat();
 
-    let source_dir = pargs.opt_free_from_os_str(parse_path).unwrap();
+    let track = (!gpx_files.is_empty())
+        .then(|| Track::load(&gpx_files))
+        .transpose()?;
 
     let remaining = pargs.finish();
     if !remaining.is_empty() {
@@ -56,11 +1010,60 @@ pub fn parse_args() -> anyhow::Result<AppArgs> {
     }
 
     Ok(AppArgs {
-        source_dir,
-        target_dir,
+        command,
+        config,
+        config_path,
         database_path,
+        force_folder,
+        layout,
+        on_collision,
+        files_from,
+        camera,
+        camera_staging,
+        auto,
+        eject,
+        tag_card,
+        card_folders,
+        pick_card_folder,
+        dates,
+        date_fallback,
+        track,
+        gpx_sidecars,
+        ffprobe,
+        ignore_extensions,
+        include_extensions,
+        min_size,
+        follow_symlinks,
+        include_hidden,
+        ignore_dirs,
+        exclude,
+        duplicates,
+        hash,
+        chunk_size: chunk_size.map(|size| size << 20),
+        dst_policy,
+        timezone,
+        cull,
+        skip_paired_jpegs,
+        burst_folders,
+        move_files,
         clean,
         dry,
+        output: output.unwrap_or_default(),
         leave,
+        retry_failed,
+        full_scan,
+        fail_on_access_errors,
+        strict,
+        background,
+        notify,
+        guided,
+        snapshot,
+        paranoid,
+        fsync,
+        force,
+        preserve,
+        retention_days,
+        jobs,
+        mirrors,
     })
 }
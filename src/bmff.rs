@@ -0,0 +1,240 @@
+//! Reading the boxes of ISO base media files, the container of HEIF images
+//! and of MP4 and QuickTime videos.
+
+use std::{
+    fs::File,
+    io::{Read, Seek},
+};
+
+use anyhow::bail;
+
+/// A box header: its type, and where its contents start and end
+pub struct BoxHeader {
+    pub kind: [u8; 4],
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Reads the big endian fields of a box in memory
+pub struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Cursor { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let Some(bytes) = self.data.get(self.pos..self.pos + len) else {
+            bail!("Truncated box");
+        };
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// The bytes left after the fields read so far
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        self.pos = self.data.len();
+        rest
+    }
+
+    /// An unsigned integer of 0, 2, 4 or 8 bytes
+    pub fn uint(&mut self, len: usize) -> anyhow::Result<u64> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u64::from(*byte)))
+    }
+
+    pub fn header(&mut self) -> anyhow::Result<Option<BoxHeader>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let start = self.pos as u64;
+        let size = self.uint(4)?;
+        let kind: [u8; 4] = self.bytes(4)?.try_into().unwrap();
+        let size = match size {
+            0 => self.data.len() as u64 - start,
+            1 => self.uint(8)?,
+            size => size,
+        };
+        let end = start
+            .checked_add(size)
+            .filter(|end| *end >= self.pos as u64 && *end <= self.data.len() as u64);
+        let Some(end) = end else {
+            bail!("Box {} overruns its parent", String::from_utf8_lossy(&kind));
+        };
+        Ok(Some(BoxHeader {
+            kind,
+            start: self.pos as u64,
+            end,
+        }))
+    }
+
+    /// The contents of the box
+    pub fn contents(&self, header: &BoxHeader) -> Cursor<'a> {
+        Cursor::new(&self.data[header.start as usize..header.end as usize])
+    }
+
+    /// The contents of the box, with the version and flags of a full box
+    /// skipped
+    pub fn full_box(&self, header: &BoxHeader) -> (u8, Cursor<'a>) {
+        let contents = &self.data[header.start as usize..header.end as usize];
+        (
+            contents.first().copied().unwrap_or(0),
+            Cursor::new(&contents[4.min(contents.len())..]),
+        )
+    }
+
+    pub fn skip_to(&mut self, header: &BoxHeader) {
+        self.pos = header.end as usize;
+    }
+}
+
+/// Read the header of the next top level box in the file
+pub fn read_header(file: &mut File, file_len: u64) -> anyhow::Result<Option<BoxHeader>> {
+    let start = file.stream_position()?;
+    if start >= file_len {
+        return Ok(None);
+    }
+    let mut header = [0; 8];
+    file.read_exact(&mut header)?;
+    let size = u64::from(u32::from_be_bytes(header[..4].try_into().unwrap()));
+    let kind: [u8; 4] = header[4..].try_into().unwrap();
+    let size = match size {
+        0 => file_len - start,
+        1 => {
+            let mut large = [0; 8];
+            file.read_exact(&mut large)?;
+            u64::from_be_bytes(large)
+        }
+        size => size,
+    };
+    let contents = file.stream_position()?;
+    let end = start
+        .checked_add(size)
+        .filter(|end| *end >= contents && *end <= file_len);
+    let Some(end) = end else {
+        bail!("Box {} overruns the file", String::from_utf8_lossy(&kind));
+    };
+    Ok(Some(BoxHeader {
+        kind,
+        start: contents,
+        end,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io::SeekFrom};
+
+    use super::*;
+
+    /// A box of `kind` with a 32-bit size field holding `size`
+    fn boxed(size: u32, kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        [&size.to_be_bytes()[..], kind, contents].concat()
+    }
+
+    /// The headers of the top level boxes of `data` read from a file, up to
+    /// the first error
+    fn read_file_headers(data: &[u8]) -> Vec<anyhow::Result<(Vec<u8>, u64, u64)>> {
+        let path = env::temp_dir().join(format!("rawdb-bmff-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, data).unwrap();
+        let mut file = File::open(&path).unwrap();
+        let mut headers = Vec::new();
+        loop {
+            match read_header(&mut file, data.len() as u64) {
+                Ok(Some(header)) => {
+                    file.seek(SeekFrom::Start(header.end)).unwrap();
+                    headers.push(Ok((header.kind.to_vec(), header.start, header.end)));
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    headers.push(Err(err));
+                    break;
+                }
+            }
+        }
+        fs::remove_file(&path).unwrap();
+        headers
+    }
+
+    #[test]
+    fn test_cursor_header() {
+        let data = [boxed(12, b"ftyp", b"heic"), boxed(9, b"free", b"!")].concat();
+        let mut cursor = Cursor::new(&data);
+        let first = cursor.header().unwrap().unwrap();
+        assert_eq!((&first.kind, first.start, first.end), (b"ftyp", 8, 12));
+        assert_eq!(cursor.contents(&first).rest(), b"heic");
+        cursor.skip_to(&first);
+        let second = cursor.header().unwrap().unwrap();
+        assert_eq!((&second.kind, second.start, second.end), (b"free", 20, 21));
+        cursor.skip_to(&second);
+        assert!(cursor.header().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cursor_header_sizes() {
+        // Size 0 runs to the end of the parent
+        let data = boxed(0, b"mdat", b"abc");
+        let header = Cursor::new(&data).header().unwrap().unwrap();
+        assert_eq!((header.start, header.end), (8, 11));
+
+        // Size 1 is followed by the 64-bit size
+        let data = boxed(1, b"mdat", &[&20u64.to_be_bytes()[..], b"abcd"].concat());
+        let header = Cursor::new(&data).header().unwrap().unwrap();
+        assert_eq!((header.start, header.end), (16, 20));
+
+        // Sizes that don't even cover the header, or overrun the parent
+        for size in [4, 7, 13, u32::MAX] {
+            let data = boxed(size, b"free", b"abcd");
+            assert!(Cursor::new(&data).header().is_err(), "size {}", size);
+        }
+        let data = boxed(1, b"mdat", &[&u64::MAX.to_be_bytes()[..], b"abcd"].concat());
+        assert!(Cursor::new(&data).header().is_err());
+
+        // Truncated headers
+        for data in [&b"\0\0\0\x0cft"[..], &boxed(1, b"mdat", &[0, 0, 0])] {
+            assert!(Cursor::new(data).header().is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_header() {
+        let data = [boxed(12, b"ftyp", b"qt  "), boxed(0, b"mdat", b"abc")].concat();
+        let headers = read_file_headers(&data)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            [(b"ftyp".to_vec(), 8, 12), (b"mdat".to_vec(), 20, 23)]
+        );
+
+        let data = boxed(1, b"mdat", &[&20u64.to_be_bytes()[..], b"abcd"].concat());
+        let headers = read_file_headers(&data);
+        assert_eq!(headers[0].as_ref().unwrap(), &(b"mdat".to_vec(), 16, 20));
+    }
+
+    #[test]
+    fn test_read_header_invalid() {
+        let invalid = [
+            // Smaller than its header
+            boxed(4, b"free", b"abcd"),
+            // Overrunning the file
+            boxed(13, b"free", b"abcd"),
+            [boxed(8, b"free", b""), boxed(9, b"mdat", b"")].concat(),
+            boxed(1, b"mdat", &[&64u64.to_be_bytes()[..], b"abcd"].concat()),
+            // Truncated headers
+            b"\0\0\0\x0cft".to_vec(),
+            boxed(1, b"mdat", &[0, 0, 0]),
+        ];
+        for data in invalid {
+            let headers = read_file_headers(&data);
+            assert!(headers.last().unwrap().is_err(), "{:?}", data);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, path::Path};
+
+use chrono::NaiveDateTime;
+
+/// Raw formats whose in-camera JPEGs are grouped with them
+const RAW_EXT: &[&str] = &[
+    "raf", "cr2", "cr3", "nef", "arw", "orf", "rw2", "pef", "dng",
+];
+const JPEG_EXT: &[&str] = &["jpg", "jpeg"];
+
+/// A RAW file and the JPEGs the camera developed from it, either recorded
+/// as a RAW+JPEG pair or as the film simulation bracketing of Fuji cameras
+pub struct BracketSet {
+    pub raw: String,
+    pub members: Vec<String>,
+}
+
+/// Split a path into its folder, stem and lowercase extension
+fn split(path: &str) -> Option<(&str, &str, String)> {
+    let path_ref = Path::new(path);
+    let folder = path_ref.parent()?.to_str()?;
+    let stem = path_ref.file_stem()?.to_str()?;
+    let ext = path_ref.extension()?.to_str()?.to_lowercase();
+    Some((folder, stem, ext))
+}
+
+/// The RAW stem a JPEG stem belongs to: the stem itself, or the stem
+/// without a `_<n>` or `-<n>` bracketing suffix
+fn raw_stem_candidates(stem: &str) -> impl Iterator<Item = &str> {
+    let unsuffixed = stem
+        .rsplit_once(['_', '-'])
+        .filter(|(_, suffix)| !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()))
+        .map(|(base, _)| base);
+    std::iter::once(stem).chain(unsuffixed)
+}
+
+/// Group the JPEGs among `images` with the RAW file of the same name in the
+/// same folder, taken at the same time
+pub fn find_bracket_sets<'a>(
+    images: impl IntoIterator<Item = (&'a str, NaiveDateTime)>,
+) -> Vec<BracketSet> {
+    let images = images.into_iter().collect::<Vec<_>>();
+    let mut raws: HashMap<(&str, &str), (NaiveDateTime, BracketSet)> = HashMap::new();
+    for (path, date) in &images {
+        if let Some((folder, stem, ext)) = split(path) {
+            if RAW_EXT.contains(&ext.as_str()) {
+                raws.insert(
+                    (folder, stem),
+                    (
+                        *date,
+                        BracketSet {
+                            raw: path.to_string(),
+                            members: Vec::new(),
+                        },
+                    ),
+                );
+            }
+        }
+    }
+
+    for (path, date) in &images {
+        let Some((folder, stem, ext)) = split(path) else {
+            continue;
+        };
+        if !JPEG_EXT.contains(&ext.as_str()) {
+            continue;
+        }
+        // A recycled file name from another day is not part of the set
+        let raw_stem = raw_stem_candidates(stem).find(|raw_stem| {
+            raws.get(&(folder, *raw_stem))
+                .is_some_and(|(raw_date, _)| raw_date == date)
+        });
+        if let Some(raw_stem) = raw_stem {
+            if let Some((_, set)) = raws.get_mut(&(folder, raw_stem)) {
+                set.members.push(path.to_string());
+            }
+        }
+    }
+
+    let mut sets = raws
+        .into_values()
+        .map(|(_, set)| set)
+        .filter(|set| !set.members.is_empty())
+        .collect::<Vec<_>>();
+    sets.sort_by(|a, b| a.raw.cmp(&b.raw));
+    for set in &mut sets {
+        set.members.sort();
+    }
+    sets
+}
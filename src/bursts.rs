@@ -0,0 +1,122 @@
+use std::{collections::BTreeMap, path::Path};
+
+use chrono::{NaiveDateTime, TimeDelta};
+
+/// Longest time between two frames of a burst, capture times only have a
+/// resolution of seconds
+const MAX_INTERVAL: TimeDelta = TimeDelta::seconds(2);
+
+/// Fewest frames taken in a row that count as a burst
+const MIN_FRAMES: usize = 3;
+
+/// Images taken in a quick sequence, with consecutive file numbers
+pub struct Burst {
+    /// Paths of every frame in the order they were taken, with the RAW and
+    /// JPEG of a frame next to each other
+    pub paths: Vec<String>,
+}
+
+impl Burst {
+    /// Name of the subfolder the burst is archived into with
+    /// `--burst-folders`, after its first frame
+    pub fn folder_name(&self) -> String {
+        let first = Path::new(&self.paths[0])
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        format!("burst-{}", first)
+    }
+}
+
+/// Split a path into its folder, the name prefix and the file number, such
+/// as `DSCF` and `1234` for `100_FUJI/DSCF1234.RAF`
+fn split(path: &str) -> Option<(&str, &str, u32)> {
+    let path_ref = Path::new(path);
+    let folder = path_ref.parent()?.to_str()?;
+    let stem = path_ref.file_stem()?.to_str()?;
+    let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = stem[prefix.len()..].parse().ok()?;
+    Some((folder, prefix, number))
+}
+
+/// The files sharing a file number, like the RAW and JPEG of one shot
+struct Frame<'a> {
+    folder: &'a str,
+    prefix: &'a str,
+    number: u32,
+    date: NaiveDateTime,
+    paths: Vec<String>,
+}
+
+impl Frame<'_> {
+    fn follows(&self, prev: &Frame) -> bool {
+        (self.folder, self.prefix) == (prev.folder, prev.prefix)
+            && prev.number.checked_add(1) == Some(self.number)
+            && (self.date - prev.date).abs() <= MAX_INTERVAL
+    }
+}
+
+/// Find the bursts among `images`: at least three frames in one folder with
+/// consecutive file numbers, each taken shortly after the one before
+pub fn find_bursts<'a>(images: impl IntoIterator<Item = (&'a str, NaiveDateTime)>) -> Vec<Burst> {
+    let mut frames: BTreeMap<(&str, &str, u32), Frame> = BTreeMap::new();
+    for (path, date) in images {
+        if let Some((folder, prefix, number)) = split(path) {
+            frames
+                .entry((folder, prefix, number))
+                .or_insert(Frame {
+                    folder,
+                    prefix,
+                    number,
+                    date,
+                    paths: Vec::new(),
+                })
+                .paths
+                .push(path.to_owned());
+        }
+    }
+
+    let mut bursts = Vec::new();
+    let mut current: Vec<Frame> = Vec::new();
+    for mut frame in frames.into_values() {
+        if !current.last().is_some_and(|prev| frame.follows(prev)) {
+            finish(&mut current, &mut bursts);
+        }
+        frame.paths.sort();
+        current.push(frame);
+    }
+    finish(&mut current, &mut bursts);
+
+    bursts
+}
+
+fn finish(current: &mut Vec<Frame>, bursts: &mut Vec<Burst>) {
+    let frames = std::mem::take(current);
+    if frames.len() >= MIN_FRAMES {
+        bursts.push(Burst {
+            paths: frames.into_iter().flat_map(|frame| frame.paths).collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_bursts() {
+        let date = |time: &str| crate::dates::parse_date(time).unwrap();
+        let bursts = find_bursts([
+            ("a/DSCF0098.RAF", date("2024-05-01 10:00:00")),
+            ("a/DSCF0099.RAF", date("2024-05-01 10:00:00")),
+            ("a/DSCF0099.JPG", date("2024-05-01 10:00:00")),
+            ("a/DSCF0100.RAF", date("2024-05-01 10:00:01")),
+            ("a/DSCF0101.RAF", date("2024-05-01 10:05:00")),
+            ("a/DSCF0102.RAF", date("2024-05-01 10:05:00")),
+            ("b/DSCF0103.RAF", date("2024-05-01 10:05:01")),
+        ]);
+        assert_eq!(bursts.len(), 1);
+        assert_eq!(bursts[0].paths.len(), 4);
+        assert_eq!(bursts[0].folder_name(), "burst-DSCF0098");
+    }
+}
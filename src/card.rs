@@ -0,0 +1,34 @@
+use std::{fs, io, path::Path};
+
+use anyhow::Context;
+use log::{info, warn};
+
+/// Marker file written to the root of a source, identifying the card
+pub const CARD_ID_FILE: &str = ".rawdb-card-id";
+
+/// Read the identifier of the card at `source_dir`, optionally assigning it
+/// a new one if it has none yet. A card that can't be written to, such as a
+/// locked one, is left without an id.
+pub fn get_card_id(source_dir: &Path, create: bool) -> anyhow::Result<Option<String>> {
+    let id_path = source_dir.join(CARD_ID_FILE);
+    match fs::read_to_string(&id_path) {
+        Ok(id) => Ok(Some(id.trim().to_owned())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound && create => {
+            let id = uuid::Uuid::new_v4().to_string();
+            if let Err(err) = fs::write(&id_path, format!("{}\n", id)) {
+                warn!(
+                    "Failed to write card id to {}, telling the card apart by its path: {}",
+                    id_path.display(),
+                    err
+                );
+                return Ok(None);
+            }
+            info!("Assigned id {} to card at {}", id, source_dir.display());
+            Ok(Some(id))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to read card id from {}", id_path.display()))
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+use chrono::NaiveDateTime;
+use rusqlite::Connection;
+
+use crate::{
+    db::{self, CatalogCounts, Provenance, QueriedImage, TableStats, TableType},
+    error,
+};
+
+/// An image database. Derefs to its connection, so the functions of
+/// [`db`] can be used on it directly.
+pub struct Catalog {
+    conn: Connection,
+}
+
+impl Catalog {
+    /// Open the database at `path`, creating or upgrading it as needed.
+    /// `clean` resets it.
+    pub fn open(path: &Path, clean: bool) -> error::Result<Self> {
+        Ok(Catalog {
+            conn: db::create_conn(path, clean)?,
+        })
+    }
+
+    /// Open the database like [`Catalog::open`] for `--paranoid` runs. It
+    /// is never reset, and nothing in it is deleted.
+    pub fn open_paranoid(path: &Path, clean: bool) -> error::Result<Self> {
+        Ok(Catalog {
+            conn: db::create_paranoid_conn(path, clean)?,
+        })
+    }
+
+    /// Open an existing database read-only, without upgrading it
+    pub fn open_existing(path: &Path) -> error::Result<Self> {
+        Ok(Catalog {
+            conn: db::open_existing(path)?,
+        })
+    }
+
+    /// Number of images in each table, and of camera images not yet archived
+    pub fn counts(&self) -> error::Result<CatalogCounts> {
+        Ok(db::get_catalog_counts(&self.conn)?)
+    }
+
+    /// Images of `table` whose name and path match the globs, taken from
+    /// `start` up to `end`
+    pub fn query(
+        &self,
+        table: TableType,
+        start: Option<NaiveDateTime>,
+        end: Option<NaiveDateTime>,
+        name: Option<&str>,
+        path: Option<&str>,
+    ) -> error::Result<Vec<QueriedImage>> {
+        Ok(db::query_images(&self.conn, table, start, end, name, path)?)
+    }
+
+    /// Where archived images with a name matching the glob `pattern` came from
+    pub fn locate(&self, pattern: &str) -> error::Result<Vec<Provenance>> {
+        Ok(db::find_provenance(&self.conn, pattern)?)
+    }
+
+    pub fn stats(&self, table: TableType) -> error::Result<TableStats> {
+        Ok(db::get_table_stats(&self.conn, table)?)
+    }
+
+    /// Count and size of the camera images not yet archived
+    pub fn unsaved(&self) -> error::Result<(u64, u64)> {
+        Ok(db::get_unsaved_stats(&self.conn)?)
+    }
+
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+}
+
+impl Deref for Catalog {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl DerefMut for Catalog {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt;
+
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Deserializer};
+
+/// A camera whose clock was off, from the `[[clock_offsets]]` of the config.
+/// Its images are dated and placed by the corrected time.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClockOffset {
+    /// The model, as recorded in the metadata, or the serial number of the
+    /// body
+    pub camera: String,
+    /// Added to the times the camera recorded, such as `-00:03` for a clock
+    /// three minutes fast
+    #[serde(deserialize_with = "deserialize_offset")]
+    pub offset: TimeDelta,
+    /// First day, by the camera's clock, the offset applies to
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub from: Option<NaiveDate>,
+    /// Last day, by the camera's clock, the offset applies to
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub to: Option<NaiveDate>,
+}
+
+impl ClockOffset {
+    /// Whether the offset corrects an image taken at `date` by the camera
+    /// `model` with the body `serial`
+    pub fn applies_to(
+        &self,
+        model: Option<&str>,
+        serial: Option<&str>,
+        date: NaiveDateTime,
+    ) -> bool {
+        let camera = Some(self.camera.as_str());
+        (model == camera || serial == camera)
+            && self.from.is_none_or(|from| date.date() >= from)
+            && self.to.is_none_or(|to| date.date() <= to)
+    }
+}
+
+/// An offset of `[+-]HH:MM[:SS]`
+fn parse_offset(s: &str) -> Option<TimeDelta> {
+    let (sign, rest) = match s.trim().split_at_checked(1)? {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return None,
+    };
+    let parts = rest
+        .split(':')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [hours, minutes] => (hours, minutes, 0),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return None,
+    };
+    if minutes >= 60 || seconds >= 60 {
+        return None;
+    }
+    let seconds = i64::from(hours) * 3600 + i64::from(minutes) * 60 + i64::from(seconds);
+    Some(TimeDelta::seconds(sign * seconds))
+}
+
+/// Shows an offset like the config writes it, such as `-00:03:00`
+pub struct DisplayOffset(pub TimeDelta);
+
+impl fmt::Display for DisplayOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < TimeDelta::zero() { '-' } else { '+' };
+        let seconds = self.0.num_seconds().abs();
+        write!(
+            f,
+            "{}{:02}:{:02}:{:02}",
+            sign,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+fn deserialize_offset<'de, D: Deserializer<'de>>(de: D) -> Result<TimeDelta, D::Error> {
+    let offset = String::deserialize(de)?;
+    parse_offset(&offset).ok_or_else(|| {
+        serde::de::Error::custom(format!(
+            "invalid offset {:?}, expected +HH:MM or -HH:MM:SS",
+            offset
+        ))
+    })
+}
+
+fn deserialize_date<'de, D: Deserializer<'de>>(de: D) -> Result<Option<NaiveDate>, D::Error> {
+    let date = String::deserialize(de)?;
+    NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+        .map(Some)
+        .map_err(|_| {
+            serde::de::Error::custom(format!("invalid date {:?}, expected YYYY-MM-DD", date))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_offsets() {
+        #[derive(Deserialize)]
+        struct Config {
+            clock_offsets: Vec<ClockOffset>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            clock_offsets = [
+                { camera = "X-T4", offset = "-00:03" },
+                { camera = "1234", offset = "+07:00", from = "2024-05-01", to = "2024-05-31" },
+            ]
+        "#,
+        )
+        .unwrap();
+        let [fast, travel] = &config.clock_offsets[..] else {
+            panic!("Expected two offsets");
+        };
+        assert_eq!(DisplayOffset(fast.offset).to_string(), "-00:03:00");
+
+        let date = |day: &str| crate::dates::parse_date(day).unwrap();
+        assert!(fast.applies_to(Some("X-T4"), None, date("2024-05-10")));
+        assert!(!fast.applies_to(Some("X-T5"), None, date("2024-05-10")));
+        assert!(travel.applies_to(Some("X-T4"), Some("1234"), date("2024-05-31 23:00")));
+        assert!(!travel.applies_to(Some("X-T4"), Some("1234"), date("2024-06-01")));
+
+        let invalid =
+            toml::from_str::<Config>(r#"clock_offsets = [{ camera = "a", offset = "3m" }]"#);
+        assert!(invalid.is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use anyhow::Context;
+use log::{info, warn};
+use rusqlite::{Connection, TransactionBehavior};
+
+use crate::db::{is_name_archived, rename_archived, NameCollision};
+
+/// What happens to an image whose name is already taken in its folder of the
+/// archive by a different file
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Fail the image, so it is tried again on every run
+    #[default]
+    Error,
+    /// Leave the image on the card without recording a failure
+    Skip,
+    /// Archive the image as `<stem>-<n>.<ext>`
+    Suffix,
+}
+
+impl CollisionPolicy {
+    pub fn label(&self) -> &'static str {
+        match self {
+            CollisionPolicy::Error => "error",
+            CollisionPolicy::Skip => "skip",
+            CollisionPolicy::Suffix => "suffix",
+        }
+    }
+}
+
+impl FromStr for CollisionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(CollisionPolicy::Error),
+            "skip" => Ok(CollisionPolicy::Skip),
+            "suffix" => Ok(CollisionPolicy::Suffix),
+            _ => anyhow::bail!(
+                "Unknown collision policy {:?} (Expected error, skip or suffix)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CollisionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// `name` with a counter appended to its stem, such as `DSCF0001-2.RAF`
+pub fn numbered_name(name: &str, n: u32) -> String {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext),
+        None => format!("{}-{}", stem, n),
+    }
+}
+
+/// Rename every file of a collision but the first to `<stem>-<n>.<ext>`, so
+/// each archived name is unique again. Copies of the first file keep the
+/// name, they are duplicates rather than a collision.
+pub fn fix_collision(
+    conn: &mut Connection,
+    target_dir: &Path,
+    collision: &NameCollision,
+) -> anyhow::Result<()> {
+    let Some((first, others)) = collision.files.split_first() else {
+        return Ok(());
+    };
+    let mut suffix = 2;
+    for file in others {
+        if file.checksum.is_some() && file.checksum == first.checksum && file.size == first.size {
+            continue;
+        }
+        let path = Path::new(&file.path);
+        let folder = path.parent().unwrap_or(Path::new(""));
+
+        let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let new_path = loop {
+            let new_name = numbered_name(&collision.name, suffix);
+            suffix += 1;
+            let new_path = folder.join(&new_name);
+            if !is_name_archived(&trans, &new_name)? && !target_dir.join(&new_path).exists() {
+                break new_path;
+            }
+        };
+        let new_path = new_path
+            .to_str()
+            .with_context(|| format!("Path {} is not utf8", new_path.display()))?;
+
+        let abs_path = target_dir.join(path);
+        let new_abs_path = target_dir.join(new_path);
+        fs::rename(&abs_path, &new_abs_path)
+            .with_context(|| format!("Failed to rename {}", abs_path.display()))?;
+        let res = rename_archived(&trans, &file.path, new_path)
+            .and_then(|()| trans.commit().map_err(Into::into));
+        if let Err(err) = res {
+            // The index still has the old name
+            if let Err(undo_err) = fs::rename(&new_abs_path, &abs_path) {
+                warn!(
+                    "Failed to rename {} back to {}: {}",
+                    new_abs_path.display(),
+                    abs_path.display(),
+                    undo_err
+                );
+            }
+            return Err(err);
+        }
+
+        info!("Renamed {} to {}", file.path, new_path);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use chrono::NaiveTime;
+use log::debug;
+use serde::{Deserialize, Deserializer};
+
+use rawdb::{
+    clock::ClockOffset,
+    collisions::CollisionPolicy,
+    cull::CullPolicy,
+    dates::DateFallback,
+    duplicates::DuplicatePolicy,
+    hash::HashAlgorithm,
+    images::Preserve,
+    layout::{DstPolicy, Template, TimeZonePolicy},
+};
+
+use crate::reporter::ReporterConfig;
+
+/// A database and target directory registered under a name
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveEntry {
+    pub db: PathBuf,
+    pub target: PathBuf,
+}
+
+/// Options used when they aren't given on the command line
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub hash: Option<HashAlgorithm>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub dst_policy: Option<DstPolicy>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub timezone: Option<TimeZonePolicy>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub date_fallback: Option<DateFallback>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub duplicates: Option<DuplicatePolicy>,
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub cull: Option<CullPolicy>,
+    /// Folders images are archived into, like `--layout`
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub layout: Option<Template>,
+    /// Like `--on-collision`
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub on_collision: Option<CollisionPolicy>,
+    /// Attributes copies keep, like `--preserve`
+    #[serde(default, deserialize_with = "deserialize_from_str")]
+    pub preserve: Option<Preserve>,
+    /// In MiB, like `--chunk-size`
+    pub chunk_size: Option<u64>,
+    /// In bytes, like `--min-size`
+    pub min_size: Option<u64>,
+    pub retention_days: Option<u64>,
+    pub jobs: Option<usize>,
+    #[serde(default)]
+    pub skip_paired_jpegs: bool,
+    #[serde(default)]
+    pub burst_folders: bool,
+    #[serde(default)]
+    pub full_scan: bool,
+    #[serde(default)]
+    pub fail_on_access_errors: bool,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub background: bool,
+    #[serde(default)]
+    pub notify: bool,
+    #[serde(default)]
+    pub snapshot: bool,
+    #[serde(default)]
+    pub paranoid: bool,
+    #[serde(default)]
+    pub fsync: bool,
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    #[serde(default)]
+    pub include_hidden: bool,
+}
+
+/// Settings read from `config.toml`
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Target directory used without `--target` or `RAWDB_TARGET`
+    pub target: Option<PathBuf>,
+    /// Database used without `--db` or `RAWDB_DB`
+    pub db: Option<PathBuf>,
+    /// ffprobe binary used without `--ffprobe` or `RAWDB_FFPROBE`, instead of
+    /// the one on the `PATH`
+    pub ffprobe: Option<PathBuf>,
+
+    /// Extensions of files that are never indexed, besides the sidecar
+    /// files that always are skipped
+    #[serde(default)]
+    pub ignore_extensions: Vec<String>,
+    /// Extensions files need to have to be indexed, such as `["raf", "jpg",
+    /// "mov"]`. Every extension that isn't ignored is indexed if empty.
+    #[serde(default)]
+    pub include_extensions: Vec<String>,
+    /// Globs of folder names that are skipped with everything below them,
+    /// besides the trash and thumbnail folders that always are
+    #[serde(default)]
+    pub ignore_dirs: Vec<String>,
+    /// Globs of files and folders that are never indexed, like `--exclude`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Volume labels or UUIDs of cards `archive --auto` archives when they
+    /// are mounted, such as `["EOS_DIGITAL", "1A2B-3C4D"]`
+    #[serde(default)]
+    pub cards: Vec<String>,
+
+    #[serde(default)]
+    pub defaults: Defaults,
+
+    /// Archives that can be selected with `--archive <name>`
+    #[serde(default)]
+    pub archives: BTreeMap<String, ArchiveEntry>,
+
+    /// Time of day folders roll over to the next date, so a late night shoot
+    /// stays in the folder of the evening it started
+    #[serde(default, deserialize_with = "deserialize_time")]
+    pub day_starts_at: Option<NaiveTime>,
+
+    /// Cameras whose clock was off, by model or serial number, such as
+    /// `{ camera = "X-T4", offset = "-00:03" }`
+    #[serde(default)]
+    pub clock_offsets: Vec<ClockOffset>,
+
+    /// Command run by `--snapshot` after a successful run, such as
+    /// `btrfs subvolume snapshot -r {target} /snapshots/{name}`
+    pub snapshot_command: Option<String>,
+
+    /// Webhook and address each archive run is reported to, such as
+    /// `[reporter] webhook = "https://example.com/hooks/rawdb"`
+    #[serde(default)]
+    pub reporter: ReporterConfig,
+
+    /// Months after which a copy of a file that wasn't verified again makes
+    /// `rawdb status` fail, 12 if unset
+    pub verify_every_months: Option<u32>,
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(de: D) -> Result<Option<NaiveTime>, D::Error> {
+    let time = String::deserialize(de)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom(format!("invalid time {:?}, expected HH:MM", time)))
+}
+
+fn deserialize_from_str<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let value = String::deserialize(de)?;
+    value.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// `$XDG_CONFIG_HOME/rawdb/config.toml`, falling back to `~/.config`
+fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("rawdb").join("config.toml"))
+}
+
+/// Load the config file at `path`, or the default one if it exists
+pub fn load_config(path: Option<&Path>) -> anyhow::Result<Config> {
+    let (path, required) = match path {
+        Some(path) => (path.to_owned(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+            return Ok(Config::default());
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read config {}", path.display()));
+        }
+    };
+    debug!("Loading config from {}", path.display());
+
+    toml::from_str(&text).with_context(|| format!("Invalid config {}", path.display()))
+}
@@ -0,0 +1,55 @@
+use std::{fmt, str::FromStr};
+
+/// Which images marked in camera are left out of the archive
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CullPolicy {
+    /// Archive every image
+    #[default]
+    Keep,
+    /// Skip images marked for deletion, rated -1
+    Rejected,
+    /// Also skip images explicitly rated 0
+    Unrated,
+}
+
+impl CullPolicy {
+    pub fn label(&self) -> &'static str {
+        match self {
+            CullPolicy::Keep => "keep",
+            CullPolicy::Rejected => "rejected",
+            CullPolicy::Unrated => "unrated",
+        }
+    }
+
+    /// Whether an image with this rating is skipped. Images without a rating
+    /// are always archived.
+    pub fn skips(&self, rating: Option<i32>) -> bool {
+        match (self, rating) {
+            (_, None) | (CullPolicy::Keep, _) => false,
+            (CullPolicy::Rejected, Some(rating)) => rating < 0,
+            (CullPolicy::Unrated, Some(rating)) => rating <= 0,
+        }
+    }
+}
+
+impl FromStr for CullPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(CullPolicy::Keep),
+            "rejected" => Ok(CullPolicy::Rejected),
+            "unrated" => Ok(CullPolicy::Unrated),
+            _ => anyhow::bail!(
+                "Unknown cull policy {:?} (Expected keep, rejected or unrated)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CullPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
@@ -0,0 +1,148 @@
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+use anyhow::Context;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime};
+
+use crate::layout::TimeZonePolicy;
+
+/// Capture dates for files without usable metadata, such as film scans, read
+/// from a CSV or JSON file mapping file names or globs to dates
+pub struct DateMapping {
+    entries: Vec<(String, NaiveDateTime)>,
+}
+
+/// Value recorded as the date source of images dated by the mapping
+pub const DATE_SOURCE: &str = "dates-from mapping";
+
+/// Value recorded as the date source of images dated by their modification
+/// time, marking the date as a guess
+pub const MTIME_DATE_SOURCE: &str = "file mtime (low confidence)";
+
+/// Where files without usable metadata that the mapping doesn't cover get
+/// their date from
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DateFallback {
+    /// Leave them unarchived
+    #[default]
+    None,
+    /// Use the file modification time, which copying may have changed
+    Mtime,
+}
+
+impl DateFallback {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DateFallback::None => "none",
+            DateFallback::Mtime => "mtime",
+        }
+    }
+}
+
+impl FromStr for DateFallback {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DateFallback::None),
+            "mtime" => Ok(DateFallback::Mtime),
+            _ => anyhow::bail!("Unknown date fallback {:?} (Expected none or mtime)", s),
+        }
+    }
+}
+
+impl fmt::Display for DateFallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Modification time of the file at `path`, in the zone camera clocks are
+/// set to
+pub fn mtime_date(path: &Path, timezone: TimeZonePolicy) -> io::Result<DateTime<FixedOffset>> {
+    let modified = DateTime::<Local>::from(fs::metadata(path)?.modified()?);
+    Ok(timezone.localize(modified.fixed_offset()))
+}
+
+/// A `YYYY-MM-DD` date, optionally followed by a time
+pub fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    let date = date.trim();
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_time(Default::default()))
+        })
+}
+
+/// Match `text` against a glob with `*` and `?` wildcards
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.split_first(), text.split_first()) {
+        (None, _) => text.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some((b'?', rest)), Some((_, text_rest))) => glob_match(rest, text_rest),
+        (Some((p, rest)), Some((t, text_rest))) => p == t && glob_match(rest, text_rest),
+        (Some(_), None) => false,
+    }
+}
+
+impl DateMapping {
+    /// Read `pattern,date` lines from a CSV file, or an object of patterns
+    /// to dates from a `.json` file. Dates are `YYYY-MM-DD`, optionally
+    /// followed by a time.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read date mapping {}", path.display()))?;
+
+        let rows = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&text)
+                .with_context(|| format!("Invalid date mapping {}", path.display()))?
+                .into_iter()
+                .map(|(pattern, date)| (pattern, date.as_str().unwrap_or_default().to_owned()))
+                .collect::<Vec<_>>()
+        } else {
+            text.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| match line.rsplit_once(',') {
+                    Some((pattern, date)) => (pattern.trim().to_owned(), date.to_owned()),
+                    None => (line.to_owned(), String::new()),
+                })
+                .collect()
+        };
+
+        let mut entries = Vec::with_capacity(rows.len());
+        for (i, (pattern, date)) in rows.into_iter().enumerate() {
+            match parse_date(&date) {
+                Some(date) => entries.push((pattern, date)),
+                // Allow a header row
+                None if i == 0 => continue,
+                None => anyhow::bail!(
+                    "Invalid date {:?} for {:?} in {} (Expected YYYY-MM-DD)",
+                    date.trim(),
+                    pattern,
+                    path.display()
+                ),
+            }
+        }
+
+        Ok(DateMapping { entries })
+    }
+
+    /// Date of the first entry matching the file name or the whole path
+    pub fn lookup(&self, path: &str) -> Option<NaiveDateTime> {
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path);
+        self.entries
+            .iter()
+            .find(|(pattern, _)| {
+                glob_match(pattern.as_bytes(), name.as_bytes())
+                    || glob_match(pattern.as_bytes(), path.as_bytes())
+            })
+            .map(|(_, date)| *date)
+    }
+}
@@ -1,14 +1,41 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
 
 use anyhow::Context;
+use chrono::NaiveDateTime;
 use log::debug;
 use log::info;
-use rusqlite::{config::DbConfig, params, Connection};
-
-use crate::images::{ImageAdv, ImageBasic};
+use rusqlite::{
+    config::DbConfig, params, types::Value, Connection, OpenFlags, OptionalExtension,
+    TransactionBehavior,
+};
+use serde::Serialize;
+
+use crate::{
+    failures::FailureKind,
+    geotag::Position,
+    hash::HashAlgorithm,
+    images::{ExifDetails, ImageAdv, ImageBasic},
+    layout::DstPolicy,
+    safety,
+};
 
 const APPLICATION_ID: i64 = 0xBEEF;
-const USER_VERSION: i64 = 2;
+const USER_VERSION: i64 = 29;
+
+/// Days removed archived files are remembered to detect moves
+const REMOVED_FILES_DAYS: u64 = 30;
+
+/// How long a concurrent run may wait for another to release the database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Claims older than this are assumed to belong to a run that crashed
+const CLAIM_TIMEOUT: chrono::TimeDelta = chrono::TimeDelta::hours(12);
 
 #[derive(Copy, Clone, Debug)]
 pub enum TableType {
@@ -24,6 +51,24 @@ impl TableType {
         }
     }
 
+    /// `keyword` and a condition keeping only the rows of `card`, bound as
+    /// `?1`. Camera images are kept per card, the disk table is the one
+    /// target.
+    fn card_filter(self, keyword: &str) -> String {
+        match self {
+            TableType::Disk => String::new(),
+            TableType::Camera => format!("{} on_camera.card = ?1", keyword),
+        }
+    }
+
+    /// The parameters of [`TableType::card_filter`]
+    fn card_params(self, card: &str) -> Vec<&str> {
+        match self {
+            TableType::Disk => Vec::new(),
+            TableType::Camera => vec![card],
+        }
+    }
+
     fn to_sql(self, is_new: bool) -> &'static str {
         match (self, is_new) {
             (TableType::Disk, false) => "on_disk",
@@ -34,13 +79,51 @@ impl TableType {
     }
 }
 
+impl FromStr for TableType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disk" => Ok(TableType::Disk),
+            "camera" => Ok(TableType::Camera),
+            _ => anyhow::bail!("Unknown table {:?} (Expected disk or camera)", s),
+        }
+    }
+}
+
 // TODO: Function that validates paths / names match up
 
 pub fn create_conn(db_file: &Path, clean: bool) -> anyhow::Result<Connection> {
-    let conn = Connection::open(db_file).context("Unable to open database file")?;
+    connect(db_file, clean, false)
+}
+
+/// Like [`create_conn`], but refuse to reset the database and guard the
+/// connection against deleting anything, see [`safety::guard_connection`]
+pub fn create_paranoid_conn(db_file: &Path, clean: bool) -> anyhow::Result<Connection> {
+    connect(db_file, clean, true)
+}
+
+fn connect(db_file: &Path, clean: bool, paranoid: bool) -> anyhow::Result<Connection> {
+    let mut conn = Connection::open(db_file).context("Unable to open database file")?;
+
+    // Multiple runs may share a database, so let readers continue while one
+    // run writes and wait for the write lock instead of failing immediately
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    let journal_mode: String =
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+    debug!("Using journal mode {}", journal_mode);
 
     let application_id: i64 = conn.pragma_query_value(None, "application_id", |row| row.get(0))?;
 
+    if paranoid {
+        let tables: i64 =
+            conn.query_row("SELECT COUNT(*) FROM sqlite_schema", [], |row| row.get(0))?;
+        if clean || (application_id != APPLICATION_ID && tables > 0) {
+            anyhow::bail!("Refusing to reset {} in paranoid mode", db_file.display());
+        }
+        safety::guard_connection(&conn)?;
+    }
+
     if clean || application_id != APPLICATION_ID {
         // TODO: Perhaps ask before doing this?
         debug!("application_id is unset, resetting database");
@@ -59,29 +142,253 @@ pub fn create_conn(db_file: &Path, clean: bool) -> anyhow::Result<Connection> {
             "Updating schema from version {} to {}",
             user_version, USER_VERSION
         );
-        update_schema(&conn, user_version)?;
-        conn.pragma_update(None, "user_version", USER_VERSION)?;
+        update_schema(&mut conn)?;
     }
 
     Ok(conn)
 }
 
-fn update_schema(conn: &Connection, current_user_version: i64) -> anyhow::Result<()> {
-    if !(0..=USER_VERSION).contains(&current_user_version) {
+/// Open an existing database read-only, without creating or upgrading it
+pub fn open_existing(db_file: &Path) -> anyhow::Result<Connection> {
+    let conn = Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("Unable to open database file")?;
+
+    let application_id: i64 = conn.pragma_query_value(None, "application_id", |row| row.get(0))?;
+    if application_id != APPLICATION_ID {
+        anyhow::bail!("{} is not an image database", db_file.display());
+    }
+    let user_version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if user_version != USER_VERSION {
         anyhow::bail!(
-            "Unsupported user version: {} (Expected {})",
-            current_user_version,
+            "{} uses schema version {} (Expected {})",
+            db_file.display(),
+            user_version,
             USER_VERSION
         );
     }
 
-    if current_user_version < 1 {
-        conn.execute_batch(include_str!("schema/v1.sql"))?;
+    Ok(conn)
+}
+
+/// Facts about a database file, read without upgrading it
+#[derive(Serialize)]
+pub struct DbInfo {
+    pub application_id: i64,
+    /// The application id marks it as an image database
+    pub is_image_database: bool,
+    pub schema_version: i64,
+    pub latest_schema_version: i64,
+    /// Opening it for any other command would upgrade the schema
+    pub migration_pending: bool,
+    pub hash_algorithm: Option<String>,
+    /// Size of the database file and its write-ahead log in bytes
+    pub size: u64,
+    /// Number of rows in each table
+    pub tables: BTreeMap<String, u64>,
+}
+
+pub fn get_db_info(db_file: &Path) -> anyhow::Result<DbInfo> {
+    let conn = Connection::open_with_flags(db_file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .context("Unable to open database file")?;
+
+    let application_id: i64 = conn.pragma_query_value(None, "application_id", |row| row.get(0))?;
+    let schema_version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    let names = conn
+        .prepare(
+            "
+        SELECT name
+        FROM sqlite_schema
+        WHERE type = 'table' AND name NOT LIKE 'sqlite_%'
+        ORDER BY name
+    ",
+        )?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut tables = BTreeMap::new();
+    for name in names {
+        let count = conn.query_row(
+            &format!("SELECT COUNT(*) FROM \"{}\"", name.replace('"', "\"\"")),
+            [],
+            |row| row.get(0),
+        )?;
+        tables.insert(name, count);
+    }
+
+    let hash_algorithm = if tables.contains_key("settings") {
+        Some(get_hash_algorithm(&conn)?.label().to_owned())
+    } else {
+        None
+    };
+
+    let mut wal = db_file.as_os_str().to_owned();
+    wal.push("-wal");
+    let size = [db_file.as_os_str(), &wal]
+        .into_iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|meta| meta.len())
+        .sum();
+
+    Ok(DbInfo {
+        application_id,
+        is_image_database: application_id == APPLICATION_ID,
+        schema_version,
+        latest_schema_version: USER_VERSION,
+        migration_pending: schema_version != USER_VERSION,
+        hash_algorithm,
+        size,
+        tables,
+    })
+}
+
+/// The steps of the schema, each run on databases older than its version
+const MIGRATIONS: &[(i64, &str)] = &[
+    (1, include_str!("schema/v1.sql")),
+    (3, include_str!("schema/v3.sql")),
+    (4, include_str!("schema/v4.sql")),
+    (5, include_str!("schema/v5.sql")),
+    (6, include_str!("schema/v6.sql")),
+    (7, include_str!("schema/v7.sql")),
+    (8, include_str!("schema/v8.sql")),
+    (9, include_str!("schema/v9.sql")),
+    (10, include_str!("schema/v10.sql")),
+    (11, include_str!("schema/v11.sql")),
+    (12, include_str!("schema/v12.sql")),
+    (13, include_str!("schema/v13.sql")),
+    (14, include_str!("schema/v14.sql")),
+    (15, include_str!("schema/v15.sql")),
+    (16, include_str!("schema/v16.sql")),
+    (17, include_str!("schema/v17.sql")),
+    (18, include_str!("schema/v18.sql")),
+    (19, include_str!("schema/v19.sql")),
+    (20, include_str!("schema/v20.sql")),
+    (21, include_str!("schema/v21.sql")),
+    (22, include_str!("schema/v22.sql")),
+    (23, include_str!("schema/v23.sql")),
+    (24, include_str!("schema/v24.sql")),
+    (25, include_str!("schema/v25.sql")),
+    (26, include_str!("schema/v26.sql")),
+    (27, include_str!("schema/v27.sql")),
+    (28, include_str!("schema/v28.sql")),
+    (29, include_str!("schema/v29.sql")),
+];
+
+/// Bring the schema up to [`USER_VERSION`] one step at a time. Each step is
+/// committed along with its version, so neither a run upgrading the database
+/// at the same time nor a crash halfway through applies a step twice.
+fn update_schema(conn: &mut Connection) -> anyhow::Result<()> {
+    for &(version, sql) in MIGRATIONS {
+        let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Read again under the write lock, another run may have upgraded the
+        // database since it was opened
+        let current: i64 = trans.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if !(0..=USER_VERSION).contains(&current) {
+            anyhow::bail!(
+                "Unsupported user version: {} (Expected {})",
+                current,
+                USER_VERSION
+            );
+        }
+        if current < version {
+            debug!("Updating schema to version {}", version);
+            trans.execute_batch(sql)?;
+            trans.pragma_update(None, "user_version", version)?;
+        }
+        trans.commit()?;
     }
 
     Ok(())
 }
 
+fn get_setting(conn: &Connection, key: &str) -> anyhow::Result<Option<String>> {
+    Ok(conn
+        .query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+            row.get(0)
+        })
+        .optional()?)
+}
+
+fn set_setting(conn: &Connection, key: &str, value: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        INSERT INTO settings (key, value)
+        VALUES (?1, ?2)
+        ON CONFLICT (key) DO UPDATE
+        SET value = excluded.value
+    ",
+        [key, value],
+    )?;
+
+    Ok(())
+}
+
+/// The algorithm all checksums in this database were computed with
+pub fn get_hash_algorithm(conn: &Connection) -> anyhow::Result<HashAlgorithm> {
+    match get_setting(conn, "hash_algorithm")? {
+        Some(label) => label.parse(),
+        None => Ok(HashAlgorithm::default()),
+    }
+}
+
+/// Change the checksum algorithm, which is only possible while no checksums
+/// have been recorded with the current one
+pub fn set_hash_algorithm(conn: &Connection, algorithm: HashAlgorithm) -> anyhow::Result<()> {
+    let current = get_hash_algorithm(conn)?;
+    if current == algorithm {
+        return Ok(());
+    }
+
+    let checksum_count: u64 = conn.query_row(
+        "SELECT COUNT(*) FROM on_disk WHERE checksum IS NOT NULL",
+        [],
+        |row| row.get(0),
+    )?;
+    if checksum_count > 0 {
+        anyhow::bail!(
+            "Database already contains {} {} checksums, refusing to switch to {}",
+            checksum_count,
+            current,
+            algorithm
+        );
+    }
+
+    info!(
+        "Switching checksum algorithm from {} to {}",
+        current, algorithm
+    );
+    set_setting(conn, "hash_algorithm", algorithm.label())
+}
+
+/// How capture times in a daylight saving switch are placed into folders
+pub fn get_dst_policy(conn: &Connection) -> anyhow::Result<DstPolicy> {
+    match get_setting(conn, "dst_policy")? {
+        Some(label) => label.parse(),
+        None => Ok(DstPolicy::default()),
+    }
+}
+
+pub fn set_dst_policy(conn: &Connection, policy: DstPolicy) -> anyhow::Result<()> {
+    set_setting(conn, "dst_policy", policy.label())
+}
+
+/// Size of the chunks archived files are checksummed in, if enabled
+pub fn get_chunk_size(conn: &Connection) -> anyhow::Result<Option<u64>> {
+    get_setting(conn, "chunk_size")?
+        .map(|size| size.parse().context("Invalid chunk_size setting"))
+        .transpose()
+}
+
+pub fn set_chunk_size(conn: &Connection, chunk_size: Option<u64>) -> anyhow::Result<()> {
+    match chunk_size {
+        Some(size) => set_setting(conn, "chunk_size", &size.to_string()),
+        None => {
+            conn.execute("DELETE FROM settings WHERE key = 'chunk_size'", [])?;
+            Ok(())
+        }
+    }
+}
+
+/// Files of a scan sharing a name and size, ordered by path
 pub struct DuplicateImage {
     pub name: String,
     pub paths: Vec<String>,
@@ -144,6 +451,7 @@ where
         SELECT path
         FROM {name}
         WHERE name = ?1 AND size = ?2
+        ORDER BY path
     "
     ))?;
 
@@ -158,29 +466,89 @@ where
         })
         .collect::<Result<Vec<_>, anyhow::Error>>()?;
 
-    conn.execute(
-        &format!(
-            "
-        DELETE FROM {name}
-        where rowid not in (
-            SELECT rowid
-            FROM {name}
-            GROUP BY name, size
-        )
-    "
-        ),
-        [],
-    )?;
-
     Ok(res)
 }
 
-pub fn update_table_get_new(
+/// Leave copies of a duplicate out of the latest scan, so they aren't indexed
+pub fn remove_from_new_table(
     conn: &Connection,
     table: TableType,
-) -> anyhow::Result<Vec<ImageBasic>> {
+    paths: &[String],
+) -> anyhow::Result<()> {
+    let name = table.to_sql(true);
+    let mut stmt = conn.prepare(&format!("DELETE FROM {name} WHERE path = ?1"))?;
+    for path in paths {
+        stmt.execute([path])?;
+    }
+
+    Ok(())
+}
+
+/// Which entries of a table the latest scan read the files of
+#[derive(Clone, Copy)]
+enum Scanned {
+    /// Every file of the directory
+    All,
+    /// The files of the folders in `scanned_folders`
+    Folders,
+    /// Only the files in `scanned_paths`
+    Paths,
+}
+
+/// Delete entries for images that are no longer present in the latest scan.
+/// Camera images of other cards are only deleted once they weren't scanned
+/// for a while, a concurrent run may still be archiving them. Entries the
+/// scan didn't read are kept.
+fn forget_missing(
+    conn: &Connection,
+    table: TableType,
+    card: &str,
+    scanned: Scanned,
+) -> anyhow::Result<()> {
     let name = table.to_sql(false);
     let new_name = table.to_sql(true);
+    let scanned_filter = match scanned {
+        Scanned::All => String::new(),
+        Scanned::Folders => format!(
+            "AND {} IN (SELECT folder FROM scanned_folders)",
+            folder_sql(&format!("{name}.path"))
+        ),
+        Scanned::Paths => format!("AND {name}.path IN (SELECT path FROM scanned_paths)"),
+    };
+
+    // Remember archived files for a while, so they can be recognized if they
+    // were only moved within the target
+    if let TableType::Disk = table {
+        let now = chrono::Utc::now().naive_utc();
+        conn.execute(
+            "
+            DELETE FROM removed_files
+            WHERE removed_at < ?1
+        ",
+            [now - chrono::Days::new(REMOVED_FILES_DAYS)],
+        )?;
+        conn.execute(
+            &format!(
+                "
+            INSERT INTO removed_files (path, size, date, rating, date_source, camera,
+                utc_offset, checksum, removed_at, make, lens, iso, shutter, aperture, latitude,
+                longitude, altitude, content_id)
+            SELECT on_disk.path, on_disk.size, on_disk.date, on_disk.rating,
+                on_disk.date_source, on_disk.camera, on_disk.utc_offset, on_disk.checksum, ?1,
+                on_disk.make, on_disk.lens, on_disk.iso, on_disk.shutter, on_disk.aperture,
+                on_disk.latitude, on_disk.longitude, on_disk.altitude, on_disk.content_id
+            FROM on_disk
+            LEFT JOIN new_on_disk
+            ON on_disk.path = new_on_disk.path
+                AND on_disk.size = new_on_disk.size
+            WHERE new_on_disk.name IS NULL
+                AND on_disk.checksum IS NOT NULL
+                {scanned_filter}
+        "
+            ),
+            [now],
+        )?;
+    }
 
     let delete_count = conn.execute(
         &format!(
@@ -193,19 +561,287 @@ pub fn update_table_get_new(
             ON {name}.path = {new_name}.path
                 AND {name}.size = {new_name}.size
             WHERE {new_name}.name IS NULL
+                {}
+                {scanned_filter}
         )
-    "
+    ",
+            table.card_filter("AND")
         ),
-        [],
+        rusqlite::params_from_iter(table.card_params(card)),
     )?;
     info!(
         "{name} - Deleting {} image entries that no longer exist",
         delete_count
     );
 
-    let keep_count = conn.query_row(&format!("SELECT COUNT(*) FROM {name}"), [], |row| {
-        row.get::<_, u64>(0)
-    })?;
+    if let TableType::Camera = table {
+        let stale = conn.execute(
+            "
+            DELETE FROM on_camera
+            WHERE card != ?1
+                AND (scanned_at IS NULL OR scanned_at < ?2)
+        ",
+            params![card, chrono::Utc::now().naive_utc() - CLAIM_TIMEOUT],
+        )?;
+        debug!("on_camera - Deleting {} entries of other cards", stale);
+    }
+
+    if let TableType::Disk = table {
+        conn.execute(
+            "
+            DELETE FROM chunks
+            WHERE path NOT IN (
+                SELECT path
+                FROM on_disk
+                UNION
+                SELECT path
+                FROM removed_files
+            )
+        ",
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Forget archived files at `paths`, remembering them like files a scan
+/// didn't find so they are still recognized if they turn up elsewhere
+pub fn prune_disk_files(conn: &Connection, paths: &[String]) -> anyhow::Result<()> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut remember = conn.prepare(
+        "
+        INSERT INTO removed_files (path, size, date, rating, date_source, camera,
+            utc_offset, checksum, removed_at, make, lens, iso, shutter, aperture, latitude,
+            longitude, altitude, content_id)
+        SELECT path, size, date, rating, date_source, camera, utc_offset, checksum, ?2,
+            make, lens, iso, shutter, aperture, latitude, longitude, altitude, content_id
+        FROM on_disk
+        WHERE path = ?1
+            AND checksum IS NOT NULL
+    ",
+    )?;
+    let mut delete = conn.prepare("DELETE FROM on_disk WHERE path = ?1")?;
+    for path in paths {
+        remember.execute(params![path, now])?;
+        delete.execute([path])?;
+    }
+
+    conn.execute(
+        "
+        DELETE FROM chunks
+        WHERE path NOT IN (
+            SELECT path
+            FROM on_disk
+            UNION
+            SELECT path
+            FROM removed_files
+        )
+    ",
+        [],
+    )?;
+
+    Ok(())
+}
+
+/// An archived file that disappeared from its path during a recent scan
+pub struct RemovedFile {
+    pub path: String,
+    pub date: NaiveDateTime,
+    pub rating: Option<i32>,
+    pub date_source: Option<String>,
+    pub camera: Option<String>,
+    pub utc_offset: Option<i32>,
+    pub exif: ExifDetails,
+    pub checksum: Vec<u8>,
+}
+
+/// The EXIF details stored in the nine columns starting at `first`
+fn exif_from_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<ExifDetails> {
+    Ok(ExifDetails {
+        make: row.get(first)?,
+        lens: row.get(first + 1)?,
+        iso: row.get(first + 2)?,
+        shutter: row.get(first + 3)?,
+        aperture: row.get(first + 4)?,
+        position: match (row.get(first + 5)?, row.get(first + 6)?) {
+            (Some(latitude), Some(longitude)) => Some(Position {
+                latitude,
+                longitude,
+                altitude: row.get(first + 7)?,
+            }),
+            _ => None,
+        },
+        content_id: row.get(first + 8)?,
+    })
+}
+
+/// Recently removed archived files of the given size
+pub fn get_removed_files(conn: &Connection, size: u64) -> anyhow::Result<Vec<RemovedFile>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path, date, rating, date_source, camera, utc_offset, checksum,
+            make, lens, iso, shutter, aperture, latitude, longitude, altitude, content_id
+        FROM removed_files
+        WHERE size = ?1
+        ORDER BY removed_at DESC
+    ",
+    )?;
+
+    let removed = stmt
+        .query_map([size], |row| {
+            Ok(RemovedFile {
+                path: row.get(0)?,
+                date: row.get(1)?,
+                rating: row.get(2)?,
+                date_source: row.get(3)?,
+                camera: row.get(4)?,
+                utc_offset: row.get(5)?,
+                exif: exif_from_row(row, 7)?,
+                checksum: row.get(6)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(removed)
+}
+
+/// Point everything recorded about a removed file at the path it was moved
+/// to. Its row in on_disk has to be added separately.
+pub fn carry_over_removed(
+    conn: &Connection,
+    removed: &RemovedFile,
+    new_path: &str,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        UPDATE chunks
+        SET path = ?2
+        WHERE path = ?1
+    ",
+        [&removed.path, new_path],
+    )?;
+    conn.execute(
+        "
+        UPDATE provenance
+        SET disk_path = ?2
+        WHERE disk_path = ?1
+    ",
+        [&removed.path, new_path],
+    )?;
+    update_related_paths(conn, &removed.path, new_path)?;
+    if !safety::is_guarded(conn) {
+        conn.execute(
+            "
+            DELETE FROM removed_files
+            WHERE path = ?1
+        ",
+            [&removed.path],
+        )?;
+    }
+    log_operation(conn, "move", Some(new_path), &removed.path)?;
+
+    Ok(())
+}
+
+/// SQL for the folder of the relative path in `column`, like the part up to
+/// its last separator
+fn folder_sql(column: &str) -> String {
+    let sep = std::path::MAIN_SEPARATOR;
+    format!("rtrim(rtrim({column}, replace({column}, '{sep}', '')), '{sep}')")
+}
+
+/// Bring the table in line with the latest scan and return the images new to
+/// it. Camera images are those of `card`, the id of the card they were
+/// scanned from, or an empty one for cards without an id.
+pub fn update_table_get_new(
+    conn: &Connection,
+    table: TableType,
+    card: &str,
+) -> anyhow::Result<Vec<ImageBasic>> {
+    update_get_new(conn, table, card, Scanned::All)
+}
+
+/// Like [`update_table_get_new`] for a scan of the target that only read
+/// the files of `folders`, keeping the entries of all other folders
+pub fn update_folders_get_new(
+    conn: &Connection,
+    folders: &[String],
+) -> anyhow::Result<Vec<ImageBasic>> {
+    conn.execute_batch(
+        "
+        CREATE TEMP TABLE IF NOT EXISTS scanned_folders(
+          folder TEXT NOT NULL PRIMARY KEY
+        ) STRICT;
+
+        DELETE FROM scanned_folders;
+    ",
+    )?;
+    let mut stmt = conn.prepare("INSERT INTO scanned_folders (folder) VALUES (?1)")?;
+    for folder in folders {
+        stmt.execute([folder])?;
+    }
+
+    update_get_new(conn, TableType::Disk, "", Scanned::Folders)
+}
+
+/// Like [`update_table_get_new`] for a scan that only read the files at
+/// `paths`, keeping the entries of all other files
+pub fn update_paths_get_new(
+    conn: &Connection,
+    table: TableType,
+    card: &str,
+    paths: &[String],
+) -> anyhow::Result<Vec<ImageBasic>> {
+    conn.execute_batch(
+        "
+        CREATE TEMP TABLE IF NOT EXISTS scanned_paths(
+          path TEXT NOT NULL PRIMARY KEY
+        ) STRICT;
+
+        DELETE FROM scanned_paths;
+    ",
+    )?;
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO scanned_paths (path) VALUES (?1)")?;
+    for path in paths {
+        stmt.execute([path])?;
+    }
+
+    update_get_new(conn, table, card, Scanned::Paths)
+}
+
+fn update_get_new(
+    conn: &Connection,
+    table: TableType,
+    card: &str,
+    scanned: Scanned,
+) -> anyhow::Result<Vec<ImageBasic>> {
+    let name = table.to_sql(false);
+    let new_name = table.to_sql(true);
+
+    // Paranoid runs keep stale entries, new images are still found by path
+    // and size below
+    if safety::is_guarded(conn) {
+        info!("{name} - Keeping entries for missing images in paranoid mode");
+    } else {
+        forget_missing(conn, table, card, scanned)?;
+    }
+    if let TableType::Camera = table {
+        conn.execute(
+            "
+            UPDATE on_camera
+            SET scanned_at = ?2
+            WHERE card = ?1
+        ",
+            params![card, chrono::Utc::now().naive_utc()],
+        )?;
+    }
+
+    let keep_count = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {name} {}", table.card_filter("WHERE")),
+        rusqlite::params_from_iter(table.card_params(card)),
+        |row| row.get::<_, u64>(0),
+    )?;
     info!("{name} - Keeping {} existing image entries", keep_count);
 
     let mut stmt = conn.prepare(&format!(
@@ -215,12 +851,14 @@ pub fn update_table_get_new(
         LEFT JOIN {name}
         ON {name}.path = {new_name}.path
             AND {name}.size = {new_name}.size
+            {}
         WHERE {name}.name IS NULL
-    "
+    ",
+        table.card_filter("AND")
     ))?;
 
     let im_basic = stmt
-        .query_map([], |row| {
+        .query_map(rusqlite::params_from_iter(table.card_params(card)), |row| {
             Ok(ImageBasic {
                 path: row.get(0)?,
                 size: row.get(1)?,
@@ -232,339 +870,3124 @@ pub fn update_table_get_new(
     Ok(im_basic)
 }
 
-pub fn add_to_table<'a, I>(conn: &Connection, table: TableType, images: I) -> anyhow::Result<()>
+/// Modification times of the target's folders as of the last scan
+pub fn get_directory_mtimes(conn: &Connection) -> anyhow::Result<HashMap<String, i64>> {
+    let mut stmt = conn.prepare("SELECT path, mtime FROM directories")?;
+    let mtimes = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok(mtimes)
+}
+
+/// Record the modification times of the target's folders, and forget the
+/// folders in `removed` unless the connection is guarded
+pub fn record_directory_mtimes(
+    conn: &Connection,
+    mtimes: &HashMap<String, i64>,
+    removed: &[String],
+) -> anyhow::Result<()> {
+    if !safety::is_guarded(conn) {
+        let mut stmt = conn.prepare("DELETE FROM directories WHERE path = ?1")?;
+        for path in removed {
+            stmt.execute([path])?;
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "
+        INSERT INTO directories (path, mtime)
+        VALUES (?1, ?2)
+        ON CONFLICT (path) DO UPDATE
+        SET mtime = excluded.mtime
+    ",
+    )?;
+
+    for (path, mtime) in mtimes {
+        stmt.execute(params![path, mtime])?;
+    }
+
+    Ok(())
+}
+
+pub fn get_last_full_scan(conn: &Connection) -> anyhow::Result<Option<NaiveDateTime>> {
+    get_setting(conn, "last_full_scan")?
+        .map(|time| time.parse().context("Invalid last_full_scan setting"))
+        .transpose()
+}
+
+pub fn set_last_full_scan(conn: &Connection, time: NaiveDateTime) -> anyhow::Result<()> {
+    set_setting(
+        conn,
+        "last_full_scan",
+        &time.format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+    )
+}
+
+/// Add `images` to the table, camera images as those of `card` like
+/// [`update_table_get_new`]
+pub fn add_to_table<'a, I>(
+    conn: &Connection,
+    table: TableType,
+    card: &str,
+    images: I,
+) -> anyhow::Result<()>
 where
     I: IntoIterator<Item = &'a ImageAdv>,
 {
     let name = table.to_sql(false);
+    let (card_columns, card_values, unique) = match table {
+        TableType::Disk => ("", "", "path"),
+        TableType::Camera => (", card, scanned_at", ", ?18, ?19", "card, path"),
+    };
     let mut stmt = conn.prepare(&format!(
         "
-        INSERT INTO {name} (name, path, size, date)
-        VALUES (?1, ?2, ?3, ?4)
+        INSERT INTO {name} (name, path, size, date, rating, date_source, camera,
+            utc_offset, make, lens, iso, shutter, aperture, latitude, longitude, altitude,
+            content_id{card_columns})
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+            ?17{card_values})
+        ON CONFLICT ({unique}) DO NOTHING
     "
     ))?;
 
+    let now = chrono::Utc::now().naive_utc();
     for image in images.into_iter() {
         debug!("Adding {} to {}", image.basic.path, table.to_sql(false));
-        stmt.execute(params![
+        let row = params![
             &image.basic.get_name(),
             &image.basic.path,
             &image.basic.size,
-            &image.date
-        ])?;
+            &image.date,
+            &image.rating,
+            &image.date_source,
+            &image.camera,
+            &image.utc_offset,
+            &image.exif.make,
+            &image.exif.lens,
+            &image.exif.iso,
+            &image.exif.shutter,
+            &image.exif.aperture,
+            image.exif.position.map(|p| p.latitude),
+            image.exif.position.map(|p| p.longitude),
+            image.exif.position.and_then(|p| p.altitude),
+            &image.exif.content_id
+        ];
+        match table {
+            TableType::Disk => stmt.execute(row)?,
+            TableType::Camera => stmt.execute(&*[row, params![card, now]].concat())?,
+        };
     }
 
     Ok(())
 }
 
-pub struct ToArchive {
-    pub to_archive: Vec<ImageAdv>,
-    pub mismatch: Vec<[(String, i64); 2]>,
-}
-
-pub fn get_images_to_archive(conn: &Connection) -> anyhow::Result<ToArchive> {
-    let mut stmt = conn.prepare(
+/// Store the checksums of files at the paths of the table, camera images as
+/// those of `card` like [`update_table_get_new`]
+pub fn record_checksums<'a, I>(
+    conn: &Connection,
+    table: TableType,
+    card: &str,
+    checksums: I,
+) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = (&'a str, &'a [u8])>,
+{
+    let name = table.to_sql(false);
+    let card_filter = match table {
+        TableType::Disk => "",
+        TableType::Camera => "AND card = ?3",
+    };
+    let mut stmt = conn.prepare(&format!(
         "
-        SELECT on_camera.path, on_camera.size, on_disk.path, on_disk.size
-        FROM on_camera
-        INNER JOIN on_disk
-        ON on_disk.name = on_camera.name
-            AND on_disk.date = on_camera.date
-            AND on_disk.size != on_camera.size
+        UPDATE {name}
+        SET checksum = ?2
+        WHERE path = ?1
+            {card_filter}
+    "
+    ))?;
+
+    for (path, checksum) in checksums {
+        match table {
+            TableType::Disk => stmt.execute(params![path, checksum])?,
+            TableType::Camera => stmt.execute(params![path, checksum, card])?,
+        };
+    }
+
+    Ok(())
+}
+
+/// Store the checksum of each `chunk_size` long chunk of an archived file
+pub fn record_chunks(
+    conn: &Connection,
+    path: &str,
+    chunk_size: u64,
+    size: u64,
+    chunks: &[Vec<u8>],
+) -> anyhow::Result<()> {
+    if !safety::is_guarded(conn) {
+        conn.execute("DELETE FROM chunks WHERE path = ?1", [path])?;
+    }
+
+    let mut stmt = conn.prepare(
+        "
+        INSERT INTO chunks (path, offset, length, checksum)
+        VALUES (?1, ?2, ?3, ?4)
     ",
     )?;
 
-    let mismatch = stmt
+    for (idx, checksum) in chunks.iter().enumerate() {
+        let offset = idx as u64 * chunk_size;
+        let length = chunk_size.min(size - offset);
+        stmt.execute(params![path, offset, length, checksum])?;
+    }
+
+    Ok(())
+}
+
+pub struct Chunk {
+    pub offset: u64,
+    pub length: u64,
+    pub checksum: Vec<u8>,
+}
+
+pub fn get_chunks(conn: &Connection, path: &str) -> anyhow::Result<Vec<Chunk>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT offset, length, checksum
+        FROM chunks
+        WHERE path = ?1
+        ORDER BY offset
+    ",
+    )?;
+
+    let chunks = stmt
+        .query_map([path], |row| {
+            Ok(Chunk {
+                offset: row.get(0)?,
+                length: row.get(1)?,
+                checksum: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(chunks)
+}
+
+pub struct RecordedFile {
+    pub path: String,
+    pub size: u64,
+    pub checksum: Option<Vec<u8>>,
+}
+
+pub fn get_recorded_files(conn: &Connection) -> anyhow::Result<Vec<RecordedFile>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path, size, checksum
+        FROM on_disk
+        ORDER BY path
+    ",
+    )?;
+
+    let files = stmt
         .query_map([], |row| {
-            Ok([(row.get(0)?, row.get(1)?), (row.get(2)?, row.get(3)?)])
+            Ok(RecordedFile {
+                path: row.get(0)?,
+                size: row.get(1)?,
+                checksum: row.get(2)?,
+            })
         })?
-        .collect::<Result<Vec<[(String, i64); 2]>, _>>()?;
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(files)
+}
+
+/// Name of the copy in the target directory, mirrors are named by their path
+pub const TARGET_COPY: &str = "target";
 
+/// Record that `paths` of `copy` matched their checksums at `verified_at`
+pub fn record_verifications<'a>(
+    conn: &Connection,
+    copy: &str,
+    paths: impl IntoIterator<Item = &'a str>,
+    verified_at: NaiveDateTime,
+) -> anyhow::Result<()> {
     let mut stmt = conn.prepare(
         "
-        SELECT on_camera.path, on_camera.size, on_camera.date
-        FROM on_camera
-        LEFT JOIN on_disk
-        ON on_disk.name = on_camera.name
-            AND on_disk.date = on_camera.date
-        WHERE on_disk.name IS NULL
-            AND on_camera.saved = 0
+        INSERT INTO verifications (copy, path, verified_at)
+        VALUES (?1, ?2, ?3)
+        ON CONFLICT (copy, path) DO UPDATE
+        SET verified_at = excluded.verified_at
     ",
     )?;
+    for path in paths {
+        stmt.execute(params![copy, path, verified_at])?;
+    }
 
-    let to_archive = stmt
+    Ok(())
+}
+
+/// How well a copy of the archive has been verified recently
+pub struct CopyCoverage {
+    pub copy: String,
+    pub files: u64,
+    /// Files not verified since the cutoff
+    pub stale: u64,
+    pub oldest: Option<NaiveDateTime>,
+}
+
+/// Verification coverage of the target and every mirror verified before.
+/// Files in the target count as verified when they were archived.
+pub fn get_verification_coverage(
+    conn: &Connection,
+    cutoff: NaiveDateTime,
+) -> anyhow::Result<Vec<CopyCoverage>> {
+    let mut copies = vec![TARGET_COPY.to_owned()];
+    copies.extend(
+        conn.prepare(
+            "
+        SELECT DISTINCT copy
+        FROM verifications
+        WHERE copy != ?1
+        ORDER BY copy
+    ",
+        )?
+        .query_map([TARGET_COPY], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?,
+    );
+
+    let mut stmt = conn.prepare(
+        "
+        WITH last AS (
+            SELECT d.path, COALESCE(
+                v.verified_at,
+                CASE WHEN ?1 = ?3 THEN (
+                    SELECT MAX(p.archived_at) FROM provenance AS p WHERE p.disk_path = d.path
+                ) END
+            ) AS verified_at
+            FROM on_disk AS d
+            LEFT JOIN verifications AS v
+                ON v.copy = ?1 AND v.path = d.path
+            WHERE d.checksum IS NOT NULL
+        )
+        SELECT
+            COUNT(*),
+            COUNT(*) FILTER (WHERE verified_at IS NULL OR verified_at < ?2),
+            MIN(verified_at)
+        FROM last
+    ",
+    )?;
+    let mut coverage = Vec::with_capacity(copies.len());
+    for copy in copies {
+        let (files, stale, oldest) = stmt.query_row(params![copy, cutoff, TARGET_COPY], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        coverage.push(CopyCoverage {
+            copy,
+            files,
+            stale,
+            oldest,
+        });
+    }
+
+    Ok(coverage)
+}
+
+pub struct InventoryFile {
+    pub path: String,
+    pub size: u64,
+    pub date: NaiveDateTime,
+    pub checksummed: bool,
+}
+
+pub fn get_inventory_files(conn: &Connection) -> anyhow::Result<Vec<InventoryFile>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path, size, date, checksum IS NOT NULL
+        FROM on_disk
+        ORDER BY path
+    ",
+    )?;
+
+    let files = stmt
         .query_map([], |row| {
-            Ok(ImageAdv {
-                basic: ImageBasic {
-                    path: row.get(0)?,
-                    size: row.get(1)?,
-                },
+            Ok(InventoryFile {
+                path: row.get(0)?,
+                size: row.get(1)?,
                 date: row.get(2)?,
+                checksummed: row.get(3)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
 
-    Ok(ToArchive {
-        to_archive,
-        mismatch,
-    })
+    Ok(files)
+}
+
+/// An archived file that isn't on any exported disc yet
+pub struct ExportFile {
+    pub path: String,
+    pub size: u64,
+    pub checksum: Option<Vec<u8>>,
+}
+
+/// Archived files taken in `[start, end)` whose path matches the glob
+/// `pattern` and that weren't exported to a disc before, oldest first
+pub fn get_unexported_files(
+    conn: &Connection,
+    start: Option<NaiveDateTime>,
+    end: Option<NaiveDateTime>,
+    pattern: Option<&str>,
+) -> anyhow::Result<Vec<ExportFile>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path, size, checksum
+        FROM on_disk
+        WHERE (?1 IS NULL OR date >= ?1)
+            AND (?2 IS NULL OR date < ?2)
+            AND (?3 IS NULL OR path GLOB ?3)
+            AND path NOT IN (SELECT path FROM disc_files)
+        ORDER BY date, path
+    ",
+    )?;
+
+    let files = stmt
+        .query_map(params![start, end, pattern], |row| {
+            Ok(ExportFile {
+                path: row.get(0)?,
+                size: row.get(1)?,
+                checksum: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(files)
+}
+
+/// An indexed image listed by `rawdb query`
+#[derive(Serialize)]
+pub struct QueriedImage {
+    pub path: String,
+    pub size: u64,
+    #[serde(serialize_with = "serialize_date")]
+    pub date: NaiveDateTime,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    /// Whether a source image was archived, always set for source images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved: Option<bool>,
+}
+
+fn serialize_date<S: serde::Serializer>(date: &NaiveDateTime, ser: S) -> Result<S::Ok, S::Error> {
+    ser.collect_str(&date.format("%Y-%m-%dT%H:%M:%S"))
+}
+
+/// Images of `table` whose name and path match the globs, taken from
+/// `start` up to `end`
+pub fn query_images(
+    conn: &Connection,
+    table: TableType,
+    start: Option<NaiveDateTime>,
+    end: Option<NaiveDateTime>,
+    name: Option<&str>,
+    path: Option<&str>,
+) -> anyhow::Result<Vec<QueriedImage>> {
+    let saved = match table {
+        TableType::Disk => "NULL",
+        TableType::Camera => "saved",
+    };
+    let table = table.to_sql(false);
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT path, size, date, camera, lens, {saved}
+        FROM {table}
+        WHERE (?1 IS NULL OR date >= ?1)
+            AND (?2 IS NULL OR date < ?2)
+            AND (?3 IS NULL OR name GLOB ?3)
+            AND (?4 IS NULL OR path GLOB ?4)
+        ORDER BY date, path
+    "
+    ))?;
+
+    let images = stmt
+        .query_map(params![start, end, name, path], |row| {
+            Ok(QueriedImage {
+                path: row.get(0)?,
+                size: row.get(1)?,
+                date: row.get(2)?,
+                camera: row.get(3)?,
+                lens: row.get(4)?,
+                saved: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(images)
+}
+
+/// Number for the next disc label, one more than the discs exported so far
+pub fn next_disc_number(conn: &Connection) -> anyhow::Result<u64> {
+    let count: u64 = conn.query_row("SELECT COUNT(*) FROM discs", [], |row| row.get(0))?;
+    Ok(count + 1)
 }
 
-pub fn set_images_as_archived<'a, I>(conn: &Connection, saved: I) -> anyhow::Result<()>
+/// Record that `files` with their checksums were written to the disc `label`
+pub fn record_disc<'a, I>(
+    conn: &Connection,
+    label: &str,
+    capacity: u64,
+    files: I,
+) -> anyhow::Result<()>
 where
-    I: IntoIterator<Item = &'a ImageAdv>,
+    I: IntoIterator<Item = (&'a str, u64, &'a [u8])>,
 {
     conn.execute(
         "
-        CREATE TEMP TABLE make_saved(
-          path TEXT NOT NULL
-        ) STRICT;
+        INSERT INTO discs (label, created_at, capacity)
+        VALUES (?1, ?2, ?3)
     ",
-        [],
+        params![label, chrono::Utc::now().naive_utc(), capacity],
     )?;
+
     let mut stmt = conn.prepare(
         "
-        INSERT INTO make_saved (path)
-        VALUES (?1)
+        INSERT INTO disc_files (disc, path, size, checksum)
+        VALUES (?1, ?2, ?3, ?4)
     ",
     )?;
+    let mut count = 0;
+    for (path, size, checksum) in files {
+        stmt.execute(params![label, path, size, checksum])?;
+        count += 1;
+    }
+    log_operation(
+        conn,
+        "export",
+        None,
+        &format!("{} files to {}", count, label),
+    )?;
 
-    for image in saved.into_iter() {
-        stmt.execute([&image.basic.path])?;
+    Ok(())
+}
+
+pub struct ArchivedFile {
+    pub path: String,
+    pub size: u64,
+    pub checksum: Option<Vec<u8>>,
+}
+
+/// Archived files in different folders that share a name but not contents
+pub struct NameCollision {
+    pub name: String,
+    pub files: Vec<ArchivedFile>,
+}
+
+pub fn get_name_collisions(conn: &Connection) -> anyhow::Result<Vec<NameCollision>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT name, path, size, checksum
+        FROM on_disk
+        WHERE name IN (
+            SELECT name
+            FROM on_disk
+            GROUP BY name
+            HAVING COUNT(DISTINCT size) > 1
+                OR COUNT(DISTINCT checksum) > 1
+        )
+        ORDER BY name, path
+    ",
+    )?;
+
+    let mut collisions: Vec<NameCollision> = Vec::new();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let file = ArchivedFile {
+            path: row.get(1)?,
+            size: row.get(2)?,
+            checksum: row.get(3)?,
+        };
+        match collisions.last_mut() {
+            Some(collision) if collision.name == name => collision.files.push(file),
+            _ => collisions.push(NameCollision {
+                name,
+                files: vec![file],
+            }),
+        }
+    }
+
+    Ok(collisions)
+}
+
+/// Archived files with the same size and checksum as another one, grouped
+/// by contents and ordered by path
+pub fn get_identical_files(conn: &Connection) -> anyhow::Result<Vec<Vec<ArchivedFile>>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path, size, checksum
+        FROM on_disk
+        WHERE checksum IS NOT NULL
+            AND (size, checksum) IN (
+                SELECT size, checksum
+                FROM on_disk
+                WHERE checksum IS NOT NULL
+                GROUP BY size, checksum
+                HAVING COUNT(*) > 1
+            )
+        ORDER BY size, checksum, path
+    ",
+    )?;
+
+    let mut groups: Vec<Vec<ArchivedFile>> = Vec::new();
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let file = ArchivedFile {
+            path: row.get(0)?,
+            size: row.get(1)?,
+            checksum: row.get(2)?,
+        };
+        match groups.last_mut() {
+            Some(group) if group[0].size == file.size && group[0].checksum == file.checksum => {
+                group.push(file)
+            }
+            _ => groups.push(vec![file]),
+        }
     }
 
+    Ok(groups)
+}
+
+pub fn is_name_archived(conn: &Connection, name: &str) -> anyhow::Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM on_disk WHERE name = ?1)",
+        [name],
+        |row| row.get(0),
+    )?)
+}
+
+/// Point everything recorded about an archived file at its new path
+pub fn rename_archived(conn: &Connection, path: &str, new_path: &str) -> anyhow::Result<()> {
+    let new_name = AsRef::<Path>::as_ref(new_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} has no file name", new_path))?;
+
+    conn.execute(
+        "UPDATE on_disk SET path = ?2, name = ?3 WHERE path = ?1",
+        params![path, new_path, new_name],
+    )?;
+    conn.execute(
+        "UPDATE chunks SET path = ?2 WHERE path = ?1",
+        params![path, new_path],
+    )?;
     conn.execute(
+        "UPDATE provenance SET disk_path = ?2 WHERE disk_path = ?1",
+        params![path, new_path],
+    )?;
+    update_related_paths(conn, path, new_path)?;
+    log_operation(
+        conn,
+        "rename",
+        Some(path),
+        &format!("Renamed to {}", new_path),
+    )
+}
+
+/// Record that the archived JPEGs at `members` were developed in camera
+/// from the RAW file at `raw`
+pub fn record_bracket(conn: &Connection, raw: &str, members: &[&str]) -> anyhow::Result<()> {
+    let mut stmt = conn.prepare(
         "
-        UPDATE on_camera
-        SET saved = 1
-        WHERE path in (
-            SELECT path
-            FROM make_saved
-        )
+        INSERT INTO brackets (raw_path, member_path)
+        VALUES (?1, ?2)
+        ON CONFLICT (member_path) DO UPDATE
+        SET raw_path = excluded.raw_path
     ",
+    )?;
+    for member in members {
+        stmt.execute([raw, member])?;
+    }
+
+    Ok(())
+}
+
+/// Record that the archived images at `paths` were taken as one burst
+pub fn record_burst(conn: &Connection, paths: &[&str]) -> anyhow::Result<()> {
+    let burst: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(burst), 0) + 1 FROM bursts",
         [],
+        |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare(
+        "
+        INSERT INTO bursts (burst, path)
+        VALUES (?1, ?2)
+        ON CONFLICT (path) DO UPDATE
+        SET burst = excluded.burst
+    ",
+    )?;
+    for path in paths {
+        stmt.execute(params![burst, path])?;
+    }
+
+    Ok(())
+}
+
+/// Every archived image of the burst `path` belongs to, by path. Empty if
+/// it isn't part of one.
+pub fn get_burst(conn: &Connection, path: &str) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path
+        FROM bursts
+        WHERE burst = (SELECT burst FROM bursts WHERE path = ?1)
+        ORDER BY path
+    ",
+    )?;
+    let burst = stmt
+        .query_map([path], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(burst)
+}
+
+/// Record that the archived `image` and `video` are a Live Photo
+pub fn record_live_photo(conn: &Connection, image: &str, video: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        INSERT INTO live_photos (image_path, video_path)
+        VALUES (?1, ?2)
+        ON CONFLICT (video_path) DO UPDATE
+        SET image_path = excluded.image_path
+    ",
+        [image, video],
+    )?;
+
+    Ok(())
+}
+
+/// The other half of the Live Photo the archived file at `path` belongs to
+pub fn get_live_photo_pair(conn: &Connection, path: &str) -> anyhow::Result<Option<String>> {
+    Ok(conn
+        .query_row(
+            "
+        SELECT CASE WHEN image_path = ?1 THEN video_path ELSE image_path END
+        FROM live_photos
+        WHERE image_path = ?1 OR video_path = ?1
+    ",
+            [path],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Point the bracket sets, bursts, Live Photos and verifications of an
+/// archived file to its new path
+fn update_related_paths(conn: &Connection, path: &str, new_path: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE verifications SET path = ?2 WHERE path = ?1",
+        [path, new_path],
+    )?;
+    conn.execute(
+        "UPDATE brackets SET raw_path = ?2 WHERE raw_path = ?1",
+        [path, new_path],
+    )?;
+    conn.execute(
+        "UPDATE brackets SET member_path = ?2 WHERE member_path = ?1",
+        [path, new_path],
     )?;
+    conn.execute(
+        "UPDATE bursts SET path = ?2 WHERE path = ?1",
+        [path, new_path],
+    )?;
+    conn.execute(
+        "UPDATE live_photos SET image_path = ?2 WHERE image_path = ?1",
+        [path, new_path],
+    )?;
+    conn.execute(
+        "UPDATE live_photos SET video_path = ?2 WHERE video_path = ?1",
+        [path, new_path],
+    )?;
+
+    Ok(())
+}
+
+/// Every archived file of the bracket set `path` belongs to, RAW first.
+/// Empty if it isn't part of one.
+pub fn get_bracket_set(conn: &Connection, path: &str) -> anyhow::Result<Vec<String>> {
+    let raw: Option<String> = conn
+        .query_row(
+            "
+        SELECT raw_path
+        FROM brackets
+        WHERE raw_path = ?1 OR member_path = ?1
+        LIMIT 1
+    ",
+            [path],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let Some(raw) = raw else {
+        return Ok(Vec::new());
+    };
+
+    let mut set = vec![raw.clone()];
+    set.extend(
+        conn.prepare(
+            "
+        SELECT member_path
+        FROM brackets
+        WHERE raw_path = ?1
+        ORDER BY member_path
+    ",
+        )?
+        .query_map([&raw], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?,
+    );
+
+    Ok(set)
+}
+
+pub struct ToArchive {
+    pub to_archive: Vec<ImageAdv>,
+    pub mismatch: Vec<[(String, i64); 2]>,
+    pub unmarked: Vec<UnmarkedImage>,
+}
+
+/// A camera image that already has a matching copy on disk, but was never
+/// marked as saved (e.g. it was copied into the archive by hand)
+pub struct UnmarkedImage {
+    pub image: ImageAdv,
+    pub disk_path: String,
+}
+
+/// Match camera images against the archive, by checksum where both sides
+/// have one and by name, date and size otherwise
+/// Unsaved camera images whose name and date are taken on disk by a file
+/// with different contents, and that aren't archived under another name
+pub fn get_colliding_images(conn: &Connection, card: &str) -> anyhow::Result<Vec<ImageAdv>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT DISTINCT on_camera.path, on_camera.size, on_camera.date, on_camera.rating,
+            on_camera.date_source, on_camera.camera, on_camera.utc_offset, on_camera.make,
+            on_camera.lens, on_camera.iso, on_camera.shutter, on_camera.aperture,
+            on_camera.latitude, on_camera.longitude, on_camera.altitude, on_camera.content_id
+        FROM on_camera
+        INNER JOIN on_disk
+        ON on_disk.name = on_camera.name
+            AND on_disk.date = on_camera.date
+            AND (on_disk.size != on_camera.size OR on_disk.checksum != on_camera.checksum)
+        WHERE on_camera.card = ?1
+            AND on_camera.saved = 0
+            AND NOT EXISTS (
+                SELECT 1
+                FROM on_disk AS same
+                WHERE same.checksum = on_camera.checksum
+            )
+    ",
+    )?;
+
+    let images = stmt
+        .query_map([card], |row| {
+            Ok(ImageAdv {
+                basic: ImageBasic {
+                    path: row.get(0)?,
+                    size: row.get(1)?,
+                },
+                date: row.get(2)?,
+                rating: row.get(3)?,
+                date_source: row.get(4)?,
+                camera: row.get(5)?,
+                utc_offset: row.get(6)?,
+                exif: exif_from_row(row, 7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(images)
+}
+
+/// The camera images of `card` to archive, see [`update_table_get_new`]
+pub fn get_images_to_archive(conn: &Connection, card: &str) -> anyhow::Result<ToArchive> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT on_camera.path, on_camera.size, on_disk.path, on_disk.size
+        FROM on_camera
+        INNER JOIN on_disk
+        ON on_disk.name = on_camera.name
+            AND on_disk.date = on_camera.date
+            AND (on_disk.size != on_camera.size OR on_disk.checksum != on_camera.checksum)
+        WHERE on_camera.card = ?1
+            AND NOT EXISTS (
+                SELECT 1
+                FROM on_disk AS same
+                WHERE same.checksum = on_camera.checksum
+            )
+    ",
+    )?;
+
+    let mismatch = stmt
+        .query_map([card], |row| {
+            Ok([(row.get(0)?, row.get(1)?), (row.get(2)?, row.get(3)?)])
+        })?
+        .collect::<Result<Vec<[(String, i64); 2]>, _>>()?;
+
+    // A camera image may match several archived files, only keep the first
+    let mut stmt = conn.prepare(
+        "
+        SELECT on_camera.path, on_camera.size, on_camera.date, on_disk.path,
+            on_camera.rating, on_camera.date_source, on_camera.camera, on_camera.utc_offset,
+            on_camera.make, on_camera.lens, on_camera.iso, on_camera.shutter, on_camera.aperture,
+            on_camera.latitude, on_camera.longitude, on_camera.altitude, on_camera.content_id
+        FROM on_camera
+        INNER JOIN on_disk
+        ON on_disk.checksum = on_camera.checksum
+        WHERE on_camera.card = ?1
+            AND on_camera.saved = 0
+        UNION
+        SELECT on_camera.path, on_camera.size, on_camera.date, on_disk.path,
+            on_camera.rating, on_camera.date_source, on_camera.camera, on_camera.utc_offset,
+            on_camera.make, on_camera.lens, on_camera.iso, on_camera.shutter, on_camera.aperture,
+            on_camera.latitude, on_camera.longitude, on_camera.altitude, on_camera.content_id
+        FROM on_camera
+        INNER JOIN on_disk
+        ON on_disk.name = on_camera.name
+            AND on_disk.date = on_camera.date
+            AND on_disk.size = on_camera.size
+        WHERE on_camera.card = ?1
+            AND on_camera.saved = 0
+            AND (on_disk.checksum IS NULL OR on_camera.checksum IS NULL)
+        ORDER BY 1, 4
+    ",
+    )?;
+
+    let mut matched = HashSet::new();
+    let unmarked = stmt
+        .query_map([card], |row| {
+            Ok(UnmarkedImage {
+                image: ImageAdv {
+                    basic: ImageBasic {
+                        path: row.get(0)?,
+                        size: row.get(1)?,
+                    },
+                    date: row.get(2)?,
+                    rating: row.get(4)?,
+                    date_source: row.get(5)?,
+                    camera: row.get(6)?,
+                    utc_offset: row.get(7)?,
+                    exif: exif_from_row(row, 8)?,
+                },
+                disk_path: row.get(3)?,
+            })
+        })?
+        .filter(|res| {
+            res.as_ref().map_or(true, |unmarked: &UnmarkedImage| {
+                matched.insert(unmarked.image.basic.path.clone())
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut stmt = conn.prepare(
+        "
+        SELECT on_camera.path, on_camera.size, on_camera.date, on_camera.rating,
+            on_camera.date_source, on_camera.camera, on_camera.utc_offset, on_camera.make,
+            on_camera.lens, on_camera.iso, on_camera.shutter, on_camera.aperture,
+            on_camera.latitude, on_camera.longitude, on_camera.altitude, on_camera.content_id
+        FROM on_camera
+        WHERE on_camera.card = ?1
+            AND on_camera.saved = 0
+            AND NOT EXISTS (
+                SELECT 1
+                FROM on_disk
+                WHERE on_disk.name = on_camera.name
+                    AND on_disk.date = on_camera.date
+            )
+            AND NOT EXISTS (
+                SELECT 1
+                FROM on_disk
+                WHERE on_disk.checksum = on_camera.checksum
+            )
+        ORDER BY on_camera.rowid
+    ",
+    )?;
+
+    let to_archive = stmt
+        .query_map([card], |row| {
+            Ok(ImageAdv {
+                basic: ImageBasic {
+                    path: row.get(0)?,
+                    size: row.get(1)?,
+                },
+                date: row.get(2)?,
+                rating: row.get(3)?,
+                date_source: row.get(4)?,
+                camera: row.get(5)?,
+                utc_offset: row.get(6)?,
+                exif: exif_from_row(row, 7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ToArchive {
+        to_archive,
+        mismatch,
+        unmarked,
+    })
+}
+
+pub fn set_images_as_archived<'a, I>(conn: &Connection, card: &str, saved: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = &'a ImageAdv>,
+{
+    conn.execute_batch(
+        "
+        CREATE TEMP TABLE IF NOT EXISTS make_saved(
+          path TEXT NOT NULL
+        ) STRICT;
+
+        DELETE FROM make_saved;
+    ",
+    )?;
+    let mut stmt = conn.prepare(
+        "
+        INSERT INTO make_saved (path)
+        VALUES (?1)
+    ",
+    )?;
+
+    for image in saved.into_iter() {
+        stmt.execute([&image.basic.path])?;
+    }
+
+    conn.execute(
+        "
+        UPDATE on_camera
+        SET saved = 1, archived_at = ?1, claimed_by = NULL, claimed_at = NULL
+        WHERE card = ?2
+            AND path in (
+                SELECT path
+                FROM make_saved
+            )
+    ",
+        params![chrono::Utc::now().naive_utc(), card],
+    )?;
+
+    Ok(())
+}
+
+/// Checksums of the camera images of `card` by path
+pub fn get_camera_checksums(
+    conn: &Connection,
+    card: &str,
+) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT path, checksum
+        FROM on_camera
+        WHERE card = ?1
+            AND checksum IS NOT NULL
+    ",
+    )?;
+
+    let checksums = stmt
+        .query_map([card], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok(checksums)
+}
+
+/// Claim pending camera images for this run, so a concurrent run archiving
+/// from the same database skips them. Returns the images that were claimed.
+pub fn claim_images(
+    conn: &Connection,
+    card: &str,
+    session: &str,
+    images: Vec<ImageAdv>,
+) -> anyhow::Result<Vec<ImageAdv>> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut stmt = conn.prepare(
+        "
+        UPDATE on_camera
+        SET claimed_by = ?1, claimed_at = ?2
+        WHERE card = ?5
+            AND path = ?3
+            AND saved = 0
+            AND (claimed_by IS NULL OR claimed_by = ?1 OR claimed_at < ?4)
+    ",
+    )?;
+
+    let mut claimed = Vec::with_capacity(images.len());
+    for image in images {
+        if stmt.execute(params![
+            session,
+            now,
+            &image.basic.path,
+            now - CLAIM_TIMEOUT,
+            card
+        ])? == 1
+        {
+            claimed.push(image);
+        }
+    }
+
+    Ok(claimed)
+}
+
+pub fn release_claims(conn: &Connection, session: &str) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        UPDATE on_camera
+        SET claimed_by = NULL, claimed_at = NULL
+        WHERE claimed_by = ?1
+    ",
+        [session],
+    )?;
+
+    Ok(())
+}
+
+/// Add an entry to the history of operations performed on the archive
+pub fn log_operation(
+    conn: &Connection,
+    kind: &str,
+    path: Option<&str>,
+    detail: &str,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        INSERT INTO operations (time, kind, path, detail)
+        VALUES (?1, ?2, ?3, ?4)
+    ",
+        params![chrono::Utc::now().naive_utc(), kind, path, detail],
+    )?;
+
+    Ok(())
+}
+
+pub struct RunStats {
+    pub started_at: NaiveDateTime,
+    pub finished_at: NaiveDateTime,
+    pub command: String,
+    pub files: u64,
+    pub bytes: u64,
+    pub failures: u64,
+}
+
+pub fn get_last_run(conn: &Connection) -> anyhow::Result<Option<RunStats>> {
+    Ok(conn
+        .query_row(
+            "
+        SELECT started_at, finished_at, command, files, bytes, failures
+        FROM runs
+        ORDER BY started_at DESC
+        LIMIT 1
+    ",
+            [],
+            |row| {
+                Ok(RunStats {
+                    started_at: row.get(0)?,
+                    finished_at: row.get(1)?,
+                    command: row.get(2)?,
+                    files: row.get(3)?,
+                    bytes: row.get(4)?,
+                    failures: row.get(5)?,
+                })
+            },
+        )
+        .optional()?)
+}
+
+/// The runs of `command` that started at or after `since`, oldest first
+pub fn get_runs_since(
+    conn: &Connection,
+    command: &str,
+    since: NaiveDateTime,
+) -> anyhow::Result<Vec<RunStats>> {
+    let runs = conn
+        .prepare(
+            "
+        SELECT started_at, finished_at, command, files, bytes, failures
+        FROM runs
+        WHERE command = ?1 AND started_at >= ?2
+        ORDER BY started_at
+    ",
+        )?
+        .query_map(params![command, since], |row| {
+            Ok(RunStats {
+                started_at: row.get(0)?,
+                finished_at: row.get(1)?,
+                command: row.get(2)?,
+                files: row.get(3)?,
+                bytes: row.get(4)?,
+                failures: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(runs)
+}
+
+pub fn record_run(conn: &Connection, run: &RunStats) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        INSERT INTO runs (started_at, finished_at, command, files, bytes, failures)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+    ",
+        params![
+            run.started_at,
+            run.finished_at,
+            run.command,
+            run.files,
+            run.bytes,
+            run.failures
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Progress of a run, as last reported by the process doing it
+pub struct RunProgress {
+    pub session: String,
+    pub pid: u32,
+    pub command: String,
+    pub stage: String,
+    pub done: u64,
+    pub total: Option<u64>,
+    /// Bytes processed in the current stage
+    pub bytes: u64,
+    pub current_file: Option<String>,
+    pub started_at: NaiveDateTime,
+    pub stage_started_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+pub fn update_progress(conn: &Connection, progress: &RunProgress) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        INSERT INTO progress (session, pid, command, stage, done, total, bytes, current_file,
+            started_at, stage_started_at, updated_at, finished_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+        ON CONFLICT (session) DO UPDATE
+        SET stage = excluded.stage,
+            done = excluded.done,
+            total = excluded.total,
+            bytes = excluded.bytes,
+            current_file = excluded.current_file,
+            stage_started_at = excluded.stage_started_at,
+            updated_at = excluded.updated_at,
+            finished_at = excluded.finished_at
+    ",
+        params![
+            progress.session,
+            progress.pid,
+            progress.command,
+            progress.stage,
+            progress.done,
+            progress.total,
+            progress.bytes,
+            progress.current_file,
+            progress.started_at,
+            progress.stage_started_at,
+            progress.updated_at,
+            progress.finished_at
+        ],
+    )?;
+
+    // Keep the table small, runs that stopped reporting long ago crashed
+    if !safety::is_guarded(conn) {
+        conn.execute(
+            "DELETE FROM progress WHERE updated_at < ?1",
+            [progress.updated_at - CLAIM_TIMEOUT],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Runs that haven't finished yet, oldest first
+pub fn get_running(conn: &Connection) -> anyhow::Result<Vec<RunProgress>> {
+    let running = conn
+        .prepare(
+            "
+        SELECT session, pid, command, stage, done, total, bytes, current_file,
+            started_at, stage_started_at, updated_at, finished_at
+        FROM progress
+        WHERE finished_at IS NULL
+        ORDER BY started_at
+    ",
+        )?
+        .query_map([], |row| {
+            Ok(RunProgress {
+                session: row.get(0)?,
+                pid: row.get(1)?,
+                command: row.get(2)?,
+                stage: row.get(3)?,
+                done: row.get(4)?,
+                total: row.get(5)?,
+                bytes: row.get(6)?,
+                current_file: row.get(7)?,
+                started_at: row.get(8)?,
+                stage_started_at: row.get(9)?,
+                updated_at: row.get(10)?,
+                finished_at: row.get(11)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(running)
+}
+
+/// Files archived from one card with one camera, by the model recorded
+/// for them
+pub struct CameraSummary {
+    pub camera: Option<String>,
+    pub card_id: Option<String>,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Everything that happened to the archive between `start` and `end`
+pub struct PeriodSummary {
+    /// Files archived, counting the photo and video of a Live Photo once
+    pub archived_files: u64,
+    pub archived_bytes: u64,
+    /// Bytes archived before the start of the period
+    pub previous_bytes: u64,
+    pub cameras: Vec<CameraSummary>,
+    pub runs: Vec<RunStats>,
+    pub repairs: u64,
+    pub failures: Vec<FailureCount>,
+    pub failed_files: Vec<FailedFile>,
+}
+
+/// A file pending because it failed to be indexed or archived
+pub struct FailedFile {
+    pub source: String,
+    pub path: String,
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+pub fn get_period_summary(
+    conn: &Connection,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> anyhow::Result<PeriodSummary> {
+    let (archived_files, archived_bytes) = conn.query_row(
+        "
+        SELECT COUNT(*) - COUNT(live_photos.video_path), COALESCE(SUM(size), 0)
+        FROM provenance
+        LEFT JOIN live_photos
+        ON live_photos.video_path = provenance.disk_path
+        WHERE archived_at >= ?1 AND archived_at < ?2
+    ",
+        [start, end],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let previous_bytes = conn.query_row(
+        "
+        SELECT COALESCE(SUM(size), 0)
+        FROM provenance
+        WHERE archived_at < ?1
+    ",
+        [start],
+        |row| row.get(0),
+    )?;
+
+    let cameras = conn
+        .prepare(
+            "
+        SELECT on_disk.camera, provenance.card_id,
+            COUNT(*) - COUNT(live_photos.video_path), SUM(provenance.size)
+        FROM provenance
+        LEFT JOIN live_photos
+        ON live_photos.video_path = provenance.disk_path
+        LEFT JOIN on_disk
+        ON on_disk.path = provenance.disk_path
+        WHERE archived_at >= ?1 AND archived_at < ?2
+        GROUP BY on_disk.camera, provenance.card_id
+        ORDER BY COUNT(*) DESC
+    ",
+        )?
+        .query_map([start, end], |row| {
+            Ok(CameraSummary {
+                camera: row.get(0)?,
+                card_id: row.get(1)?,
+                files: row.get(2)?,
+                bytes: row.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let runs = conn
+        .prepare(
+            "
+        SELECT started_at, finished_at, command, files, bytes, failures
+        FROM runs
+        WHERE started_at >= ?1 AND started_at < ?2
+        ORDER BY started_at
+    ",
+        )?
+        .query_map([start, end], |row| {
+            Ok(RunStats {
+                started_at: row.get(0)?,
+                finished_at: row.get(1)?,
+                command: row.get(2)?,
+                files: row.get(3)?,
+                bytes: row.get(4)?,
+                failures: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let repairs = conn.query_row(
+        "
+        SELECT COUNT(*)
+        FROM operations
+        WHERE kind = 'repair' AND time >= ?1 AND time < ?2
+    ",
+        [start, end],
+        |row| row.get(0),
+    )?;
+
+    let failures = conn
+        .prepare(
+            "
+        SELECT source, kind, COUNT(*)
+        FROM failures
+        WHERE last_seen >= ?1 AND last_seen < ?2
+        GROUP BY source, kind
+        ORDER BY source, COUNT(*) DESC
+    ",
+        )?
+        .query_map([start, end], |row| {
+            Ok((row.get(0)?, row.get::<_, String>(1)?, row.get(2)?))
+        })?
+        .map(|row| {
+            let (source, kind, count) = row?;
+            Ok(FailureCount {
+                source,
+                kind: FailureKind::from_label(&kind).unwrap_or(FailureKind::Other),
+                count,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let failed_files = conn
+        .prepare(
+            "
+        SELECT source, path, kind, message
+        FROM failures
+        WHERE last_seen >= ?1 AND last_seen < ?2
+        ORDER BY source, path
+    ",
+        )?
+        .query_map([start, end], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get::<_, String>(2)?,
+                row.get(3)?,
+            ))
+        })?
+        .map(|row| {
+            let (source, path, kind, message) = row?;
+            Ok(FailedFile {
+                source,
+                path,
+                kind: FailureKind::from_label(&kind).unwrap_or(FailureKind::Other),
+                message,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(PeriodSummary {
+        archived_files,
+        archived_bytes,
+        previous_bytes,
+        cameras,
+        runs,
+        repairs,
+        failures,
+        failed_files,
+    })
+}
+
+/// Where an archived image came from, kept even after the card is wiped
+pub struct Provenance {
+    pub name: String,
+    pub date: NaiveDateTime,
+    pub size: u64,
+    pub card_id: Option<String>,
+    pub source_path: String,
+    pub disk_path: String,
+    pub archived_at: NaiveDateTime,
+    /// Where the capture date was read from
+    pub date_source: Option<String>,
+}
+
+pub struct ProvenanceEntry<'a> {
+    pub image: &'a ImageAdv,
+    pub disk_path: &'a str,
+    pub checksum: Option<&'a [u8]>,
+    pub utc_offset: Option<i32>,
+    /// The DST policy the image was placed with, if rawdb placed it
+    pub dst_policy: Option<DstPolicy>,
+}
+
+pub fn record_provenance<'a, I>(
+    conn: &Connection,
+    card_id: Option<&str>,
+    entries: I,
+) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = ProvenanceEntry<'a>>,
+{
+    let mut stmt = conn.prepare(
+        "
+        INSERT INTO provenance (name, date, size, checksum, card_id, source_path, disk_path,
+            archived_at, utc_offset, dst_policy, date_source)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+    ",
+    )?;
+
+    let now = chrono::Utc::now().naive_utc();
+    for entry in entries {
+        stmt.execute(params![
+            entry.image.basic.get_name(),
+            &entry.image.date,
+            &entry.image.basic.size,
+            entry.checksum,
+            card_id,
+            &entry.image.basic.path,
+            entry.disk_path,
+            now,
+            entry.utc_offset,
+            entry.dst_policy.as_ref().map(DstPolicy::label),
+            &entry.image.date_source,
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Find where archived images with a name matching the glob `pattern` came from
+pub fn find_provenance(conn: &Connection, pattern: &str) -> anyhow::Result<Vec<Provenance>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT provenance.name, provenance.date, provenance.size, card_id, source_path,
+            disk_path, archived_at, COALESCE(provenance.date_source, on_disk.date_source)
+        FROM provenance
+        LEFT JOIN on_disk
+        ON on_disk.path = provenance.disk_path
+        WHERE provenance.name GLOB ?1
+        ORDER BY provenance.date, provenance.name
+    ",
+    )?;
+
+    let found = stmt
+        .query_map([pattern], |row| {
+            Ok(Provenance {
+                name: row.get(0)?,
+                date: row.get(1)?,
+                size: row.get(2)?,
+                card_id: row.get(3)?,
+                source_path: row.get(4)?,
+                disk_path: row.get(5)?,
+                archived_at: row.get(6)?,
+                date_source: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(found)
+}
+
+/// A pending camera image at a path this card was previously archived from
+pub struct PreviousImport {
+    pub image: ImageAdv,
+    pub date: NaiveDateTime,
+    pub size: u64,
+    pub checksum: Option<Vec<u8>>,
+}
+
+impl PreviousImport {
+    /// Whether the camera image could still be the file archived back then
+    pub fn may_be_same(&self) -> bool {
+        self.date == self.image.date && self.size == self.image.basic.size
+    }
+}
+
+/// Find unarchived camera images whose path was archived from the same card
+/// before. If the contents differ, the card was formatted and the camera
+/// recycled the file name.
+pub fn get_previous_imports(
+    conn: &Connection,
+    card_id: &str,
+) -> anyhow::Result<Vec<PreviousImport>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT on_camera.path, on_camera.size, on_camera.date,
+            provenance.date, provenance.size, provenance.checksum, on_camera.rating,
+            on_camera.date_source, on_camera.camera, on_camera.utc_offset, on_camera.make,
+            on_camera.lens, on_camera.iso, on_camera.shutter, on_camera.aperture,
+            on_camera.latitude, on_camera.longitude, on_camera.altitude, on_camera.content_id
+        FROM on_camera
+        INNER JOIN provenance
+        ON provenance.source_path = on_camera.path
+        WHERE provenance.card_id = ?1
+            AND on_camera.card = ?1
+            AND on_camera.saved = 0
+    ",
+    )?;
+
+    let previous = stmt
+        .query_map([card_id], |row| {
+            Ok(PreviousImport {
+                image: ImageAdv {
+                    basic: ImageBasic {
+                        path: row.get(0)?,
+                        size: row.get(1)?,
+                    },
+                    date: row.get(2)?,
+                    rating: row.get(6)?,
+                    date_source: row.get(7)?,
+                    camera: row.get(8)?,
+                    utc_offset: row.get(9)?,
+                    exif: exif_from_row(row, 10)?,
+                },
+                date: row.get(3)?,
+                size: row.get(4)?,
+                checksum: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(previous)
+}
+
+pub struct RetainedImage {
+    pub path: String,
+    pub size: u64,
+    pub archived_at: Option<NaiveDateTime>,
+}
+
+/// Archived images still on the camera, split by whether they can be removed
+pub struct CardRetention {
+    /// Archived before the cutoff and still present on disk with the same size
+    pub removable: Vec<RetainedImage>,
+    /// Archived, but no longer found on disk with a matching size
+    pub unverified: Vec<RetainedImage>,
+    /// Archived after the cutoff
+    pub recent: Vec<RetainedImage>,
+}
+
+pub fn get_card_retention(
+    conn: &Connection,
+    card: &str,
+    cutoff: NaiveDateTime,
+) -> anyhow::Result<CardRetention> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT on_camera.path, on_camera.size, on_camera.archived_at, EXISTS (
+            SELECT 1
+            FROM on_disk
+            WHERE (on_disk.name = on_camera.name
+                    AND on_disk.date = on_camera.date
+                    AND on_disk.size = on_camera.size)
+                OR on_disk.checksum = on_camera.checksum
+        )
+        FROM on_camera
+        WHERE on_camera.card = ?1
+            AND on_camera.saved = 1
+    ",
+    )?;
+
+    let mut retention = CardRetention {
+        removable: Vec::new(),
+        unverified: Vec::new(),
+        recent: Vec::new(),
+    };
+
+    let rows = stmt.query_map([card], |row| {
+        Ok((
+            RetainedImage {
+                path: row.get(0)?,
+                size: row.get(1)?,
+                archived_at: row.get(2)?,
+            },
+            row.get::<_, bool>(3)?,
+        ))
+    })?;
+
+    for row in rows {
+        let (image, verified) = row?;
+        if !verified {
+            retention.unverified.push(image);
+        } else if image.archived_at.is_some_and(|date| date > cutoff) {
+            retention.recent.push(image);
+        } else {
+            // Images archived before archived_at was tracked are always old enough
+            retention.removable.push(image);
+        }
+    }
+
+    Ok(retention)
+}
+
+pub fn record_failure(
+    conn: &Connection,
+    table: TableType,
+    image: &ImageBasic,
+    err: &anyhow::Error,
+) -> anyhow::Result<()> {
+    conn.execute(
+        "
+        INSERT INTO failures (source, path, size, kind, message, last_seen)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT (source, path) DO UPDATE
+        SET size = excluded.size,
+            kind = excluded.kind,
+            message = excluded.message,
+            attempts = attempts + 1,
+            last_seen = excluded.last_seen
+    ",
+        params![
+            table.label(),
+            &image.path,
+            &image.size,
+            FailureKind::classify(err).label(),
+            err.to_string(),
+            chrono::Utc::now().naive_utc(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+pub fn clear_failures<'a, I>(conn: &Connection, table: TableType, images: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = &'a ImageBasic>,
+{
+    if safety::is_guarded(conn) {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare(
+        "
+        DELETE FROM failures
+        WHERE source = ?1 AND path = ?2
+    ",
+    )?;
+
+    for image in images {
+        stmt.execute(params![table.label(), &image.path])?;
+    }
+
+    Ok(())
+}
+
+/// Forget failures for files that are no longer present in the latest scan
+pub fn prune_failures(conn: &Connection, table: TableType) -> anyhow::Result<()> {
+    if safety::is_guarded(conn) {
+        return Ok(());
+    }
+
+    let new_name = table.to_sql(true);
+    let delete_count = conn.execute(
+        &format!(
+            "
+        DELETE FROM failures
+        WHERE source = ?1
+            AND path NOT IN (
+                SELECT path
+                FROM {new_name}
+            )
+    "
+        ),
+        [table.label()],
+    )?;
+    debug!(
+        "{} - Forgetting {} failures for files that no longer exist",
+        table.label(),
+        delete_count
+    );
+
+    Ok(())
+}
+
+/// Paths of new files that already failed at least `max_attempts` times
+/// without changing size since
+pub fn get_exhausted_failures(
+    conn: &Connection,
+    table: TableType,
+    max_attempts: u64,
+) -> anyhow::Result<HashSet<String>> {
+    let new_name = table.to_sql(true);
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT failures.path
+        FROM failures
+        INNER JOIN {new_name}
+        ON {new_name}.path = failures.path
+            AND {new_name}.size = failures.size
+        WHERE failures.source = ?1
+            AND failures.attempts >= ?2
+    "
+    ))?;
+
+    let paths = stmt
+        .query_map(params![table.label(), max_attempts], |row| row.get(0))?
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    Ok(paths)
+}
+
+pub struct FailureCount {
+    pub source: String,
+    pub kind: FailureKind,
+    pub count: u64,
+}
+
+pub fn get_failure_counts(conn: &Connection) -> anyhow::Result<Vec<FailureCount>> {
+    let mut stmt = conn.prepare(
+        "
+        SELECT source, kind, COUNT(*)
+        FROM failures
+        GROUP BY source, kind
+        ORDER BY source, COUNT(*) DESC
+    ",
+    )?;
+
+    let counts = stmt
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get::<_, String>(1)?, row.get(2)?))
+        })?
+        .map(|row| {
+            let (source, kind, count) = row?;
+            Ok(FailureCount {
+                source,
+                kind: FailureKind::from_label(&kind).unwrap_or(FailureKind::Other),
+                count,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(counts)
+}
+
+pub struct CatalogCounts {
+    pub on_disk: u64,
+    pub on_camera: u64,
+    pub unsaved: u64,
+}
+
+pub fn get_catalog_counts(conn: &Connection) -> anyhow::Result<CatalogCounts> {
+    Ok(conn.query_row(
+        "
+        SELECT
+            (SELECT COUNT(*) FROM on_disk),
+            (SELECT COUNT(*) FROM on_camera),
+            (SELECT COUNT(*) FROM on_camera WHERE saved = 0)
+    ",
+        [],
+        |row| {
+            Ok(CatalogCounts {
+                on_disk: row.get(0)?,
+                on_camera: row.get(1)?,
+                unsaved: row.get(2)?,
+            })
+        },
+    )?)
+}
+
+/// Every column of every row of an image table
+pub struct TableDump {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+pub fn dump_table(conn: &Connection, table: TableType) -> anyhow::Result<TableDump> {
+    let name = table.to_sql(false);
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {name} ORDER BY path"))?;
+    let columns = stmt
+        .column_names()
+        .into_iter()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let rows = stmt
+        .query_map([], |row| {
+            (0..columns.len())
+                .map(|i| row.get::<_, Value>(i))
+                .collect::<Result<Vec<_>, _>>()
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(TableDump { columns, rows })
+}
+
+/// Images of one table, in total and by extension
+pub struct TableStats {
+    pub files: u64,
+    pub bytes: u64,
+    /// Capture dates of the oldest and newest image
+    pub first: Option<NaiveDateTime>,
+    pub last: Option<NaiveDateTime>,
+    /// Files and bytes by lowercase extension, empty for files without one
+    pub extensions: BTreeMap<String, (u64, u64)>,
+}
+
+pub fn get_table_stats(conn: &Connection, table: TableType) -> anyhow::Result<TableStats> {
+    let name = table.to_sql(false);
+    let (files, bytes, first, last) = conn.query_row(
+        &format!("SELECT COUNT(*), COALESCE(SUM(size), 0), MIN(date), MAX(date) FROM {name}"),
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+
+    let mut extensions = BTreeMap::new();
+    let mut stmt = conn.prepare(&format!("SELECT name, size FROM {name}"))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let file_name: String = row.get(0)?;
+        let size: u64 = row.get(1)?;
+        let ext = Path::new(&file_name)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let entry: &mut (u64, u64) = extensions.entry(ext).or_default();
+        entry.0 += 1;
+        entry.1 += size;
+    }
+
+    Ok(TableStats {
+        files,
+        bytes,
+        first,
+        last,
+        extensions,
+    })
+}
+
+/// Camera images that weren't archived yet, and their bytes
+pub fn get_unsaved_stats(conn: &Connection) -> anyhow::Result<(u64, u64)> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM on_camera WHERE saved = 0",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::identity;
+
+    use super::*;
+    use itertools::Itertools;
+    use rand::prelude::*;
+
+    const IN_MEMORY: &str = ":memory:";
+
+    fn gen_random_image(counter: &mut u32) -> ImageAdv {
+        let mut rng = rand::rng();
+        *counter += 1;
+        ImageAdv {
+            basic: ImageBasic {
+                path: format!("/path/{}.jpg", counter),
+                size: rng.random::<u32>() as u64,
+            },
+            date: chrono::Utc::now().naive_utc(),
+            rating: None,
+            date_source: None,
+            camera: None,
+            utc_offset: None,
+            exif: ExifDetails::default(),
+        }
+    }
+
+    fn gen_random_groups(enabled: Vec<bool>) -> Vec<Vec<ImageAdv>> {
+        let mut vecs: Vec<Vec<ImageAdv>> = Vec::new();
+        let mut rng = rand::rng();
+        let mut image_counter = 0;
+
+        for gen_images in enabled.into_iter() {
+            let mut vec: Vec<ImageAdv> = Vec::new();
+            if gen_images {
+                vec.extend(
+                    (0..rng.random_range(1..100)).map(|_| gen_random_image(&mut image_counter)),
+                );
+            }
+            vecs.push(vec);
+        }
+
+        vecs
+    }
+
+    #[test]
+    fn test_create_table() {
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+
+        let app_id: i64 = conn
+            .pragma_query_value(None, "application_id", |row| row.get(0))
+            .unwrap();
+        assert_eq!(
+            app_id, APPLICATION_ID,
+            "create_conn did not set the application_id correctly"
+        );
+
+        let user_version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(
+            user_version, USER_VERSION,
+            "create_conn did not set the user_version correctly"
+        );
+    }
+
+    #[test]
+    fn test_update_schema_again() {
+        // A run that read the old version before another one upgraded the
+        // database skips the steps that were already applied
+        let mut conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        update_schema(&mut conn).unwrap();
+
+        let user_version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(user_version, USER_VERSION);
+    }
+
+    fn test_update_table(find_new: bool, find_common: bool, find_old: bool, table: TableType) {
+        let vecs: Vec<Vec<ImageAdv>> = gen_random_groups(vec![find_new, find_common, find_old]);
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+
+        // Setup tables
+        populate_new_table(
+            &conn,
+            table,
+            vecs[0].iter().chain(vecs[1].iter()).map(|i| &i.basic),
+            false,
+        )
+        .unwrap();
+        add_to_table(&conn, table, "", vecs[1].iter().chain(vecs[2].iter())).unwrap();
+
+        let actual_new = update_table_get_new(&conn, table, "").unwrap();
+
+        assert_eq!(
+            vecs[0].iter().map(|i| &i.basic).collect::<Vec<_>>(),
+            actual_new.iter().collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_update_table_loop() {
+        for params in (0..4).map(|_| [false, true]).multi_cartesian_product() {
+            let [new, common, old, is_camera] = params.try_into().unwrap();
+            let table = if is_camera {
+                TableType::Camera
+            } else {
+                TableType::Disk
+            };
+            println!(
+                "new: {}, common: {}, old: {}, table: {:?}",
+                new, common, old, table
+            );
+            test_update_table(new, common, old, table);
+        }
+    }
+
+    fn test_archive_images(find_new: bool, find_common: bool, find_old: bool, set_archived: bool) {
+        let vecs: Vec<Vec<ImageAdv>> = gen_random_groups(vec![find_new, find_common, find_old]);
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+
+        // Setup tables
+        add_to_table(
+            &conn,
+            TableType::Camera,
+            "",
+            vecs[0].iter().chain(vecs[1].iter()),
+        )
+        .unwrap();
+        add_to_table(
+            &conn,
+            TableType::Disk,
+            "",
+            vecs[1].iter().chain(vecs[2].iter()),
+        )
+        .unwrap();
+        if set_archived {
+            set_images_as_archived(&conn, "", vecs[1].iter()).unwrap();
+        }
+
+        let actual_common = get_images_to_archive(&conn, "").unwrap();
+
+        assert_eq!(vecs[0], actual_common.to_archive);
+
+        assert_eq!(actual_common.mismatch.len(), 0);
+
+        let expected_unmarked = if set_archived { 0 } else { vecs[1].len() };
+        assert_eq!(actual_common.unmarked.len(), expected_unmarked);
+    }
+
+    #[test]
+    fn test_archive_images_loop() {
+        for params in (0..4).map(|_| [false, true]).multi_cartesian_product() {
+            let [new, common, old, set_archived] = params.try_into().unwrap();
+            println!(
+                "new: {}, common: {}, old: {}, set_archived: {}",
+                new, common, old, set_archived
+            );
+            test_archive_images(new, common, old, set_archived);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_images() {
+        let mut counter = 0;
+        let mut images = (0..50)
+            .map(|_| gen_random_image(&mut counter).basic)
+            .collect::<Vec<_>>();
+        for i in 0..20 {
+            let mut dup = images[i].clone();
+            dup.path = dup.path.replace("path", "path2");
+            images.push(dup);
+        }
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        let duplicates = populate_new_table(&conn, TableType::Disk, &images, false).unwrap();
+
+        assert_eq!(duplicates.len(), 20);
+        #[allow(clippy::useless_vec)]
+        let mut found = vec![false; 20];
+
+        for dup_class in &duplicates {
+            let index: usize = dup_class
+                .name
+                .split('.')
+                .next()
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+                - 1;
+            assert!(!found[index]);
+            found[index] = true;
+            assert_eq!(dup_class.paths.len(), 2);
+        }
+
+        assert!(found.iter().cloned().all(identity));
+
+        // Every copy stays in the scan until it is left out
+        let left_out = duplicates
+            .iter()
+            .map(|dup| dup.paths[1].clone())
+            .collect_vec();
+        assert!(left_out.iter().all(|path| path.contains("path2")));
+        remove_from_new_table(&conn, TableType::Disk, &left_out).unwrap();
+        assert_eq!(
+            update_table_get_new(&conn, TableType::Disk, "")
+                .unwrap()
+                .len(),
+            50
+        );
+    }
+
+    fn test_trunc_images(set_archived: bool) {
+        let mut image_counter = 0;
+        let mut common = (0..50)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+
+        // Setup tables
+        add_to_table(&conn, TableType::Camera, "", common.iter()).unwrap();
+
+        common[40].basic.size -= 5;
+        common[2].basic.size += 1;
+        common[7].basic.size = 0;
+        add_to_table(&conn, TableType::Disk, "", common.iter()).unwrap();
+
+        if set_archived {
+            set_images_as_archived(&conn, "", common.iter()).unwrap();
+        }
+
+        let mut actual_common = get_images_to_archive(&conn, "").unwrap();
+
+        assert_eq!(actual_common.to_archive.len(), 0);
+
+        actual_common.mismatch.sort_by(|a, b| a[0].0.cmp(&b[0].0));
+
+        for (idx, im_idx) in [2, 40, 7].into_iter().enumerate() {
+            assert_eq!(actual_common.mismatch[idx][1].0, common[im_idx].basic.path);
+            assert_eq!(
+                actual_common.mismatch[idx][1].1,
+                common[im_idx].basic.size as i64
+            );
+        }
+    }
+
+    #[test]
+    fn test_trunc_images_loop() {
+        for set_archived in [false, true] {
+            println!("set_archived: {}", set_archived);
+            test_trunc_images(set_archived);
+        }
+    }
+
+    #[test]
+    fn test_checksum_matching() {
+        let mut image_counter = 0;
+        let camera = (0..3)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Camera, "", camera.iter()).unwrap();
+        record_checksums(
+            &conn,
+            TableType::Camera,
+            "",
+            camera
+                .iter()
+                .zip([[1u8], [2], [3]].iter())
+                .map(|(image, checksum)| (image.basic.path.as_str(), checksum.as_slice())),
+        )
+        .unwrap();
+
+        // The first image was renamed in the archive, the second has the same
+        // name and date but other contents
+        let mut renamed = camera[0].clone();
+        renamed.basic.path = "renamed/other.RAF".to_owned();
+        let disk = [renamed, camera[1].clone()];
+        add_to_table(&conn, TableType::Disk, "", disk.iter()).unwrap();
+        record_checksums(
+            &conn,
+            TableType::Disk,
+            "",
+            [
+                (disk[0].basic.path.as_str(), &[1u8][..]),
+                (disk[1].basic.path.as_str(), &[9u8][..]),
+            ],
+        )
+        .unwrap();
+
+        let matched = get_images_to_archive(&conn, "").unwrap();
+        assert_eq!(matched.unmarked.len(), 1);
+        assert_eq!(matched.unmarked[0].image.basic.path, camera[0].basic.path);
+        assert_eq!(matched.unmarked[0].disk_path, "renamed/other.RAF");
+        assert_eq!(matched.mismatch.len(), 1);
+        assert_eq!(matched.mismatch[0][0].0, camera[1].basic.path);
+        assert_eq!(matched.to_archive.len(), 1);
+        assert_eq!(matched.to_archive[0].basic.path, camera[2].basic.path);
+    }
+
+    #[test]
+    fn test_card_retention() {
+        let mut image_counter = 0;
+        let images = (0..30)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+
+        add_to_table(&conn, TableType::Camera, "", images.iter()).unwrap();
+        add_to_table(&conn, TableType::Disk, "", images[..20].iter()).unwrap();
+        set_images_as_archived(&conn, "", images[..25].iter()).unwrap();
+
+        let now = chrono::Utc::now().naive_utc();
+
+        let retention = get_card_retention(&conn, "", now).unwrap();
+        assert_eq!(retention.removable.len(), 20);
+        assert_eq!(retention.unverified.len(), 5);
+        assert_eq!(retention.recent.len(), 0);
+
+        let retention = get_card_retention(&conn, "", now - chrono::Days::new(1)).unwrap();
+        assert_eq!(retention.removable.len(), 0);
+        assert_eq!(retention.unverified.len(), 5);
+        assert_eq!(retention.recent.len(), 20);
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut image_counter = 0;
+        let images = (0..10)
+            .map(|_| gen_random_image(&mut image_counter).basic)
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        populate_new_table(&conn, TableType::Camera, &images, false).unwrap();
+
+        for image in &images[..6] {
+            let err = FailureKind::NoExif.error(format!("No exif data found in {}", image.path));
+            record_failure(&conn, TableType::Camera, image, &err).unwrap();
+        }
+        let io_err = anyhow::Error::from(std::io::Error::other("disk on fire"));
+        record_failure(&conn, TableType::Camera, &images[6], &io_err).unwrap();
+        // Recording again only bumps the attempt count
+        record_failure(&conn, TableType::Camera, &images[6], &io_err).unwrap();
+
+        let counts = get_failure_counts(&conn).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[0].kind, FailureKind::NoExif);
+        assert_eq!(counts[0].count, 6);
+        assert_eq!(counts[1].kind, FailureKind::IoError);
+        assert_eq!(counts[1].count, 1);
+
+        clear_failures(&conn, TableType::Camera, &images[..2]).unwrap();
+        populate_new_table(&conn, TableType::Camera, &images[..5], false).unwrap();
+        prune_failures(&conn, TableType::Camera).unwrap();
+
+        let exhausted = get_exhausted_failures(&conn, TableType::Camera, 2).unwrap();
+        assert_eq!(exhausted.len(), 0);
+        let exhausted = get_exhausted_failures(&conn, TableType::Camera, 1).unwrap();
+        assert_eq!(exhausted.len(), 3);
+
+        let counts = get_failure_counts(&conn).unwrap();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].kind, FailureKind::NoExif);
+        assert_eq!(counts[0].count, 3);
+    }
+
+    #[test]
+    fn test_claim_images() {
+        let mut image_counter = 0;
+        let images = (0..20)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Camera, "", images.iter()).unwrap();
+
+        let first = claim_images(&conn, "", "first", images[..15].to_vec()).unwrap();
+        assert_eq!(first.len(), 15);
+
+        let second = claim_images(&conn, "", "second", images.clone()).unwrap();
+        assert_eq!(second, images[15..]);
+
+        set_images_as_archived(&conn, "", first[..5].iter()).unwrap();
+        release_claims(&conn, "first").unwrap();
+
+        let third = claim_images(&conn, "", "third", images.clone()).unwrap();
+        assert_eq!(third, images[5..15]);
+    }
+
+    #[test]
+    fn test_interleaved_card_scans() {
+        let mut image_counter = 0;
+        let images = (0..10)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        let scan = |card: &str, images: &[ImageAdv]| {
+            populate_new_table(
+                &conn,
+                TableType::Camera,
+                images.iter().map(|i| &i.basic),
+                false,
+            )
+            .unwrap();
+            let new = update_table_get_new(&conn, TableType::Camera, card).unwrap();
+            add_to_table(&conn, TableType::Camera, card, images).unwrap();
+            new.len()
+        };
+
+        // Both cards hold the same paths, each scan only sees its own card
+        assert_eq!(scan("a", &images), 10);
+        assert_eq!(scan("b", &images[..6]), 6);
+        assert_eq!(scan("a", &images[..8]), 0);
+
+        let a = get_images_to_archive(&conn, "a").unwrap().to_archive;
+        assert_eq!(a, images[..8]);
+        let b = get_images_to_archive(&conn, "b").unwrap().to_archive;
+        assert_eq!(b, images[..6]);
+
+        let claimed = claim_images(&conn, "b", "second", b).unwrap();
+        assert_eq!(claimed.len(), 6);
+        set_images_as_archived(&conn, "a", images[..4].iter()).unwrap();
+        let a = get_images_to_archive(&conn, "a").unwrap().to_archive;
+        assert_eq!(a, images[4..8]);
+        let b = get_images_to_archive(&conn, "b").unwrap().to_archive;
+        assert_eq!(b, images[..6]);
+    }
+
+    #[test]
+    fn test_hash_algorithm() {
+        let mut image_counter = 0;
+        let image = gen_random_image(&mut image_counter);
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        assert_eq!(get_hash_algorithm(&conn).unwrap(), HashAlgorithm::Blake3);
+
+        set_hash_algorithm(&conn, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(get_hash_algorithm(&conn).unwrap(), HashAlgorithm::Sha256);
+
+        add_to_table(&conn, TableType::Disk, "", [&image]).unwrap();
+        record_checksums(
+            &conn,
+            TableType::Disk,
+            "",
+            [(image.basic.path.as_str(), &[0u8; 32][..])],
+        )
+        .unwrap();
+
+        assert!(set_hash_algorithm(&conn, HashAlgorithm::Blake3).is_err());
+        set_hash_algorithm(&conn, HashAlgorithm::Sha256).unwrap();
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut image_counter = 0;
+        let images = (0..2)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", images.iter()).unwrap();
+
+        let chunk_size = 4 << 20;
+        let size = 3 * chunk_size - 100;
+        let checksums = (0..3u8).map(|i| vec![i; 32]).collect::<Vec<_>>();
+        for image in &images {
+            record_chunks(&conn, &image.basic.path, chunk_size, size, &checksums).unwrap();
+        }
+
+        let chunks = get_chunks(&conn, &images[0].basic.path).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[2].offset, 2 * chunk_size);
+        assert_eq!(chunks[2].length, chunk_size - 100);
+        assert_eq!(chunks[1].checksum, checksums[1]);
+
+        // Chunks are dropped along with the on_disk rows they belong to
+        populate_new_table(&conn, TableType::Disk, [&images[1].basic], false).unwrap();
+        update_table_get_new(&conn, TableType::Disk, "").unwrap();
+        assert_eq!(get_chunks(&conn, &images[0].basic.path).unwrap().len(), 0);
+        assert_eq!(get_chunks(&conn, &images[1].basic.path).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_moved_files() {
+        let mut image_counter = 0;
+        let images = (0..2)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        let checksum = vec![7u8; 32];
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
+        record_checksums(
+            &conn,
+            TableType::Disk,
+            "",
+            [(images[0].basic.path.as_str(), checksum.as_slice())],
+        )
+        .unwrap();
+        record_chunks(
+            &conn,
+            &images[0].basic.path,
+            1 << 20,
+            100,
+            std::slice::from_ref(&checksum),
+        )
+        .unwrap();
+
+        // Only files with a checksum are remembered, along with their chunks
+        populate_new_table(&conn, TableType::Disk, [], false).unwrap();
+        update_table_get_new(&conn, TableType::Disk, "").unwrap();
+        assert!(get_removed_files(&conn, images[1].basic.size)
+            .unwrap()
+            .is_empty());
+        let removed = get_removed_files(&conn, images[0].basic.size).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].date, images[0].date);
+        assert_eq!(removed[0].checksum, checksum);
+
+        carry_over_removed(&conn, &removed[0], "/moved/1.jpg").unwrap();
+        assert_eq!(get_chunks(&conn, "/moved/1.jpg").unwrap().len(), 1);
+        assert!(get_removed_files(&conn, images[0].basic.size)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_verification_coverage() {
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        conn.execute_batch(
+            "
+            INSERT INTO on_disk (name, path, size, date, checksum)
+            VALUES
+                ('1.RAF', 'a/1.RAF', 1, '2024-01-01 00:00:00', x'01'),
+                ('2.RAF', 'a/2.RAF', 1, '2024-01-01 00:00:00', x'02'),
+                ('3.RAF', 'a/3.RAF', 1, '2024-01-01 00:00:00', NULL);
+        ",
+        )
+        .unwrap();
+        let old =
+            NaiveDateTime::parse_from_str("2024-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let new =
+            NaiveDateTime::parse_from_str("2025-01-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let cutoff =
+            NaiveDateTime::parse_from_str("2024-06-01 00:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+
+        record_verifications(&conn, TARGET_COPY, ["a/1.RAF"], new).unwrap();
+        record_verifications(&conn, "/mnt/mirror", ["a/1.RAF", "a/2.RAF"], old).unwrap();
+        let coverage = get_verification_coverage(&conn, cutoff).unwrap();
+        assert_eq!(coverage.len(), 2);
+        assert_eq!(coverage[0].copy, TARGET_COPY);
+        assert_eq!((coverage[0].files, coverage[0].stale), (2, 1));
+        assert_eq!(coverage[1].copy, "/mnt/mirror");
+        assert_eq!((coverage[1].files, coverage[1].stale), (2, 2));
+        assert_eq!(coverage[1].oldest, Some(old));
+    }
+
+    #[test]
+    fn test_progress() {
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let mut progress = RunProgress {
+            session: "1-1".to_owned(),
+            pid: 1,
+            command: "archive".to_owned(),
+            stage: "archiving".to_owned(),
+            done: 3,
+            total: Some(10),
+            bytes: 3 << 20,
+            current_file: Some("DCIM/1.RAF".to_owned()),
+            started_at: now,
+            stage_started_at: now,
+            updated_at: now,
+            finished_at: None,
+        };
+        update_progress(&conn, &progress).unwrap();
+        progress.done = 4;
+        update_progress(&conn, &progress).unwrap();
+
+        let running = get_running(&conn).unwrap();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].done, 4);
+        assert_eq!(running[0].total, Some(10));
+
+        progress.finished_at = Some(now);
+        update_progress(&conn, &progress).unwrap();
+        assert!(get_running(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_brackets() {
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        record_bracket(&conn, "a/1.RAF", &["a/1.JPG", "a/1_2.JPG"]).unwrap();
+
+        let set = ["a/1.RAF", "a/1.JPG", "a/1_2.JPG"];
+        assert_eq!(get_bracket_set(&conn, "a/1_2.JPG").unwrap(), set);
+        assert_eq!(get_bracket_set(&conn, "a/1.RAF").unwrap(), set);
+        assert!(get_bracket_set(&conn, "a/2.JPG").unwrap().is_empty());
+
+        add_to_table(&conn, TableType::Disk, "", []).unwrap();
+        rename_archived(&conn, "a/1.RAF", "b/1.RAF").unwrap();
+        assert_eq!(get_bracket_set(&conn, "a/1.JPG").unwrap()[0], "b/1.RAF");
+    }
+
+    #[test]
+    fn test_bursts() {
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        record_burst(&conn, &["a/1.RAF", "a/2.RAF", "a/3.RAF"]).unwrap();
+        record_burst(&conn, &["a/7.RAF", "a/8.RAF", "a/9.RAF"]).unwrap();
+        assert_eq!(
+            get_burst(&conn, "a/2.RAF").unwrap(),
+            ["a/1.RAF", "a/2.RAF", "a/3.RAF"]
+        );
+        assert!(get_burst(&conn, "a/4.RAF").unwrap().is_empty());
+
+        add_to_table(&conn, TableType::Disk, "", []).unwrap();
+        rename_archived(&conn, "a/9.RAF", "b/9.RAF").unwrap();
+        assert_eq!(get_burst(&conn, "a/7.RAF").unwrap()[2], "b/9.RAF");
+    }
+
+    #[test]
+    fn test_provenance() {
+        let mut image_counter = 0;
+        let images = (0..12)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        record_provenance(
+            &conn,
+            Some("card"),
+            images.iter().map(|image| ProvenanceEntry {
+                image,
+                disk_path: &image.basic.path,
+                checksum: None,
+                utc_offset: None,
+                dst_policy: None,
+            }),
+        )
+        .unwrap();
+
+        // Provenance survives the camera rows being reconciled away
+        populate_new_table(&conn, TableType::Camera, [], false).unwrap();
+        update_table_get_new(&conn, TableType::Camera, "").unwrap();
+
+        assert_eq!(find_provenance(&conn, "1*.jpg").unwrap().len(), 4);
+        let found = find_provenance(&conn, "7.jpg").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].card_id.as_deref(), Some("card"));
+        assert_eq!(found[0].source_path, images[6].basic.path);
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_name_collisions() {
+        let mut image_counter = 0;
+        let images = (0..4)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        // The same name a day later in another folder, once with the same
+        // contents and once with different contents
+        let mut copies = images.clone();
+        for (copy, image) in copies.iter_mut().zip(&images) {
+            copy.basic.path = format!("/other{}", image.basic.path);
+            copy.date += chrono::TimeDelta::days(1);
+        }
+        copies[0].basic.size += 1;
 
-#[cfg(test)]
-mod tests {
-    use std::convert::identity;
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", images.iter().chain(&copies)).unwrap();
 
-    use super::*;
-    use itertools::Itertools;
-    use rand::prelude::*;
+        let collisions = get_name_collisions(&conn).unwrap();
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].name, "1.jpg");
+        assert_eq!(collisions[0].files.len(), 2);
 
-    const IN_MEMORY: &str = ":memory:";
+        rename_archived(&conn, &copies[0].basic.path, "/other/path/1-2.jpg").unwrap();
+        assert!(get_name_collisions(&conn).unwrap().is_empty());
+        assert!(is_name_archived(&conn, "1-2.jpg").unwrap());
+    }
 
-    fn gen_random_image(counter: &mut u32) -> ImageAdv {
-        let mut rng = rand::rng();
-        *counter += 1;
-        ImageAdv {
-            basic: ImageBasic {
-                path: format!("/path/{}.jpg", counter),
-                size: rng.random::<u32>() as u64,
-            },
-            date: chrono::Utc::now().naive_utc(),
-        }
+    #[test]
+    fn test_identical_files() {
+        let mut image_counter = 0;
+        let images = (0..4)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        // The same image archived again under another date
+        let mut copy = images[0].clone();
+        copy.basic.path = "/other/path/1.jpg".to_owned();
+        copy.date += chrono::TimeDelta::days(1);
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", images.iter().chain([&copy])).unwrap();
+        record_checksums(
+            &conn,
+            TableType::Disk,
+            "",
+            images
+                .iter()
+                .chain([&copy])
+                .zip([[1u8], [2], [3], [4], [1]].iter())
+                .map(|(image, checksum)| (image.basic.path.as_str(), checksum.as_slice())),
+        )
+        .unwrap();
+
+        let groups = get_identical_files(&conn).unwrap();
+        assert_eq!(groups.len(), 1);
+        let paths = groups[0]
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect_vec();
+        assert_eq!(paths, ["/other/path/1.jpg", "/path/1.jpg"]);
     }
 
-    fn gen_random_groups(enabled: Vec<bool>) -> Vec<Vec<ImageAdv>> {
-        let mut vecs: Vec<Vec<ImageAdv>> = Vec::new();
-        let mut rng = rand::rng();
+    #[test]
+    fn test_colliding_images() {
         let mut image_counter = 0;
+        let images = (0..2)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        // Same names and dates on the card, only the second is the same image
+        let camera = images
+            .iter()
+            .map(|image| {
+                let mut image = image.clone();
+                image.basic.path = image.basic.path.replace("/path/", "/card/");
+                image
+            })
+            .collect::<Vec<_>>();
 
-        for gen_images in enabled.into_iter() {
-            let mut vec: Vec<ImageAdv> = Vec::new();
-            if gen_images {
-                vec.extend(
-                    (0..rng.random_range(1..100)).map(|_| gen_random_image(&mut image_counter)),
-                );
-            }
-            vecs.push(vec);
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
+        add_to_table(&conn, TableType::Camera, "", &camera).unwrap();
+        for (table, list, checksums) in [
+            (TableType::Disk, &images, [[1u8], [2]]),
+            (TableType::Camera, &camera, [[3], [2]]),
+        ] {
+            record_checksums(
+                &conn,
+                table,
+                "",
+                list.iter()
+                    .zip(checksums.iter())
+                    .map(|(image, checksum)| (image.basic.path.as_str(), checksum.as_slice())),
+            )
+            .unwrap();
         }
 
-        vecs
+        let colliding = get_colliding_images(&conn, "").unwrap();
+        assert_eq!(colliding.len(), 1);
+        assert_eq!(colliding[0].basic.path, "/card/1.jpg");
     }
 
     #[test]
-    fn test_create_table() {
+    fn test_table_stats() {
+        let mut image_counter = 0;
+        let mut images = (0..3)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        images[2].basic.path = "/path/3.RAF".to_owned();
+        images[0].date -= chrono::TimeDelta::days(10);
+
         let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
 
-        let app_id: i64 = conn
-            .pragma_query_value(None, "application_id", |row| row.get(0))
-            .unwrap();
+        let stats = get_table_stats(&conn, TableType::Disk).unwrap();
+        assert_eq!(stats.files, 3);
         assert_eq!(
-            app_id, APPLICATION_ID,
-            "create_conn did not set the application_id correctly"
+            stats.bytes,
+            images.iter().map(|image| image.basic.size).sum::<u64>()
         );
+        assert_eq!(stats.first, Some(images[0].date));
+        assert_eq!(stats.last, Some(images[1].date.max(images[2].date)));
+        assert_eq!(stats.extensions["jpg"].0, 2);
+        assert_eq!(stats.extensions["raf"], (1, images[2].basic.size));
 
-        let user_version: i64 = conn
-            .pragma_query_value(None, "user_version", |row| row.get(0))
-            .unwrap();
-        assert_eq!(
-            user_version, USER_VERSION,
-            "create_conn did not set the user_version correctly"
-        );
+        let stats = get_table_stats(&conn, TableType::Camera).unwrap();
+        assert_eq!((stats.files, stats.first), (0, None));
     }
 
-    fn test_update_table(find_new: bool, find_common: bool, find_old: bool, table: TableType) {
-        let vecs: Vec<Vec<ImageAdv>> = gen_random_groups(vec![find_new, find_common, find_old]);
+    #[test]
+    fn test_dump_table() {
+        let mut image_counter = 0;
+        let images = (0..3)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
 
         let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
 
-        // Setup tables
-        populate_new_table(
+        let dump = dump_table(&conn, TableType::Disk).unwrap();
+        assert_eq!(dump.rows.len(), 3);
+        let path = dump.columns.iter().position(|c| c == "path").unwrap();
+        assert_eq!(dump.rows[0][path], Value::Text("/path/1.jpg".to_owned()));
+        assert!(dump.rows.iter().all(|row| row.len() == dump.columns.len()));
+    }
+
+    #[test]
+    fn test_prune_disk_files() {
+        let mut image_counter = 0;
+        let images = (0..3)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
+        record_checksums(
             &conn,
-            table,
-            vecs[0].iter().chain(vecs[1].iter()).map(|i| &i.basic),
-            false,
+            TableType::Disk,
+            "",
+            [("/path/1.jpg", [1u8].as_slice())],
         )
         .unwrap();
-        add_to_table(&conn, table, vecs[1].iter().chain(vecs[2].iter())).unwrap();
-
-        let actual_new = update_table_get_new(&conn, table).unwrap();
 
-        assert_eq!(
-            vecs[0].iter().map(|i| &i.basic).collect::<Vec<_>>(),
-            actual_new.iter().collect::<Vec<_>>(),
-        );
+        prune_disk_files(&conn, &["/path/1.jpg".to_owned(), "/path/2.jpg".to_owned()]).unwrap();
+        let paths = get_recorded_files(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect_vec();
+        assert_eq!(paths, ["/path/3.jpg"]);
+
+        // Only the checksummed file can be recognized if it turns up again
+        let removed = get_removed_files(&conn, images[0].basic.size).unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(get_removed_files(&conn, images[1].basic.size)
+            .unwrap()
+            .is_empty());
     }
 
     #[test]
-    fn test_update_table_loop() {
-        for params in (0..4).map(|_| [false, true]).multi_cartesian_product() {
-            let [new, common, old, is_camera] = params.try_into().unwrap();
-            let table = if is_camera {
-                TableType::Camera
-            } else {
-                TableType::Disk
-            };
-            println!(
-                "new: {}, common: {}, old: {}, table: {:?}",
-                new, common, old, table
-            );
-            test_update_table(new, common, old, table);
-        }
+    fn test_error_variants() {
+        use crate::error::RawdbError;
+
+        let mut image_counter = 0;
+        let image = gen_random_image(&mut image_counter);
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", std::slice::from_ref(&image)).unwrap();
+        let err = conn
+            .execute(
+                "INSERT INTO on_disk (name, path, size, date) VALUES ('a', ?1, 1, '')",
+                [&image.basic.path],
+            )
+            .context("Failed to index")
+            .unwrap_err();
+        assert!(matches!(
+            RawdbError::from(err),
+            RawdbError::Database {
+                code: Some(rusqlite::ErrorCode::ConstraintViolation),
+                ..
+            }
+        ));
+
+        let err = FailureKind::NoExif.error("No exif");
+        assert!(matches!(
+            RawdbError::from(err),
+            RawdbError::Metadata {
+                kind: FailureKind::NoExif,
+                ..
+            }
+        ));
+        let err = std::fs::metadata("/nonexistent/image.raf")
+            .context("Failed to read")
+            .unwrap_err();
+        assert!(matches!(
+            RawdbError::from(err),
+            RawdbError::Io {
+                kind: std::io::ErrorKind::NotFound,
+                ..
+            }
+        ));
+        let err = crate::error::mismatch(FailureKind::IoError, "Copy is corrupt");
+        let err = RawdbError::from(err);
+        assert_eq!(err.kind(), FailureKind::IoError);
+        assert!(matches!(err, RawdbError::Verification { .. }));
     }
 
-    fn test_archive_images(find_new: bool, find_common: bool, find_old: bool, set_archived: bool) {
-        let vecs: Vec<Vec<ImageAdv>> = gen_random_groups(vec![find_new, find_common, find_old]);
+    #[test]
+    fn test_disc_export() {
+        let mut image_counter = 0;
+        let mut images = (0..4)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        images[3].date -= chrono::TimeDelta::days(400);
 
         let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
 
-        // Setup tables
-        add_to_table(
+        let start = Some(chrono::Utc::now().naive_utc() - chrono::TimeDelta::days(1));
+        let files = get_unexported_files(&conn, start, None, None).unwrap();
+        assert_eq!(files.len(), 3);
+        let files = get_unexported_files(&conn, None, None, Some("*/2.jpg")).unwrap();
+        assert_eq!(files.len(), 1);
+
+        assert_eq!(next_disc_number(&conn).unwrap(), 1);
+        let checksum = [0u8; 32];
+        record_disc(
             &conn,
-            TableType::Camera,
-            vecs[0].iter().chain(vecs[1].iter()),
+            "rawdb-0001",
+            25_000_000_000,
+            images[..2]
+                .iter()
+                .map(|image| (image.basic.path.as_str(), image.basic.size, &checksum[..])),
         )
         .unwrap();
-        add_to_table(&conn, TableType::Disk, vecs[1].iter().chain(vecs[2].iter())).unwrap();
-        if set_archived {
-            set_images_as_archived(&conn, vecs[1].iter()).unwrap();
-        }
+        assert_eq!(next_disc_number(&conn).unwrap(), 2);
+        let files = get_unexported_files(&conn, start, None, None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, images[2].basic.path);
+    }
 
-        let actual_common = get_images_to_archive(&conn).unwrap();
+    #[test]
+    fn test_incremental_scan_state() {
+        let mut image_counter = 0;
+        let images = (0..5)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
 
-        assert_eq!(vecs[0], actual_common.to_archive);
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images[..4]).unwrap();
+        record_failure(
+            &conn,
+            TableType::Disk,
+            &images[4].basic,
+            &FailureKind::NoExif.error("No exif"),
+        )
+        .unwrap();
 
-        assert_eq!(actual_common.mismatch.len(), 0);
+        let mut mtimes =
+            HashMap::from([("".to_owned(), 1), ("a".to_owned(), 2), ("b".to_owned(), 2)]);
+        record_directory_mtimes(&conn, &mtimes, &[]).unwrap();
+        mtimes.insert("a".to_owned(), 3);
+        mtimes.remove("b");
+        record_directory_mtimes(&conn, &mtimes, &["b".to_owned()]).unwrap();
+        assert_eq!(get_directory_mtimes(&conn).unwrap(), mtimes);
+
+        assert_eq!(get_last_full_scan(&conn).unwrap(), None);
+        let now = chrono::Utc::now().naive_utc();
+        set_last_full_scan(&conn, now).unwrap();
+        assert_eq!(get_last_full_scan(&conn).unwrap(), Some(now));
     }
 
     #[test]
-    fn test_archive_images_loop() {
-        for params in (0..4).map(|_| [false, true]).multi_cartesian_product() {
-            let [new, common, old, set_archived] = params.try_into().unwrap();
-            println!(
-                "new: {}, common: {}, old: {}, set_archived: {}",
-                new, common, old, set_archived
-            );
-            test_archive_images(new, common, old, set_archived);
+    fn test_update_folders() {
+        let mut image_counter = 0;
+        let mut images = (0..6)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        let sep = std::path::MAIN_SEPARATOR;
+        let folders = ["a".to_owned(), format!("a{sep}b"), "c".to_owned()];
+        for (i, image) in images.iter_mut().enumerate() {
+            image.basic.path = format!("{}{sep}{}.jpg", folders[i % 3], i);
         }
+        images[5].basic.path = format!("{}.jpg", 5);
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
+
+        // Only "a" changed, losing one image and gaining another, the
+        // folders around it are kept as they are
+        let added = gen_random_image(&mut image_counter);
+        let added = ImageBasic {
+            path: format!("a{sep}new.jpg"),
+            ..added.basic
+        };
+        populate_new_table(&conn, TableType::Disk, [&added], false).unwrap();
+        let new = update_folders_get_new(&conn, &["a".to_owned()]).unwrap();
+        assert_eq!(new, [added]);
+
+        let kept = get_recorded_files(&conn).unwrap();
+        assert_eq!(kept.len(), 4);
+        assert!(kept
+            .iter()
+            .all(|file| file.path != images[0].basic.path && file.path != images[3].basic.path));
     }
 
     #[test]
-    fn test_duplicate_images() {
-        let mut counter = 0;
-        let mut images = (0..50)
-            .map(|_| gen_random_image(&mut counter).basic)
+    fn test_guarded_connection() {
+        let mut image_counter = 0;
+        let images = (0..5)
+            .map(|_| gen_random_image(&mut image_counter))
             .collect::<Vec<_>>();
-        for i in 0..20 {
-            let mut dup = images[i].clone();
-            dup.path = dup.path.replace("path", "path2");
-            images.push(dup);
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        safety::guard_connection(&conn).unwrap();
+
+        // Scratch tables can still be rebuilt, persistent rows stay put
+        for leave in [false, true] {
+            populate_new_table(
+                &conn,
+                TableType::Disk,
+                images.iter().map(|i| &i.basic),
+                leave,
+            )
+            .unwrap();
         }
+        add_to_table(&conn, TableType::Disk, "", &images).unwrap();
+        assert!(forget_missing(&conn, TableType::Disk, "", Scanned::All).is_err());
+        set_chunk_size(&conn, Some(4 << 20)).unwrap();
+        assert!(set_chunk_size(&conn, None).is_err());
+        assert_eq!(get_chunk_size(&conn).unwrap(), Some(4 << 20));
+    }
 
-        let duplicates = populate_new_table(
-            &create_conn(IN_MEMORY.as_ref(), false).unwrap(),
-            TableType::Disk,
-            &images,
-            false,
+    #[test]
+    fn test_previous_imports() {
+        let mut image_counter = 0;
+        let images = (0..6)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        record_provenance(
+            &conn,
+            Some("card"),
+            images.iter().map(|image| ProvenanceEntry {
+                image,
+                disk_path: &image.basic.path,
+                checksum: None,
+                utc_offset: None,
+                dst_policy: None,
+            }),
         )
         .unwrap();
 
-        assert_eq!(duplicates.len(), 20);
-        let mut found = vec![false; 20];
-
-        for dup_class in duplicates {
-            let index: usize = dup_class
-                .name
-                .split('.')
-                .next()
-                .unwrap()
-                .parse::<usize>()
-                .unwrap()
-                - 1;
-            assert!(!found[index]);
-            found[index] = true;
-            assert_eq!(dup_class.paths.len(), 2);
+        // The card is formatted and the first three names come back with new
+        // images, the rest are re-seen as they were
+        let mut reused = images.clone();
+        for image in &mut reused[..3] {
+            image.date += chrono::TimeDelta::days(1);
         }
+        populate_new_table(
+            &conn,
+            TableType::Camera,
+            reused.iter().map(|i| &i.basic),
+            false,
+        )
+        .unwrap();
+        update_table_get_new(&conn, TableType::Camera, "card").unwrap();
+        add_to_table(&conn, TableType::Camera, "card", &reused).unwrap();
 
-        assert!(found.iter().cloned().all(identity));
+        let previous = get_previous_imports(&conn, "card").unwrap();
+        assert_eq!(previous.len(), 6);
+        assert_eq!(previous.iter().filter(|p| !p.may_be_same()).count(), 3);
+        assert!(get_previous_imports(&conn, "other").unwrap().is_empty());
     }
 
-    fn test_trunc_images(set_archived: bool) {
+    #[test]
+    fn test_period_summary() {
         let mut image_counter = 0;
-        let mut common = (0..50)
+        let images = (0..10)
             .map(|_| gen_random_image(&mut image_counter))
             .collect::<Vec<_>>();
 
         let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        record_provenance(
+            &conn,
+            Some("card"),
+            images.iter().map(|image| ProvenanceEntry {
+                image,
+                disk_path: &image.basic.path,
+                checksum: None,
+                utc_offset: None,
+                dst_policy: None,
+            }),
+        )
+        .unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        record_run(
+            &conn,
+            &RunStats {
+                started_at: now,
+                finished_at: now,
+                command: "archive".to_owned(),
+                files: 10,
+                bytes: 0,
+                failures: 0,
+            },
+        )
+        .unwrap();
 
-        // Setup tables
-        add_to_table(&conn, TableType::Camera, common.iter()).unwrap();
-
-        common[40].basic.size -= 5;
-        common[2].basic.size += 1;
-        common[7].basic.size = 0;
-        add_to_table(&conn, TableType::Disk, common.iter()).unwrap();
+        let total_bytes = images.iter().map(|i| i.basic.size).sum::<u64>();
+        let hour = chrono::TimeDelta::hours(1);
+        let summary = get_period_summary(&conn, now - hour, now + hour).unwrap();
+        assert_eq!(summary.archived_files, 10);
+        assert_eq!(summary.archived_bytes, total_bytes);
+        assert_eq!(summary.previous_bytes, 0);
+        assert_eq!(summary.cameras.len(), 1);
+        assert_eq!(summary.runs.len(), 1);
+
+        let summary = get_period_summary(&conn, now + hour, now + hour * 2).unwrap();
+        assert_eq!(summary.archived_files, 0);
+        assert_eq!(summary.previous_bytes, total_bytes);
+        assert_eq!(summary.runs.len(), 0);
+    }
 
-        if set_archived {
-            set_images_as_archived(&conn, common.iter()).unwrap();
+    #[test]
+    fn test_runs_since() {
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let hour = chrono::TimeDelta::hours(1);
+        for (started_at, command, files) in [
+            (now - hour, "archive", 1),
+            (now, "archive", 2),
+            (now, "index", 3),
+        ] {
+            record_run(
+                &conn,
+                &RunStats {
+                    started_at,
+                    finished_at: started_at,
+                    command: command.to_owned(),
+                    files,
+                    bytes: 0,
+                    failures: 0,
+                },
+            )
+            .unwrap();
         }
 
-        let mut actual_common = get_images_to_archive(&conn).unwrap();
+        let runs = get_runs_since(&conn, "archive", now).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].files, 2);
+        assert_eq!(
+            get_runs_since(&conn, "archive", now - hour).unwrap().len(),
+            2
+        );
+        assert!(get_runs_since(&conn, "index", now + hour)
+            .unwrap()
+            .is_empty());
+    }
 
-        assert_eq!(actual_common.to_archive.len(), 0);
+    #[test]
+    fn test_exif_details() {
+        let mut image_counter = 0;
+        let mut image = gen_random_image(&mut image_counter);
+        image.exif = ExifDetails {
+            make: Some("FUJIFILM".to_owned()),
+            lens: Some("XF23mmF2 R WR".to_owned()),
+            iso: Some(800),
+            shutter: Some("1/250".to_owned()),
+            aperture: Some(5.6),
+            position: Some(Position {
+                latitude: 46.5584,
+                longitude: 7.8348,
+                altitude: None,
+            }),
+            content_id: None,
+        };
 
-        actual_common.mismatch.sort_by(|a, b| a[0].0.cmp(&b[0].0));
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        add_to_table(&conn, TableType::Camera, "", std::slice::from_ref(&image)).unwrap();
+        let to_archive = get_images_to_archive(&conn, "").unwrap().to_archive;
+        assert_eq!(to_archive.len(), 1);
+        assert_eq!(to_archive[0].exif, image.exif);
 
-        for (idx, im_idx) in [2, 40, 7].into_iter().enumerate() {
-            assert_eq!(actual_common.mismatch[idx][1].0, common[im_idx].basic.path);
-            assert_eq!(
-                actual_common.mismatch[idx][1].1,
-                common[im_idx].basic.size as i64
-            );
-        }
+        let queried = query_images(&conn, TableType::Camera, None, None, None, None).unwrap();
+        assert_eq!(queried[0].lens.as_deref(), Some("XF23mmF2 R WR"));
     }
 
     #[test]
-    fn test_trunc_images_loop() {
-        for set_archived in [false, true] {
-            println!("set_archived: {}", set_archived);
-            test_trunc_images(set_archived);
-        }
+    fn test_live_photos() {
+        let mut image_counter = 0;
+        let mut images = (0..3)
+            .map(|_| gen_random_image(&mut image_counter))
+            .collect::<Vec<_>>();
+        images[0].basic.path = "a/IMG_1.HEIC".to_owned();
+        images[1].basic.path = "a/IMG_1.MOV".to_owned();
+        images[2].basic.path = "a/IMG_2.MOV".to_owned();
+        images[0].exif.content_id = Some("A1".to_owned());
+        images[1].exif.content_id = Some("A1".to_owned());
+        images[2].exif.content_id = Some("B2".to_owned());
+
+        let conn = create_conn(IN_MEMORY.as_ref(), false).unwrap();
+        record_provenance(
+            &conn,
+            Some("card"),
+            images[..2].iter().map(|image| ProvenanceEntry {
+                image,
+                disk_path: &image.basic.path,
+                checksum: None,
+                utc_offset: None,
+                dst_policy: None,
+            }),
+        )
+        .unwrap();
+        record_live_photo(&conn, "a/IMG_1.HEIC", "a/IMG_1.MOV").unwrap();
+        assert_eq!(
+            get_live_photo_pair(&conn, "a/IMG_1.MOV")
+                .unwrap()
+                .as_deref(),
+            Some("a/IMG_1.HEIC")
+        );
+        assert_eq!(
+            get_live_photo_pair(&conn, "a/IMG_1.HEIC")
+                .unwrap()
+                .as_deref(),
+            Some("a/IMG_1.MOV")
+        );
+
+        let now = chrono::Utc::now().naive_utc();
+        let hour = chrono::TimeDelta::hours(1);
+        let summary = get_period_summary(&conn, now - hour, now + hour).unwrap();
+        assert_eq!(summary.archived_files, 1);
+        assert_eq!(summary.cameras[0].files, 1);
     }
 }
@@ -0,0 +1,60 @@
+//! The layout of camera cards. Images are in `DCIM` as the DCF standard has
+//! it, AVCHD and XAVC S camcorders keep their clips in folders of their own,
+//! and cameras write catalogs and thumbnails next to both that are no images
+//! to archive.
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+/// Folders of a card holding images and clips, relative to its root. AVCHD
+/// clips are the `.MTS` files of a `STREAM` folder, the `CLIPINF` and
+/// `PLAYLIST` folders beside it only index them.
+const MEDIA_DIRS: &[&str] = &[
+    "DCIM",
+    "PRIVATE/AVCHD/BDMV/STREAM",
+    "AVCHD/BDMV/STREAM",
+    "PRIVATE/M4ROOT/CLIP",
+    "MP_ROOT",
+];
+
+/// Folders of catalogs inside the media folders, such as Canon's `CANONMSC`
+/// in `DCIM`
+const CATALOG_DIRS: &[&str] = &["CANONMSC", "MISC", "THMBNL"];
+
+// thm: Thumbnail of a video
+// ctg: Canon catalog
+// xml: Metadata of an XAVC S clip
+const CATALOG_EXT: &[&str] = &["thm", "ctg", "xml"];
+
+/// The folders of the card at `dir` that hold images and clips, none if it
+/// isn't laid out like a camera card
+pub fn media_dirs(dir: &Path) -> Vec<PathBuf> {
+    MEDIA_DIRS
+        .iter()
+        .map(|media| dir.join(media))
+        .filter(|path| path.is_dir())
+        .collect()
+}
+
+/// Whether the folder `name` inside a media folder holds a catalog
+pub fn is_catalog_dir(name: &OsStr) -> bool {
+    name.to_str().is_some_and(|name| {
+        CATALOG_DIRS
+            .iter()
+            .any(|catalog| catalog.eq_ignore_ascii_case(name))
+    })
+}
+
+/// Whether the file `name` inside a media folder is a catalog or a thumbnail
+pub fn is_catalog_file(name: &OsStr) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| {
+            CATALOG_EXT
+                .iter()
+                .any(|catalog| catalog.eq_ignore_ascii_case(ext))
+        })
+}
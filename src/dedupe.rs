@@ -0,0 +1,92 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use log::{info, warn};
+use rusqlite::Connection;
+
+use crate::{
+    db::{log_operation, ArchivedFile},
+    error,
+    failures::FailureKind,
+    hash::{self, HashAlgorithm},
+    safety::Safety,
+};
+
+/// Whether two paths already are links to the same file
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> anyhow::Result<bool> {
+    Ok(false)
+}
+
+/// Replace every file of `group` but the first with a hard link to the
+/// first, once its contents are read again and still match. Returns the
+/// number of files linked and the bytes that freed.
+pub fn link_duplicates(
+    conn: &Connection,
+    target_dir: &Path,
+    group: &[ArchivedFile],
+    algorithm: HashAlgorithm,
+    safety: Safety,
+) -> anyhow::Result<(usize, u64)> {
+    let Some((kept, duplicates)) = group.split_first() else {
+        return Ok((0, 0));
+    };
+    let kept_path = target_dir.join(&kept.path);
+    let (checksum, _) = hash::hash_file_chunked(&kept_path, algorithm, None)
+        .with_context(|| format!("Failed to read {}", kept_path.display()))?;
+    if kept.checksum.as_ref() != Some(&checksum) {
+        return Err(error::mismatch(
+            FailureKind::Other,
+            format!("{} no longer matches its checksum", kept.path),
+        ));
+    }
+
+    let mut linked = 0;
+    let mut freed = 0;
+    for file in duplicates {
+        let path = target_dir.join(&file.path);
+        if same_file(&kept_path, &path)? {
+            continue;
+        }
+        let (file_checksum, _) = hash::hash_file_chunked(&path, algorithm, None)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if file_checksum != checksum {
+            warn!("{} changed since it was indexed, keeping it", file.path);
+            continue;
+        }
+
+        // Link next to the duplicate first, so it is replaced in one step
+        let file_name = path
+            .file_name()
+            .expect("Archived images always have a file name");
+        let temp_path = path.with_file_name(format!(".{}.dedupe", file_name.to_string_lossy()));
+        safety.check_overwrite(&path)?;
+        if temp_path.exists() {
+            safety.remove_file(&temp_path)?;
+        }
+        fs::hard_link(&kept_path, &temp_path)
+            .with_context(|| format!("Failed to link {}", temp_path.display()))?;
+        fs::rename(&temp_path, &path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+        log_operation(
+            conn,
+            "dedupe",
+            Some(&file.path),
+            &format!("Replaced with a hard link to {}", kept.path),
+        )?;
+        info!("Linked {} to {}", file.path, kept.path);
+        linked += 1;
+        freed += file.size;
+    }
+
+    Ok((linked, freed))
+}
@@ -0,0 +1,103 @@
+use std::{fmt, fmt::Write, str::FromStr};
+
+use rusqlite::types::Value;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+use crate::{db::TableDump, hash, inventory::escape_csv};
+
+/// How `rawdb export --format` writes a table
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    Csv,
+    /// One JSON object per row
+    Json,
+}
+
+impl DumpFormat {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DumpFormat::Csv => "csv",
+            DumpFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for DumpFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(DumpFormat::Csv),
+            "json" => Ok(DumpFormat::Json),
+            _ => anyhow::bail!("Unknown export format {:?} (Expected csv or json)", s),
+        }
+    }
+}
+
+impl fmt::Display for DumpFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Checksums and other blobs are written as hex
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(value) => (*value).into(),
+        Value::Real(value) => (*value).into(),
+        Value::Text(value) => value.as_str().into(),
+        Value::Blob(value) => hash::to_hex(value).into(),
+    }
+}
+
+/// A row as a JSON object, with its fields in the order of the columns
+struct JsonRow<'a> {
+    columns: &'a [String],
+    values: &'a [Value],
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let mut map = ser.serialize_map(Some(self.columns.len()))?;
+        for (column, value) in self.columns.iter().zip(self.values) {
+            map.serialize_entry(column, &to_json(value))?;
+        }
+        map.end()
+    }
+}
+
+fn to_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(value) => value.to_string(),
+        Value::Real(value) => value.to_string(),
+        Value::Text(value) => escape_csv(value),
+        Value::Blob(value) => hash::to_hex(value),
+    }
+}
+
+pub fn write_table(dump: &TableDump, format: DumpFormat) -> anyhow::Result<String> {
+    let mut out = String::new();
+    match format {
+        DumpFormat::Csv => {
+            let header = dump.columns.iter().map(|column| escape_csv(column));
+            writeln!(out, "{}", header.collect::<Vec<_>>().join(","))?;
+            for row in &dump.rows {
+                let fields = row.iter().map(to_field).collect::<Vec<_>>();
+                writeln!(out, "{}", fields.join(","))?;
+            }
+        }
+        DumpFormat::Json => {
+            for row in &dump.rows {
+                let row = JsonRow {
+                    columns: &dump.columns,
+                    values: row,
+                };
+                writeln!(out, "{}", serde_json::to_string(&row)?)?;
+            }
+        }
+    }
+
+    Ok(out)
+}
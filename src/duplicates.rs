@@ -0,0 +1,71 @@
+use std::{fmt, io, str::FromStr};
+
+/// Which files are indexed when a name and size is found more than once
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Index the first path in sorted order
+    #[default]
+    KeepFirst,
+    /// Index every copy
+    KeepAll,
+    /// Fail before indexing anything
+    Abort,
+    /// Ask which copy to index
+    Ask,
+}
+
+impl DuplicatePolicy {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DuplicatePolicy::KeepFirst => "keep-first",
+            DuplicatePolicy::KeepAll => "keep-all",
+            DuplicatePolicy::Abort => "abort",
+            DuplicatePolicy::Ask => "ask",
+        }
+    }
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-first" => Ok(DuplicatePolicy::KeepFirst),
+            "keep-all" => Ok(DuplicatePolicy::KeepAll),
+            "abort" => Ok(DuplicatePolicy::Abort),
+            "ask" => Ok(DuplicatePolicy::Ask),
+            _ => anyhow::bail!(
+                "Unknown duplicate policy {:?} (Expected keep-first, keep-all, abort or ask)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Ask which of `paths` to index, `None` to index all of them
+pub fn ask_which(name: &str, paths: &[String]) -> anyhow::Result<Option<usize>> {
+    eprintln!("{} was found {} times:", name, paths.len());
+    for (i, path) in paths.iter().enumerate() {
+        eprintln!("  {}) {}", i + 1, path);
+    }
+    loop {
+        eprint!("Index which one? [1-{}, a for all] ", paths.len());
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            anyhow::bail!("No choice was made for {}", name);
+        }
+        match answer.trim() {
+            "a" | "all" => return Ok(None),
+            choice => match choice.parse::<usize>() {
+                Ok(index) if (1..=paths.len()).contains(&index) => return Ok(Some(index - 1)),
+                _ => eprintln!("Invalid choice {:?}", choice),
+            },
+        }
+    }
+}
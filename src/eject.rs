@@ -0,0 +1,115 @@
+//! Ejecting a card once it was archived: flushing what was written to it,
+//! unmounting it and powering down the reader where the system supports it.
+
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::path::Path;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::process::Command;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use anyhow::{bail, Context};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use log::{debug, info};
+
+#[cfg(target_os = "linux")]
+use rawdb::volumes;
+
+/// Run `program` with `args`, failing with what it printed if it fails
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    debug!("Running {} {:?}", program, args);
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Whether the block device `device`, or the disk it is a partition of,
+/// holds removable media. Built-in SD readers don't flag their cards as
+/// removable, so SD cards count as well.
+#[cfg(target_os = "linux")]
+fn is_removable(device: &Path) -> bool {
+    let Some(name) = device
+        .canonicalize()
+        .ok()
+        .and_then(|device| device.file_name().map(ToOwned::to_owned))
+    else {
+        return false;
+    };
+    let Ok(mut disk) = Path::new("/sys/class/block").join(name).canonicalize() else {
+        return false;
+    };
+    if disk.join("partition").exists() {
+        disk.pop();
+    }
+    let read = |file: &str| fs::read_to_string(disk.join(file)).unwrap_or_default();
+    read("removable").trim() == "1" || read("device/type").trim() == "SD"
+}
+
+/// Flush, unmount and power down the card mounted at or containing `dir`.
+/// udisks unmounts cards as the user that mounted them, `umount` is only
+/// tried without it.
+#[cfg(target_os = "linux")]
+pub fn eject(dir: &Path) -> anyhow::Result<()> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to open {}", dir.display()))?;
+    let volume = volumes::mounted_volumes()?
+        .into_iter()
+        .filter(|volume| dir.starts_with(&volume.mount_point))
+        .max_by_key(|volume| volume.mount_point.as_os_str().len())
+        .with_context(|| format!("{} is not on a mounted volume", dir.display()))?;
+    if volume.mount_point == Path::new("/") || !is_removable(&volume.device) {
+        bail!("{} is not on a card, it wasn't ejected", dir.display());
+    }
+    let mount_point = volume.mount_point.to_string_lossy();
+    let device = volume.device.to_string_lossy();
+
+    // SAFETY: sync has no arguments and can't fail
+    unsafe { libc::sync() };
+    if let Err(err) = run("udisksctl", &["unmount", "--block-device", &device]) {
+        debug!("Unmounting with udisks failed: {:#}", err);
+        run("umount", &[&mount_point])
+            .with_context(|| format!("Failed to unmount {}", mount_point))?;
+    }
+    // Built-in readers can't be powered down, the card is safe to remove
+    // either way
+    match run("udisksctl", &["power-off", "--block-device", &device]) {
+        Ok(()) => info!("Ejected the card at {}", mount_point),
+        Err(err) => {
+            debug!("Powering down {} failed: {:#}", device, err);
+            info!("Unmounted the card at {}", mount_point);
+        }
+    }
+    Ok(())
+}
+
+/// Flush, unmount and eject the card at `dir` with `diskutil`
+#[cfg(target_os = "macos")]
+pub fn eject(dir: &Path) -> anyhow::Result<()> {
+    // SAFETY: sync has no arguments and can't fail
+    unsafe { libc::sync() };
+    run("diskutil", &["eject", &dir.to_string_lossy()])
+        .with_context(|| format!("Failed to eject {}", dir.display()))?;
+    info!("Ejected the card at {}", dir.display());
+    Ok(())
+}
+
+/// Other systems leave ejecting the card to the user
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn eject(dir: &Path) -> anyhow::Result<()> {
+    log::warn!(
+        "Ejecting cards is not supported on this system, eject {} by hand",
+        dir.display()
+    );
+    Ok(())
+}
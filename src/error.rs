@@ -0,0 +1,137 @@
+use std::{fmt, io};
+
+use rusqlite::ErrorCode;
+
+use crate::failures::FailureKind;
+
+pub type Result<T, E = RawdbError> = std::result::Result<T, E>;
+
+/// Errors of the [`Catalog`](crate::Catalog), [`Scanner`](crate::Scanner)
+/// and [`Archiver`](crate::Archiver), sorted by what went wrong. Each keeps
+/// the error it was made from, with all of its context.
+#[derive(Debug, thiserror::Error)]
+pub enum RawdbError {
+    /// Reading or writing a file failed, `kind` is
+    /// [`io::ErrorKind::NotFound`] for a file that vanished
+    #[error("{error:#}")]
+    Io {
+        kind: io::ErrorKind,
+        error: anyhow::Error,
+    },
+    /// The metadata of an image is missing, unparseable or of an unsupported
+    /// format
+    #[error("{error:#}")]
+    Metadata {
+        kind: FailureKind,
+        error: anyhow::Error,
+    },
+    /// The database failed, such as a constraint that was violated
+    #[error("{error:#}")]
+    Database {
+        code: Option<ErrorCode>,
+        error: anyhow::Error,
+    },
+    /// A file doesn't match the checksum it was recorded or copied with
+    #[error("{error:#}")]
+    Verification { error: anyhow::Error },
+    #[error("{0:#}")]
+    Other(anyhow::Error),
+}
+
+impl RawdbError {
+    /// The failure kind the error is recorded with
+    pub fn kind(&self) -> FailureKind {
+        FailureKind::classify(self.inner())
+    }
+
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            RawdbError::Io { error, .. }
+            | RawdbError::Metadata { error, .. }
+            | RawdbError::Database { error, .. }
+            | RawdbError::Verification { error }
+            | RawdbError::Other(error) => error,
+        }
+    }
+
+    pub fn into_inner(self) -> anyhow::Error {
+        match self {
+            RawdbError::Io { error, .. }
+            | RawdbError::Metadata { error, .. }
+            | RawdbError::Database { error, .. }
+            | RawdbError::Verification { error }
+            | RawdbError::Other(error) => error,
+        }
+    }
+}
+
+impl From<anyhow::Error> for RawdbError {
+    fn from(error: anyhow::Error) -> Self {
+        if let Some(raw) = error.downcast_ref::<RawdbError>() {
+            // Already sorted when it crossed the API before
+            return match raw {
+                RawdbError::Io { kind, .. } => RawdbError::Io { kind: *kind, error },
+                RawdbError::Metadata { kind, .. } => RawdbError::Metadata { kind: *kind, error },
+                RawdbError::Database { code, .. } => RawdbError::Database { code: *code, error },
+                RawdbError::Verification { .. } => RawdbError::Verification { error },
+                RawdbError::Other(_) => RawdbError::Other(error),
+            };
+        }
+        if error.downcast_ref::<Mismatch>().is_some() {
+            return RawdbError::Verification { error };
+        }
+        if let Some(db_err) = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<rusqlite::Error>())
+        {
+            let code = db_err.sqlite_error_code();
+            return RawdbError::Database { code, error };
+        }
+
+        match FailureKind::classify(&error) {
+            kind @ (FailureKind::NoExif
+            | FailureKind::UnparseableDate
+            | FailureKind::UnsupportedFormat) => RawdbError::Metadata { kind, error },
+            FailureKind::IoError => {
+                let kind = error
+                    .chain()
+                    .find_map(|cause| cause.downcast_ref::<io::Error>())
+                    .map_or(io::ErrorKind::Other, io::Error::kind);
+                RawdbError::Io { kind, error }
+            }
+            FailureKind::Collision | FailureKind::Other => RawdbError::Other(error),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for RawdbError {
+    fn from(error: rusqlite::Error) -> Self {
+        anyhow::Error::from(error).into()
+    }
+}
+
+/// Attached to errors about a file that doesn't match its checksum, below
+/// the human readable message like a [`FailureKind`]
+#[derive(Debug)]
+pub struct Mismatch;
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("checksum mismatch")
+    }
+}
+
+/// Create an error of `kind` about a file that doesn't match its checksum
+pub fn mismatch<M>(kind: FailureKind, msg: M) -> anyhow::Error
+where
+    M: fmt::Display + Send + Sync + 'static,
+{
+    anyhow::Error::msg(kind).context(Mismatch).context(msg)
+}
+
+/// A run that went through but left some files behind, such as images that
+/// couldn't be read or copied. rawdb exits with a code of its own for it, so
+/// scripts can tell it apart from a run that failed as a whole.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct PartialFailure(pub String);
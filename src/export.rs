@@ -0,0 +1,152 @@
+use std::{
+    fmt::Write,
+    fs::{self, File, OpenOptions},
+    path::Path,
+};
+
+use anyhow::Context;
+use chrono::NaiveDate;
+use indicatif::{ProgressBar, ProgressIterator};
+
+use crate::{
+    db::ExportFile,
+    error,
+    failures::FailureKind,
+    hash::{hash_file_chunked, to_hex, HashAlgorithm},
+    images::copy_hashed,
+    report::format_size,
+};
+
+/// Which archived files to export
+pub struct Selection {
+    /// First capture date to include
+    pub from: Option<NaiveDate>,
+    /// Last capture date to include
+    pub to: Option<NaiveDate>,
+    /// Glob the path relative to the target has to match
+    pub pattern: Option<String>,
+}
+
+/// Space left free on every disc for the file system and the manifest
+const DISC_RESERVE: u64 = 64 << 20;
+
+/// The files going onto one disc
+pub struct DiscPlan {
+    pub label: String,
+    pub files: Vec<ExportFile>,
+    pub bytes: u64,
+}
+
+/// Fill discs of `capacity` bytes with `files` in order, numbering the
+/// labels from `first_number`
+pub fn plan_discs(
+    files: Vec<ExportFile>,
+    capacity: u64,
+    first_number: u64,
+) -> anyhow::Result<Vec<DiscPlan>> {
+    let usable = capacity.saturating_sub(DISC_RESERVE);
+    let mut discs: Vec<DiscPlan> = Vec::new();
+    for file in files {
+        if file.size > usable {
+            anyhow::bail!(
+                "{} ({}) doesn't fit on a disc of {}",
+                file.path,
+                format_size(file.size),
+                format_size(capacity)
+            );
+        }
+
+        if discs
+            .last()
+            .is_none_or(|disc| disc.bytes + file.size > usable)
+        {
+            discs.push(DiscPlan {
+                label: format!("rawdb-{:04}", first_number + discs.len() as u64),
+                files: Vec::new(),
+                bytes: 0,
+            });
+        }
+        let disc = discs.last_mut().unwrap();
+        disc.bytes += file.size;
+        disc.files.push(file);
+    }
+
+    Ok(discs)
+}
+
+/// Copy the files of a disc into `<staging_dir>/<label>` and write a
+/// manifest of their checksums next to them. Every copy must match the
+/// checksum recorded for the archived file, and is read back to check it
+/// was written correctly. Returns the checksum of each file.
+pub fn stage_disc(
+    disc: &DiscPlan,
+    target_dir: &Path,
+    staging_dir: &Path,
+    algorithm: HashAlgorithm,
+    pb: ProgressBar,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let disc_dir = staging_dir.join(&disc.label);
+    fs::create_dir_all(staging_dir)
+        .with_context(|| format!("Failed to create directory {}", staging_dir.display()))?;
+    fs::create_dir(&disc_dir)
+        .with_context(|| format!("Failed to create staging folder {}", disc_dir.display()))?;
+
+    pb.set_length(disc.files.len() as u64);
+    let mut manifest = String::new();
+    let mut checksums = Vec::with_capacity(disc.files.len());
+    for file in disc
+        .files
+        .iter()
+        .progress_with(pb)
+        .with_message(format!("Staging {}", disc.label))
+    {
+        let source = target_dir.join(&file.path);
+        let staged = disc_dir.join(&file.path);
+        if let Some(folder) = staged.parent() {
+            fs::create_dir_all(folder)
+                .with_context(|| format!("Failed to create directory {}", folder.display()))?;
+        }
+
+        let mut source_file =
+            File::open(&source).with_context(|| format!("Failed to open {}", source.display()))?;
+        let mut staged_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&staged)
+            .with_context(|| format!("Failed to create {}", staged.display()))?;
+        let (checksum, _) = copy_hashed(&mut source_file, &mut staged_file, algorithm, None)
+            .with_context(|| format!("Failed to copy {}", source.display()))?;
+        drop(staged_file);
+
+        if file
+            .checksum
+            .as_ref()
+            .is_some_and(|recorded| *recorded != checksum)
+        {
+            return Err(error::mismatch(
+                FailureKind::Other,
+                format!(
+                    "{} doesn't match its recorded checksum, run verify before exporting it",
+                    file.path
+                ),
+            ));
+        }
+        let (written, _) = hash_file_chunked(&staged, algorithm, None)
+            .with_context(|| format!("Failed to read back {}", staged.display()))?;
+        if written != checksum {
+            return Err(error::mismatch(
+                FailureKind::IoError,
+                format!("Staged copy {} is corrupt", staged.display()),
+            ));
+        }
+
+        writeln!(manifest, "{}  {}", to_hex(&checksum), file.path)?;
+        checksums.push(checksum);
+    }
+
+    let manifest_path = disc_dir.join(format!("MANIFEST.{}", algorithm));
+    fs::write(&manifest_path, manifest)
+        .with_context(|| format!("Failed to write {}", manifest_path.display()))?;
+
+    Ok(checksums)
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+
+use crate::error::RawdbError;
+
+/// Target of the records of [`record_left_behind`]
+pub const LEFT_BEHIND_TARGET: &str = "left-behind";
+
+/// Machine-readable reason a single file could not be indexed or archived
+///
+/// Attached to errors as context below the human readable message, so the
+/// message is still what gets logged
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FailureKind {
+    NoExif,
+    UnparseableDate,
+    IoError,
+    UnsupportedFormat,
+    Collision,
+    Other,
+}
+
+const ALL_KINDS: &[FailureKind] = &[
+    FailureKind::NoExif,
+    FailureKind::UnparseableDate,
+    FailureKind::IoError,
+    FailureKind::UnsupportedFormat,
+    FailureKind::Collision,
+    FailureKind::Other,
+];
+
+impl FailureKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FailureKind::NoExif => "no-exif",
+            FailureKind::UnparseableDate => "unparseable-date",
+            FailureKind::IoError => "io-error",
+            FailureKind::UnsupportedFormat => "unsupported-format",
+            FailureKind::Collision => "collision",
+            FailureKind::Other => "other",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Self> {
+        ALL_KINDS.iter().copied().find(|kind| kind.label() == label)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            FailureKind::NoExif => "missing EXIF",
+            FailureKind::UnparseableDate => "an unparseable date",
+            FailureKind::IoError => "IO errors",
+            FailureKind::UnsupportedFormat => "an unsupported format",
+            FailureKind::Collision => "a name collision in the archive",
+            FailureKind::Other => "other errors",
+        }
+    }
+
+    /// Create an error with this kind and a human readable message
+    pub fn error<M>(self, msg: M) -> anyhow::Error
+    where
+        M: fmt::Display + Send + Sync + 'static,
+    {
+        anyhow::Error::msg(self).context(msg)
+    }
+
+    /// Find the failure kind attached to an error, falling back to
+    /// [`FailureKind::IoError`] for untagged IO errors
+    pub fn classify(err: &anyhow::Error) -> FailureKind {
+        if let Some(kind) = err.downcast_ref::<FailureKind>() {
+            return *kind;
+        }
+        if let Some(raw) = err.downcast_ref::<RawdbError>() {
+            return raw.kind();
+        }
+
+        if err.chain().any(|cause| cause.is::<std::io::Error>()) {
+            FailureKind::IoError
+        } else {
+            FailureKind::Other
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Log `message` about a file the run failed on or skipped, with `reason` as
+/// its `reason` key, so the logger can list such files grouped by why at the
+/// end of the run
+pub fn record_left_behind(reason: &str, message: impl fmt::Display) {
+    log::warn!(target: LEFT_BEHIND_TARGET, reason; "{}", message);
+}
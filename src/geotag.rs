@@ -0,0 +1,187 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// Where an image was taken, in degrees north and east and meters above sea
+/// level
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+/// Longest time between two points of a track that a position is still
+/// interpolated between. Loggers record less often while standing still, but
+/// a longer gap usually means the logger was off.
+const MAX_GAP: TimeDelta = TimeDelta::minutes(15);
+
+struct TrackPoint {
+    time: DateTime<Utc>,
+    position: Position,
+}
+
+/// The timestamped points of one or more GPX files, sorted by time
+pub struct Track {
+    points: Vec<TrackPoint>,
+}
+
+impl Track {
+    /// Read the track points of the GPX files at `paths`. Points without a
+    /// time are left out.
+    pub fn load(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut points = Vec::new();
+        for path in paths {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read GPX file {}", path.display()))?;
+            read_points(&text, &mut points)
+                .with_context(|| format!("Invalid GPX file {}", path.display()))?;
+        }
+        if points.is_empty() {
+            anyhow::bail!("The GPX files don't have any timestamped track points");
+        }
+        points.sort_by_key(|point| point.time);
+
+        Ok(Track { points })
+    }
+
+    /// Position at `time`, interpolated between the points around it
+    pub fn locate(&self, time: DateTime<Utc>) -> Option<Position> {
+        let next = self.points.partition_point(|point| point.time < time);
+        let after = self.points.get(next)?;
+        if after.time == time {
+            return Some(after.position);
+        }
+        let before = &self.points[next.checked_sub(1)?];
+        let gap = after.time - before.time;
+        if gap > MAX_GAP {
+            return None;
+        }
+
+        let fraction =
+            (time - before.time).num_milliseconds() as f64 / gap.num_milliseconds() as f64;
+        let between = |from: f64, to: f64| from + (to - from) * fraction;
+        let (from, to) = (before.position, after.position);
+        Some(Position {
+            latitude: between(from.latitude, to.latitude),
+            longitude: between(from.longitude, to.longitude),
+            altitude: from.altitude.zip(to.altitude).map(|(a, b)| between(a, b)),
+        })
+    }
+}
+
+fn read_points(text: &str, points: &mut Vec<TrackPoint>) -> anyhow::Result<()> {
+    let doc = roxmltree::Document::parse(text)?;
+    for node in doc
+        .descendants()
+        .filter(|node| node.tag_name().name() == "trkpt")
+    {
+        let child_text = |name: &str| {
+            node.children()
+                .find(|child| child.tag_name().name() == name)
+                .and_then(|child| child.text())
+                .map(str::trim)
+        };
+        let Some(time) = child_text("time") else {
+            continue;
+        };
+        let time = DateTime::parse_from_rfc3339(time)
+            .with_context(|| format!("Invalid track point time {:?}", time))?;
+        let coordinate = |name: &str| -> anyhow::Result<f64> {
+            let value = node
+                .attribute(name)
+                .with_context(|| format!("Track point at {} without {}", time, name))?;
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {} {:?}", name, value))
+        };
+        points.push(TrackPoint {
+            time: time.to_utc(),
+            position: Position {
+                latitude: coordinate("lat")?,
+                longitude: coordinate("lon")?,
+                altitude: child_text("ele").and_then(|ele| ele.parse().ok()),
+            },
+        });
+    }
+
+    Ok(())
+}
+
+/// A coordinate as XMP writes it, degrees and decimal minutes followed by
+/// the hemisphere, such as `47,30.1234N`
+fn xmp_coordinate(degrees: f64, positive: char, negative: char) -> String {
+    let hemisphere = if degrees < 0.0 { negative } else { positive };
+    let degrees = degrees.abs();
+    format!(
+        "{},{:.6}{}",
+        degrees.trunc(),
+        degrees.fract() * 60.0,
+        hemisphere
+    )
+}
+
+/// Write `position` into an XMP sidecar next to the image at `path`, unless
+/// the image already has one. Returns whether a sidecar was written.
+pub fn write_sidecar(path: &Path, position: Position) -> anyhow::Result<bool> {
+    let with_ext = |base: &Path, ext: &str| {
+        let mut name = base.as_os_str().to_owned();
+        name.push(ext);
+        PathBuf::from(name)
+    };
+    // Named after the whole file name like copied sidecars, or its stem
+    let stem = path.with_extension("");
+    let taken = [path, stem.as_path()]
+        .into_iter()
+        .any(|base| with_ext(base, ".xmp").exists() || with_ext(base, ".XMP").exists());
+    if taken {
+        return Ok(false);
+    }
+    let sidecar = with_ext(path, ".xmp");
+
+    let altitude = position
+        .altitude
+        .map(|altitude| {
+            format!(
+                "\n   exif:GPSAltitude=\"{}/10\"\n   exif:GPSAltitudeRef=\"{}\"",
+                (altitude.abs() * 10.0).round(),
+                u8::from(altitude < 0.0)
+            )
+        })
+        .unwrap_or_default();
+    let xmp = format!(
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+   xmlns:exif="http://ns.adobe.com/exif/1.0/"
+   exif:GPSVersionID="2.2.0.0"
+   exif:GPSLatitude="{}"
+   exif:GPSLongitude="{}"{}/>
+ </rdf:RDF>
+</x:xmpmeta>
+"#,
+        xmp_coordinate(position.latitude, 'N', 'S'),
+        xmp_coordinate(position.longitude, 'E', 'W'),
+        altitude
+    );
+
+    // Never replace a sidecar, such as one copied from the card
+    let mut file = match File::create_new(&sidecar) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to create sidecar {}", sidecar.display()))
+        }
+    };
+    file.write_all(xmp.as_bytes())
+        .with_context(|| format!("Failed to write sidecar {}", sidecar.display()))?;
+
+    Ok(true)
+}
@@ -0,0 +1,155 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use sha2::Digest;
+
+/// Checksum algorithm used for every checksum stored in a database
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn label(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => anyhow::bail!("Unknown hash algorithm {:?} (Expected blake3 or sha256)", s),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Size of the buffer used when streaming files through a hasher
+pub const BUFFER_SIZE: usize = 1 << 20;
+
+/// Hashes a whole file, and optionally each fixed size chunk of it as well
+pub struct ChunkedHasher {
+    algorithm: HashAlgorithm,
+    whole: Hasher,
+    chunk_size: Option<u64>,
+    chunk: Hasher,
+    chunk_len: u64,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl ChunkedHasher {
+    pub fn new(algorithm: HashAlgorithm, chunk_size: Option<u64>) -> Self {
+        ChunkedHasher {
+            algorithm,
+            whole: algorithm.hasher(),
+            chunk_size,
+            chunk: algorithm.hasher(),
+            chunk_len: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.whole.update(data);
+
+        let Some(chunk_size) = self.chunk_size else {
+            return;
+        };
+        while !data.is_empty() {
+            let take = ((chunk_size - self.chunk_len) as usize).min(data.len());
+            self.chunk.update(&data[..take]);
+            self.chunk_len += take as u64;
+            data = &data[take..];
+
+            if self.chunk_len == chunk_size {
+                let chunk = std::mem::replace(&mut self.chunk, self.algorithm.hasher());
+                self.chunks.push(chunk.finalize());
+                self.chunk_len = 0;
+            }
+        }
+    }
+
+    /// Returns the checksum of the whole file and of each chunk
+    pub fn finalize(mut self) -> (Vec<u8>, Vec<Vec<u8>>) {
+        if self.chunk_len > 0 {
+            self.chunks.push(self.chunk.finalize());
+        }
+
+        (self.whole.finalize(), self.chunks)
+    }
+}
+
+pub fn hash_file_chunked(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    chunk_size: Option<u64>,
+) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut file = File::open(path)?;
+    let mut hasher = ChunkedHasher::new(algorithm, chunk_size);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize())
+}
+
+pub fn to_hex(checksum: &[u8]) -> String {
+    checksum
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
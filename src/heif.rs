@@ -0,0 +1,291 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+
+use crate::bmff::{read_header, Cursor};
+
+/// Extensions of HEIF containers, `hif` is used by Fujifilm and Canon
+pub const HEIF_EXT: &[&str] = &["heic", "heif", "hif"];
+
+/// Largest `meta` box read into memory, it only holds item descriptions
+const MAX_META_SIZE: u64 = 16 << 20;
+/// Largest Exif item read, more than any real camera writes
+const MAX_EXIF_SIZE: u64 = 16 << 20;
+
+/// Find the id of the Exif item in an `iinf` box
+fn find_exif_item(version: u8, mut iinf: Cursor) -> anyhow::Result<Option<u64>> {
+    let count = iinf.uint(if version == 0 { 2 } else { 4 })?;
+    for _ in 0..count {
+        let Some(header) = iinf.header()? else {
+            break;
+        };
+        if &header.kind == b"infe" {
+            let (version, mut infe) = iinf.full_box(&header);
+            // Older versions describe the item without a type
+            if version >= 2 {
+                let id = infe.uint(if version == 2 { 2 } else { 4 })?;
+                infe.uint(2)?;
+                if infe.bytes(4)? == b"Exif" {
+                    return Ok(Some(id));
+                }
+            }
+        }
+        iinf.skip_to(&header);
+    }
+    Ok(None)
+}
+
+/// Find the extents of `item` in the file from an `iloc` box, as offset and
+/// length pairs
+fn find_extents(version: u8, mut iloc: Cursor, item: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+    let sizes = iloc.uint(2)?;
+    let offset_size = (sizes >> 12) as usize;
+    let length_size = ((sizes >> 8) & 0xf) as usize;
+    let base_offset_size = ((sizes >> 4) & 0xf) as usize;
+    let index_size = if version == 0 {
+        0
+    } else {
+        (sizes & 0xf) as usize
+    };
+
+    let count = iloc.uint(if version < 2 { 2 } else { 4 })?;
+    for _ in 0..count {
+        let id = iloc.uint(if version < 2 { 2 } else { 4 })?;
+        let construction = if version == 0 { 0 } else { iloc.uint(2)? & 0xf };
+        iloc.uint(2)?;
+        let base_offset = iloc.uint(base_offset_size)?;
+        let extent_count = iloc.uint(2)?;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            iloc.uint(index_size)?;
+            let offset = iloc.uint(offset_size)?;
+            let length = iloc.uint(length_size)?;
+            let offset = base_offset
+                .checked_add(offset)
+                .context("Exif item overruns the file")?;
+            extents.push((offset, length));
+        }
+        if id == item {
+            if construction != 0 {
+                bail!("Exif item is not stored at a file offset");
+            }
+            return Ok(extents);
+        }
+    }
+    bail!("Exif item has no location")
+}
+
+/// Extract the Exif data of a HEIF image, as the TIFF structure exiv2 can
+/// read from a buffer
+pub fn read_exif(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let file_len = file.metadata()?.len();
+
+    let mut meta = None;
+    while let Some(header) = read_header(&mut file, file_len)? {
+        if &header.kind == b"meta" {
+            if header.end - header.start > MAX_META_SIZE {
+                bail!("meta box of {} is too large", path.display());
+            }
+            let mut contents = vec![0; (header.end - header.start) as usize];
+            file.read_exact(&mut contents)?;
+            meta = Some(contents);
+            break;
+        }
+        file.seek(SeekFrom::Start(header.end))?;
+    }
+    let Some(meta) = meta else {
+        bail!("No meta box found in {}", path.display());
+    };
+
+    // The meta box is a full box holding the item boxes
+    let mut boxes = Cursor::new(meta.get(4..).unwrap_or_default());
+    let mut exif_item = None;
+    let mut iloc = None;
+    while let Some(header) = boxes.header()? {
+        match &header.kind {
+            b"iinf" => {
+                let (version, iinf) = boxes.full_box(&header);
+                exif_item = find_exif_item(version, iinf)?;
+            }
+            b"iloc" => iloc = Some(boxes.full_box(&header)),
+            _ => {}
+        }
+        boxes.skip_to(&header);
+    }
+    let (Some(item), Some((version, iloc))) = (exif_item, iloc) else {
+        bail!("No Exif item found in {}", path.display());
+    };
+
+    let mut exif = Vec::new();
+    for (offset, length) in find_extents(version, iloc, item)? {
+        if (exif.len() as u64).saturating_add(length) > MAX_EXIF_SIZE {
+            bail!("Exif item of {} is too large", path.display());
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let start = exif.len();
+        exif.resize(start + length as usize, 0);
+        file.read_exact(&mut exif[start..])
+            .map_err(|err| io::Error::new(err.kind(), "Exif item overruns the file"))?;
+    }
+
+    // The item starts with the offset of the TIFF header within it
+    let mut cursor = Cursor::new(&exif);
+    let header_offset = cursor.uint(4)? as usize;
+    match exif.get(4 + header_offset..) {
+        Some(tiff) if !tiff.is_empty() => Ok(tiff.to_vec()),
+        _ => bail!("Exif item of {} is empty", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn boxed(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let size = (8 + contents.len()) as u32;
+        [&size.to_be_bytes()[..], kind, contents].concat()
+    }
+
+    fn full_box(kind: &[u8; 4], version: u8, contents: &[u8]) -> Vec<u8> {
+        boxed(kind, &[&[version, 0, 0, 0][..], contents].concat())
+    }
+
+    /// An item description of `version`, typed from version 2 on
+    fn infe(version: u8, id: u32, item_type: &[u8; 4]) -> Vec<u8> {
+        let contents = match version {
+            0 | 1 => [&(id as u16).to_be_bytes()[..], &[0, 0], b"name\0"].concat(),
+            2 => [&(id as u16).to_be_bytes()[..], &[0, 0], item_type, b"\0"].concat(),
+            _ => [&id.to_be_bytes()[..], &[0, 0], item_type, b"\0"].concat(),
+        };
+        full_box(b"infe", version, &contents)
+    }
+
+    /// The contents of an `iinf` box of `version` after its version and flags
+    fn iinf(version: u8, entries: &[Vec<u8>]) -> Vec<u8> {
+        let count = entries.len() as u32;
+        let count = if version == 0 {
+            (count as u16).to_be_bytes().to_vec()
+        } else {
+            count.to_be_bytes().to_vec()
+        };
+        [count, entries.concat()].concat()
+    }
+
+    /// The id, construction method, base offset and extents of an item
+    type Item<'a> = (u32, u16, u32, &'a [(u32, u32)]);
+
+    /// The contents of an `iloc` box of `version` after its version and
+    /// flags, with 4 byte offsets, lengths and base offsets and no extent
+    /// indexes
+    fn iloc(version: u8, items: &[Item]) -> Vec<u8> {
+        let id = |value: u32| {
+            if version < 2 {
+                (value as u16).to_be_bytes().to_vec()
+            } else {
+                value.to_be_bytes().to_vec()
+            }
+        };
+        let mut data = vec![0x44, 0x40];
+        data.extend(id(items.len() as u32));
+        for (item, construction, base_offset, extents) in items {
+            data.extend(id(*item));
+            if version > 0 {
+                data.extend(construction.to_be_bytes());
+            }
+            data.extend([0, 0]);
+            data.extend(base_offset.to_be_bytes());
+            data.extend((extents.len() as u16).to_be_bytes());
+            for (offset, length) in *extents {
+                data.extend(offset.to_be_bytes());
+                data.extend(length.to_be_bytes());
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_find_exif_item() {
+        let entries = [infe(2, 1, b"hvc1"), infe(2, 7, b"Exif")];
+        let found = find_exif_item(0, Cursor::new(&iinf(0, &entries))).unwrap();
+        assert_eq!(found, Some(7));
+
+        let entries = [infe(3, 1, b"hvc1"), infe(3, 0x10001, b"Exif")];
+        let found = find_exif_item(1, Cursor::new(&iinf(1, &entries))).unwrap();
+        assert_eq!(found, Some(0x10001));
+
+        // Untyped item descriptions are skipped
+        let entries = [
+            infe(0, 1, b"Exif"),
+            infe(1, 2, b"Exif"),
+            infe(2, 3, b"mime"),
+        ];
+        let found = find_exif_item(0, Cursor::new(&iinf(0, &entries))).unwrap();
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn test_find_extents() {
+        let items: &[Item] = &[(1, 0, 0, &[(100, 10)]), (2, 0, 1000, &[(8, 20), (50, 4)])];
+        for version in 0..=2 {
+            let data = iloc(version, items);
+            let extents = find_extents(version, Cursor::new(&data), 2).unwrap();
+            assert_eq!(extents, [(1008, 20), (1050, 4)], "version {}", version);
+            let extents = find_extents(version, Cursor::new(&data), 1).unwrap();
+            assert_eq!(extents, [(100, 10)]);
+        }
+
+        let data = iloc(1, &[(1, 1, 0, &[(100, 10)])]);
+        assert!(find_extents(1, Cursor::new(&data), 1).is_err());
+        let data = iloc(2, items);
+        assert!(find_extents(2, Cursor::new(&data), 3).is_err());
+    }
+
+    /// A HEIF file whose Exif item `exif` is split over two extents in
+    /// `mdat`, or that has no Exif item
+    fn heif_file(exif: Option<&[u8]>) -> Vec<u8> {
+        let ftyp = boxed(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let exif = exif.unwrap_or_default();
+        let split = exif.len() / 2;
+        let meta = |start: u32| {
+            let mut entries = vec![infe(2, 1, b"hvc1")];
+            if !exif.is_empty() {
+                entries.push(infe(2, 2, b"Exif"));
+            }
+            let extents = [
+                (start, split as u32),
+                (start + split as u32, (exif.len() - split) as u32),
+            ];
+            let iinf = full_box(b"iinf", 0, &iinf(0, &entries));
+            let iloc = full_box(b"iloc", 1, &iloc(1, &[(2, 0, 0, &extents)]));
+            full_box(b"meta", 0, &[iinf, iloc].concat())
+        };
+        let start = (ftyp.len() + meta(0).len() + 8) as u32;
+        [ftyp, meta(start), boxed(b"mdat", exif)].concat()
+    }
+
+    fn read_exif_of(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let path = env::temp_dir().join(format!("rawdb-heif-{}.heic", uuid::Uuid::new_v4()));
+        fs::write(&path, data).unwrap();
+        let res = read_exif(&path);
+        fs::remove_file(&path).unwrap();
+        res
+    }
+
+    #[test]
+    fn test_read_exif() {
+        let tiff = b"MM\0*\0\0\0\x08\0\0";
+        let exif = [&[0, 0, 0, 6][..], b"Exif\0\0", tiff].concat();
+        assert_eq!(read_exif_of(&heif_file(Some(&exif))).unwrap(), tiff);
+
+        let err = read_exif_of(&heif_file(None)).unwrap_err();
+        assert!(err.to_string().contains("No Exif item"), "{:#}", err);
+    }
+}
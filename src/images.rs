@@ -1,8 +1,35 @@
-use anyhow::{anyhow, bail, Context};
-use std::{ffi::OsStr, fs, path::Path};
+use anyhow::{anyhow, Context};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
 
-use chrono::{DateTime, NaiveDateTime};
-use rexiv2::Metadata;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, Timelike};
+use indicatif::ProgressBar;
+use log::{debug, warn};
+
+use crate::{
+    card::CARD_ID_FILE,
+    clock::{ClockOffset, DisplayOffset},
+    collisions::{numbered_name, CollisionPolicy},
+    dates, dcim, error,
+    failures::{self, FailureKind},
+    geotag::Position,
+    hash::{self, ChunkedHasher, HashAlgorithm},
+    heif::{self, HEIF_EXT},
+    layout::{Layout, TimeZonePolicy},
+    metadata::{self, Metadata},
+    pool,
+    safety::{Safety, QUARANTINE_DIR},
+    sniff::{self, ContentType},
+    video::{self, VideoMetadata},
+};
 use walkdir::{DirEntry, WalkDir};
 
 pub trait ImageExt: Sized {
@@ -43,6 +70,34 @@ impl ImageBasic {
 pub struct ImageAdv {
     pub basic: ImageBasic,
     pub date: NaiveDateTime,
+    /// Rating set in camera, -1 for images marked as rejected
+    pub rating: Option<i32>,
+    /// Where the date was read from, the metadata tag or tool
+    pub date_source: Option<String>,
+    /// Model of the camera, as recorded in its metadata
+    pub camera: Option<String>,
+    /// Offset from UTC of `date` in seconds, if the metadata records it
+    pub utc_offset: Option<i32>,
+    pub exif: ExifDetails,
+}
+
+/// Camera, lens and exposure settings and other details recorded in an
+/// image's metadata
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExifDetails {
+    /// Maker of the camera
+    pub make: Option<String>,
+    pub lens: Option<String>,
+    pub iso: Option<i32>,
+    /// Exposure time in seconds, such as `1/250` or `2`
+    pub shutter: Option<String>,
+    /// The f-number, such as 2.8
+    pub aperture: Option<f64>,
+    /// From the GPS tags, or interpolated from a GPX track while archiving
+    pub position: Option<Position>,
+    /// Identifier Apple devices give both the photo and the video of a Live
+    /// Photo
+    pub content_id: Option<String>,
 }
 
 // mov: Quicktime movie
@@ -50,57 +105,545 @@ pub struct ImageAdv {
 // avi: AVI video
 // webm: WebM video
 // mkv: Matroska video
-const VIDEO_EXT: &[&str] = &["mov", "mp4", "avi", "webm", "mkv"];
+// mts, m2ts: AVCHD clip
+const VIDEO_EXT: &[&str] = &["mov", "mp4", "avi", "webm", "mkv", "mts", "m2ts"];
+
+/// QuickTime tag of the content identifier of Live Photo videos, the photo
+/// records it in the Apple maker note
+const LIVE_PHOTO_VIDEO_TAG: &str = "com.apple.quicktime.content.identifier";
+const LIVE_PHOTO_IMAGE_TAG: &str = "Exif.Apple.ContentIdentifier";
+
+/// Whether the file at `path` is a video, by its extension
+pub fn is_video(path: &str) -> bool {
+    has_extension(Path::new(path), VIDEO_EXT)
+}
+
+/// What the file at `abs_path` holds, by its first bytes, or by its
+/// extension if they don't tell
+fn content_type(abs_path: &Path) -> io::Result<ContentType> {
+    let by_extension = if has_extension(abs_path, VIDEO_EXT) {
+        ContentType::Video
+    } else if has_extension(abs_path, HEIF_EXT) {
+        ContentType::Heif
+    } else {
+        ContentType::Image
+    };
+    sniff::detect_or(abs_path, by_extension)
+}
+
+/// How the metadata of new files is read
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ReadOptions<'a> {
+    /// Zone the creation times of videos are shown in
+    pub timezone: TimeZonePolicy,
+    /// Corrections of cameras whose clock was off
+    pub clock_offsets: &'a [ClockOffset],
+    /// Binary probing videos other than MP4 and QuickTime, see
+    /// [`video::find_ffprobe`]
+    pub ffprobe: Option<&'a Path>,
+}
 
 impl ImageAdv {
-    pub fn from_basic(basic: ImageBasic, base: &Path) -> anyhow::Result<Self> {
+    /// Read the metadata of `basic` below `base`. The capture times of
+    /// cameras whose clock was off are corrected by the matching clock
+    /// offsets of `options`.
+    pub fn from_basic(
+        basic: ImageBasic,
+        base: &Path,
+        options: ReadOptions,
+    ) -> anyhow::Result<Self> {
         let abs_path = base.join(&basic.path);
 
-        let is_movie = abs_path
-            .extension()
-            .and_then(OsStr::to_str)
-            .map(|ext| VIDEO_EXT.contains(&ext.to_lowercase().as_str()))
-            .unwrap_or(false);
+        let content_type = content_type(&abs_path)
+            .with_context(|| format!("Failed to read {}", abs_path.display()))?;
+        if content_type == ContentType::Other {
+            return Err(FailureKind::UnsupportedFormat.error(format!(
+                "{} is neither an image nor a video",
+                abs_path.display()
+            )));
+        }
 
-        let date = if is_movie {
-            let metadata = ffprobe::ffprobe(&abs_path).with_context(|| {
-                format!("No metadata found on video file {}", abs_path.display())
-            })?;
+        let (date, utc_offset, rating, date_source, camera, exif) =
+            if content_type == ContentType::Video {
+                let metadata = video::read_metadata(&abs_path, options.ffprobe)
+                    .map_err(|err| match FailureKind::classify(&err) {
+                        FailureKind::IoError => err,
+                        _ => err.context(FailureKind::UnsupportedFormat),
+                    })
+                    .with_context(|| {
+                        format!("No metadata found on video file {}", abs_path.display())
+                    })?;
 
-            let Some(stream) = metadata.streams.into_iter().next() else {
-                bail!("Video format has no streams: {}", abs_path.display())
-            };
+                let content_id = metadata.tags.get(LIVE_PHOTO_VIDEO_TAG).cloned();
+                if metadata.streams == 0 {
+                    return Err(FailureKind::UnsupportedFormat.error(format!(
+                        "Video format has no streams: {}",
+                        abs_path.display()
+                    )));
+                }
+                let (date, utc_offset, date_source) =
+                    read_video_date(&metadata, &abs_path, options.timezone)?;
+                (
+                    date,
+                    utc_offset,
+                    None,
+                    date_source,
+                    None,
+                    ExifDetails {
+                        content_id,
+                        ..Default::default()
+                    },
+                )
+            } else {
+                let metadata = if content_type == ContentType::Heif {
+                    read_heif_metadata(&abs_path)?
+                } else {
+                    Metadata::new_from_path(&abs_path)
+                        .context(FailureKind::UnsupportedFormat)
+                        .with_context(|| {
+                            format!("Unrecognized image format in {}", abs_path.display())
+                        })?
+                };
+
+                if !metadata.has_exif() {
+                    return Err(FailureKind::NoExif
+                        .error(format!("No exif data found in {}", abs_path.display())));
+                }
 
-            let Some(date_str) = stream.tags.and_then(|tags| tags.creation_time) else {
-                bail!(
-                    "No creation time found in video file {}",
-                    abs_path.display()
+                let (date, utc_offset, date_source) = read_exif_date(&metadata, &abs_path)?;
+                let camera = read_text(&metadata, "Exif.Image.Model");
+                let serial = read_text(&metadata, "Exif.Photo.BodySerialNumber");
+                let correction = options
+                    .clock_offsets
+                    .iter()
+                    .find(|offset| offset.applies_to(camera.as_deref(), serial.as_deref(), date));
+                let (date, date_source) = match correction {
+                    Some(correction) => (
+                        date + correction.offset,
+                        format!(
+                            "{} corrected by {}",
+                            date_source,
+                            DisplayOffset(correction.offset)
+                        ),
+                    ),
+                    None => (date, date_source.to_owned()),
+                };
+                (
+                    date,
+                    utc_offset,
+                    read_rating(&metadata),
+                    date_source,
+                    camera,
+                    read_exif_details(&metadata),
                 )
             };
-            DateTime::parse_from_rfc3339(&date_str)?.naive_local()
-        } else {
-            let metadata = Metadata::new_from_path(&abs_path)
-                .with_context(|| format!("Unrecognized image format in {}", abs_path.display()))?;
 
-            if !metadata.has_exif() {
-                bail!("No exif data found in {}", abs_path.display());
+        Ok(ImageAdv {
+            basic,
+            date,
+            rating,
+            date_source: Some(date_source),
+            camera,
+            utc_offset,
+            exif,
+        })
+    }
+}
+
+/// What reading a new file found out about it
+pub struct Inspected {
+    pub checksum: Vec<u8>,
+    pub image: anyhow::Result<ImageAdv>,
+}
+
+/// Hash every file of `images` and read its metadata, on `jobs` threads.
+/// Files that can't be read fail as a whole, files without usable metadata
+/// only fail `image`. The results keep the order of `images`.
+pub fn inspect_images(
+    dir: &Path,
+    images: Vec<ImageBasic>,
+    algorithm: HashAlgorithm,
+    options: ReadOptions,
+    mut jobs: usize,
+    pb: &ProgressBar,
+) -> Vec<(ImageBasic, anyhow::Result<Inspected>)> {
+    let inspect = |basic: &ImageBasic| {
+        let abs_path = dir.join(&basic.path);
+        let (checksum, _) = hash::hash_file_chunked(&abs_path, algorithm, None)
+            .with_context(|| format!("Failed to read {}", abs_path.display()))?;
+        Ok(Inspected {
+            checksum,
+            image: ImageAdv::from_basic(basic.clone(), dir, options),
+        })
+    };
+
+    // gexiv2 has to be set up before it's used from several threads
+    if jobs > 1 {
+        if let Err(err) = metadata::initialize() {
+            warn!(
+                "Failed to set up exiv2 for threads, reading metadata serially: {}",
+                err
+            );
+            jobs = 1;
+        }
+    }
+
+    let results = pool::map_ordered(&images, jobs, inspect, |_, _| pb.inc(1));
+    images.into_iter().zip(results).collect()
+}
+
+/// Date tags in order of preference, with the tags holding their fraction
+/// of a second and offset from UTC. Image.DateTime changes whenever the file
+/// is edited.
+const DATE_TAGS: &[(&str, &str, &str)] = &[
+    (
+        "Exif.Photo.DateTimeOriginal",
+        "Exif.Photo.SubSecTimeOriginal",
+        "Exif.Photo.OffsetTimeOriginal",
+    ),
+    (
+        "Exif.Photo.DateTimeDigitized",
+        "Exif.Photo.SubSecTimeDigitized",
+        "Exif.Photo.OffsetTimeDigitized",
+    ),
+    (
+        "Exif.Image.DateTime",
+        "Exif.Photo.SubSecTime",
+        "Exif.Photo.OffsetTime",
+    ),
+];
+
+fn sync_file(file: &File, options: CopyOptions) -> io::Result<()> {
+    if options.fsync {
+        file.sync_all()
+    } else {
+        Ok(())
+    }
+}
+
+/// Flush the entries of `dir`, so the names of new copies survive a power
+/// loss as well
+#[cfg(unix)]
+fn sync_dir(dir: &Path, options: CopyOptions) -> anyhow::Result<()> {
+    if !options.fsync {
+        return Ok(());
+    }
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync directory {}", dir.display()))
+}
+
+/// Directories can't be opened to flush them here, the file system commits
+/// their entries with the files
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path, _options: CopyOptions) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Attributes of the original file its copies keep
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Preserve {
+    /// The modification time, as set by the camera
+    pub mtime: bool,
+    /// The permissions
+    pub mode: bool,
+}
+
+impl Default for Preserve {
+    fn default() -> Self {
+        Preserve {
+            mtime: true,
+            mode: false,
+        }
+    }
+}
+
+impl FromStr for Preserve {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut preserve = Preserve {
+            mtime: false,
+            mode: false,
+        };
+        if s == "none" {
+            return Ok(preserve);
+        }
+        for attribute in s.split(',') {
+            match attribute.trim() {
+                "mtime" => preserve.mtime = true,
+                "mode" => preserve.mode = true,
+                _ => anyhow::bail!(
+                    "Unknown attribute {:?} to preserve (Expected mtime, mode or none)",
+                    attribute
+                ),
             }
+        }
+        Ok(preserve)
+    }
+}
 
-            let date_str = metadata
-                .get_tag_string("Exif.Image.DateTime")
-                .with_context(|| format!("No exif date found in {}", abs_path.display()))?;
+impl fmt::Display for Preserve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.mtime, self.mode) {
+            (true, true) => f.write_str("mtime,mode"),
+            (true, false) => f.write_str("mtime"),
+            (false, true) => f.write_str("mode"),
+            (false, false) => f.write_str("none"),
+        }
+    }
+}
+
+/// How copies are written into the archive and its mirrors
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CopyOptions<'a> {
+    /// Attributes of the original the copies keep
+    pub preserve: Preserve,
+    /// Flush every copy and the folder holding it to disk before it is
+    /// recorded as saved
+    pub fsync: bool,
+    /// Whether failed copies may be deleted
+    pub safety: Safety<'a>,
+}
+
+/// QuickTime key of Apple devices with the local capture time and its offset
+const QUICKTIME_CREATION_DATE: &str = "com.apple.quicktime.creationdate";
+const QUICKTIME_DATE_SOURCE: &str = "QuickTime creationdate";
+
+/// Handlers of cameras that write their local time into `creation_time`,
+/// which is meant to be UTC
+const LOCAL_TIME_HANDLERS: &[&str] = &["GoPro", "DJI"];
+
+/// A creation time as containers write it, with or without an offset.
+/// Times without one are UTC.
+fn parse_video_time(time: &str) -> Option<DateTime<FixedOffset>> {
+    let time = time.trim();
+    let parsed = DateTime::parse_from_rfc3339(time)
+        .or_else(|_| DateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .ok()
+        .or_else(|| {
+            ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S%.f"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(time, format).ok())
+                .map(|date| date.and_utc().fixed_offset())
+        })?;
+    // Cameras without a set clock count from the epoch of the container
+    (parsed.year() > 1970).then_some(parsed)
+}
+
+/// The time a video was taken, its offset from UTC in seconds if known, and
+/// where it was read from. The QuickTime keys of Apple devices come first, as
+/// they have the local time, then the `creation_time` of the container and
+/// of each stream.
+fn read_video_date(
+    metadata: &VideoMetadata,
+    abs_path: &Path,
+    timezone: TimeZonePolicy,
+) -> anyhow::Result<(NaiveDateTime, Option<i32>, String)> {
+    let candidates = metadata
+        .tags
+        .get(QUICKTIME_CREATION_DATE)
+        .map(|time| (QUICKTIME_DATE_SOURCE, time.as_str()))
+        .into_iter()
+        .chain(
+            metadata
+                .creation_times
+                .iter()
+                .map(|(source, time)| (*source, time.as_str())),
+        )
+        .collect::<Vec<_>>();
+
+    let Some((source, created)) = candidates
+        .iter()
+        .find_map(|(source, time)| Some((*source, parse_video_time(time)?)))
+    else {
+        return Err(match candidates.first() {
+            Some((_, time)) => FailureKind::UnparseableDate.error(format!(
+                "Unable to parse creation time {:?} in {}",
+                time,
+                abs_path.display()
+            )),
+            None => FailureKind::NoExif.error(format!(
+                "No creation time found in video file {}",
+                abs_path.display()
+            )),
+        });
+    };
+
+    if source == QUICKTIME_DATE_SOURCE {
+        return Ok((
+            created.naive_local(),
+            Some(created.offset().local_minus_utc()),
+            source.to_owned(),
+        ));
+    }
+    let local_time = metadata.handlers.iter().any(|handler| {
+        LOCAL_TIME_HANDLERS
+            .iter()
+            .any(|prefix| handler.trim().starts_with(prefix))
+    });
+    if local_time {
+        // Placed in the zone camera clocks are set to, like photos without
+        // an offset
+        return Ok((
+            created.naive_utc(),
+            None,
+            format!("{} (local time)", source),
+        ));
+    }
+
+    // Other videos record an instant, shown in the zone camera clocks are
+    // set to like the times of photos
+    let local = timezone.localize(created);
+    Ok((
+        local.naive_local(),
+        Some(local.offset().local_minus_utc()),
+        source.to_owned(),
+    ))
+}
 
-            NaiveDateTime::parse_from_str(&date_str, "%Y:%m:%d %H:%M:%S")
-                .with_context(|| format!("Unable to parse exif date in {}", abs_path.display()))?
+/// The time an image was taken, its offset from UTC in seconds if recorded,
+/// and the tag it was read from
+fn read_exif_date(
+    metadata: &Metadata,
+    abs_path: &Path,
+) -> anyhow::Result<(NaiveDateTime, Option<i32>, &'static str)> {
+    let mut unparseable = None;
+    for (tag, subsec_tag, offset_tag) in DATE_TAGS {
+        let Some(date_str) = metadata.tag_string(tag) else {
+            continue;
         };
+        let date = match NaiveDateTime::parse_from_str(date_str.trim(), "%Y:%m:%d %H:%M:%S") {
+            Ok(date) => date,
+            Err(err) => {
+                unparseable.get_or_insert((tag, err));
+                continue;
+            }
+        };
+        // Digits of the fraction, so "5" is half a second
+        let subsec = metadata
+            .tag_string(subsec_tag)
+            .map(|digits| digits.trim().chars().take(9).collect::<String>())
+            .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| format!("{:0<9}", digits).parse::<u32>().ok());
+        let date = subsec
+            .and_then(|nanos| date.with_nanosecond(nanos))
+            .unwrap_or(date);
+        let utc_offset = metadata
+            .tag_string(offset_tag)
+            .and_then(|offset| offset.trim().parse::<FixedOffset>().ok())
+            .map(|offset| offset.local_minus_utc());
+        return Ok((date, utc_offset, tag));
+    }
 
-        Ok(ImageAdv { basic, date })
+    match unparseable {
+        Some((tag, err)) => Err(err)
+            .context(FailureKind::UnparseableDate)
+            .with_context(|| format!("Unable to parse {} in {}", tag, abs_path.display())),
+        None => {
+            Err(FailureKind::NoExif.error(format!("No exif date found in {}", abs_path.display())))
+        }
     }
 }
 
+fn has_extension(path: &Path, exts: &[&str]) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| exts.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Read the metadata of a HEIF image. exiv2 only reads these containers when
+/// built with BMFF support, otherwise their Exif item is read on its own.
+fn read_heif_metadata(abs_path: &Path) -> anyhow::Result<Metadata> {
+    if let Ok(metadata) = Metadata::new_from_path(abs_path) {
+        if metadata.has_exif() {
+            return Ok(metadata);
+        }
+    }
+
+    let exif = heif::read_exif(abs_path)
+        .map_err(|err| match FailureKind::classify(&err) {
+            FailureKind::IoError => err,
+            _ => err.context(FailureKind::UnsupportedFormat),
+        })
+        .with_context(|| format!("Unable to read HEIF metadata in {}", abs_path.display()))?;
+    Metadata::new_from_buffer(&exif)
+        .context(FailureKind::NoExif)
+        .with_context(|| format!("Unreadable exif data in {}", abs_path.display()))
+}
+
+/// Cameras write the rating to the embedded XMP, or to the exif rating tag
+fn read_rating(metadata: &Metadata) -> Option<i32> {
+    ["Xmp.xmp.Rating", "Exif.Image.Rating"]
+        .into_iter()
+        .find_map(|tag| metadata.tag_numeric(tag))
+}
+
+/// A text tag, trimmed, if it is set
+fn read_text(metadata: &Metadata, tag: &str) -> Option<String> {
+    metadata
+        .tag_string(tag)
+        .map(|text| text.trim().to_owned())
+        .filter(|text| !text.is_empty())
+}
+
+/// A rational tag like `28/10` as a decimal
+fn read_decimal(metadata: &Metadata, tag: &str) -> Option<f64> {
+    let text = read_text(metadata, tag)?;
+    match text.split_once('/') {
+        Some((num, den)) => {
+            let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+            (den != 0.0).then(|| num / den)
+        }
+        None => text.parse().ok(),
+    }
+}
+
+/// Reduce an exposure time like `10/2500` to `1/250`, or `20/10` to `2`
+fn format_shutter(time: &str) -> String {
+    let Some((Ok(num), Ok(den))) = time
+        .split_once('/')
+        .map(|(num, den)| (num.parse::<u32>(), den.parse::<u32>()))
+    else {
+        return time.to_owned();
+    };
+    match (num, den) {
+        (0, _) | (_, 0) => time.to_owned(),
+        (num, den) if num % den == 0 => (num / den).to_string(),
+        (num, den) if den % num == 0 => format!("1/{}", den / num),
+        (num, den) => format!("{:.1}", f64::from(num) / f64::from(den)),
+    }
+}
+
+fn read_exif_details(metadata: &Metadata) -> ExifDetails {
+    // Versions of exiv2 name the tag after EXIF 2.2 or 2.3
+    let iso = [
+        "Exif.Photo.PhotographicSensitivity",
+        "Exif.Photo.ISOSpeedRatings",
+    ]
+    .into_iter()
+    .find_map(|tag| metadata.tag_numeric(tag))
+    .filter(|iso| *iso > 0);
+    ExifDetails {
+        make: read_text(metadata, "Exif.Image.Make"),
+        lens: read_text(metadata, "Exif.Photo.LensModel"),
+        iso,
+        shutter: read_text(metadata, "Exif.Photo.ExposureTime").map(|time| format_shutter(&time)),
+        aperture: read_decimal(metadata, "Exif.Photo.FNumber"),
+        position: metadata.gps_position(),
+        content_id: read_text(metadata, LIVE_PHOTO_IMAGE_TAG),
+    }
+}
+
+/// Walked images are read in the default zone, without clock corrections or
+/// ffprobe
 impl ImageExt for ImageAdv {
     fn from_entry(entry: &DirEntry, base: &Path) -> anyhow::Result<Self> {
-        ImageAdv::from_basic(ImageBasic::from_entry(entry, base)?, base)
+        ImageAdv::from_basic(
+            ImageBasic::from_entry(entry, base)?,
+            base,
+            ReadOptions::default(),
+        )
     }
 }
 
@@ -108,58 +651,963 @@ impl ImageExt for ImageAdv {
 // pp3: Rawtherapee sidecar file
 // pto: Hugin (panorama) project file
 // txt: Text file
-const IGNORE_EXT: &[&str] = &["xmp", "pp3", "pto", "txt"];
+// part: Copy still being written by rawdb
+const IGNORE_EXT: &[&str] = &["xmp", "pp3", "pto", "txt", PART_EXT];
 
+/// Extension added to copies until they are verified
+const PART_EXT: &str = "part";
+
+/// Ignored files that belong to an image and are copied along with it
+const SIDECAR_EXT: &[&str] = &["xmp", "pp3"];
+
+/// Which files and folders a walk of a directory indexes
+#[derive(Clone, Debug, Default)]
+pub struct FileFilter {
+    /// Extensions of files that are ignored as well, such as `.LRV`
+    pub ignore_extensions: Vec<String>,
+    /// Extensions files need to have to be indexed, any if empty
+    pub include_extensions: Vec<String>,
+    /// Globs of folder names that are skipped as well
+    pub ignore_dirs: Vec<String>,
+    /// Globs of files and folders that are never indexed, such as
+    /// `_rejected` or `exports/*`. They are matched against the name and the
+    /// path relative to the scanned directory.
+    pub exclude: Vec<String>,
+    /// Follow symbolic links to files and folders, they are skipped otherwise
+    pub follow_symlinks: bool,
+    /// Size in bytes below which files are skipped, such as the empty stubs a
+    /// camera leaves when its battery dies
+    pub min_size: u64,
+    /// Index hidden files. They are ignored otherwise, such as `.DS_Store`
+    /// and the `._IMG_1234.CR3` AppleDouble files macOS writes next to every
+    /// file on a card.
+    pub include_hidden: bool,
+}
+
+/// Whether the extension `ext`, in lowercase, is one of `exts` as given,
+/// such as `.LRV`
+fn has_listed_extension(exts: &[String], ext: &str) -> bool {
+    exts.iter()
+        .any(|listed| listed.trim_start_matches('.').eq_ignore_ascii_case(ext))
+}
+
+/// Folders of other tools and of the OS that never hold images to index,
+/// such as the trash of darktable and digiKam and Synology's thumbnails
+const IGNORE_DIRS: &[&str] = &[
+    ".dtrash",
+    "@eaDir",
+    "#recycle",
+    "#snapshot",
+    ".Trash*",
+    "$RECYCLE.BIN",
+    "System Volume Information",
+    ".Spotlight-V100",
+    ".fseventsd",
+    ".thumbnails",
+];
+
+impl FileFilter {
+    /// Whether the folder `name` is skipped with everything below it
+    fn is_ignored_dir(&self, name: &OsStr) -> bool {
+        let Some(name) = name.to_str() else {
+            return false;
+        };
+        IGNORE_DIRS
+            .iter()
+            .copied()
+            .chain(self.ignore_dirs.iter().map(String::as_str))
+            .any(|pattern| dates::glob_match(pattern.as_bytes(), name.as_bytes()))
+    }
+
+    /// Whether `path`, relative to the scanned directory, is excluded
+    fn is_excluded(&self, path: &Path) -> bool {
+        let (Some(path), Some(name)) = (path.to_str(), path.file_name().and_then(OsStr::to_str))
+        else {
+            return false;
+        };
+        self.exclude.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches(['/', '\\']).as_bytes();
+            dates::glob_match(pattern, name.as_bytes())
+                || dates::glob_match(pattern, path.as_bytes())
+        })
+    }
+
+    /// Whether the walk of `dir` skips `entry`, rawdb's quarantine, ignored
+    /// folders and excluded paths
+    fn is_skipped(&self, entry: &DirEntry, dir: &Path) -> bool {
+        entry.file_name() == QUARANTINE_DIR
+            || (entry.depth() > 0
+                && entry.file_type().is_dir()
+                && self.is_ignored_dir(entry.file_name()))
+            || (entry.depth() > 0
+                && entry
+                    .path()
+                    .strip_prefix(dir)
+                    .is_ok_and(|path| self.is_excluded(path)))
+    }
+
+    /// Walk everything below `dir`, following symbolic links if asked to
+    fn walk(&self, dir: &Path) -> WalkDir {
+        WalkDir::new(dir).follow_links(self.follow_symlinks)
+    }
+}
+
+/// Whether a walk error is about a symbolic link to one of its own parent
+/// folders, warning about it if it is. The link is skipped instead of
+/// walking in circles.
+fn is_symlink_loop(err: &walkdir::Error) -> bool {
+    let Some(ancestor) = err.loop_ancestor() else {
+        return false;
+    };
+    let path = err.path().unwrap_or(ancestor);
+    warn!(
+        "Skipping {}, it links back to {}",
+        path.display(),
+        ancestor.display()
+    );
+    failures::record_left_behind("links back to a parent folder", path.display());
+    true
+}
+
+impl FileFilter {
+    /// Whether the file at `path` of `size` bytes is below the minimum size,
+    /// warning about it if it is
+    fn is_too_small(&self, path: &Path, size: u64) -> bool {
+        let too_small = size < self.min_size;
+        if too_small {
+            warn!("Skipping {}, it only has {} bytes", path.display(), size);
+            failures::record_left_behind(
+                "smaller than --min-size",
+                format_args!("{} ({} bytes)", path.display(), size),
+            );
+        }
+        too_small
+    }
+
+    /// Whether the walked file `entry` is below the minimum size, only
+    /// reading its size if there is one
+    fn is_entry_too_small(&self, entry: &DirEntry) -> anyhow::Result<bool> {
+        if self.min_size == 0 {
+            return Ok(false);
+        }
+        Ok(self.is_too_small(entry.path(), entry.metadata()?.len()))
+    }
+
+    /// Whether `file_name` is a hidden file that isn't indexed
+    fn is_hidden(&self, file_name: &OsStr) -> bool {
+        file_name.as_encoded_bytes().starts_with(b".") && !self.include_hidden
+    }
+
+    /// Files that are never indexed, sidecars, rawdb's own marker files,
+    /// hidden files and files without one of the included extensions
+    fn is_ignored(&self, file_name: &OsStr) -> bool {
+        let ext = AsRef::<Path>::as_ref(file_name)
+            .extension()
+            .and_then(OsStr::to_str)
+            .map(str::to_lowercase);
+        let excluded = !self.include_extensions.is_empty()
+            && !ext
+                .as_ref()
+                .is_some_and(|ext| has_listed_extension(&self.include_extensions, ext));
+        file_name == CARD_ID_FILE
+            || self.is_hidden(file_name)
+            || excluded
+            || ext.is_some_and(|ext| {
+                IGNORE_EXT.contains(&ext.as_str())
+                    || has_listed_extension(&self.ignore_extensions, &ext)
+            })
+    }
+}
+
+/// Every file below `dir` that `filter` indexes
 pub fn load_images<'a, I: ImageExt>(
     dir: &'a Path,
+    filter: &'a FileFilter,
+) -> impl Iterator<Item = anyhow::Result<I>> + use<'a, I> {
+    load_images_below(dir, dir.to_owned(), filter, false)
+}
+
+/// Walk `dir` like [`load_images`], but only its camera folders if it is
+/// laid out like a camera card, skipping the catalogs and thumbnails in
+/// them. Returns which folders are walked, none for the whole of `dir`.
+pub fn load_card_images<'a, I: ImageExt>(
+    dir: &'a Path,
+    filter: &'a FileFilter,
+) -> (
+    Vec<PathBuf>,
+    impl Iterator<Item = anyhow::Result<I>> + use<'a, I>,
+) {
+    let media_dirs = dcim::media_dirs(dir);
+    let (roots, card) = if media_dirs.is_empty() {
+        (vec![dir.to_owned()], false)
+    } else {
+        (media_dirs.clone(), true)
+    };
+    let images = roots
+        .into_iter()
+        .flat_map(move |root| load_images_below(dir, root, filter, card));
+    (media_dirs, images)
+}
+
+/// Whether `entry` is a catalog or a thumbnail a camera keeps next to its
+/// images
+fn is_card_catalog(entry: &DirEntry) -> bool {
+    if entry.file_type().is_dir() {
+        dcim::is_catalog_dir(entry.file_name())
+    } else {
+        dcim::is_catalog_file(entry.file_name())
+    }
+}
+
+/// The images below `root`, with paths relative to `dir`. The catalogs of a
+/// camera card are skipped if `card` is set.
+fn load_images_below<'a, I: ImageExt>(
+    dir: &'a Path,
+    root: PathBuf,
+    filter: &'a FileFilter,
+    card: bool,
 ) -> impl Iterator<Item = anyhow::Result<I>> + use<'a, I> {
-    WalkDir::new(dir)
+    filter
+        .walk(&root)
         .into_iter()
-        .map(|res| match res {
-            Ok(entry) if entry.file_type().is_file() => {
-                let ext = AsRef::<Path>::as_ref(entry.file_name())
-                    .extension()
-                    .and_then(OsStr::to_str);
-                match ext {
-                    Some(ext) if IGNORE_EXT.contains(&ext.to_lowercase().as_str()) => Ok(None),
-                    _ => Ok(Some(I::from_entry(&entry, dir)?)),
+        .filter_entry(move |entry| {
+            !filter.is_skipped(entry, dir) && (!card || !is_card_catalog(entry))
+        })
+        .map(move |res| match res {
+            Ok(entry) if entry.file_type().is_file() && !filter.is_ignored(entry.file_name()) => {
+                if filter.is_entry_too_small(&entry)? {
+                    return Ok(None);
                 }
+                Ok(Some(I::from_entry(&entry, dir)?))
             }
-            Ok(_dir_entry) => Ok(None),
+            Ok(_) => Ok(None),
+            Err(err) if is_symlink_loop(&err) => Ok(None),
             Err(err) => Err(err.into()),
         })
         .filter_map(Result::transpose)
 }
 
+/// Path of the entry a scan error is about, if it couldn't be read for lack
+/// of permissions
+pub fn access_denied(err: &anyhow::Error) -> Option<PathBuf> {
+    let err = err.downcast_ref::<walkdir::Error>()?;
+    if err.io_error()?.kind() != io::ErrorKind::PermissionDenied {
+        return None;
+    }
+    err.path().map(Path::to_owned)
+}
+
+/// Folder of a path relative to the scanned directory, empty for its root
+fn folder_of(path: &str) -> &str {
+    match path.rfind(std::path::MAIN_SEPARATOR) {
+        Some(end) => &path[..end],
+        None => "",
+    }
+}
+
+pub struct FolderScan {
+    /// Files of the changed folders
+    pub images: Vec<ImageBasic>,
+    /// Modification time of every folder, in nanoseconds since the epoch
+    pub mtimes: HashMap<String, i64>,
+    /// Folders whose files were read, or that no longer exist. Their part of
+    /// the index is replaced by the scan, the rest is kept.
+    pub changed: Vec<String>,
+    /// Known folders that no longer exist
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+    /// Entries that couldn't be read for lack of permissions
+    pub denied: Vec<PathBuf>,
+}
+
+/// Walk `dir` like [`load_images`], but only read the files of folders whose
+/// mtime doesn't match `known_mtimes`, or of all folders if `full` is set.
+/// Adding, removing or renaming a file changes the mtime of its folder, but
+/// not of the folders above it, so every folder is still listed. Folders that
+/// can't be read are left out of the scan, keeping their part of the index.
+pub fn scan_changed_folders(
+    dir: &Path,
+    filter: &FileFilter,
+    known_mtimes: &HashMap<String, i64>,
+    full: bool,
+) -> anyhow::Result<FolderScan> {
+    let mut images = Vec::new();
+    let mut mtimes = HashMap::new();
+    let mut changed = HashSet::new();
+    let mut unchanged = 0;
+    let mut denied = Vec::new();
+    let mut denied_folders = Vec::new();
+    for entry in filter
+        .walk(dir)
+        .into_iter()
+        .filter_entry(|entry| !filter.is_skipped(entry, dir))
+    {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) if is_symlink_loop(&err) => continue,
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                let Some(denied_path) = access_denied(&err) else {
+                    return Err(err);
+                };
+                if let Some(folder) = denied_path.strip_prefix(dir).ok().and_then(Path::to_str) {
+                    mtimes.remove(folder);
+                    changed.remove(folder);
+                    denied_folders.push(folder.to_owned());
+                }
+                denied.push(denied_path);
+                continue;
+            }
+        };
+        let path = entry
+            .path()
+            .strip_prefix(dir)
+            .context("Image path is not relative to base")?
+            .to_str()
+            .ok_or_else(|| anyhow!("Path {} is not utf8", entry.path().display()))?;
+
+        if entry.file_type().is_dir() {
+            let mtime = entry
+                .metadata()?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos() as i64);
+            if !full && known_mtimes.get(path) == Some(&mtime) {
+                unchanged += 1;
+            } else {
+                changed.insert(path.to_owned());
+            }
+            mtimes.insert(path.to_owned(), mtime);
+        } else if entry.file_type().is_file()
+            && changed.contains(folder_of(path))
+            && !filter.is_ignored(entry.file_name())
+            && !filter.is_entry_too_small(&entry)?
+        {
+            images.push(ImageBasic::from_entry(&entry, dir)?);
+        }
+    }
+
+    // Folders below one that couldn't be read weren't listed, but still exist
+    let is_denied = |folder: &str| {
+        denied_folders.iter().any(|denied| {
+            folder == denied
+                || folder
+                    .strip_prefix(denied.as_str())
+                    .is_some_and(|rest| rest.starts_with(std::path::MAIN_SEPARATOR))
+        })
+    };
+    let removed = known_mtimes
+        .keys()
+        .filter(|folder| !mtimes.contains_key(*folder) && !is_denied(folder))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut changed = changed.into_iter().collect::<Vec<_>>();
+    changed.extend(removed.iter().cloned());
+
+    Ok(FolderScan {
+        images,
+        mtimes,
+        changed,
+        removed,
+        unchanged,
+        denied,
+    })
+}
+
+/// Read a list of paths, one per line, from a file or from stdin for `-`
+pub fn read_file_list(list: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let text = if list == Path::new("-") {
+        io::read_to_string(io::stdin()).context("Failed to read file list from stdin")?
+    } else {
+        fs::read_to_string(list)
+            .with_context(|| format!("Failed to read file list {}", list.display()))?
+    };
+
+    Ok(text
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Load exactly the listed files instead of walking `dir`. Paths may be
+/// relative to `dir` or absolute paths inside it.
+pub fn load_listed_images(
+    dir: &Path,
+    files: &[PathBuf],
+    filter: &FileFilter,
+) -> anyhow::Result<Vec<ImageBasic>> {
+    let base = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to open {}", dir.display()))?;
+
+    let mut images = Vec::new();
+    for file in files {
+        let abs_path = base
+            .join(file)
+            .canonicalize()
+            .with_context(|| format!("Failed to open listed file {}", file.display()))?;
+        let path = abs_path.strip_prefix(&base).map_err(|_| {
+            anyhow!(
+                "Listed file {} is not inside {}",
+                file.display(),
+                dir.display()
+            )
+        })?;
+
+        if path.file_name().is_some_and(|name| filter.is_ignored(name))
+            || path
+                .ancestors()
+                .any(|path| !path.as_os_str().is_empty() && filter.is_excluded(path))
+            || path
+                .parent()
+                .is_some_and(|parent| parent.iter().any(|name| filter.is_ignored_dir(name)))
+        {
+            continue;
+        }
+
+        let metadata = fs::metadata(&abs_path)?;
+        if !metadata.is_file() || filter.is_too_small(&abs_path, metadata.len()) {
+            continue;
+        }
+
+        images.push(ImageBasic {
+            path: path
+                .to_str()
+                .ok_or_else(|| anyhow!("Path {} is not utf8", abs_path.display()))?
+                .to_owned(),
+            size: metadata.len(),
+        });
+    }
+
+    Ok(images)
+}
+
+/// The copy of an image written into the archive
+pub struct ArchivedCopy {
+    /// Path of the copy, relative to the target directory
+    pub path: String,
+    pub checksum: Vec<u8>,
+    /// Checksums of each chunk, if the database stores chunk checksums
+    pub chunks: Vec<Vec<u8>>,
+    /// Offset from UTC the capture time was placed with
+    pub utc_offset: Option<i32>,
+    /// Sidecar files copied next to it
+    pub sidecars: usize,
+    /// Where it was taken, interpolated from a GPX track for images without
+    /// GPS tags
+    pub position: Option<Position>,
+    /// The file it was copied from
+    pub source: PathBuf,
+}
+
+/// A copy written next to its target and renamed into place once verified,
+/// so an interrupted run never leaves a partial file under the real name
+struct PartFile<'a> {
+    file: File,
+    path: PathBuf,
+    target: PathBuf,
+    options: CopyOptions<'a>,
+}
+
+impl<'a> PartFile<'a> {
+    /// Start a copy to `target`. The lock on the part file claims the name,
+    /// so two copies to the same target can never both be written, while a
+    /// part file left by an interrupted run is resumed or overwritten.
+    fn create(target: &Path, options: CopyOptions<'a>) -> anyhow::Result<Self> {
+        if target.exists() {
+            return Err(
+                FailureKind::Collision.error(format!("File {} already exists", target.display()))
+            );
+        }
+        let mut name = target
+            .file_name()
+            .expect("Copies always have a file name")
+            .to_owned();
+        name.push(".");
+        name.push(PART_EXT);
+        let path = target.with_file_name(name);
+        options.safety.check_overwrite(&path)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(FailureKind::Collision.error(format!(
+                    "File {} is already being written",
+                    target.display()
+                )));
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+
+        Ok(PartFile {
+            file,
+            path,
+            target: target.to_owned(),
+            options,
+        })
+    }
+
+    /// Copy `source` into the part file, returning its checksums. Bytes an
+    /// interrupted run already wrote are kept if they hash the same as the
+    /// start of `source`, so large copies continue where they stopped. With
+    /// `reflink`, a source on the same copy on write file system is cloned
+    /// instead, and only read to hash it.
+    fn copy_from(
+        &mut self,
+        source: &mut File,
+        algorithm: HashAlgorithm,
+        chunk_size: Option<u64>,
+        reflink: bool,
+    ) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+        let mut hasher = ChunkedHasher::new(algorithm, chunk_size);
+        let written = self.file.metadata()?.len();
+        let mut cloned = false;
+        if written > 0 && self.resume(source, written, algorithm, &mut hasher)? {
+            debug!(
+                "Resuming the copy to {} after {} bytes",
+                self.target.display(),
+                written
+            );
+        } else {
+            hasher = ChunkedHasher::new(algorithm, chunk_size);
+            source.rewind()?;
+            self.file.set_len(0)?;
+            self.file.rewind()?;
+            cloned = reflink && clone_file(source, &self.file);
+            if cloned {
+                debug!("Cloned {} as a reflink", self.target.display());
+            }
+        }
+
+        let mut buffer = vec![0; hash::BUFFER_SIZE];
+        loop {
+            let read = source.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            if !cloned {
+                self.file.write_all(&buffer[..read])?;
+            }
+        }
+        self.file.flush()?;
+        if let Err(err) = self.keep_attributes(source) {
+            warn!(
+                "Failed to keep the attributes of {} on its copy: {}",
+                self.target.display(),
+                err
+            );
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    /// Give the copy the modification time and permissions of `source`, as
+    /// far as they are preserved
+    fn keep_attributes(&self, source: &File) -> io::Result<()> {
+        let preserve = self.options.preserve;
+        let metadata = source.metadata()?;
+        if preserve.mtime {
+            self.file.set_modified(metadata.modified()?)?;
+        }
+        if preserve.mode {
+            self.file.set_permissions(metadata.permissions())?;
+        }
+        Ok(())
+    }
+
+    /// Whether the first `written` bytes of the part file match `source`,
+    /// feeding those bytes of `source` to `hasher`
+    fn resume(
+        &mut self,
+        source: &mut File,
+        written: u64,
+        algorithm: HashAlgorithm,
+        hasher: &mut ChunkedHasher,
+    ) -> io::Result<bool> {
+        if written > source.metadata()?.len() {
+            return Ok(false);
+        }
+        let mut buffer = vec![0; hash::BUFFER_SIZE];
+
+        let mut copied = algorithm.hasher();
+        let mut part = (&mut self.file).take(written);
+        loop {
+            let read = part.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            copied.update(&buffer[..read]);
+        }
+
+        let mut original = algorithm.hasher();
+        let mut prefix = (&mut *source).take(written);
+        let mut total = 0;
+        loop {
+            let read = prefix.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            original.update(&buffer[..read]);
+            hasher.update(&buffer[..read]);
+            total += read as u64;
+        }
+
+        Ok(total == written && copied.finalize() == original.finalize())
+    }
+
+    fn discard(self) -> anyhow::Result<()> {
+        self.options.safety.remove_file(&self.path)
+    }
+
+    /// Move the verified copy to its real name
+    fn finish(self) -> anyhow::Result<()> {
+        sync_file(&self.file, self.options)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        if self.target.exists() {
+            let target = self.target.clone();
+            self.discard()?;
+            return Err(
+                FailureKind::Collision.error(format!("File {} already exists", target.display()))
+            );
+        }
+        fs::rename(&self.path, &self.target).with_context(|| {
+            format!(
+                "Failed to move {} to {}",
+                self.path.display(),
+                self.target.display()
+            )
+        })
+    }
+}
+
+pub fn copy_hashed(
+    source: &mut File,
+    target: &mut File,
+    algorithm: HashAlgorithm,
+    chunk_size: Option<u64>,
+) -> io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut hasher = ChunkedHasher::new(algorithm, chunk_size);
+    let mut buffer = vec![0; hash::BUFFER_SIZE];
+    loop {
+        let read = source.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        target.write_all(&buffer[..read])?;
+    }
+    target.flush()?;
+
+    Ok(hasher.finalize())
+}
+
+/// Create every folder the images will be archived into and make sure each
+/// one is writable, so problems surface before any copying starts. Returns
+/// the number of folders.
+pub fn prepare_folders<'a>(
+    images: impl IntoIterator<Item = &'a ImageAdv>,
+    target_base: &Path,
+    layout: &Layout,
+    safety: Safety,
+) -> anyhow::Result<usize> {
+    let folders = images
+        .into_iter()
+        .map(|image| target_base.join(layout.place(image).folder))
+        .collect::<BTreeSet<_>>();
+
+    for folder in &folders {
+        fs::create_dir_all(folder)
+            .with_context(|| format!("Failed to create directory {}", folder.display()))?;
+
+        // The probe would have to be deleted again
+        if safety.is_paranoid() {
+            continue;
+        }
+        let probe = folder.join(".rawdb-write-test");
+        File::create(&probe)
+            .and_then(|_| fs::remove_file(&probe))
+            .with_context(|| format!("Directory {} is not writable", folder.display()))?;
+    }
+
+    Ok(folders.len())
+}
+
+/// Share the data of `source` with the empty `target` on file systems with
+/// copy on write, like btrfs and XFS. False if they can't share it, such as
+/// when they are on different file systems.
+#[cfg(target_os = "linux")]
+fn clone_file(source: &File, target: &File) -> bool {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: FICLONE only takes the two open descriptors
+    let res = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    res == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_source: &File, _target: &File) -> bool {
+    false
+}
+
+/// Space available to this user on the file system holding `dir`
+#[cfg(unix)]
+pub fn available_space(dir: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is nul terminated and stat is only read after success
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
+
+/// Start a copy to the first free `<stem>-<n>.<ext>` next to `target`, and
+/// point `target` at it
+fn create_numbered<'a>(
+    target: &mut PathBuf,
+    options: CopyOptions<'a>,
+) -> anyhow::Result<PartFile<'a>> {
+    let name = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Path {} is not utf8", target.display()))?
+        .to_owned();
+    for n in 2.. {
+        target.set_file_name(numbered_name(&name, n));
+        match PartFile::create(target, options) {
+            Err(err) if FailureKind::classify(&err) == FailureKind::Collision => continue,
+            res => return res,
+        }
+    }
+    unreachable!("A free name is always found")
+}
+
 pub fn archive_image(
     image: &ImageAdv,
     source_base: &Path,
     target_base: &Path,
-) -> anyhow::Result<()> {
-    let mut target = target_base.join(image.date.format("%Y-%m-%d").to_string());
+    layout: &Layout,
+    algorithm: HashAlgorithm,
+    chunk_size: Option<u64>,
+    options: CopyOptions,
+) -> anyhow::Result<ArchivedCopy> {
+    let placement = layout.place(image);
+    let folder = placement.folder;
+    let mut target = target_base.join(&folder);
     fs::create_dir_all(&target)
         .with_context(|| format!("Failed to create directory {}", target.display()))?;
 
     target.push(image.basic.get_name());
 
-    if fs::exists(&target)? {
-        bail!("File {} already exists", target.display());
-    }
-
+    let mut part = match PartFile::create(&target, options) {
+        Err(err)
+            if layout.on_collision == CollisionPolicy::Suffix
+                && FailureKind::classify(&err) == FailureKind::Collision =>
+        {
+            create_numbered(&mut target, options)?
+        }
+        res => res?,
+    };
     let abs_path = source_base.join(&image.basic.path);
-    fs::copy(&abs_path, &target).with_context(|| {
-        format!(
-            "Failed to copy to {} to {}",
-            abs_path.display(),
-            target.display()
-        )
-    })?;
+    let copy_res = File::open(&abs_path)
+        .and_then(|mut source_file| part.copy_from(&mut source_file, algorithm, chunk_size, true));
+    let (checksum, chunks) = match copy_res {
+        Ok(hashes) => hashes,
+        // The part file is kept, the next run continues the copy where it
+        // stopped. Only copies that turn out wrong below are discarded.
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!(
+                    "Failed to copy {} to {}",
+                    abs_path.display(),
+                    target.display()
+                )
+            });
+        }
+    };
 
-    let new_len = fs::metadata(&target)?.len();
+    let new_len = fs::metadata(&part.path)?.len();
 
     if new_len != image.basic.size {
-        fs::remove_file(&target)?;
-        bail!("Length mismatch for {}", target.display());
+        part.discard()?;
+        return Err(FailureKind::IoError.error(format!("Length mismatch for {}", target.display())));
+    }
+
+    // Read the copy back to make sure what landed on disk is what was read
+    let (new_checksum, new_chunks) = hash::hash_file_chunked(&part.path, algorithm, chunk_size)
+        .with_context(|| format!("Failed to read back {}", part.path.display()))?;
+    if new_checksum != checksum || new_chunks != chunks {
+        part.discard()?;
+        return Err(error::mismatch(
+            FailureKind::IoError,
+            format!(
+                "{} mismatch for {} ({} != {})",
+                algorithm,
+                target.display(),
+                hash::to_hex(&new_checksum),
+                hash::to_hex(&checksum)
+            ),
+        ));
+    }
+    part.finish()?;
+
+    let path = folder
+        .join(target.file_name().expect("Copies always have a file name"))
+        .to_str()
+        .ok_or_else(|| anyhow!("Path {} is not utf8", target.display()))?
+        .to_owned();
+
+    let mut sidecars = 0;
+    for sidecar in find_sidecars(&abs_path) {
+        match copy_sidecar(&sidecar, &target_base.join(&folder), options) {
+            Ok(true) => sidecars += 1,
+            Ok(false) => {}
+            Err(err) => warn!("{:#}", err),
+        }
+    }
+    sync_dir(&target_base.join(&folder), options)?;
+
+    Ok(ArchivedCopy {
+        path,
+        checksum,
+        chunks,
+        utc_offset: placement.utc_offset,
+        sidecars,
+        position: None,
+        source: abs_path,
+    })
+}
+
+/// Sidecars of the image at `path`, named after either the whole file name
+/// (`IMG_1.CR3.xmp`) or its stem (`IMG_1.xmp`)
+fn find_sidecars(path: &Path) -> Vec<PathBuf> {
+    let (Some(name), Some(stem)) = (path.file_name(), path.file_stem()) else {
+        return Vec::new();
+    };
+    let mut found = Vec::new();
+    for base in [name, stem] {
+        for ext in SIDECAR_EXT {
+            // Cards are usually case insensitive, so stop at the first match
+            let candidate = [ext.to_string(), ext.to_uppercase()]
+                .into_iter()
+                .map(|ext| {
+                    let mut name = base.to_owned();
+                    name.push(".");
+                    name.push(ext);
+                    path.with_file_name(name)
+                })
+                .find(|candidate| candidate.is_file());
+            found.extend(candidate);
+        }
+    }
+    found
+}
+
+/// Copy a sidecar into `folder`, unless a file of that name is already
+/// there, like the sidecar shared by a RAW and its JPEG
+fn copy_sidecar(sidecar: &Path, folder: &Path, options: CopyOptions) -> anyhow::Result<bool> {
+    let name = sidecar.file_name().expect("Sidecars have a file name");
+    let target = folder.join(name);
+    let mut target_file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&target)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to create {}", target.display()));
+        }
+    };
+    let copy_res = File::open(sidecar)
+        .and_then(|mut file| io::copy(&mut file, &mut target_file))
+        .and_then(|_| sync_file(&target_file, options));
+    drop(target_file);
+    if let Err(err) = copy_res {
+        options.safety.remove_file(&target)?;
+        return Err(err).with_context(|| format!("Failed to copy sidecar {}", sidecar.display()));
+    }
+
+    Ok(true)
+}
+
+/// Copy an archived file from the target into the same place in a mirror,
+/// and read it back to check it against the checksum taken while archiving
+pub fn mirror_copy(
+    copy: &ArchivedCopy,
+    target_base: &Path,
+    mirror_base: &Path,
+    algorithm: HashAlgorithm,
+    options: CopyOptions,
+) -> anyhow::Result<()> {
+    let source = target_base.join(&copy.path);
+    let target = mirror_base.join(&copy.path);
+    if let Some(folder) = target.parent() {
+        fs::create_dir_all(folder)
+            .with_context(|| format!("Failed to create directory {}", folder.display()))?;
+    }
+
+    // A mirror sharing its data with the target would not be a second copy
+    let mut part = PartFile::create(&target, options)?;
+    let copy_res = File::open(&source)
+        .and_then(|mut source_file| part.copy_from(&mut source_file, algorithm, None, false));
+    if let Err(err) = copy_res {
+        part.discard()?;
+        return Err(err).with_context(|| {
+            format!(
+                "Failed to copy {} to {}",
+                source.display(),
+                target.display()
+            )
+        });
+    }
+
+    let (checksum, _) = hash::hash_file_chunked(&part.path, algorithm, None)
+        .with_context(|| format!("Failed to read back {}", part.path.display()))?;
+    if checksum != copy.checksum {
+        part.discard()?;
+        return Err(error::mismatch(
+            FailureKind::IoError,
+            format!(
+                "{} mismatch for {} ({} != {})",
+                algorithm,
+                target.display(),
+                hash::to_hex(&checksum),
+                hash::to_hex(&copy.checksum)
+            ),
+        ));
+    }
+    part.finish()?;
+
+    if let Some(folder) = target.parent() {
+        for sidecar in find_sidecars(&source) {
+            if let Err(err) = copy_sidecar(&sidecar, folder, options) {
+                warn!("{:#}", err);
+            }
+        }
+        sync_dir(folder, options)?;
     }
 
     Ok(())
@@ -0,0 +1,220 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::NaiveDateTime;
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str};
+use rusqlite::Connection;
+
+use crate::{db::get_inventory_files, report::format_size};
+
+/// Everything archived onto one physical volume
+#[derive(Default)]
+pub struct Volume {
+    pub files: u64,
+    pub bytes: u64,
+    pub first: Option<NaiveDateTime>,
+    pub last: Option<NaiveDateTime>,
+    pub checksummed: u64,
+}
+
+/// Name of the volume `folder` is stored on, its mount point
+#[cfg(unix)]
+fn volume_of(folder: &Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(dev) = fs::metadata(folder).map(|meta| meta.dev()) else {
+        return "missing".to_owned();
+    };
+    let mut mount = folder.canonicalize().unwrap_or_else(|_| folder.to_owned());
+    while let Some(parent) = mount.parent() {
+        match fs::metadata(parent) {
+            Ok(meta) if meta.dev() == dev => mount = parent.to_owned(),
+            _ => break,
+        }
+    }
+    mount.display().to_string()
+}
+
+#[cfg(not(unix))]
+fn volume_of(folder: &Path) -> String {
+    match folder.canonicalize() {
+        Ok(path) => path
+            .ancestors()
+            .last()
+            .unwrap_or(&path)
+            .display()
+            .to_string(),
+        Err(_) => "missing".to_owned(),
+    }
+}
+
+/// Summarize the archive per volume the files are stored on. Folders that
+/// can't be read are grouped as `missing`.
+pub fn build_inventory(
+    conn: &Connection,
+    target_dir: &Path,
+) -> anyhow::Result<BTreeMap<String, Volume>> {
+    let mut folders: HashMap<PathBuf, String> = HashMap::new();
+    let mut volumes: BTreeMap<String, Volume> = BTreeMap::new();
+    for file in get_inventory_files(conn)? {
+        let folder = target_dir
+            .join(&file.path)
+            .parent()
+            .map(Path::to_owned)
+            .unwrap_or_else(|| target_dir.to_owned());
+        let name = folders
+            .entry(folder)
+            .or_insert_with_key(|folder| volume_of(folder));
+
+        let volume = volumes.entry(name.clone()).or_default();
+        volume.files += 1;
+        volume.bytes += file.size;
+        volume.first = Some(volume.first.map_or(file.date, |first| first.min(file.date)));
+        volume.last = Some(volume.last.map_or(file.date, |last| last.max(file.date)));
+        if file.checksummed {
+            volume.checksummed += 1;
+        }
+    }
+
+    Ok(volumes)
+}
+
+fn format_date(date: Option<NaiveDateTime>) -> String {
+    date.map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+pub fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+pub fn to_csv(volumes: &BTreeMap<String, Volume>) -> anyhow::Result<String> {
+    let mut csv = String::new();
+    writeln!(csv, "volume,files,bytes,first_date,last_date,checksummed")?;
+    for (name, volume) in volumes {
+        writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            escape_csv(name),
+            volume.files,
+            volume.bytes,
+            format_date(volume.first),
+            format_date(volume.last),
+            volume.checksummed
+        )?;
+    }
+
+    Ok(csv)
+}
+
+/// Lines of text describing the inventory, for the PDF
+fn describe(volumes: &BTreeMap<String, Volume>) -> Vec<String> {
+    let files = volumes.values().map(|volume| volume.files).sum::<u64>();
+    let bytes = volumes.values().map(|volume| volume.bytes).sum();
+    let mut lines = vec![
+        format!(
+            "Generated {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M")
+        ),
+        format!(
+            "{} files ({}) on {} volumes",
+            files,
+            format_size(bytes),
+            volumes.len()
+        ),
+    ];
+
+    for (name, volume) in volumes {
+        lines.push(String::new());
+        lines.push(format!("Volume {}", name));
+        lines.push(format!(
+            "    {} files, {} ({} bytes)",
+            volume.files,
+            format_size(volume.bytes),
+            volume.bytes
+        ));
+        lines.push(format!(
+            "    Taken {} to {}",
+            format_date(volume.first),
+            format_date(volume.last)
+        ));
+        lines.push(format!(
+            "    {} of {} files have checksums",
+            volume.checksummed, volume.files
+        ));
+    }
+
+    lines
+}
+
+pub fn to_pdf(volumes: &BTreeMap<String, Volume>) -> Vec<u8> {
+    // A4 in points, using the Helvetica base font every reader ships with
+    const WIDTH: f32 = 595.0;
+    const HEIGHT: f32 = 842.0;
+    const MARGIN: f32 = 56.0;
+    const FONT_SIZE: f32 = 11.0;
+    const LEADING: f32 = 15.0;
+    const LINES_PER_PAGE: usize = ((HEIGHT - 2.0 * MARGIN - 2.0 * LEADING) / LEADING) as usize;
+    let font_name = Name(b"F1");
+
+    let lines = describe(volumes);
+    let pages = lines.chunks(LINES_PER_PAGE).collect::<Vec<_>>();
+
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let font_id = Ref::new(3);
+    let page_ids = (0..pages.len())
+        .map(|i| Ref::new(4 + 2 * i as i32))
+        .collect::<Vec<_>>();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(pages.len() as i32);
+    pdf.type1_font(font_id).base_font(Name(b"Helvetica"));
+
+    for (page_lines, page_id) in pages.iter().zip(&page_ids) {
+        let content_id = Ref::new(page_id.get() + 1);
+        let mut page = pdf.page(*page_id);
+        page.media_box(Rect::new(0.0, 0.0, WIDTH, HEIGHT));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        page.resources().fonts().pair(font_name, font_id);
+        page.finish();
+
+        let mut content = Content::new();
+        content.begin_text();
+        content.set_leading(LEADING);
+        if *page_id == page_ids[0] {
+            content.set_font(font_name, 18.0);
+            content.next_line(MARGIN, HEIGHT - MARGIN);
+            content.show(Str(b"Archive inventory"));
+            content.next_line_using_leading();
+        } else {
+            content.next_line(MARGIN, HEIGHT - MARGIN);
+        }
+        content.set_font(font_name, FONT_SIZE);
+        for line in page_lines.iter() {
+            // The standard encoding only covers ASCII reliably
+            let text = line
+                .chars()
+                .map(|c| if c.is_ascii() { c } else { '?' })
+                .collect::<String>();
+            content.next_line_using_leading();
+            content.show(Str(text.as_bytes()));
+        }
+        content.end_text();
+        pdf.stream(content_id, &content.finish());
+    }
+
+    pdf.finish()
+}
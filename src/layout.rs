@@ -0,0 +1,543 @@
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr};
+
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, NaiveTime, TimeZone};
+
+use crate::{collisions::CollisionPolicy, images::ImageAdv};
+
+/// How capture times that are ambiguous or skipped because of a daylight
+/// saving switch in the local time zone are placed into folders
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DstPolicy {
+    /// Use the earlier of the two possible instants
+    #[default]
+    Earliest,
+    /// Use the later of the two possible instants
+    Latest,
+    /// Assign folders by UTC date, which never repeats or skips an hour
+    Utc,
+}
+
+impl DstPolicy {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DstPolicy::Earliest => "earliest",
+            DstPolicy::Latest => "latest",
+            DstPolicy::Utc => "utc",
+        }
+    }
+}
+
+impl FromStr for DstPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "earliest" => Ok(DstPolicy::Earliest),
+            "latest" => Ok(DstPolicy::Latest),
+            "utc" => Ok(DstPolicy::Utc),
+            _ => anyhow::bail!(
+                "Unknown DST policy {:?} (Expected earliest, latest or utc)",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DstPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// The zone camera clocks are set to, for images that don't record their
+/// offset from UTC
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeZonePolicy {
+    /// The local time zone of this machine
+    #[default]
+    Local,
+    /// A fixed offset, such as `+09:00` while travelling or `utc`
+    Fixed(FixedOffset),
+}
+
+impl TimeZonePolicy {
+    /// The same instant in this zone
+    pub fn localize(&self, instant: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        match self {
+            TimeZonePolicy::Local => instant.with_timezone(&Local).fixed_offset(),
+            TimeZonePolicy::Fixed(offset) => instant.with_timezone(offset),
+        }
+    }
+}
+
+impl FromStr for TimeZonePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(TimeZonePolicy::Local),
+            "utc" => Ok(TimeZonePolicy::Fixed(FixedOffset::east_opt(0).unwrap())),
+            _ => s.parse().map(TimeZonePolicy::Fixed).map_err(|_| {
+                anyhow::anyhow!(
+                    "Unknown time zone {:?} (Expected local, utc or an offset like +09:00)",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl fmt::Display for TimeZonePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeZonePolicy::Local => f.write_str("local"),
+            TimeZonePolicy::Fixed(offset) => write!(f, "{}", offset),
+        }
+    }
+}
+
+/// A value filled into a layout template
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Field {
+    Year,
+    Month,
+    Day,
+    /// `YYYY-MM-DD`
+    Date,
+    /// Camera model, `unknown` if the metadata has none
+    Camera,
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "year" => Ok(Field::Year),
+            "month" => Ok(Field::Month),
+            "day" => Ok(Field::Day),
+            "date" => Ok(Field::Date),
+            "camera" => Ok(Field::Camera),
+            _ => anyhow::bail!(
+                "Unknown layout field {{{}}} (Expected year, month, day, date or camera)",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// Folders images are archived into, such as `{year}/{year}-{month}/{date}`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    /// Parts of each folder level
+    levels: Vec<Vec<Part>>,
+    source: String,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        "{date}".parse().expect("Default layout is valid")
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut levels = Vec::new();
+        for level in s.split('/') {
+            if level.is_empty() || level == "." || level == ".." || level.contains('\\') {
+                anyhow::bail!("Invalid folder {:?} in layout {:?}", level, s);
+            }
+            let mut parts = Vec::new();
+            let mut rest = level;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    parts.push(Part::Text(rest[..start].to_owned()));
+                }
+                let Some(end) = rest[start..].find('}') else {
+                    anyhow::bail!("Unclosed {{ in layout {:?}", s);
+                };
+                parts.push(Part::Field(rest[start + 1..start + end].parse()?));
+                rest = &rest[start + end + 1..];
+            }
+            if rest.contains('}') {
+                anyhow::bail!("Unopened }} in layout {:?}", s);
+            }
+            if !rest.is_empty() {
+                parts.push(Part::Text(rest.to_owned()));
+            }
+            levels.push(parts);
+        }
+
+        Ok(Template {
+            levels,
+            source: s.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Template {
+    fn render(&self, date: NaiveDateTime, camera: Option<&str>) -> PathBuf {
+        self.levels
+            .iter()
+            .map(|parts| {
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        Part::Text(text) => text.clone(),
+                        Part::Field(Field::Year) => date.format("%Y").to_string(),
+                        Part::Field(Field::Month) => date.format("%m").to_string(),
+                        Part::Field(Field::Day) => date.format("%d").to_string(),
+                        Part::Field(Field::Date) => date.format("%Y-%m-%d").to_string(),
+                        Part::Field(Field::Camera) => folder_name(camera.unwrap_or("unknown")),
+                    })
+                    .collect::<String>()
+            })
+            .collect()
+    }
+}
+
+/// Replace characters that can't be part of a folder name on common file
+/// systems
+fn folder_name(value: &str) -> String {
+    let name = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    match name.as_str() {
+        "" | "." | ".." => "unknown".to_owned(),
+        _ => name,
+    }
+}
+
+/// Where an image goes in the archive
+pub struct Placement {
+    /// Folder relative to the target directory
+    pub folder: PathBuf,
+    /// Offset from UTC the capture time was resolved with, in seconds
+    pub utc_offset: Option<i32>,
+    /// The capture time was repeated or skipped by a daylight saving switch
+    pub ambiguous: bool,
+}
+
+/// Decides the folder each image is archived into
+pub struct Layout {
+    pub force_folder: Option<String>,
+    pub template: Template,
+    /// Zone of images that don't record their offset from UTC
+    pub timezone: TimeZonePolicy,
+    pub dst_policy: DstPolicy,
+    /// Time of day a new date folder starts
+    pub day_start: NaiveTime,
+    /// What to do when the image's name is taken in its folder
+    pub on_collision: CollisionPolicy,
+    /// Subfolder the frames of each burst go into, by their source path
+    pub burst_folders: HashMap<String, String>,
+    /// Photos that Live Photo videos are placed like, by the source path of
+    /// the video, as the video starts a little earlier
+    pub placed_with: HashMap<String, ImageAdv>,
+}
+
+impl Layout {
+    pub fn place(&self, image: &ImageAdv) -> Placement {
+        self.place_in(&Local, image)
+    }
+
+    /// Place `image` with `zone` as the local time zone
+    fn place_in<Tz: TimeZone>(&self, zone: &Tz, image: &ImageAdv) -> Placement {
+        let image = self.placed_with.get(&image.basic.path).unwrap_or(image);
+        // A known offset never repeats or skips an hour
+        let fixed = image
+            .utc_offset
+            .and_then(FixedOffset::east_opt)
+            .or(match self.timezone {
+                TimeZonePolicy::Fixed(offset) => Some(offset),
+                TimeZonePolicy::Local => None,
+            });
+        let (instant, ambiguous) = match fixed {
+            Some(offset) => (offset.from_local_datetime(&image.date).single(), false),
+            None => resolve(zone, image.date, self.dst_policy),
+        };
+        let utc_offset = instant
+            .as_ref()
+            .map(|instant| instant.offset().local_minus_utc());
+
+        let date = match (self.dst_policy, &instant) {
+            (DstPolicy::Utc, Some(instant)) => instant.naive_utc(),
+            (_, Some(instant)) => instant.naive_local(),
+            (_, None) => image.date,
+        };
+        // Images taken before the day starts belong to the previous date
+        let date = date - self.day_start.signed_duration_since(NaiveTime::MIN);
+        let mut folder = match &self.force_folder {
+            Some(folder) => PathBuf::from(folder),
+            None => self.template.render(date, image.camera.as_deref()),
+        };
+        if let Some(burst) = self.burst_folders.get(&image.basic.path) {
+            folder.push(burst);
+        }
+
+        Placement {
+            folder,
+            utc_offset,
+            ambiguous,
+        }
+    }
+}
+
+/// Turn a capture time in the local time zone `zone` into an instant
+/// following `policy`. Times in the gap of a forward switch are read with the
+/// offset from before it.
+fn resolve<Tz: TimeZone>(
+    zone: &Tz,
+    date: NaiveDateTime,
+    policy: DstPolicy,
+) -> (Option<DateTime<FixedOffset>>, bool) {
+    let (instant, ambiguous) = match zone.from_local_datetime(&date) {
+        LocalResult::Single(instant) => (Some(instant), false),
+        LocalResult::Ambiguous(earliest, latest) => match policy {
+            DstPolicy::Latest => (Some(latest), true),
+            DstPolicy::Earliest | DstPolicy::Utc => (Some(earliest), true),
+        },
+        LocalResult::None => {
+            let before = date - chrono::TimeDelta::hours(1);
+            let instant = zone
+                .from_local_datetime(&before)
+                .earliest()
+                .map(|instant| instant + chrono::TimeDelta::hours(1));
+            (instant, true)
+        }
+    };
+    (instant.map(|instant| instant.fixed_offset()), ambiguous)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeDelta};
+
+    use super::*;
+    use crate::images::{ExifDetails, ImageBasic};
+
+    fn date(time: &str) -> NaiveDateTime {
+        crate::dates::parse_date(time).unwrap()
+    }
+
+    fn offset(hours: i32) -> FixedOffset {
+        FixedOffset::east_opt(hours * 3600).unwrap()
+    }
+
+    /// Central European Time in 2024, when clocks went forward at 01:00 UTC
+    /// on March 31 and back at 01:00 UTC on October 27
+    #[derive(Copy, Clone, Debug)]
+    struct Cet;
+
+    impl TimeZone for Cet {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Cet
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let (winter, summer) = (offset(1), offset(2));
+            let valid = |offset: FixedOffset| {
+                let utc = *local - TimeDelta::seconds(offset.local_minus_utc().into());
+                self.offset_from_utc_datetime(&utc) == offset
+            };
+            match (valid(winter), valid(summer)) {
+                (true, true) => LocalResult::Ambiguous(summer, winter),
+                (true, false) => LocalResult::Single(winter),
+                (false, true) => LocalResult::Single(summer),
+                (false, false) => LocalResult::None,
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let summer = date("2024-03-31 01:00") <= *utc && *utc < date("2024-10-27 01:00");
+            offset(if summer { 2 } else { 1 })
+        }
+    }
+
+    fn image(taken: &str) -> ImageAdv {
+        ImageAdv {
+            basic: ImageBasic {
+                path: "a/IMG_1.CR3".to_owned(),
+                size: 0,
+            },
+            date: date(taken),
+            rating: None,
+            date_source: None,
+            camera: Some("EOS R5".to_owned()),
+            utc_offset: None,
+            exif: ExifDetails::default(),
+        }
+    }
+
+    fn layout(dst_policy: DstPolicy, day_start: &str) -> Layout {
+        Layout {
+            force_folder: None,
+            template: Template::default(),
+            timezone: TimeZonePolicy::Local,
+            dst_policy,
+            day_start: NaiveTime::parse_from_str(day_start, "%H:%M").unwrap(),
+            on_collision: CollisionPolicy::default(),
+            burst_folders: HashMap::new(),
+            placed_with: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_template() {
+        let template = "{year}/{year}-{month}/{date} {camera}"
+            .parse::<Template>()
+            .unwrap();
+        assert_eq!(
+            template.to_string(),
+            "{year}/{year}-{month}/{date} {camera}"
+        );
+        assert_eq!(
+            template.render(date("2024-05-01 10:00"), Some("EOS R5")),
+            PathBuf::from("2024/2024-05/2024-05-01 EOS R5")
+        );
+        assert_eq!(
+            template.render(date("2024-05-01 10:00"), None),
+            PathBuf::from("2024/2024-05/2024-05-01 unknown")
+        );
+        let template = "day-{day}".parse::<Template>().unwrap();
+        assert_eq!(
+            template.render(date("2024-05-01 10:00"), None),
+            PathBuf::from("day-01")
+        );
+
+        for (layout, error) in [
+            ("{year", "Unclosed {"),
+            ("{year}/x}", "Unopened }"),
+            ("{year}/../x", "Invalid folder"),
+            ("{year}//{date}", "Invalid folder"),
+            ("{year}/{week}", "Unknown layout field {week}"),
+        ] {
+            let err = layout.parse::<Template>().unwrap_err().to_string();
+            assert!(err.contains(error), "{:?}: {}", layout, err);
+        }
+    }
+
+    #[test]
+    fn test_folder_name() {
+        assert_eq!(folder_name("EOS R5"), "EOS R5");
+        assert_eq!(folder_name("a/b:c*d?"), "a_b_c_d_");
+        assert_eq!(folder_name("new\nline\\path"), "new_line_path");
+        assert_eq!(folder_name(".."), "unknown");
+        assert_eq!(folder_name(""), "unknown");
+    }
+
+    #[test]
+    fn test_place_dst() {
+        let folder = |layout: &Layout, taken: &str| {
+            let placement = layout.place_in(&Cet, &image(taken));
+            (
+                placement.folder.to_string_lossy().into_owned(),
+                placement.utc_offset.map(|offset| offset / 3600),
+                placement.ambiguous,
+            )
+        };
+        let (earliest, latest, utc) = (
+            layout(DstPolicy::Earliest, "00:00"),
+            layout(DstPolicy::Latest, "00:00"),
+            layout(DstPolicy::Utc, "00:00"),
+        );
+
+        let summer = "2024-05-01 10:00";
+        assert_eq!(
+            folder(&earliest, summer),
+            ("2024-05-01".into(), Some(2), false)
+        );
+        // The repeated hour of the switch back
+        let repeated = "2024-10-27 02:30";
+        assert_eq!(
+            folder(&earliest, repeated),
+            ("2024-10-27".into(), Some(2), true)
+        );
+        assert_eq!(
+            folder(&latest, repeated),
+            ("2024-10-27".into(), Some(1), true)
+        );
+        // The hour skipped by the switch forward is read as winter time
+        let skipped = "2024-03-31 02:30";
+        assert_eq!(
+            folder(&latest, skipped),
+            ("2024-03-31".into(), Some(2), true)
+        );
+
+        // UTC dates move the first hours of a day to the day before
+        assert_eq!(
+            folder(&utc, "2024-05-01 01:30"),
+            ("2024-04-30".into(), Some(2), false)
+        );
+        assert_eq!(folder(&utc, repeated), ("2024-10-27".into(), Some(2), true));
+        assert_eq!(
+            folder(&utc, "2024-10-27 00:30"),
+            ("2024-10-26".into(), Some(2), false)
+        );
+    }
+
+    #[test]
+    fn test_place_day_start() {
+        let night = layout(DstPolicy::Earliest, "04:00");
+        let folder = |taken: &str| night.place_in(&Cet, &image(taken)).folder;
+        assert_eq!(folder("2024-05-01 03:59"), PathBuf::from("2024-04-30"));
+        assert_eq!(folder("2024-05-01 04:00"), PathBuf::from("2024-05-01"));
+        assert_eq!(folder("2024-01-01 02:00"), PathBuf::from("2023-12-31"));
+
+        // Offsets recorded by the camera and fixed zones skip the DST rules
+        let fixed = Layout {
+            timezone: TimeZonePolicy::Fixed(offset(9)),
+            ..layout(DstPolicy::Utc, "00:00")
+        };
+        let placement = fixed.place_in(&Cet, &image("2024-10-27 02:30"));
+        assert_eq!(placement.folder, PathBuf::from("2024-10-26"));
+        assert_eq!(
+            (placement.utc_offset, placement.ambiguous),
+            (Some(9 * 3600), false)
+        );
+        let recorded = ImageAdv {
+            utc_offset: Some(-5 * 3600),
+            ..image("2024-10-27 22:00")
+        };
+        let placement = fixed.place_in(&Cet, &recorded);
+        assert_eq!(placement.folder, PathBuf::from("2024-10-28"));
+
+        let forced = Layout {
+            force_folder: Some("import".to_owned()),
+            ..layout(DstPolicy::Earliest, "04:00")
+        };
+        assert_eq!(
+            forced.place_in(&Cet, &image("2024-05-01 03:00")).folder,
+            PathBuf::from("import")
+        );
+    }
+}
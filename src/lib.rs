@@ -0,0 +1,56 @@
+//! Indexes the images of a target directory and of camera cards in a
+//! SQLite database, and archives the camera images not yet in the target.
+//!
+//! A [`Catalog`] is the database, a [`Scanner`] indexes a directory into one
+//! of its tables, and an [`Archiver`] copies images into the target and
+//! records the copies. They fail with a [`RawdbError`], while the modules
+//! below them return [`anyhow::Error`]s tagged with a
+//! [`FailureKind`](failures::FailureKind).
+
+pub mod archiver;
+pub mod bmff;
+pub mod brackets;
+pub mod bursts;
+pub mod card;
+pub mod catalog;
+pub mod clock;
+pub mod collisions;
+pub mod cull;
+pub mod dates;
+pub mod db;
+pub mod dcim;
+pub mod dedupe;
+pub mod dump;
+pub mod duplicates;
+pub mod error;
+pub mod export;
+pub mod failures;
+pub mod geotag;
+pub mod hash;
+pub mod heif;
+pub mod images;
+pub mod inventory;
+pub mod layout;
+pub mod livephotos;
+pub mod manifest;
+pub mod metadata;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod output;
+pub mod pool;
+pub mod progress;
+pub mod ptp;
+pub mod repair;
+pub mod report;
+pub mod safety;
+pub mod scan;
+pub mod snapshot;
+pub mod sniff;
+pub mod verify;
+pub mod video;
+pub mod volumes;
+
+pub use archiver::Archiver;
+pub use catalog::Catalog;
+pub use error::RawdbError;
+pub use scan::Scanner;
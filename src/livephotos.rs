@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use crate::images::{is_video, ImageAdv};
+
+/// The photo and the video of an Apple Live Photo, which share a content
+/// identifier
+pub struct LivePhoto<'a> {
+    pub image: &'a ImageAdv,
+    pub video: &'a ImageAdv,
+}
+
+/// Pair the Live Photo videos among `images` with their photo
+pub fn find_live_photos<'a>(images: impl IntoIterator<Item = &'a ImageAdv>) -> Vec<LivePhoto<'a>> {
+    let (videos, photos) = images
+        .into_iter()
+        .filter(|image| image.exif.content_id.is_some())
+        .partition::<Vec<_>, _>(|image| is_video(&image.basic.path));
+    let photos = photos
+        .into_iter()
+        .map(|image| (image.exif.content_id.as_deref(), image))
+        .collect::<HashMap<_, _>>();
+
+    let mut pairs = videos
+        .into_iter()
+        .filter_map(|video| {
+            let image = photos.get(&video.exif.content_id.as_deref())?;
+            Some(LivePhoto { image, video })
+        })
+        .collect::<Vec<_>>();
+    pairs.sort_by(|a, b| a.image.basic.path.cmp(&b.image.basic.path));
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::images::{ExifDetails, ImageBasic};
+
+    fn image(path: &str, content_id: Option<&str>) -> ImageAdv {
+        ImageAdv {
+            basic: ImageBasic {
+                path: path.to_owned(),
+                size: 0,
+            },
+            date: NaiveDateTime::default(),
+            rating: None,
+            date_source: None,
+            camera: None,
+            utc_offset: None,
+            exif: ExifDetails {
+                content_id: content_id.map(str::to_owned),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_find_live_photos() {
+        let images = [
+            image("a/IMG_1.HEIC", Some("A1")),
+            image("a/IMG_1.MOV", Some("A1")),
+            image("a/IMG_2.MOV", Some("B2")),
+            image("a/IMG_3.HEIC", None),
+            image("a/IMG_3.MOV", None),
+        ];
+
+        let pairs = find_live_photos(&images);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].image.basic.path, "a/IMG_1.HEIC");
+        assert_eq!(pairs[0].video.basic.path, "a/IMG_1.MOV");
+    }
+}
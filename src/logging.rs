@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+use log::{kv::Key, warn, Level, Log, Metadata, Record};
+use rawdb::failures::{FailureKind, LEFT_BEHIND_TARGET};
+
+/// How many messages of each failure kind are shown before the rest are
+/// only counted
+const SHOWN_PER_KIND: u64 = 5;
+
+/// Messages logged per failure kind since the last [`summarize_repeated`]
+static REPEATED: Mutex<Option<HashMap<FailureKind, u64>>> = Mutex::new(None);
+
+/// Files the run failed on or skipped, grouped by why in the order the
+/// reasons first came up, until [`summarize_run`]
+static LEFT_BEHIND: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new());
+
+/// Keep `message` about a file the run failed on or skipped for the summary
+/// of [`summarize_run`], grouped under `reason`
+fn keep_left_behind(reason: &str, message: impl fmt::Display) {
+    let mut left_behind = LEFT_BEHIND.lock().unwrap_or_else(PoisonError::into_inner);
+    let message = message.to_string();
+    match left_behind.iter_mut().find(|(known, _)| known == reason) {
+        Some((_, messages)) => messages.push(message),
+        None => left_behind.push((reason.to_owned(), vec![message])),
+    }
+}
+
+/// Log the files the run failed on or skipped, grouped by why, so they
+/// aren't lost among the progress bars. Starts over for the next run.
+pub fn summarize_run() {
+    let left_behind =
+        std::mem::take(&mut *LEFT_BEHIND.lock().unwrap_or_else(PoisonError::into_inner));
+    if left_behind.is_empty() {
+        return;
+    }
+
+    let total = left_behind
+        .iter()
+        .map(|(_, messages)| messages.len())
+        .sum::<usize>();
+    warn!("{} files failed or were skipped:", total);
+    for (reason, messages) in left_behind {
+        warn!("  {} ({}):", reason, messages.len());
+        for message in messages.iter().take(SHOWN_PER_KIND as usize) {
+            warn!("    {}", message);
+        }
+        if messages.len() > SHOWN_PER_KIND as usize {
+            warn!("    and {} more", messages.len() - SHOWN_PER_KIND as usize);
+        }
+    }
+}
+
+/// Wraps a logger, dropping warnings and errors about a failure kind once it
+/// was logged [`SHOWN_PER_KIND`] times. Such messages are logged with the
+/// failure kind's label as their target. Records of
+/// [`record_left_behind`](rawdb::failures::record_left_behind) are only kept
+/// for [`summarize_run`].
+pub struct DedupLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> DedupLogger<L> {
+    pub fn new(inner: L) -> Self {
+        DedupLogger { inner }
+    }
+}
+
+impl<L: Log> Log for DedupLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.target() == LEFT_BEHIND_TARGET {
+            let reason = record.key_values().get(Key::from("reason"));
+            let reason = reason.as_ref().and_then(|reason| reason.to_borrowed_str());
+            keep_left_behind(reason.unwrap_or("other reasons"), record.args());
+            return;
+        }
+        if record.level() <= Level::Warn {
+            if let Some(kind) = FailureKind::from_label(record.target()) {
+                keep_left_behind(kind.description(), record.args());
+                let mut repeated = REPEATED.lock().unwrap_or_else(PoisonError::into_inner);
+                let count = repeated
+                    .get_or_insert_with(HashMap::new)
+                    .entry(kind)
+                    .or_default();
+                *count += 1;
+                if *count > SHOWN_PER_KIND {
+                    return;
+                }
+            }
+        }
+        self.inner.log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Log how many messages of each failure kind were dropped, and start
+/// counting again
+pub fn summarize_repeated() {
+    let repeated = REPEATED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+        .unwrap_or_default();
+    let mut repeated = repeated
+        .into_iter()
+        .filter(|(_, count)| *count > SHOWN_PER_KIND)
+        .collect::<Vec<_>>();
+    repeated.sort_by_key(|(kind, _)| kind.label());
+
+    for (kind, count) in repeated {
+        warn!(
+            "{} more files skipped due to {} (see `rawdb report` for the full list)",
+            count - SHOWN_PER_KIND,
+            kind.description()
+        );
+    }
+}
@@ -1,70 +1,194 @@
 mod args;
-mod db;
-mod images;
+mod config;
+mod eject;
+mod logging;
+mod notify;
+mod priority;
+mod reporter;
+mod service;
+mod tether;
+mod watch;
 
-use std::path::Path;
-
-use args::parse_args;
-use db::{
-    add_to_table, get_images_to_archive, populate_new_table, set_images_as_archived,
-    update_table_get_new,
-    TableType::{self, *},
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use images::{archive_image, load_images, ImageAdv, ImageBasic};
-use indicatif::{MultiProgress, ProgressBar, ProgressIterator, ProgressStyle};
+
+use anyhow::Context;
+use args::{parse_args, AppArgs, Command};
+use chrono::{Datelike, NaiveDateTime, NaiveTime};
+use config::Config;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
-use log::{error, info, warn, LevelFilter};
-use rusqlite::Connection;
+use log::{debug, error, info, warn, LevelFilter};
+use rawdb::{
+    brackets, bursts, card,
+    collisions::{self, CollisionPolicy},
+    db::{
+        self, add_to_table, claim_images, get_card_retention, get_catalog_counts,
+        get_failure_counts, get_hash_algorithm, get_images_to_archive, get_previous_imports,
+        record_failure, record_provenance, record_run, release_claims, set_images_as_archived,
+        ProvenanceEntry, RunStats,
+        TableType::{self, *},
+    },
+    dedupe, dump,
+    error::PartialFailure,
+    export,
+    failures::{self, FailureKind},
+    hash::{self, HashAlgorithm},
+    images::{
+        self, load_images, prepare_folders, ArchivedCopy, CopyOptions, FileFilter, ImageAdv,
+        ImageBasic, ReadOptions,
+    },
+    inventory,
+    layout::Layout,
+    livephotos, manifest,
+    output::{self, OutputFormat},
+    pool,
+    progress::{self, ProgressTracker},
+    ptp, repair,
+    report::{self, format_size},
+    safety::Safety,
+    scan::{IndexSummary, Scan},
+    snapshot,
+    verify::{self, Problem},
+    video, volumes, Archiver, Catalog, RawdbError, Scanner,
+};
+use rusqlite::{Connection, TransactionBehavior};
+
+use crate::reporter::RunReport;
+
+/// Months a copy of a file may go without being verified again, unless
+/// configured otherwise
+const VERIFY_EVERY_MONTHS: u32 = 12;
 
 fn get_prog_style() -> ProgressStyle {
     ProgressStyle::with_template("{msg} [{elapsed} / {duration}] {wide_bar} {pos} / {len}")
         .expect("Illegal Progress Bar Template")
 }
 
-fn find_new_files(
-    conn: &mut Connection,
-    table: TableType,
-    dir: &Path,
-    label: &str,
-    pb: ProgressBar,
-    leave: bool,
-) -> anyhow::Result<()> {
-    // Read file structure on disk, find rows that don't exist in in on_disk
-    // An unknown file in the target is an error
-    info!("Scanning {} at {}", label, dir.display());
-    let target_images = load_images::<ImageBasic>(dir).collect::<Result<Vec<_>, _>>()?;
-    info!("  Found {} {} images", target_images.len(), label);
-
-    let trans = conn.transaction()?;
-    let duplicates = populate_new_table(&trans, table, &target_images, leave)?;
-    for dup in duplicates {
-        error!("Possible duplicate file detected: {}", dup.name);
-        for path in dup.paths {
-            error!("  {}", path);
-        }
-    }
-    let new_on = update_table_get_new(&trans, table)?;
-
-    // For those new rows, read their metadata by actually opening the files
-    pb.set_length(new_on.len() as u64);
-    let new_on_adv = new_on
-        .into_iter()
-        .progress_with(pb)
-        .with_message(format!("Indexing new {} images", table.label()))
-        .filter_map(|i| {
-            ImageAdv::from_basic(i, dir)
-                .inspect_err(|err| warn!("{}", err))
-                .ok()
-        })
-        .collect::<Vec<_>>();
+/// Scan options given on the command line
+fn scanner(args: &AppArgs) -> Scanner<'_> {
+    Scanner {
+        jobs: args.jobs,
+        dates: args.dates.as_ref(),
+        date_fallback: args.date_fallback,
+        timezone: args.timezone,
+        duplicates: args.duplicates,
+        leave: args.leave,
+        retry_failed: args.retry_failed,
+        fail_on_access_errors: args.fail_on_access_errors,
+        strict: args.strict,
+        card: "",
+        clock_offsets: &args.config.clock_offsets,
+        ffprobe: args.ffprobe.as_deref(),
+        filter: file_filter(args),
+    }
+}
 
-    // With that new metadata, add the rows to the database
-    add_to_table(&trans, table, &new_on_adv)?;
-    trans.commit()?;
+/// What the run may delete or overwrite, quarantining into the target of
+/// the command with `--paranoid`
+fn safety(args: &AppArgs) -> Safety<'_> {
+    Safety::new(args.paranoid, args.command.target_dir())
+}
+
+/// Which files of a directory are indexed, as configured
+fn file_filter(args: &AppArgs) -> FileFilter {
+    FileFilter {
+        ignore_extensions: args.ignore_extensions.clone(),
+        include_extensions: args.include_extensions.clone(),
+        ignore_dirs: args.ignore_dirs.clone(),
+        exclude: args.exclude.clone(),
+        follow_symlinks: args.follow_symlinks,
+        min_size: args.min_size,
+        include_hidden: args.include_hidden,
+    }
+}
+
+fn archiver<'a>(
+    conn: &Connection,
+    args: &'a AppArgs,
+    target_dir: &'a Path,
+    layout: &'a Layout,
+) -> anyhow::Result<Archiver<'a>> {
+    Ok(Archiver {
+        track: args.track.as_ref(),
+        write_sidecars: args.gpx_sidecars,
+        copy_options: CopyOptions {
+            preserve: args.preserve,
+            fsync: args.fsync,
+            safety: safety(args),
+        },
+        ..Archiver::new(conn, target_dir, layout)?
+    })
+}
+
+fn report_retention(conn: &Connection, card: &str, retention_days: u64) -> anyhow::Result<()> {
+    let now = chrono::Utc::now().naive_utc();
+    let retention = get_card_retention(conn, card, now - chrono::Days::new(retention_days))?;
+
+    for image in &retention.unverified {
+        warn!(
+            "{} is marked as archived, but no matching copy was found on disk",
+            image.path
+        );
+    }
+
+    let archived = retention.removable.len() + retention.unverified.len() + retention.recent.len();
+    if archived == 0 {
+        return Ok(());
+    }
+
+    let freed = retention.removable.iter().map(|i| i.size).sum::<u64>();
+    let oldest = retention
+        .removable
+        .iter()
+        .filter_map(|i| i.archived_at)
+        .min()
+        .map(|date| (now - date).num_days());
+
+    if retention.removable.len() == archived {
+        info!(
+            "All {} archived files older than {} days can be removed, freeing {}",
+            retention.removable.len(),
+            retention_days,
+            format_size(freed)
+        );
+    } else {
+        info!(
+            "{} of {} archived files are older than {} days and can be removed, freeing {}",
+            retention.removable.len(),
+            archived,
+            retention_days,
+            format_size(freed)
+        );
+    }
+    if let Some(oldest) = oldest {
+        info!("  The oldest was archived {} days ago", oldest);
+    }
+    if !retention.recent.is_empty() {
+        info!(
+            "  {} files were archived within the last {} days",
+            retention.recent.len(),
+            retention_days
+        );
+    }
+    if !retention.unverified.is_empty() {
+        warn!(
+            "  {} archived files could not be verified on disk and should be kept",
+            retention.unverified.len()
+        );
+    }
 
     Ok(())
 }
 
+/// Run `inner` with a progress bar, then count the failures it logged too
+/// often to show
 fn wrap_multi<F, T>(multi: &MultiProgress, inner: F) -> T
 where
     F: FnOnce(ProgressBar) -> T,
@@ -73,10 +197,28 @@ where
     let res = inner(pb.clone());
     pb.finish();
     multi.remove(&pb);
+    logging::summarize_repeated();
     res
 }
 
-fn main() -> anyhow::Result<()> {
+/// Exit code of a run that finished but left some files behind
+const EXIT_PARTIAL: u8 = 2;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            if err.is::<PartialFailure>() {
+                ExitCode::from(EXIT_PARTIAL)
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let logger_inner = env_logger::builder()
         .filter_level(LevelFilter::Info)
         .format_timestamp(None)
@@ -85,70 +227,2400 @@ fn main() -> anyhow::Result<()> {
         .build();
 
     let multi = MultiProgress::new();
-    LogWrapper::new(multi.clone(), logger_inner)
+    LogWrapper::new(multi.clone(), logging::DedupLogger::new(logger_inner))
         .try_init()
         .expect("Failed to initialize logger");
 
-    let args = parse_args()?;
+    let mut args = parse_args()?;
+    // Only commands reading the metadata of new files need ffprobe
+    if matches!(
+        args.command.name(),
+        "archive" | "index" | "tether" | "watch" | "orphans"
+    ) {
+        args.ffprobe = video::find_ffprobe(args.ffprobe.take())?;
+    }
+    if args.paranoid {
+        info!("Paranoid mode, nothing will be deleted or overwritten");
+    }
 
-    info!("Loading database at {}", args.database_path.display());
-    let mut conn = db::create_conn(&args.database_path, args.clean)?;
+    let Some(database_path) = &args.database_path else {
+        return list_archives(&args.config);
+    };
+    if let Command::DbInfo { json } = args.command {
+        return print_db_info(database_path, json);
+    }
+    if let Command::InstallService { target_dir } = &args.command {
+        return install_service(&args, target_dir, database_path);
+    }
+    info!("Loading database at {}", database_path.display());
+    let mut catalog = if args.paranoid {
+        Catalog::open_paranoid(database_path, args.clean)?
+    } else {
+        Catalog::open(database_path, args.clean)?
+    };
 
     if args.clean {
         info!("Database cleaned, exiting...");
         return Ok(());
     }
 
-    wrap_multi(&multi, |pb| {
-        find_new_files(&mut conn, Disk, &args.target_dir, "target", pb, args.leave)
-    })?;
+    if let Some(algorithm) = args.hash {
+        db::set_hash_algorithm(&catalog, algorithm)?;
+    }
+    if let Some(policy) = args.dst_policy {
+        db::set_dst_policy(&catalog, policy)?;
+    }
+    if let Some(chunk_size) = args.chunk_size {
+        db::set_chunk_size(&catalog, Some(chunk_size).filter(|size| *size > 0))?;
+    }
+
+    let res = match &args.command {
+        Command::Archive {
+            source_dirs,
+            target_dir,
+        } => {
+            let started_at = chrono::Utc::now().naive_utc();
+            let mut summaries = Vec::new();
+            let res = run_archive(
+                &mut catalog,
+                &multi,
+                &args,
+                target_dir,
+                source_dirs,
+                &mut summaries,
+            );
+            let res = snapshot_after(&catalog, &args, target_dir, res);
+            if args.notify {
+                notify_outcome(&catalog, started_at, &res);
+            }
+            report_run(&args, target_dir, started_at, summaries, &res);
+            res
+        }
+        Command::Status => print_status(&catalog, &args.config),
+        Command::Stats => print_stats(&catalog),
+        Command::Archives => unreachable!("Listing archives doesn't open a database"),
+        Command::DbInfo { .. } => unreachable!("Database info is read without upgrading"),
+        Command::Index { target_dir } => {
+            let res = run_index(&mut catalog, &multi, &args, target_dir);
+            snapshot_after(&catalog, &args, target_dir, res)
+        }
+        Command::ImportManifest {
+            manifest,
+            target_dir,
+        } => import_manifest(&mut catalog, manifest, target_dir),
+        Command::Tether {
+            session_dir,
+            target_dir,
+        } => run_tether(&mut catalog, &args, target_dir, session_dir),
+        Command::Watch {
+            mount_dir,
+            target_dir,
+        } => run_watch(&mut catalog, &multi, &args, target_dir, mount_dir),
+        Command::Verify {
+            target_dir,
+            mirror_dir,
+        } => run_verify(
+            &mut catalog,
+            &multi,
+            &file_filter(&args),
+            target_dir,
+            mirror_dir.as_deref(),
+        ),
+        Command::Repair {
+            target_dir,
+            mirror_dir,
+        } => run_repair(
+            &catalog,
+            &multi,
+            &file_filter(&args),
+            target_dir,
+            mirror_dir,
+            safety(&args),
+        ),
+        Command::Locate { pattern } => print_locate(&catalog, pattern),
+        Command::Query {
+            table,
+            name,
+            selection,
+            json,
+        } => print_query(&catalog, *table, name.as_deref(), selection, *json),
+        Command::Collisions { target_dir, fix } => run_collisions(&mut catalog, target_dir, *fix),
+        Command::Dedupe { target_dir } => run_dedupe(&catalog, target_dir, args.dry, safety(&args)),
+        Command::Prune { target_dir } => run_prune(&mut catalog, target_dir, args.dry),
+        Command::InstallService { .. } => {
+            unreachable!("Installing the service doesn't open a database")
+        }
+        Command::Orphans { target_dir, adopt } => {
+            run_orphans(&mut catalog, &multi, &args, target_dir, *adopt)
+        }
+        Command::Dump { table, format, out } => {
+            write_dump(&catalog, *table, *format, out.as_deref(), safety(&args))
+        }
+        Command::Inventory { target_dir, out } => {
+            write_inventory(&catalog, target_dir, out.as_deref(), safety(&args))
+        }
+        Command::Export {
+            target_dir,
+            staging_dir,
+            selection,
+            disc_size,
+        } => run_export(
+            &catalog,
+            &multi,
+            &args,
+            target_dir,
+            staging_dir,
+            selection,
+            *disc_size,
+        ),
+        Command::Report { month, out } => {
+            write_report(&catalog, month.as_deref(), out.as_deref(), safety(&args))
+        }
+    };
+    logging::summarize_run();
+    res
+}
+
+fn write_report(
+    conn: &Connection,
+    month: Option<&str>,
+    out: Option<&Path>,
+    safety: Safety,
+) -> anyhow::Result<()> {
+    let month = match month {
+        Some(month) => report::parse_month(month)?,
+        None => chrono::Local::now().date_naive().with_day(1).unwrap(),
+    };
+    let html = report::monthly_report(conn, month)?;
+
+    match out {
+        Some(out) => {
+            safety.check_overwrite(out)?;
+            fs::write(out, html).with_context(|| format!("Failed to write {}", out.display()))?;
+            info!("Report written to {}", out.display());
+        }
+        None => print!("{}", html),
+    }
 
-    let Some(source_dir) = args.source_dir else {
+    Ok(())
+}
+
+fn write_inventory(
+    conn: &Connection,
+    target_dir: &Path,
+    out: Option<&Path>,
+    safety: Safety,
+) -> anyhow::Result<()> {
+    let volumes = inventory::build_inventory(conn, target_dir)?;
+
+    let Some(out) = out else {
+        print!("{}", inventory::to_csv(&volumes)?);
         return Ok(());
     };
+    let contents = match out.extension().and_then(|ext| ext.to_str()) {
+        Some("pdf") => inventory::to_pdf(&volumes),
+        Some("csv") => inventory::to_csv(&volumes)?.into_bytes(),
+        _ => anyhow::bail!("Inventory must be written to a .csv or .pdf file"),
+    };
+    safety.check_overwrite(out)?;
+    fs::write(out, contents).with_context(|| format!("Failed to write {}", out.display()))?;
+    info!(
+        "Inventory of {} volumes written to {}",
+        volumes.len(),
+        out.display()
+    );
 
-    wrap_multi(&multi, |pb| {
-        find_new_files(&mut conn, Camera, &source_dir, "source", pb, args.leave)
-    })?;
+    Ok(())
+}
 
-    let table_join = get_images_to_archive(&conn)?;
+fn import_manifest(
+    conn: &mut Connection,
+    manifest: &Path,
+    target_dir: &Path,
+) -> anyhow::Result<()> {
+    let images = manifest::load_manifest(manifest, target_dir)?;
 
-    for mismatch in table_join.mismatch {
-        error!("Truncation detected");
-        for (path, size) in mismatch {
-            error!("{path} - {size} bytes");
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let before = get_catalog_counts(&trans)?.on_disk;
+    add_to_table(&trans, Disk, "", &images)?;
+    let added = get_catalog_counts(&trans)?.on_disk - before;
+    db::log_operation(
+        &trans,
+        "import",
+        None,
+        &format!("{} files from {}", added, manifest.display()),
+    )?;
+    trans.commit()?;
+
+    info!("Indexed {} files listed in {}", added, manifest.display());
+    if added < images.len() as u64 {
+        info!("  {} were already indexed", images.len() as u64 - added);
+    }
+
+    Ok(())
+}
+
+fn write_dump(
+    conn: &Connection,
+    table: TableType,
+    format: dump::DumpFormat,
+    out: Option<&Path>,
+    safety: Safety,
+) -> anyhow::Result<()> {
+    let dump = db::dump_table(conn, table)?;
+    let contents = dump::write_table(&dump, format)?;
+    let Some(out) = out else {
+        print!("{}", contents);
+        return Ok(());
+    };
+    safety.check_overwrite(out)?;
+    fs::write(out, contents).with_context(|| format!("Failed to write {}", out.display()))?;
+    info!(
+        "Exported {} {} images to {}",
+        dump.rows.len(),
+        table.label(),
+        out.display()
+    );
+
+    Ok(())
+}
+
+/// Write the udev rule and systemd unit archiving the cards of the config
+/// into `target_dir` when they are inserted, or print them for `--dry-run`
+fn install_service(args: &AppArgs, target_dir: &Path, database_path: &Path) -> anyhow::Result<()> {
+    // The unit runs as the user that installs it, not as root
+    let user = env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .context("Failed to tell the user to archive as, set USER")?;
+    let mut command = vec![env::current_exe()?];
+    if let Some(config_path) = &args.config_path {
+        command.extend([PathBuf::from("--config"), std::path::absolute(config_path)?]);
+    }
+    command.extend([
+        PathBuf::from("--target"),
+        std::path::absolute(target_dir)?,
+        PathBuf::from("--db"),
+        std::path::absolute(database_path)?,
+        PathBuf::from("archive"),
+    ]);
+    let command = command
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+
+    let rule = service::udev_rule(&args.config.cards)?;
+    let unit = service::unit(&command, &user)?;
+    if args.dry {
+        println!("# {}\n{}", service::UDEV_RULE_PATH, rule);
+        println!("# {}\n{}", service::UNIT_PATH, unit);
+        return Ok(());
+    }
+    service::install(&rule, &unit)?;
+    info!(
+        "Cards {} are archived into {} as {} when inserted",
+        args.config.cards.join(", "),
+        target_dir.display(),
+        user
+    );
+    Ok(())
+}
+
+fn print_db_info(database_path: &Path, json: bool) -> anyhow::Result<()> {
+    let info = db::get_db_info(database_path)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    println!("Database:        {}", database_path.display());
+    println!("Image database:  {}", info.is_image_database);
+    println!(
+        "Schema version:  {} (latest {}{})",
+        info.schema_version,
+        info.latest_schema_version,
+        if info.migration_pending {
+            ", upgraded on next use"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "Hash algorithm:  {}",
+        info.hash_algorithm.as_deref().unwrap_or("unset")
+    );
+    println!("Size:            {}", format_size(info.size));
+    for (table, rows) in &info.tables {
+        println!("  {:<20} {} rows", table, rows);
+    }
+
+    Ok(())
+}
+
+fn print_locate(catalog: &Catalog, pattern: &str) -> anyhow::Result<()> {
+    let found = catalog.locate(pattern)?;
+    if found.is_empty() {
+        anyhow::bail!("No archived images match {:?}", pattern);
+    }
+
+    for image in found {
+        println!("{} ({}, {} bytes)", image.name, image.date, image.size);
+        println!(
+            "  dated by: {}",
+            image.date_source.as_deref().unwrap_or("unknown")
+        );
+        println!(
+            "  card:     {}",
+            image.card_id.as_deref().unwrap_or("unknown")
+        );
+        println!("  source:   {}", image.source_path);
+        println!("  archived: {} at {}", image.disk_path, image.archived_at);
+        let set = db::get_bracket_set(catalog, &image.disk_path)?;
+        if !set.is_empty() {
+            println!("  set:      {}", set.join(", "));
+        }
+        if let Some(pair) = db::get_live_photo_pair(catalog, &image.disk_path)? {
+            println!("  live:     {}", pair);
+        }
+        let burst = db::get_burst(catalog, &image.disk_path)?;
+        if !burst.is_empty() {
+            println!("  burst:    {} images", burst.len());
+        }
+    }
+
+    Ok(())
+}
+
+fn print_query(
+    catalog: &Catalog,
+    table: TableType,
+    name: Option<&str>,
+    selection: &export::Selection,
+    json: bool,
+) -> anyhow::Result<()> {
+    let start = selection.from.map(|from| from.and_time(NaiveTime::MIN));
+    let end = selection
+        .to
+        .map(|to| (to + chrono::Days::new(1)).and_time(NaiveTime::MIN));
+    let found = catalog.query(table, start, end, name, selection.pattern.as_deref())?;
+
+    if json {
+        for image in &found {
+            output::print_json(image)?;
+        }
+    } else {
+        for image in &found {
+            let saved = match image.saved {
+                Some(true) => "  saved",
+                Some(false) => "  unsaved",
+                None => "",
+            };
+            println!(
+                "{}  {:>10}  {}{}",
+                image.date.format("%Y-%m-%d %H:%M:%S"),
+                format_size(image.size),
+                image.path,
+                saved
+            );
+        }
+    }
+    info!(
+        "{} images, {}",
+        found.len(),
+        format_size(found.iter().map(|image| image.size).sum())
+    );
+
+    Ok(())
+}
+
+fn run_repair(
+    conn: &Connection,
+    multi: &MultiProgress,
+    filter: &FileFilter,
+    target_dir: &Path,
+    mirror_dir: &Path,
+    safety: Safety,
+) -> anyhow::Result<()> {
+    let corrupt = wrap_multi(multi, |pb| {
+        verify::verify_archive(conn, target_dir, filter, pb)
+    })?
+    .corrupt;
+    let algorithm = get_hash_algorithm(conn)?;
+
+    let mut failed = 0;
+    for file in &corrupt {
+        match repair::repair_file(conn, file, target_dir, mirror_dir, algorithm, safety) {
+            Ok(detail) => info!("{} - {}", file.path, detail),
+            Err(err) => {
+                error!("{} - Unable to repair: {:#}", file.path, err);
+                failed += 1;
+            }
         }
     }
 
+    info!(
+        "Repaired {} of {} corrupt images",
+        corrupt.len() - failed,
+        corrupt.len()
+    );
+    if failed > 0 {
+        anyhow::bail!("{} archived images could not be repaired", failed);
+    }
+
+    Ok(())
+}
+
+/// Take a snapshot after a run, if requested, unless it failed as a whole.
+/// The partial failure of a run that archived the rest is kept.
+fn snapshot_after(
+    conn: &Connection,
+    args: &AppArgs,
+    target_dir: &Path,
+    res: anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match res {
+        Err(err) if !err.is::<PartialFailure>() => Err(err),
+        res => snapshot_target(conn, args, target_dir).and(res),
+    }
+}
+
+/// Fail the run with `problems`, the files it left behind, as a
+/// [`PartialFailure`], or as a whole with `--strict`
+fn check_partial(args: &AppArgs, problems: &[String]) -> anyhow::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let message = problems.join(", ");
+    if args.strict {
+        anyhow::bail!("{}", message);
+    }
+    Err(PartialFailure(message).into())
+}
+
+/// Take a snapshot of the target after a successful run, if requested
+fn snapshot_target(conn: &Connection, args: &AppArgs, target_dir: &Path) -> anyhow::Result<()> {
+    let Some(command) = args
+        .config
+        .snapshot_command
+        .as_deref()
+        .filter(|_| args.snapshot)
+    else {
+        return Ok(());
+    };
     if args.dry {
-        eprintln!("Images to archive:");
-        for image in &table_join.to_archive {
-            eprintln!("  {}", image.basic.path);
+        info!("Dry run, not taking a snapshot");
+        return Ok(());
+    }
+
+    let name = snapshot::take_snapshot(command, target_dir)?;
+    db::log_operation(conn, "snapshot", None, &name)?;
+    info!("Took snapshot {}", name);
+
+    Ok(())
+}
+
+fn run_index(
+    conn: &mut Connection,
+    multi: &MultiProgress,
+    args: &AppArgs,
+    target_dir: &Path,
+) -> anyhow::Result<()> {
+    let started_at = chrono::Utc::now().naive_utc();
+    if args.background {
+        priority::lower_priority().context("Failed to lower process priority")?;
+    }
+
+    let mut progress = ProgressTracker::start(conn, &new_session(), "index");
+    progress.stage(conn, "scanning target", None);
+    let summary = wrap_multi(multi, |pb| {
+        let scan = Scan::target(conn, args.full_scan)?;
+        scanner(args).scan(conn, Disk, target_dir, "target", pb, scan)
+    });
+    progress.finish(conn);
+    let summary = summary?;
+    record_run(
+        conn,
+        &RunStats {
+            started_at,
+            finished_at: chrono::Utc::now().naive_utc(),
+            command: "index".to_string(),
+            files: summary.indexed as u64,
+            bytes: summary.bytes,
+            failures: summary.failed as u64,
+        },
+    )?;
+
+    info!(
+        "Indexed {} new images ({}), {} images in the target",
+        summary.indexed,
+        format_size(summary.bytes),
+        summary.found
+    );
+    if summary.denied > 0 {
+        warn!(
+            "Skipped {} unreadable entries in the target",
+            summary.denied
+        );
+    }
+    if summary.failed > 0 {
+        check_partial(
+            args,
+            &[format!(
+                "{} images in the target could not be indexed",
+                summary.failed
+            )],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_export(
+    conn: &Connection,
+    multi: &MultiProgress,
+    args: &AppArgs,
+    target_dir: &Path,
+    staging_dir: &Path,
+    selection: &export::Selection,
+    disc_size: u64,
+) -> anyhow::Result<()> {
+    let start = selection.from.map(|from| from.and_time(NaiveTime::MIN));
+    let end = selection
+        .to
+        .map(|to| (to + chrono::Days::new(1)).and_time(NaiveTime::MIN));
+    let files = db::get_unexported_files(conn, start, end, selection.pattern.as_deref())?;
+    if files.is_empty() {
+        info!("No archived images left to export");
+        return Ok(());
+    }
+
+    let discs = export::plan_discs(files, disc_size, db::next_disc_number(conn)?)?;
+    info!(
+        "Exporting {} images ({}) onto {} discs of {}",
+        discs.iter().map(|disc| disc.files.len()).sum::<usize>(),
+        format_size(discs.iter().map(|disc| disc.bytes).sum()),
+        discs.len(),
+        format_size(disc_size)
+    );
+
+    if args.dry {
+        for disc in &discs {
+            eprintln!(
+                "{}: {} images ({})",
+                disc.label,
+                disc.files.len(),
+                format_size(disc.bytes)
+            );
+        }
+        return Ok(());
+    }
+
+    let algorithm = get_hash_algorithm(conn)?;
+    for disc in &discs {
+        let checksums = wrap_multi(multi, |pb| {
+            export::stage_disc(disc, target_dir, staging_dir, algorithm, pb)
+        })?;
+        db::record_disc(
+            conn,
+            &disc.label,
+            disc_size,
+            disc.files
+                .iter()
+                .zip(&checksums)
+                .map(|(file, checksum)| (file.path.as_str(), file.size, checksum.as_slice())),
+        )?;
+        info!(
+            "Staged {} with {} images ({}) at {}",
+            disc.label,
+            disc.files.len(),
+            format_size(disc.bytes),
+            staging_dir.join(&disc.label).display()
+        );
+    }
+
+    Ok(())
+}
+
+fn run_verify(
+    conn: &mut Connection,
+    multi: &MultiProgress,
+    filter: &FileFilter,
+    target_dir: &Path,
+    mirror_dir: Option<&Path>,
+) -> anyhow::Result<()> {
+    let started_at = chrono::Utc::now().naive_utc();
+    // Mirrors are told apart by where they are mounted
+    let (copy, dir) = match mirror_dir {
+        Some(mirror_dir) => {
+            let mirror_dir = mirror_dir
+                .canonicalize()
+                .with_context(|| format!("Failed to open mirror {}", mirror_dir.display()))?;
+            (mirror_dir.to_string_lossy().into_owned(), mirror_dir)
+        }
+        None => (db::TARGET_COPY.to_owned(), target_dir.to_owned()),
+    };
+    let report = wrap_multi(multi, |pb| verify::verify_archive(conn, &dir, filter, pb))?;
+
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    db::record_verifications(
+        &trans,
+        &copy,
+        report.verified.iter().map(String::as_str),
+        started_at,
+    )?;
+    record_run(
+        &trans,
+        &RunStats {
+            started_at,
+            finished_at: chrono::Utc::now().naive_utc(),
+            command: "verify".to_string(),
+            files: report.checked,
+            bytes: report.bytes,
+            failures: report.corrupt.len() as u64,
+        },
+    )?;
+    trans.commit()?;
+
+    let corrupt = report.corrupt;
+    for file in &corrupt {
+        match &file.problem {
+            Problem::Missing(err) => error!("{} - missing: {}", file.path, err),
+            Problem::Unreadable(err) => error!("{} - unreadable: {}", file.path, err),
+            Problem::SizeChanged { actual } if *actual < file.size => error!(
+                "{} - truncated from {} to {} bytes",
+                file.path, file.size, actual
+            ),
+            Problem::SizeChanged { actual } => error!(
+                "{} - size changed from {} to {} bytes",
+                file.path, file.size, actual
+            ),
+            Problem::ChecksumMismatch { ranges } => {
+                for range in ranges {
+                    error!(
+                        "{} - bytes {} to {} are corrupt",
+                        file.path, range.start, range.end
+                    );
+                }
+            }
+        }
+    }
+
+    for path in &report.untracked {
+        warn!("{} - not in the database", path);
+    }
+    if !report.untracked.is_empty() {
+        warn!(
+            "{} files in {} are not in the database, run `rawdb index` to add them",
+            report.untracked.len(),
+            dir.display()
+        );
+    }
+    if report.size_only > 0 {
+        info!(
+            "{} archived images have no checksum, only their size was checked",
+            report.size_only
+        );
+    }
+
+    if !corrupt.is_empty() {
+        anyhow::bail!("{} archived images failed verification", corrupt.len());
+    }
+    info!("All archived images verified");
+
+    Ok(())
+}
+
+fn run_collisions(conn: &mut Connection, target_dir: &Path, fix: bool) -> anyhow::Result<()> {
+    let found = db::get_name_collisions(conn)?;
+    for collision in &found {
+        println!("{}", collision.name);
+        for file in &collision.files {
+            let checksum = file.checksum.as_deref().map(hash::to_hex);
+            println!(
+                "  {} ({} bytes, {})",
+                file.path,
+                file.size,
+                checksum.as_deref().unwrap_or("no checksum")
+            );
+        }
+
+        if fix {
+            collisions::fix_collision(conn, target_dir, collision)?;
+        }
+    }
+
+    if found.is_empty() {
+        info!("No archived images share a name");
+    } else if !fix {
+        info!(
+            "{} names are shared by different images, use --fix to rename them",
+            found.len()
+        );
+    }
+
+    Ok(())
+}
+
+fn run_orphans(
+    conn: &mut Connection,
+    multi: &MultiProgress,
+    args: &AppArgs,
+    target_dir: &Path,
+    adopt: bool,
+) -> anyhow::Result<()> {
+    let known = db::get_recorded_files(conn)?
+        .into_iter()
+        .map(|file| file.path)
+        .collect::<HashSet<_>>();
+    let mut orphans = Vec::new();
+    let filter = file_filter(args);
+    for image in load_images::<ImageBasic>(target_dir, &filter) {
+        let image = image?;
+        if !known.contains(&image.path) {
+            orphans.push(image);
         }
+    }
+    orphans.sort_by(|a, b| a.path.cmp(&b.path));
 
+    for orphan in &orphans {
+        println!("{} ({})", orphan.path, format_size(orphan.size));
+    }
+    if orphans.is_empty() {
+        info!("Every file in the target is indexed");
+        return Ok(());
+    }
+    if !adopt {
+        info!(
+            "{} files in the target aren't indexed, use --adopt to index them",
+            orphans.len()
+        );
         return Ok(());
     }
-    wrap_multi(&multi, |pb| {
-        pb.set_length(table_join.to_archive.len() as u64);
 
-        let trans = conn.transaction()?;
-        let success = table_join
-            .to_archive
-            .into_iter()
-            .progress_with(pb)
-            .with_message("Archiving images")
-            .filter_map(|image| {
-                archive_image(&image, &source_dir, &args.target_dir)
-                    .inspect_err(|err| error!("{}", err))
-                    .map(|_| image)
-                    .ok()
-            })
-            .collect::<Vec<_>>();
+    let found = orphans.len();
+    let rows = wrap_multi(multi, |pb| {
+        scanner(args).inspect(conn, Disk, target_dir, "target", orphans, &pb)
+    })?;
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    rows.record(&trans, Disk, "")?;
+    db::log_operation(
+        &trans,
+        "adopt",
+        None,
+        &format!("Indexed {} files found in the target", rows.images.len()),
+    )?;
+    trans.commit()?;
 
-        set_images_as_archived(&trans, success.iter())?;
-        trans.commit()?;
-        info!("Archived {} images", success.len());
+    info!("Adopted {} of {} unindexed files", rows.images.len(), found);
+    if !rows.failures.is_empty() {
+        check_partial(
+            args,
+            &[format!(
+                "{} files could not be indexed",
+                rows.failures.len()
+            )],
+        )?;
+    }
 
-        Ok(())
-    })
+    Ok(())
+}
+
+fn run_prune(conn: &mut Connection, target_dir: &Path, dry: bool) -> anyhow::Result<()> {
+    // An unmounted target would look like every file is gone
+    if !target_dir.is_dir() {
+        anyhow::bail!(
+            "Target {} can't be read, is it mounted?",
+            target_dir.display()
+        );
+    }
+
+    let mut gone = Vec::new();
+    let mut bytes = 0;
+    for file in db::get_recorded_files(conn)? {
+        let path = target_dir.join(&file.path);
+        match fs::symlink_metadata(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if dry {
+                    println!("{}", file.path);
+                }
+                bytes += file.size;
+                gone.push(file.path);
+            }
+            Err(err) => warn!("Unable to check {}: {}", path.display(), err),
+            Ok(_) => {}
+        }
+    }
+
+    if gone.is_empty() {
+        info!("Every indexed file still exists");
+        return Ok(());
+    }
+    if dry {
+        info!(
+            "{} indexed files ({}) no longer exist and would be forgotten",
+            gone.len(),
+            format_size(bytes)
+        );
+        return Ok(());
+    }
+
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    db::prune_disk_files(&trans, &gone)?;
+    db::log_operation(
+        &trans,
+        "prune",
+        None,
+        &format!("Forgot {} files that no longer exist", gone.len()),
+    )?;
+    trans.commit()?;
+    info!(
+        "Forgot {} indexed files ({}) that no longer exist",
+        gone.len(),
+        format_size(bytes)
+    );
+
+    Ok(())
+}
+
+fn run_dedupe(
+    conn: &Connection,
+    target_dir: &Path,
+    dry: bool,
+    safety: Safety,
+) -> anyhow::Result<()> {
+    let groups = db::get_identical_files(conn)?;
+    let algorithm = get_hash_algorithm(conn)?;
+
+    let mut linked = 0;
+    let mut freed = 0;
+    let mut failed = 0;
+    for group in &groups {
+        if dry {
+            for file in &group[1..] {
+                println!("{} -> {}", file.path, group[0].path);
+                linked += 1;
+                freed += file.size;
+            }
+            continue;
+        }
+        match dedupe::link_duplicates(conn, target_dir, group, algorithm, safety) {
+            Ok((files, bytes)) => {
+                linked += files;
+                freed += bytes;
+            }
+            Err(err) => {
+                error!("{} - Unable to deduplicate: {:#}", group[0].path, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if groups.is_empty() {
+        info!("No archived images share their contents");
+    } else if dry {
+        info!(
+            "{} duplicates could be linked, freeing up to {}",
+            linked,
+            format_size(freed)
+        );
+    } else {
+        info!(
+            "Linked {} duplicates, freeing {}",
+            linked,
+            format_size(freed)
+        );
+    }
+    if failed > 0 {
+        anyhow::bail!("{} groups of identical images could not be linked", failed);
+    }
+
+    Ok(())
+}
+
+fn list_archives(config: &Config) -> anyhow::Result<()> {
+    if config.archives.is_empty() {
+        info!("No archives are registered in the config");
+        return Ok(());
+    }
+
+    for (name, archive) in &config.archives {
+        println!("{}", name);
+        println!("  database: {}", archive.db.display());
+        if archive.target.is_dir() {
+            println!("  target:   {}", archive.target.display());
+        } else {
+            println!("  target:   {} (not mounted)", archive.target.display());
+        }
+
+        if !archive.db.exists() {
+            println!("  No database yet");
+            continue;
+        }
+        let conn = match db::open_existing(&archive.db) {
+            Ok(conn) => conn,
+            Err(err) => {
+                println!("  {:#}", err);
+                continue;
+            }
+        };
+        let counts = get_catalog_counts(&conn)?;
+        println!(
+            "  {} images archived, {} camera images not yet archived",
+            counts.on_disk, counts.unsaved
+        );
+        match db::get_last_run(&conn)? {
+            Some(run) => println!(
+                "  Last {} finished {}",
+                run.command,
+                run.finished_at.format("%Y-%m-%d %H:%M")
+            ),
+            None => println!("  Never run"),
+        }
+    }
+
+    Ok(())
+}
+
+fn print_stats(catalog: &Catalog) -> anyhow::Result<()> {
+    for (label, table) in [("On disk", Disk), ("On camera", Camera)] {
+        let stats = catalog.stats(table)?;
+        println!(
+            "{}: {} images, {}",
+            label,
+            stats.files,
+            format_size(stats.bytes)
+        );
+        if let (Some(first), Some(last)) = (stats.first, stats.last) {
+            println!(
+                "  taken {} to {}",
+                first.format("%Y-%m-%d"),
+                last.format("%Y-%m-%d")
+            );
+        }
+        for (ext, (files, bytes)) in &stats.extensions {
+            let ext = if ext.is_empty() { "(none)" } else { ext };
+            println!(
+                "  {:<8} {:>8} images {:>10}",
+                ext,
+                files,
+                format_size(*bytes)
+            );
+        }
+    }
+
+    let (unsaved, unsaved_bytes) = catalog.unsaved()?;
+    println!(
+        "{} camera images not yet archived, {}",
+        unsaved,
+        format_size(unsaved_bytes)
+    );
+
+    Ok(())
+}
+
+fn print_status(catalog: &Catalog, config: &Config) -> anyhow::Result<()> {
+    let counts = catalog.counts()?;
+    println!("{} images on disk", counts.on_disk);
+    println!(
+        "{} images on camera, {} not yet archived",
+        counts.on_camera, counts.unsaved
+    );
+
+    for failure in get_failure_counts(catalog)? {
+        println!(
+            "{} - {} files pending due to {}",
+            failure.source,
+            failure.count,
+            failure.kind.description()
+        );
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    for run in db::get_running(catalog)? {
+        let total = run.total.map(|total| format!("/{}", total));
+        println!(
+            "Running {} (pid {}) since {}: {} {}{}",
+            run.command,
+            run.pid,
+            run.started_at
+                .and_utc()
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M"),
+            run.stage,
+            run.done,
+            total.as_deref().unwrap_or("")
+        );
+        if let Some(current_file) = &run.current_file {
+            println!("  current file: {}", current_file);
+        }
+        if let Some(throughput) = progress::throughput(&run) {
+            println!("  throughput:   {}/s", format_size(throughput as u64));
+        }
+        let idle = (now - run.updated_at).num_seconds();
+        if idle > 60 {
+            println!("  no progress for {} seconds, it may have crashed", idle);
+        }
+    }
+
+    let months = config.verify_every_months.unwrap_or(VERIFY_EVERY_MONTHS);
+    let cutoff = now
+        .checked_sub_months(chrono::Months::new(months))
+        .unwrap_or(NaiveDateTime::MIN);
+    let mut stale = 0;
+    for coverage in db::get_verification_coverage(catalog, cutoff)? {
+        if coverage.stale == 0 {
+            continue;
+        }
+        stale += coverage.stale;
+        println!(
+            "{} - {} of {} files not verified in the last {} months{}",
+            coverage.copy,
+            coverage.stale,
+            coverage.files,
+            months,
+            coverage
+                .oldest
+                .map(|oldest| format!(", oldest verified {}", oldest.format("%Y-%m-%d")))
+                .unwrap_or_default()
+        );
+    }
+    if stale > 0 {
+        anyhow::bail!(
+            "{} copies of archived files are due for verification, run `rawdb verify`",
+            stale
+        );
+    }
+
+    Ok(())
+}
+
+/// Find camera images at paths previously archived from this card that hold
+/// a different image, which means the card was formatted in between
+fn find_recycled_names(
+    conn: &Connection,
+    card_id: &str,
+    source_dir: &Path,
+    algorithm: HashAlgorithm,
+) -> anyhow::Result<HashSet<String>> {
+    let mut candidates = HashSet::new();
+    let mut reseen = HashSet::new();
+    for previous in get_previous_imports(conn, card_id)? {
+        let same = previous.may_be_same()
+            && match &previous.checksum {
+                Some(checksum) => {
+                    let abs_path = source_dir.join(&previous.image.basic.path);
+                    let (actual, _) = hash::hash_file_chunked(&abs_path, algorithm, None)
+                        .with_context(|| format!("Failed to read {}", abs_path.display()))?;
+                    actual == *checksum
+                }
+                None => true,
+            };
+        if same {
+            reseen.insert(previous.image.basic.path.clone());
+        }
+        candidates.insert(previous.image.basic.path);
+    }
+
+    let recycled = &candidates - &reseen;
+    if !recycled.is_empty() {
+        warn!(
+            "Card {} appears to have been formatted, {} file names were reused for new images",
+            card_id,
+            recycled.len()
+        );
+        for path in &recycled {
+            debug!("  {}", path);
+        }
+    }
+
+    Ok(recycled)
+}
+
+fn run_tether(
+    conn: &mut Connection,
+    args: &AppArgs,
+    target_dir: &Path,
+    session_dir: &Path,
+) -> anyhow::Result<()> {
+    let layout = Layout {
+        force_folder: args.force_folder.clone(),
+        template: args.layout.clone(),
+        timezone: args.timezone,
+        dst_policy: db::get_dst_policy(conn)?,
+        day_start: args.config.day_starts_at.unwrap_or(NaiveTime::MIN),
+        on_collision: args.on_collision,
+        burst_folders: HashMap::new(),
+        placed_with: HashMap::new(),
+    };
+
+    info!(
+        "Watching {} for tethered captures, press Ctrl-C to stop",
+        session_dir.display()
+    );
+    let mut folder = tether::HotFolder::new(session_dir, file_filter(args));
+    loop {
+        let ready = folder.poll()?;
+        if !ready.is_empty() {
+            let archiver = archiver(conn, args, target_dir, &layout)?;
+            archive_tethered(conn, args, &archiver, session_dir, ready)?;
+        }
+        std::thread::sleep(tether::POLL_INTERVAL);
+    }
+}
+
+/// Index and archive files that finished writing into the session folder,
+/// one at a time as they arrive instead of scanning the whole source
+fn archive_tethered(
+    conn: &mut Connection,
+    args: &AppArgs,
+    archiver: &Archiver,
+    session_dir: &Path,
+    ready: Vec<ImageBasic>,
+) -> anyhow::Result<()> {
+    let started_at = chrono::Utc::now().naive_utc();
+    let mut images = Vec::new();
+    let mut failures = Vec::new();
+    for basic in ready {
+        let options = ReadOptions {
+            timezone: args.timezone,
+            clock_offsets: &args.config.clock_offsets,
+            ffprobe: args.ffprobe.as_deref(),
+        };
+        match ImageAdv::from_basic(basic.clone(), session_dir, options) {
+            Ok(image) => images.push(image),
+            Err(err) => {
+                warn!(target: FailureKind::classify(&err).label(), "{}", err);
+                failures.push((basic, err));
+            }
+        }
+    }
+
+    // Captures already in the archive under the same name and date are skipped
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    add_to_table(&trans, Camera, "", &images)?;
+    let paths = images
+        .iter()
+        .map(|image| image.basic.path.as_str())
+        .collect::<HashSet<_>>();
+    let to_archive = get_images_to_archive(&trans, "")?
+        .to_archive
+        .into_iter()
+        .filter(|image| paths.contains(image.basic.path.as_str()))
+        .collect::<Vec<_>>();
+    trans.commit()?;
+
+    let mut success = Vec::new();
+    for image in to_archive {
+        match archiver
+            .archive(&image, &[session_dir])
+            .map_err(RawdbError::into_inner)
+        {
+            Ok(copy) => {
+                info!("Archived {} to {}", image.basic.path, copy.path);
+                success.push((image, copy));
+            }
+            Err(err) if archiver.skips_collision(FailureKind::classify(&err)) => {
+                warn!(target: FailureKind::Collision.label(), "{}, skipping it", err);
+            }
+            Err(err) => {
+                error!(target: FailureKind::classify(&err).label(), "{}", err);
+                failures.push((image.basic, err));
+            }
+        }
+    }
+
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    archiver.record(&trans, "", None, &success)?;
+    for (image, err) in &failures {
+        record_failure(&trans, Camera, image, err)?;
+    }
+    record_run(
+        &trans,
+        &RunStats {
+            started_at,
+            finished_at: chrono::Utc::now().naive_utc(),
+            command: "tether".to_string(),
+            files: success.len() as u64,
+            bytes: success.iter().map(|(image, _)| image.basic.size).sum(),
+            failures: failures.len() as u64,
+        },
+    )?;
+    trans.commit()?;
+
+    Ok(())
+}
+
+/// Run the whole archive of each card as it is mounted, logging the outcome
+/// and carrying on with the next card either way
+fn run_watch(
+    conn: &mut Connection,
+    multi: &MultiProgress,
+    args: &AppArgs,
+    target_dir: &Path,
+    mount_dir: &Path,
+) -> anyhow::Result<()> {
+    info!(
+        "Watching {} for cards, press Ctrl-C to stop",
+        mount_dir.display()
+    );
+    let mut watcher = watch::CardWatcher::new(mount_dir);
+    loop {
+        for card in watcher.poll() {
+            info!("Card detected at {}", card.display());
+            let started_at = chrono::Utc::now().naive_utc();
+            let mut summaries = Vec::new();
+            let res = run_archive(
+                conn,
+                multi,
+                args,
+                target_dir,
+                std::slice::from_ref(&card),
+                &mut summaries,
+            );
+            let res = snapshot_after(conn, args, target_dir, res);
+            if args.notify {
+                notify_outcome(conn, started_at, &res);
+            }
+            report_run(args, target_dir, started_at, summaries, &res);
+            logging::summarize_run();
+            match res {
+                Ok(()) => info!("Finished archiving {}", card.display()),
+                Err(err) => error!("Failed to archive {}: {:#}", card.display(), err),
+            }
+        }
+        watcher.wait();
+    }
+}
+
+/// Show how the archive runs since `started_at` went in a desktop
+/// notification, for `--notify`
+fn notify_outcome(conn: &Connection, started_at: NaiveDateTime, res: &anyhow::Result<()>) {
+    // Runs that left some files behind are shown with their failures
+    if let Some(err) = res.as_ref().err().filter(|err| !err.is::<PartialFailure>()) {
+        notify::notify("rawdb failed", &format!("{:#}", err), true);
+        return;
+    }
+    let runs = match db::get_runs_since(conn, "archive", started_at) {
+        Ok(runs) => runs,
+        Err(err) => {
+            warn!("Failed to read the runs to notify about: {:#}", err);
+            return;
+        }
+    };
+    let archived = runs.iter().map(|run| run.files).sum::<u64>();
+    let failures = runs.iter().map(|run| run.failures).sum::<u64>();
+    let body = match failures {
+        0 => format!("Archived {} images", archived),
+        _ => format!("Archived {} images, {} failures", archived, failures),
+    };
+    notify::notify("rawdb finished archiving", &body, failures > 0);
+}
+
+/// Send the report of the archive run that started at `started_at` to the
+/// `[reporter]` of the config. Dry runs archive nothing to report.
+fn report_run(
+    args: &AppArgs,
+    target_dir: &Path,
+    started_at: NaiveDateTime,
+    summaries: Vec<output::Summary>,
+    res: &anyhow::Result<()>,
+) {
+    if args.dry {
+        return;
+    }
+    let report = RunReport::new(
+        "archive",
+        target_dir,
+        started_at,
+        summaries,
+        res.as_ref().err(),
+    );
+    reporter::send(&args.config.reporter, &report);
+}
+
+fn new_session() -> String {
+    format!("{}-{}", std::process::id(), chrono::Utc::now().timestamp())
+}
+
+/// Archive every source in turn, or only index the target without any.
+/// Images that can't be read from one source are copied from another. The
+/// outcome of each source is added to `summaries`.
+fn run_archive(
+    conn: &mut Connection,
+    multi: &MultiProgress,
+    args: &AppArgs,
+    target_dir: &Path,
+    source_dirs: &[PathBuf],
+    summaries: &mut Vec<output::Summary>,
+) -> anyhow::Result<()> {
+    // Cameras and phones read over PTP or MTP are archived like a card, from
+    // the folder their files were downloaded into
+    let staging = args
+        .camera_staging
+        .clone()
+        .unwrap_or_else(|| env::temp_dir().join("rawdb-camera"));
+    let mut cards = Vec::new();
+    let mut source_dirs = source_dirs
+        .iter()
+        .map(|source| match ptp::parse_mtp(source) {
+            Some((device, folder)) => ptp::download(device, Some(folder), &staging),
+            None => {
+                cards.push(source.clone());
+                Ok(source.clone())
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if let Some(port) = &args.camera {
+        source_dirs.insert(0, ptp::download(port.as_deref(), None, &staging)?);
+    }
+    if args.auto {
+        let found = volumes::find_cards(&args.config.cards)?;
+        if found.is_empty() && source_dirs.is_empty() {
+            info!("None of the cards of the config is mounted");
+            return Ok(());
+        }
+        for card in &found {
+            info!("Found card {}", card.display());
+        }
+        source_dirs.extend(found.iter().cloned());
+        cards.extend(found);
+    }
+    let source_dirs = source_dirs.as_slice();
+
+    let mut scans = RunScans::default();
+    if source_dirs.is_empty() {
+        let mut progress = ProgressTracker::start(conn, &new_session(), "archive");
+        let res = archive_with_progress(
+            conn,
+            multi,
+            args,
+            target_dir,
+            &[],
+            &mut scans,
+            &mut progress,
+            summaries,
+        );
+        progress.finish(conn);
+        return res;
+    }
+
+    let mut failed = 0;
+    let mut partial = 0;
+    for (idx, source_dir) in source_dirs.iter().enumerate() {
+        // The source being archived comes first, then the others to fall
+        // back on
+        let mut sources = vec![source_dir.as_path()];
+        sources.extend(
+            source_dirs
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != idx)
+                .map(|(_, dir)| dir.as_path()),
+        );
+        if source_dirs.len() > 1 {
+            info!("Archiving from {}", source_dir.display());
+        }
+
+        let mut progress = ProgressTracker::start(conn, &new_session(), "archive");
+        let res = archive_with_progress(
+            conn,
+            multi,
+            args,
+            target_dir,
+            &sources,
+            &mut scans,
+            &mut progress,
+            summaries,
+        );
+        progress.finish(conn);
+        match res {
+            Err(err) if source_dirs.len() > 1 => {
+                error!("Failed to archive {}: {:#}", source_dir.display(), err);
+                if err.is::<PartialFailure>() {
+                    partial += 1;
+                } else {
+                    failed += 1;
+                }
+            }
+            res => res?,
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} sources could not be archived",
+            failed,
+            source_dirs.len()
+        );
+    }
+    if partial > 0 {
+        return Err(PartialFailure(format!(
+            "{} of {} sources could not be archived completely",
+            partial,
+            source_dirs.len()
+        ))
+        .into());
+    }
+
+    if args.eject && !args.dry {
+        for card in &cards {
+            eject::eject(card)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// What a run indexed so far, so the target and each source are only scanned
+/// once however many sources are archived
+#[derive(Default)]
+struct RunScans {
+    target_scanned: bool,
+    /// Card id and summary of each source, the summary is taken by the run
+    /// archiving from it
+    sources: HashMap<PathBuf, (Option<String>, IndexSummary)>,
+}
+
+/// Camera images are kept per card, so concurrent runs on other cards don't
+/// replace them. Sources without a card id are told apart by their path.
+fn card_scope(card_id: Option<&str>, source_dir: &Path) -> String {
+    card_id.map_or_else(|| source_dir.display().to_string(), str::to_owned)
+}
+
+/// Index the target and all of `sources` unless this run already did, then
+/// archive from the first of `sources`, falling back on the others
+#[allow(clippy::too_many_arguments)]
+fn archive_with_progress(
+    conn: &mut Connection,
+    multi: &MultiProgress,
+    args: &AppArgs,
+    target_dir: &Path,
+    sources: &[&Path],
+    scans: &mut RunScans,
+    progress: &mut ProgressTracker,
+    summaries: &mut Vec<output::Summary>,
+) -> anyhow::Result<()> {
+    let started_at = chrono::Utc::now().naive_utc();
+    if args.background {
+        priority::lower_priority().context("Failed to lower process priority")?;
+    }
+
+    // Copies of earlier sources were indexed as they were archived
+    let target_summary = if scans.target_scanned {
+        IndexSummary::default()
+    } else {
+        progress.stage(conn, "scanning target", None);
+        wrap_multi(multi, |pb| {
+            let scan = Scan::target(conn, args.full_scan)?;
+            scanner(args).scan(conn, Disk, target_dir, "target", pb, scan)
+        })?
+    };
+    scans.target_scanned = true;
+
+    let Some(&source_dir) = sources.first() else {
+        return Ok(());
+    };
+
+    let files = args
+        .files_from
+        .as_deref()
+        .map(images::read_file_list)
+        .transpose()?;
+    // Every source is indexed before the first is archived from, so copies
+    // on the others can be checked against the index
+    for &dir in sources {
+        if scans.sources.contains_key(dir) {
+            continue;
+        }
+        // Source media are only written to when asked to
+        let card_id = card::get_card_id(dir, args.tag_card && !args.dry)?;
+        let card = card_scope(card_id.as_deref(), dir);
+        progress.stage(conn, "scanning source", None);
+        let summary = wrap_multi(multi, |pb| {
+            Scanner {
+                card: &card,
+                ..scanner(args)
+            }
+            .scan(
+                conn,
+                Camera,
+                dir,
+                "source",
+                pb,
+                files.as_deref().map_or(Scan::Walk, Scan::Listed),
+            )
+        })?;
+        scans.sources.insert(dir.to_owned(), (card_id, summary));
+    }
+    let (card_id, source_summary) = scans
+        .sources
+        .get_mut(source_dir)
+        .map(|(card_id, summary)| (card_id.clone(), std::mem::take(summary)))
+        .context("The source was indexed")?;
+    let card = &card_scope(card_id.as_deref(), source_dir);
+
+    let algorithm = get_hash_algorithm(conn)?;
+    let recycled = match &card_id {
+        Some(card_id) => find_recycled_names(conn, card_id, source_dir, algorithm)?,
+        None => HashSet::new(),
+    };
+
+    let mut table_join = get_images_to_archive(conn, card)?;
+
+    // Images that only share their name with an archived file get one of
+    // their own
+    let mut colliding = match args.on_collision {
+        CollisionPolicy::Suffix => db::get_colliding_images(conn, card)?,
+        _ => Vec::new(),
+    };
+    // The index keeps the files of the card that weren't listed, they are
+    // left for another run
+    if let Some(listed) = &source_summary.listed {
+        let listed = listed.iter().collect::<HashSet<_>>();
+        table_join
+            .to_archive
+            .retain(|image| listed.contains(&image.basic.path));
+        table_join
+            .mismatch
+            .retain(|mismatch| listed.contains(&mismatch[0].0));
+        table_join
+            .unmarked
+            .retain(|unmarked| listed.contains(&unmarked.image.basic.path));
+        colliding.retain(|image| listed.contains(&image.basic.path));
+    }
+    let renamed = colliding
+        .iter()
+        .map(|image| image.basic.path.clone())
+        .collect::<HashSet<_>>();
+    table_join
+        .mismatch
+        .retain(|mismatch| !renamed.contains(&mismatch[0].0));
+    table_join.to_archive.extend(colliding);
+    if !renamed.is_empty() {
+        info!(
+            "Archiving {} images whose name is taken by a different file under a new name",
+            renamed.len()
+        );
+    }
+    let truncated = table_join.mismatch.len();
+
+    for mismatch in table_join.mismatch {
+        if mismatch[0].1 == mismatch[1].1 {
+            error!("Different contents under the same name and date");
+        } else {
+            error!("Truncation detected");
+        }
+        for (path, size) in mismatch {
+            error!("{path} - {size} bytes");
+        }
+    }
+
+    // Camera images that were copied into the archive by hand are never
+    // marked as saved, so verify the copy on disk and backfill them
+    let mut backfill = Vec::new();
+    for unmarked in table_join.unmarked {
+        if recycled.contains(&unmarked.image.basic.path) {
+            warn!(
+                "{} matches {}, but it is a different image with a recycled name",
+                unmarked.image.basic.path, unmarked.disk_path
+            );
+            continue;
+        }
+        let disk_path = target_dir.join(&unmarked.disk_path);
+        match fs::metadata(&disk_path) {
+            Ok(meta) if meta.len() == unmarked.image.basic.size => {
+                info!(
+                    "{} already archived at {}, marking as saved",
+                    unmarked.image.basic.path,
+                    disk_path.display()
+                );
+                backfill.push(unmarked);
+            }
+            Ok(meta) => warn!(
+                "{} matches {}, but its size changed ({} != {} bytes)",
+                unmarked.image.basic.path,
+                disk_path.display(),
+                meta.len(),
+                unmarked.image.basic.size
+            ),
+            Err(err) => warn!(
+                "{} matches {}, but it could not be read: {}",
+                unmarked.image.basic.path,
+                disk_path.display(),
+                err
+            ),
+        }
+    }
+
+    let (culled, to_archive) = table_join
+        .to_archive
+        .into_iter()
+        .partition::<Vec<_>, _>(|image| args.cull.skips(image.rating));
+    table_join.to_archive = to_archive;
+
+    let card_folders = if args.pick_card_folder {
+        pick_card_folders(&table_join.to_archive)?
+    } else {
+        args.card_folders.clone()
+    };
+    if !card_folders.is_empty() {
+        let pending = table_join.to_archive.len();
+        table_join.to_archive.retain(|image| {
+            let folder = card_folder_of(image);
+            card_folders.iter().any(|selected| {
+                folder == *selected || Path::new(&folder).ends_with(selected.as_str())
+            })
+        });
+        info!(
+            "Only archiving {} of {} images from the card folders {}",
+            table_join.to_archive.len(),
+            pending,
+            card_folders.join(", ")
+        );
+    }
+
+    if let Some(folder) = &args.force_folder {
+        info!(
+            "Archiving all images into {}",
+            target_dir.join(folder).display()
+        );
+    }
+
+    let mut layout = Layout {
+        force_folder: args.force_folder.clone(),
+        template: args.layout.clone(),
+        timezone: args.timezone,
+        dst_policy: db::get_dst_policy(conn)?,
+        day_start: args.config.day_starts_at.unwrap_or(NaiveTime::MIN),
+        on_collision: args.on_collision,
+        burst_folders: HashMap::new(),
+        placed_with: HashMap::new(),
+    };
+
+    // Keep the JPEGs recorded with a RAW next to it, and archive each set
+    // in one go
+    let mut bracket_sets = brackets::find_bracket_sets(
+        table_join
+            .to_archive
+            .iter()
+            .map(|image| (image.basic.path.as_str(), image.date)),
+    );
+    let mut paired_jpegs = Vec::new();
+    if args.skip_paired_jpegs {
+        let members = bracket_sets
+            .iter()
+            .flat_map(|set| &set.members)
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+        let (skipped, to_archive) = table_join
+            .to_archive
+            .into_iter()
+            .partition::<Vec<_>, _>(|image| members.contains(image.basic.path.as_str()));
+        table_join.to_archive = to_archive;
+        paired_jpegs = skipped;
+        bracket_sets.clear();
+    }
+    let raw_of = bracket_sets
+        .iter()
+        .flat_map(|set| {
+            set.members
+                .iter()
+                .map(|member| (member.as_str(), set.raw.as_str()))
+        })
+        .collect::<HashMap<_, _>>();
+    if !bracket_sets.is_empty() {
+        let position = table_join
+            .to_archive
+            .iter()
+            .enumerate()
+            .map(|(idx, image)| (image.basic.path.clone(), idx))
+            .collect::<HashMap<_, _>>();
+        table_join.to_archive.sort_by_key(|image| {
+            let path = image.basic.path.as_str();
+            match raw_of.get(path) {
+                Some(raw) => (position[*raw], true),
+                None => (position[path], false),
+            }
+        });
+        info!(
+            "Grouped {} JPEGs with their RAW files into {} sets",
+            bracket_sets
+                .iter()
+                .map(|set| set.members.len())
+                .sum::<usize>(),
+            bracket_sets.len()
+        );
+    }
+
+    let bursts = bursts::find_bursts(
+        table_join
+            .to_archive
+            .iter()
+            .map(|image| (image.basic.path.as_str(), image.date)),
+    );
+    if !bursts.is_empty() {
+        info!(
+            "Found {} bursts of {} images",
+            bursts.len(),
+            bursts.iter().map(|burst| burst.paths.len()).sum::<usize>()
+        );
+    }
+    if args.burst_folders {
+        for burst in &bursts {
+            let folder = burst.folder_name();
+            for path in &burst.paths {
+                layout.burst_folders.insert(path.clone(), folder.clone());
+            }
+        }
+    }
+
+    // Archive the video of a Live Photo into the folder of its photo
+    let live_photos = livephotos::find_live_photos(&table_join.to_archive)
+        .into_iter()
+        .map(|pair| {
+            layout
+                .placed_with
+                .insert(pair.video.basic.path.clone(), pair.image.clone());
+            (pair.image.basic.path.clone(), pair.video.basic.path.clone())
+        })
+        .collect::<Vec<_>>();
+    if !live_photos.is_empty() {
+        info!(
+            "Paired {} Live Photo videos with their photos",
+            live_photos.len()
+        );
+    }
+
+    let ambiguous = table_join
+        .to_archive
+        .iter()
+        .filter(|image| layout.place(image).ambiguous)
+        .count();
+    if ambiguous > 0 {
+        warn!(
+            "{} images were taken during a daylight saving switch, placing them by the {} policy",
+            ambiguous, layout.dst_policy
+        );
+    }
+
+    let duplicates = || {
+        target_summary
+            .duplicates
+            .iter()
+            .chain(&source_summary.duplicates)
+    };
+    if args.dry && args.output == OutputFormat::Json {
+        let to_archive = table_join
+            .to_archive
+            .iter()
+            .map(|image| output::PlannedImage {
+                path: image.basic.path.clone(),
+                destination: layout
+                    .place(image)
+                    .folder
+                    .join(image.basic.get_name())
+                    .to_string_lossy()
+                    .into_owned(),
+                size: image.basic.size,
+                raw: raw_of
+                    .get(image.basic.path.as_str())
+                    .map(|raw| raw.to_string()),
+            })
+            .collect::<Vec<_>>();
+        return output::print_json(&output::Plan {
+            source: source_dir.display().to_string(),
+            bytes: to_archive.iter().map(|image| image.size).sum(),
+            to_archive,
+            culled: culled
+                .iter()
+                .map(|image| image.basic.path.clone())
+                .collect(),
+            paired_jpegs: paired_jpegs
+                .iter()
+                .map(|image| image.basic.path.clone())
+                .collect(),
+            duplicates: duplicates().cloned().collect(),
+        });
+    }
+    if args.dry {
+        eprintln!("Images to archive:");
+        for image in &table_join.to_archive {
+            match raw_of.get(image.basic.path.as_str()) {
+                Some(_) => eprintln!("    with {}", image.basic.path),
+                None => eprintln!("  {}", image.basic.path),
+            }
+        }
+        if !culled.is_empty() {
+            eprintln!("Images skipped by the {} cull policy:", args.cull);
+            for image in &culled {
+                eprintln!("  {}", image.basic.path);
+            }
+        }
+        if !paired_jpegs.is_empty() {
+            eprintln!("JPEGs skipped alongside their RAW files:");
+            for image in &paired_jpegs {
+                eprintln!("  {}", image.basic.path);
+            }
+        }
+
+        return Ok(());
+    }
+
+    let bytes = table_join
+        .to_archive
+        .iter()
+        .map(|image| image.basic.size)
+        .sum();
+    for dir in [target_dir]
+        .into_iter()
+        .chain(args.mirrors.iter().map(PathBuf::as_path))
+    {
+        check_free_space(dir, bytes, args.force)?;
+    }
+
+    if args.guided {
+        let warnings = duplicates().count() + truncated + recycled.len();
+        if !confirm_import(&table_join.to_archive, target_dir, &layout, warnings)? {
+            info!("Nothing was archived");
+            return Ok(());
+        }
+    }
+
+    if !culled.is_empty() {
+        info!(
+            "Skipping {} images marked in camera, by the {} cull policy:",
+            culled.len(),
+            args.cull
+        );
+        for image in &culled {
+            info!(
+                "  {} (rated {})",
+                image.basic.path,
+                image.rating.unwrap_or(0)
+            );
+            failures::record_left_behind(
+                "culled in camera",
+                format_args!("{} (rated {})", image.basic.path, image.rating.unwrap_or(0)),
+            );
+        }
+    }
+
+    if !paired_jpegs.is_empty() {
+        info!(
+            "Skipping {} JPEGs recorded alongside their RAW files",
+            paired_jpegs.len()
+        );
+        for image in &paired_jpegs {
+            failures::record_left_behind("JPEGs recorded alongside RAW files", &image.basic.path);
+        }
+    }
+
+    let folders = prepare_folders(&table_join.to_archive, target_dir, &layout, safety(args))?;
+    info!(
+        "Archiving {} images ({}) into {} folders",
+        table_join.to_archive.len(),
+        format_size(bytes),
+        folders
+    );
+
+    // Claim the images so a concurrent run importing into the same database
+    // doesn't try to archive them as well
+    let session = progress.session().to_owned();
+    let pending = table_join.to_archive.len();
+    let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let to_archive = claim_images(&trans, card, &session, table_join.to_archive)?;
+    trans.commit()?;
+    if to_archive.len() < pending {
+        info!(
+            "Skipping {} images claimed by another running archive",
+            pending - to_archive.len()
+        );
+    }
+
+    // Each set is archived by one thread, so its JPEGs are only copied once
+    // its RAW is
+    let units = group_sets(&to_archive, &raw_of);
+
+    // Mirrors are told apart by where they are mounted, like for `verify`
+    let mirrors = args
+        .mirrors
+        .iter()
+        .map(|mirror| {
+            mirror
+                .canonicalize()
+                .with_context(|| format!("Failed to open mirror {}", mirror.display()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Copies on the other sources are only used if they match the index
+    let checksums = match sources.len() {
+        1 => None,
+        _ => Some(db::get_camera_checksums(conn, card)?),
+    };
+    let archiver = Archiver {
+        checksums: checksums.as_ref(),
+        ..archiver(conn, args, target_dir, &layout)?
+    };
+    progress.stage(conn, "archiving", Some(to_archive.len() as u64));
+    let archive_res = wrap_multi(multi, |pb| {
+        pb.set_length(to_archive.len() as u64);
+
+        pb.set_message("Archiving images");
+
+        // Copies run on several threads, the database is only touched from
+        // this one. With `--strict`, images after the first failed copy are
+        // left as they are, without a result.
+        let failed = AtomicBool::new(false);
+        let results = pool::map_ordered(
+            &units,
+            args.jobs.unwrap_or(1),
+            |unit| {
+                let mut results: Vec<Option<anyhow::Result<_>>> = Vec::with_capacity(unit.len());
+                for image in &to_archive[unit.clone()] {
+                    if args.strict && failed.load(Ordering::Relaxed) {
+                        results.push(None);
+                        continue;
+                    }
+                    if let Some(Some(Err(_))) = results.first() {
+                        results.push(Some(Err(anyhow::anyhow!(
+                            "{} was not archived because its RAW file failed",
+                            image.basic.path
+                        ))));
+                        continue;
+                    }
+                    let res = archiver.archive(image, sources);
+                    if res
+                        .as_ref()
+                        .is_err_and(|err| !archiver.skips_collision(err.kind()))
+                    {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    results.push(Some(res.map_err(RawdbError::into_inner).map(|copy| {
+                        let mirrored = mirrors
+                            .iter()
+                            .map(|mirror| {
+                                images::mirror_copy(
+                                    &copy,
+                                    target_dir,
+                                    mirror,
+                                    algorithm,
+                                    archiver.copy_options,
+                                )
+                            })
+                            .collect::<Vec<_>>();
+                        (copy, mirrored)
+                    })));
+                }
+                results
+            },
+            |unit, results| {
+                for (image, res) in to_archive[unit.clone()].iter().zip(results) {
+                    pb.inc(1);
+                    let Some(res) = res else {
+                        continue;
+                    };
+                    progress.advance(conn, &image.basic.path, image.basic.size);
+                    match res {
+                        Ok((_, mirrored)) => {
+                            for err in mirrored.iter().filter_map(|res| res.as_ref().err()) {
+                                error!(target: FailureKind::classify(err).label(), "{}", err);
+                            }
+                        }
+                        Err(err) if archiver.skips_collision(FailureKind::classify(err)) => {
+                            warn!(target: FailureKind::Collision.label(), "{}, skipping it", err);
+                        }
+                        Err(err) => error!(target: FailureKind::classify(err).label(), "{}", err),
+                    }
+                }
+            },
+        );
+        let mut failures = Vec::new();
+        let mut success = Vec::new();
+        // Paths written to each mirror, in the order of `mirrors`
+        let mut mirrored = vec![Vec::new(); mirrors.len()];
+        let mut mirror_failures = 0;
+        // Card files that are safe to delete with `--move`
+        let mut movable = Vec::new();
+        let mut stopped = 0;
+        for (image, res) in to_archive.into_iter().zip(results.into_iter().flatten()) {
+            match res {
+                None => stopped += 1,
+                Some(Ok((copy, mirror_res))) => {
+                    let mut all_mirrored = true;
+                    for (paths, res) in mirrored.iter_mut().zip(mirror_res) {
+                        match res {
+                            Ok(()) => paths.push(copy.path.clone()),
+                            Err(_) => {
+                                mirror_failures += 1;
+                                all_mirrored = false;
+                            }
+                        }
+                    }
+                    if all_mirrored {
+                        movable.push((copy.source.clone(), image.basic.size));
+                    }
+                    success.push((image, copy));
+                }
+                Some(Err(err)) if archiver.skips_collision(FailureKind::classify(&err)) => {}
+                Some(Err(err)) => failures.push((image, err)),
+            }
+        }
+        logging::summarize_repeated();
+        if stopped > 0 {
+            warn!(
+                "Stopped after the first failure, {} images were not archived",
+                stopped
+            );
+        }
+
+        let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        archiver.record(&trans, card, card_id.as_deref(), &success)?;
+        set_images_as_archived(&trans, card, backfill.iter().map(|u| &u.image))?;
+        record_provenance(
+            &trans,
+            card_id.as_deref(),
+            backfill.iter().map(|unmarked| ProvenanceEntry {
+                image: &unmarked.image,
+                disk_path: &unmarked.disk_path,
+                checksum: None,
+                utc_offset: None,
+                dst_policy: None,
+            }),
+        )?;
+        for (image, err) in &failures {
+            record_failure(&trans, Camera, &image.basic, err)?;
+        }
+        let copied = success
+            .iter()
+            .map(|(image, copy)| (image.basic.path.as_str(), copy.path.as_str()))
+            .collect::<HashMap<_, _>>();
+        for set in &bracket_sets {
+            let Some(raw) = copied.get(set.raw.as_str()) else {
+                continue;
+            };
+            let members = set
+                .members
+                .iter()
+                .filter_map(|member| copied.get(member.as_str()).copied())
+                .collect::<Vec<_>>();
+            db::record_bracket(&trans, raw, &members)?;
+        }
+        for (image, video) in &live_photos {
+            if let (Some(image), Some(video)) =
+                (copied.get(image.as_str()), copied.get(video.as_str()))
+            {
+                db::record_live_photo(&trans, image, video)?;
+            }
+        }
+        for burst in &bursts {
+            let frames = burst
+                .paths
+                .iter()
+                .filter_map(|path| copied.get(path.as_str()).copied())
+                .collect::<Vec<_>>();
+            if frames.len() > 1 {
+                db::record_burst(&trans, &frames)?;
+            }
+        }
+        // A mirror copy read back after writing counts as verified
+        let finished_at = chrono::Utc::now().naive_utc();
+        for (mirror, paths) in mirrors.iter().zip(&mirrored) {
+            db::record_verifications(
+                &trans,
+                &mirror.to_string_lossy(),
+                paths.iter().map(String::as_str),
+                finished_at,
+            )?;
+        }
+        record_run(
+            &trans,
+            &RunStats {
+                started_at,
+                finished_at: chrono::Utc::now().naive_utc(),
+                command: "archive".to_string(),
+                files: success.len() as u64,
+                bytes: success.iter().map(|(image, _)| image.basic.size).sum(),
+                failures: (failures.len() + mirror_failures) as u64,
+            },
+        )?;
+        trans.commit()?;
+        info!("Archived {} images", success.len());
+        let sidecars = success.iter().map(|(_, copy)| copy.sidecars).sum::<usize>();
+        if sidecars > 0 {
+            info!("Copied {} sidecar files next to them", sidecars);
+        }
+        let geotagged = success
+            .iter()
+            .filter(|(_, copy)| copy.position.is_some())
+            .count();
+        if geotagged > 0 {
+            info!("Geotagged {} images from the GPX track", geotagged);
+        }
+        for (mirror, paths) in mirrors.iter().zip(&mirrored) {
+            info!("Mirrored {} images to {}", paths.len(), mirror.display());
+        }
+        // Only once the copies are committed as saved
+        if args.move_files {
+            let removed = remove_sources(&movable, archiver.copy_options.safety);
+            info!(
+                "Removed {} archived images from {}",
+                removed,
+                source_dir.display()
+            );
+        }
+        let summary = output::Summary {
+            source: source_dir.display().to_string(),
+            archived: success.len() as u64,
+            bytes: success.iter().map(|(image, _)| image.basic.size).sum(),
+            backfilled: backfill.len() as u64,
+            failures: failures
+                .iter()
+                .map(|(image, err)| output::FailedImage {
+                    path: image.basic.path.clone(),
+                    error: format!("{:#}", err),
+                })
+                .collect(),
+            mirror_failures: mirror_failures as u64,
+            duplicates: duplicates().cloned().collect(),
+        };
+        if args.output == OutputFormat::Json {
+            output::print_json(&summary)?;
+        }
+        summaries.push(summary);
+        if args.guided {
+            verify_copies(target_dir, &success, algorithm)?;
+        }
+        if !backfill.is_empty() {
+            info!(
+                "Marked {} previously archived images as saved",
+                backfill.len()
+            );
+        }
+        let mut problems = Vec::new();
+        if !failures.is_empty() {
+            problems.push(format!("{} images could not be archived", failures.len()));
+        }
+        if mirror_failures > 0 {
+            problems.push(format!(
+                "{} copies could not be written to a mirror",
+                mirror_failures
+            ));
+        }
+
+        Ok::<_, anyhow::Error>(problems)
+    });
+
+    release_claims(conn, &session)?;
+    let mut problems = archive_res?;
+    for (summary, label) in [(&target_summary, "target"), (&source_summary, "source")] {
+        if summary.failed > 0 {
+            problems.insert(
+                0,
+                format!(
+                    "{} images in the {} could not be indexed",
+                    summary.failed, label
+                ),
+            );
+        }
+    }
+
+    // The index still lists the files `--move` just removed
+    if !args.move_files {
+        report_retention(conn, card, args.retention_days)?;
+    }
+    if args.guided {
+        info!("Next steps:");
+        info!("  Eject the card at {}", source_dir.display());
+        info!("  Run `rawdb status` to review files that couldn't be archived");
+        info!("  Run `rawdb verify` now and then to catch bit rot in the archive");
+    }
+
+    check_partial(args, &problems)
+}
+
+/// Delete the card files of verified copies for `--move`, returning how many
+/// were removed. Files that changed size since they were copied are kept.
+fn remove_sources(sources: &[(PathBuf, u64)], safety: Safety) -> usize {
+    let mut removed = 0;
+    for (path, size) in sources {
+        let res = match fs::metadata(path) {
+            Ok(meta) if meta.len() != *size => Err(anyhow::anyhow!(
+                "{} changed since it was copied and was not removed",
+                path.display()
+            )),
+            Ok(_) => safety.remove_file(path),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        match res {
+            Ok(()) => removed += 1,
+            Err(err) => warn!(target: FailureKind::classify(&err).label(), "{:#}", err),
+        }
+    }
+    logging::summarize_repeated();
+    removed
+}
+
+/// Split images sorted by find_bracket_sets into the ranges of sets of a RAW
+/// file and the JPEGs following it, and of single images
+fn group_sets(images: &[ImageAdv], raw_of: &HashMap<&str, &str>) -> Vec<Range<usize>> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    for (idx, image) in images.iter().enumerate().skip(1) {
+        let first = images[start].basic.path.as_str();
+        if raw_of.get(image.basic.path.as_str()) != Some(&first) {
+            units.push(start..idx);
+            start = idx;
+        }
+    }
+    if start < images.len() {
+        units.push(start..images.len());
+    }
+    units
+}
+
+/// Folder of a camera image relative to the card, such as `DCIM/105CANON`
+fn card_folder_of(image: &ImageAdv) -> String {
+    Path::new(&image.basic.path)
+        .parent()
+        .map(|folder| folder.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// List the card folders with pending images and let the user choose which
+/// ones to archive
+fn pick_card_folders(to_archive: &[ImageAdv]) -> anyhow::Result<Vec<String>> {
+    let mut folders: BTreeMap<String, (usize, NaiveDateTime, NaiveDateTime)> = BTreeMap::new();
+    for image in to_archive {
+        folders
+            .entry(card_folder_of(image))
+            .and_modify(|(count, first, last)| {
+                *count += 1;
+                *first = (*first).min(image.date);
+                *last = (*last).max(image.date);
+            })
+            .or_insert((1, image.date, image.date));
+    }
+    if folders.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    eprintln!("Card folders with images to archive:");
+    let names = folders.keys().cloned().collect::<Vec<_>>();
+    for (i, (folder, (count, first, last))) in folders.iter().enumerate() {
+        eprintln!(
+            "  {}) {} - {} images, {} to {}",
+            i + 1,
+            folder,
+            count,
+            first.format("%Y-%m-%d"),
+            last.format("%Y-%m-%d")
+        );
+    }
+    eprint!("Folders to archive (e.g. 1 3): ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    let mut picked = Vec::new();
+    for choice in answer
+        .split([' ', ','])
+        .filter(|choice| !choice.trim().is_empty())
+    {
+        let index = choice
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|index| (1..=names.len()).contains(index))
+            .ok_or_else(|| anyhow::anyhow!("Invalid folder choice {:?}", choice.trim()))?;
+        picked.push(names[index - 1].clone());
+    }
+    if picked.is_empty() {
+        anyhow::bail!("No card folder was picked");
+    }
+
+    Ok(picked)
+}
+
+/// Fail before copying anything if `bytes` won't fit on the file system of
+/// `dir`, or only warn with `--force`
+fn check_free_space(dir: &Path, bytes: u64, force: bool) -> anyhow::Result<()> {
+    let Some(available) = images::available_space(dir) else {
+        return Ok(());
+    };
+    if available >= bytes {
+        return Ok(());
+    }
+    let message = format!(
+        "Not enough space on {}, {} needed but only {} free",
+        dir.display(),
+        format_size(bytes),
+        format_size(available)
+    );
+    if !force {
+        anyhow::bail!("{} (use --force to archive anyway)", message);
+    }
+    warn!("{}", message);
+    Ok(())
+}
+
+/// Show what a guided import is about to do and ask for confirmation
+fn confirm_import(
+    to_archive: &[ImageAdv],
+    target_dir: &Path,
+    layout: &Layout,
+    warnings: usize,
+) -> anyhow::Result<bool> {
+    let bytes = to_archive.iter().map(|image| image.basic.size).sum();
+    let folders = to_archive
+        .iter()
+        .map(|image| layout.place(image).folder)
+        .collect::<HashSet<_>>();
+    eprintln!("Import plan:");
+    eprintln!(
+        "  {} images ({}) into {} folders of {}",
+        to_archive.len(),
+        format_size(bytes),
+        folders.len(),
+        target_dir.display()
+    );
+    if warnings > 0 {
+        eprintln!(
+            "  {} possible duplicates or truncated files, see the warnings above",
+            warnings
+        );
+    }
+
+    if let Some(available) = images::available_space(target_dir) {
+        eprintln!("  {} free on the target", format_size(available));
+    }
+    if to_archive.is_empty() {
+        return Ok(true);
+    }
+
+    eprint!("Archive these images? [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Read back the copies of a guided import and check them against the
+/// checksums taken while copying
+fn verify_copies(
+    target_dir: &Path,
+    success: &[(ImageAdv, ArchivedCopy)],
+    algorithm: HashAlgorithm,
+) -> anyhow::Result<()> {
+    let mut corrupt = 0;
+    for (_, copy) in success {
+        let abs_path = target_dir.join(&copy.path);
+        let (checksum, _) = hash::hash_file_chunked(&abs_path, algorithm, None)
+            .with_context(|| format!("Failed to read {}", abs_path.display()))?;
+        if checksum != copy.checksum {
+            error!("{} doesn't match the copied data", copy.path);
+            corrupt += 1;
+        }
+    }
+    if corrupt > 0 {
+        anyhow::bail!("{} archived copies failed verification", corrupt);
+    }
+    info!("Verified all {} archived copies", success.len());
+
+    Ok(())
 }
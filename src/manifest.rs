@@ -0,0 +1,104 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use log::warn;
+
+use crate::{
+    dates::parse_date,
+    images::{ExifDetails, ImageAdv, ImageBasic},
+};
+
+/// Value recorded as the date source of images loaded from a manifest
+pub const MANIFEST_DATE_SOURCE: &str = "import manifest";
+
+/// A CSV field, unquoted if it was quoted
+fn unquote(field: &str) -> String {
+    let field = field.trim();
+    match field.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+        Some(field) => field.replace("\"\"", "\""),
+        None => field.to_owned(),
+    }
+}
+
+/// Read `path,size,date` rows of a manifest, with paths relative to
+/// `target_dir` or absolute within it. Rows of files that are missing or
+/// changed size are skipped, so the next scan indexes them normally.
+pub fn load_manifest(manifest: &Path, target_dir: &Path) -> anyhow::Result<Vec<ImageAdv>> {
+    let text = fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read manifest {}", manifest.display()))?;
+
+    let mut images = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Paths may contain commas, sizes and dates can't
+        let mut fields = line.rsplitn(3, ',');
+        let (date, size, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(date), Some(size), Some(path)) => (date, size, unquote(path)),
+            _ => anyhow::bail!(
+                "Invalid row {} of {} (Expected path,size,date)",
+                i + 1,
+                manifest.display()
+            ),
+        };
+        let (Ok(size), Some(date)) = (size.trim().parse::<u64>(), parse_date(&unquote(date)))
+        else {
+            // Allow a header row
+            if i == 0 {
+                continue;
+            }
+            anyhow::bail!(
+                "Invalid size or date in row {} of {}",
+                i + 1,
+                manifest.display()
+            );
+        };
+
+        let path = Path::new(&path);
+        let rel_path = match path.strip_prefix(target_dir) {
+            Ok(rel_path) => rel_path,
+            Err(_) if path.is_absolute() => anyhow::bail!(
+                "{} is not in the target {}",
+                path.display(),
+                target_dir.display()
+            ),
+            Err(_) => path,
+        };
+        let Some(rel_path) = rel_path.to_str().filter(|p| !p.is_empty()) else {
+            anyhow::bail!("Path {} is not utf8", path.display());
+        };
+
+        match fs::metadata(target_dir.join(rel_path)) {
+            Ok(meta) if meta.len() == size => {}
+            Ok(meta) => {
+                warn!(
+                    "{} is {} bytes, not {} as in the manifest, skipping it",
+                    rel_path,
+                    meta.len(),
+                    size
+                );
+                continue;
+            }
+            Err(err) => {
+                warn!("{} can't be read, skipping it: {}", rel_path, err);
+                continue;
+            }
+        }
+
+        images.push(ImageAdv {
+            basic: ImageBasic {
+                path: rel_path.to_owned(),
+                size,
+            },
+            date,
+            rating: None,
+            date_source: Some(MANIFEST_DATE_SOURCE.to_owned()),
+            camera: None,
+            utc_offset: None,
+            exif: ExifDetails::default(),
+        });
+    }
+
+    Ok(images)
+}
@@ -0,0 +1,257 @@
+//! The Exif tags of images, read by exiv2 through gexiv2 with the default
+//! `exiv2` feature. Builds without it and with the `pure-exif` feature read
+//! them with kamadak-exif instead, which needs no system libraries but
+//! doesn't read XMP, maker notes or CR3 files. Tags are named the way exiv2
+//! names them either way, such as `Exif.Photo.DateTimeOriginal`.
+
+#[cfg(not(any(feature = "exiv2", feature = "pure-exif")))]
+compile_error!("Enable the exiv2 or the pure-exif feature to read image metadata");
+
+#[cfg(feature = "exiv2")]
+pub use self::exiv2::{initialize, Metadata};
+#[cfg(all(feature = "pure-exif", not(feature = "exiv2")))]
+pub use self::pure::{initialize, Metadata};
+
+#[cfg(feature = "exiv2")]
+mod exiv2 {
+    use std::path::Path;
+
+    use crate::geotag::Position;
+
+    pub struct Metadata(rexiv2::Metadata);
+
+    impl Metadata {
+        pub fn new_from_path(path: &Path) -> anyhow::Result<Self> {
+            Ok(Metadata(rexiv2::Metadata::new_from_path(path)?))
+        }
+
+        /// Metadata of an Exif TIFF structure, without an image around it
+        pub fn new_from_buffer(exif: &[u8]) -> anyhow::Result<Self> {
+            Ok(Metadata(rexiv2::Metadata::new_from_buffer(exif)?))
+        }
+
+        pub fn has_exif(&self) -> bool {
+            self.0.has_exif()
+        }
+
+        /// The value of `tag` as exiv2 writes it, like `28/10` for a rational
+        pub fn tag_string(&self, tag: &str) -> Option<String> {
+            self.0.get_tag_string(tag).ok()
+        }
+
+        pub fn tag_numeric(&self, tag: &str) -> Option<i32> {
+            self.0.has_tag(tag).then(|| self.0.get_tag_numeric(tag))
+        }
+
+        pub fn gps_position(&self) -> Option<Position> {
+            self.0.get_gps_info().map(|gps| Position {
+                latitude: gps.latitude,
+                longitude: gps.longitude,
+                altitude: self
+                    .0
+                    .has_tag("Exif.GPSInfo.GPSAltitude")
+                    .then_some(gps.altitude),
+            })
+        }
+    }
+
+    /// Set up gexiv2 before it's used from several threads
+    pub fn initialize() -> anyhow::Result<()> {
+        Ok(rexiv2::initialize()?)
+    }
+}
+
+#[cfg(all(feature = "pure-exif", not(feature = "exiv2")))]
+mod pure {
+    use std::{
+        fs::File,
+        io::{BufReader, Cursor, Read, Seek, SeekFrom},
+        path::Path,
+    };
+
+    use anyhow::Context as _;
+    use exif::{Context, Exif, Field, In, Reader, Tag, Value};
+
+    use crate::geotag::Position;
+
+    /// The tags the archive reads, by their exiv2 names. exiv2 knows the
+    /// rating, which isn't part of the Exif standard, and the EXIF 2.2 name of
+    /// the ISO tag.
+    const TAGS: &[(&str, Tag)] = &[
+        ("Exif.Image.Make", Tag::Make),
+        ("Exif.Image.Model", Tag::Model),
+        ("Exif.Image.DateTime", Tag::DateTime),
+        ("Exif.Image.Rating", Tag(Context::Tiff, 0x4746)),
+        ("Exif.Photo.DateTimeOriginal", Tag::DateTimeOriginal),
+        ("Exif.Photo.SubSecTimeOriginal", Tag::SubSecTimeOriginal),
+        ("Exif.Photo.OffsetTimeOriginal", Tag::OffsetTimeOriginal),
+        ("Exif.Photo.DateTimeDigitized", Tag::DateTimeDigitized),
+        ("Exif.Photo.SubSecTimeDigitized", Tag::SubSecTimeDigitized),
+        ("Exif.Photo.OffsetTimeDigitized", Tag::OffsetTimeDigitized),
+        ("Exif.Photo.SubSecTime", Tag::SubSecTime),
+        ("Exif.Photo.OffsetTime", Tag::OffsetTime),
+        ("Exif.Photo.BodySerialNumber", Tag::BodySerialNumber),
+        ("Exif.Photo.LensModel", Tag::LensModel),
+        ("Exif.Photo.ExposureTime", Tag::ExposureTime),
+        ("Exif.Photo.FNumber", Tag::FNumber),
+        (
+            "Exif.Photo.PhotographicSensitivity",
+            Tag::PhotographicSensitivity,
+        ),
+        ("Exif.Photo.ISOSpeedRatings", Tag::PhotographicSensitivity),
+        ("Exif.GPSInfo.GPSAltitude", Tag::GPSAltitude),
+    ];
+
+    /// Fujifilm RAF files start with this, and embed a JPEG holding the Exif
+    const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW";
+
+    pub struct Metadata {
+        exif: Option<Exif>,
+    }
+
+    impl Metadata {
+        pub fn new_from_path(path: &Path) -> anyhow::Result<Self> {
+            let mut file = BufReader::new(
+                File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+            );
+            let mut header = [0; 92];
+            let header_len = file.read(&mut header)?;
+            file.seek(SeekFrom::Start(0))?;
+
+            let exif = if header[..header_len].starts_with(RAF_MAGIC) {
+                // Offset and length of the JPEG, big endian
+                let offset = u32::from_be_bytes(header[84..88].try_into()?);
+                let len = u32::from_be_bytes(header[88..92].try_into()?);
+                let mut jpeg = Vec::new();
+                file.seek(SeekFrom::Start(offset.into()))?;
+                file.take(len.into()).read_to_end(&mut jpeg)?;
+                Reader::new().read_from_container(&mut Cursor::new(jpeg))
+            } else if let Some(magic) = tiff_variant(&header[..header_len]) {
+                // Olympus and Panasonic RAWs are TIFF files with their own
+                // magic number
+                let mut tiff = Vec::new();
+                file.read_to_end(&mut tiff)?;
+                tiff[2..4].copy_from_slice(&magic);
+                Reader::new().read_raw(tiff)
+            } else {
+                Reader::new().read_from_container(&mut file)
+            };
+            Self::from_result(exif)
+        }
+
+        /// Metadata of an Exif TIFF structure, without an image around it
+        pub fn new_from_buffer(exif: &[u8]) -> anyhow::Result<Self> {
+            Self::from_result(Reader::new().read_raw(exif.to_vec()))
+        }
+
+        fn from_result(exif: Result<Exif, exif::Error>) -> anyhow::Result<Self> {
+            match exif {
+                Ok(exif) => Ok(Metadata { exif: Some(exif) }),
+                Err(exif::Error::NotFound(_)) => Ok(Metadata { exif: None }),
+                Err(err) => Err(err.into()),
+            }
+        }
+
+        fn field(&self, tag: &str) -> Option<&Field> {
+            let (_, tag) = TAGS.iter().find(|(name, _)| *name == tag)?;
+            self.exif.as_ref()?.get_field(*tag, In::PRIMARY)
+        }
+
+        pub fn has_exif(&self) -> bool {
+            self.exif
+                .as_ref()
+                .is_some_and(|exif| exif.fields().len() > 0)
+        }
+
+        /// The value of `tag` as exiv2 writes it, like `28/10` for a rational
+        pub fn tag_string(&self, tag: &str) -> Option<String> {
+            match &self.field(tag)?.value {
+                Value::Ascii(texts) => {
+                    let text = texts.first()?;
+                    let text = text.split(|b| *b == 0).next().unwrap_or_default();
+                    Some(String::from_utf8_lossy(text).into_owned())
+                }
+                Value::Rational(values) => values
+                    .first()
+                    .map(|value| format!("{}/{}", value.num, value.denom)),
+                Value::SRational(values) => values
+                    .first()
+                    .map(|value| format!("{}/{}", value.num, value.denom)),
+                value => value_numeric(value).map(|value| value.to_string()),
+            }
+        }
+
+        pub fn tag_numeric(&self, tag: &str) -> Option<i32> {
+            value_numeric(&self.field(tag)?.value)
+        }
+
+        pub fn gps_position(&self) -> Option<Position> {
+            let exif = self.exif.as_ref()?;
+            let coordinate = |tag: Tag, ref_tag: Tag, negative: u8| {
+                let Value::Rational(parts) = &exif.get_field(tag, In::PRIMARY)?.value else {
+                    return None;
+                };
+                let degrees = parts
+                    .iter()
+                    .zip([1.0, 60.0, 3600.0])
+                    .map(|(part, scale)| part.to_f64() / scale)
+                    .sum::<f64>();
+                let hemisphere = match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+                    Value::Ascii(texts) => texts.first()?.first().copied(),
+                    _ => None,
+                };
+                Some(if hemisphere == Some(negative) {
+                    -degrees
+                } else {
+                    degrees
+                })
+            };
+            let altitude = match exif.get_field(Tag::GPSAltitude, In::PRIMARY) {
+                Some(Field {
+                    value: Value::Rational(values),
+                    ..
+                }) => values.first().map(|value| {
+                    // A reference of 1 is below sea level
+                    let below = exif
+                        .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                        .and_then(|field| field.value.get_uint(0))
+                        == Some(1);
+                    if below {
+                        -value.to_f64()
+                    } else {
+                        value.to_f64()
+                    }
+                }),
+                _ => None,
+            };
+            Some(Position {
+                latitude: coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?,
+                longitude: coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?,
+                altitude,
+            })
+        }
+    }
+
+    fn value_numeric(value: &Value) -> Option<i32> {
+        match value {
+            Value::SByte(values) => values.first().map(|value| (*value).into()),
+            Value::SShort(values) => values.first().map(|value| (*value).into()),
+            Value::SLong(values) => values.first().copied(),
+            value => value.get_uint(0).and_then(|value| value.try_into().ok()),
+        }
+    }
+
+    /// The TIFF magic number to read a RAW with a variant of TIFF's as TIFF
+    fn tiff_variant(header: &[u8]) -> Option<[u8; 2]> {
+        match header.get(..4)? {
+            b"IIRO" | b"IIRS" | b"IIU\0" => Some([42, 0]),
+            b"MMOR" => Some([0, 42]),
+            _ => None,
+        }
+    }
+
+    /// kamadak-exif is safe to use from several threads as it is
+    pub fn initialize() -> anyhow::Result<()> {
+        Ok(())
+    }
+}
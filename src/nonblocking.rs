@@ -0,0 +1,76 @@
+//! Async variants of [`load_images`](images::load_images) and
+//! [`Archiver::archive`], enabled by the `async` feature. The work runs on
+//! tokio's blocking pool, so reads, hashing and writes of several images
+//! overlap without tying up the async runtime.
+
+use std::{path::PathBuf, sync::Arc};
+
+use tokio::{sync::Semaphore, task};
+
+use crate::{
+    archiver::Archiver,
+    error,
+    images::{self, ArchivedCopy, FileFilter, ImageAdv, ImageExt},
+};
+
+/// Every image below `dir`, like [`images::load_images`]
+pub async fn load_images<I>(dir: PathBuf, filter: FileFilter) -> anyhow::Result<Vec<I>>
+where
+    I: ImageExt + Send + 'static,
+{
+    task::spawn_blocking(move || images::load_images(&dir, &filter).collect()).await?
+}
+
+/// Copy `image` from the first of `sources` into the target, like
+/// [`Archiver::archive`]. The archiver is shared with the blocking pool, so
+/// everything it borrows has to live for the whole program.
+pub async fn archive_image(
+    archiver: Arc<Archiver<'static>>,
+    image: ImageAdv,
+    sources: Vec<PathBuf>,
+) -> error::Result<ArchivedCopy> {
+    task::spawn_blocking(move || {
+        let sources = sources.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+        archiver.archive(&image, &sources)
+    })
+    .await
+    .map_err(anyhow::Error::from)?
+}
+
+/// Copy `images` into the target like [`archive_image`], at most `jobs` at a
+/// time, with the result of each in the order of `images`
+pub async fn archive_images(
+    archiver: Arc<Archiver<'static>>,
+    images: Vec<ImageAdv>,
+    sources: Vec<PathBuf>,
+    jobs: usize,
+) -> Vec<(ImageAdv, error::Result<ArchivedCopy>)> {
+    let slots = Arc::new(Semaphore::new(jobs.max(1)));
+    let sources = Arc::new(sources);
+    let mut handles = Vec::with_capacity(images.len());
+    for image in images {
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let (archiver, sources) = (archiver.clone(), sources.clone());
+        let copied = image.clone();
+        let handle = task::spawn_blocking(move || {
+            let _slot = slot;
+            let sources = sources.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+            archiver.archive(&copied, &sources)
+        });
+        handles.push((image, handle));
+    }
+
+    let mut results = Vec::with_capacity(handles.len());
+    for (image, handle) in handles {
+        let res = match handle.await {
+            Ok(res) => res,
+            Err(err) => Err(anyhow::Error::from(err).into()),
+        };
+        results.push((image, res));
+    }
+    results
+}
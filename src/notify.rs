@@ -0,0 +1,62 @@
+//! Desktop notifications for `--notify`, shown through libnotify's
+//! `notify-send` on Linux, `osascript` on macOS and the WinRT toast API from
+//! PowerShell on Windows. A notification that can't be shown is only logged,
+//! it never fails the run.
+
+use std::process::Command;
+
+use log::{debug, warn};
+
+/// Show a notification titled `summary`, marked as urgent if `error` is set
+pub fn notify(summary: &str, body: &str, error: bool) {
+    let mut command = command(summary, body, error);
+    debug!("Running {:?}", command);
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Showing a notification failed with {}", status),
+        Err(err) => warn!("Failed to show a notification: {}", err),
+    }
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn command(summary: &str, body: &str, error: bool) -> Command {
+    let mut command = Command::new("notify-send");
+    command
+        .args(["--app-name", "rawdb", "--urgency"])
+        .arg(if error { "critical" } else { "normal" })
+        .args([summary, body]);
+    command
+}
+
+#[cfg(target_os = "macos")]
+fn command(summary: &str, body: &str, _error: bool) -> Command {
+    // AppleScript strings escape quotes and backslashes with a backslash
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut command = Command::new("osascript");
+    command.arg("-e").arg(format!(
+        "display notification {} with title \"rawdb\" subtitle {}",
+        quote(body),
+        quote(summary)
+    ));
+    command
+}
+
+#[cfg(windows)]
+fn command(summary: &str, body: &str, _error: bool) -> Command {
+    // PowerShell strings in single quotes only escape single quotes
+    let quote = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    let script = format!(
+        "\
+[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] > $null
+$template = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02)
+$texts = $template.GetElementsByTagName('text')
+$texts.Item(0).AppendChild($template.CreateTextNode({})) > $null
+$texts.Item(1).AppendChild($template.CreateTextNode({})) > $null
+[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('rawdb').Show([Windows.UI.Notifications.ToastNotification]::new($template))",
+        quote(summary),
+        quote(body)
+    );
+    let mut command = Command::new("powershell");
+    command.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+    command
+}
@@ -0,0 +1,105 @@
+use std::{fmt, str::FromStr};
+
+use serde::Serialize;
+
+/// How archive results are written to stdout
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Only log messages on stderr
+    #[default]
+    Text,
+    /// One JSON object per archived source on stdout, besides the log
+    Json,
+}
+
+impl OutputFormat {
+    pub fn label(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Json => "json",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => anyhow::bail!("Unknown output format {:?} (Expected text or json)", s),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Files found more than once under the same name and date
+#[derive(Serialize, Clone)]
+pub struct Duplicate {
+    /// `target` or `source`
+    pub found_in: &'static str,
+    pub name: String,
+    /// `identical`, `unreadable` if some couldn't be hashed, or `different`
+    /// when only the name and size match
+    pub contents: &'static str,
+    pub paths: Vec<String>,
+    /// The paths indexed under `--duplicates`
+    pub indexed: Vec<String>,
+}
+
+/// An image a dry run would archive
+#[derive(Serialize)]
+pub struct PlannedImage {
+    /// Relative to the source
+    pub path: String,
+    /// Relative to the target
+    pub destination: String,
+    pub size: u64,
+    /// The RAW file a JPEG was recorded with, archived along with it
+    pub raw: Option<String>,
+}
+
+/// What a dry run would do
+#[derive(Serialize)]
+pub struct Plan {
+    pub source: String,
+    pub to_archive: Vec<PlannedImage>,
+    pub bytes: u64,
+    /// Images skipped by the cull policy
+    pub culled: Vec<String>,
+    /// JPEGs skipped by `--skip-paired-jpegs`
+    pub paired_jpegs: Vec<String>,
+    pub duplicates: Vec<Duplicate>,
+}
+
+#[derive(Serialize)]
+pub struct FailedImage {
+    pub path: String,
+    pub error: String,
+}
+
+/// The outcome of archiving a source
+#[derive(Serialize)]
+pub struct Summary {
+    pub source: String,
+    pub archived: u64,
+    pub bytes: u64,
+    /// Images already in the archive that were marked as saved
+    pub backfilled: u64,
+    pub failures: Vec<FailedImage>,
+    /// Copies that could not be written to a mirror
+    pub mirror_failures: u64,
+    pub duplicates: Vec<Duplicate>,
+}
+
+/// Write `value` to stdout as a single line of JSON
+pub fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
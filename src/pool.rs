@@ -0,0 +1,49 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+/// Run `work` on every item on up to `jobs` threads. `done` is called on the
+/// calling thread as each item finishes, in whatever order they finish. The
+/// results keep the order of `items`.
+pub fn map_ordered<T, R, W, D>(items: &[T], jobs: usize, work: W, mut done: D) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    W: Fn(&T) -> R + Sync,
+    D: FnMut(&T, &R),
+{
+    let jobs = jobs.clamp(1, items.len().max(1));
+    let next = AtomicUsize::new(0);
+    let mut results = Vec::with_capacity(items.len());
+    results.resize_with(items.len(), || None);
+
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..jobs {
+            let (tx, next, work) = (tx.clone(), &next, &work);
+            scope.spawn(move || loop {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(idx) else {
+                    break;
+                };
+                if tx.send((idx, work(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+        for (idx, res) in rx {
+            done(&items[idx], &res);
+            results[idx] = Some(res);
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|res| res.expect("Every item is processed"))
+        .collect()
+}
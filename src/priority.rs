@@ -0,0 +1,55 @@
+use log::debug;
+
+/// Niceness applied by `--background`, the same default as `nice(1)`
+#[cfg(unix)]
+const BACKGROUND_NICENESS: libc::c_int = 10;
+
+/// Lower the CPU and IO priority of this process, so long archive runs
+/// don't make the rest of the system unresponsive
+#[cfg(unix)]
+pub fn lower_priority() -> anyhow::Result<()> {
+    // SAFETY: setpriority has no memory safety requirements
+    let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, BACKGROUND_NICENESS) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    debug!("Set niceness to {}", BACKGROUND_NICENESS);
+
+    lower_io_priority()
+}
+
+#[cfg(target_os = "linux")]
+fn lower_io_priority() -> anyhow::Result<()> {
+    // From linux/ioprio.h, which libc doesn't expose
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    // SAFETY: ioprio_set only takes integer arguments
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    debug!("Set IO scheduling class to idle");
+
+    Ok(())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn lower_io_priority() -> anyhow::Result<()> {
+    // Other unixes schedule IO by CPU priority, which is already lowered
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn lower_priority() -> anyhow::Result<()> {
+    log::warn!("--background is not supported on this platform, ignoring");
+    Ok(())
+}
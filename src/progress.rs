@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+use log::debug;
+use rusqlite::Connection;
+
+use crate::db::{self, RunProgress};
+
+/// Least time between two writes of the progress to the database
+const WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Publishes the progress of the current run to the database, so `rawdb
+/// status` can follow it from another process
+pub struct ProgressTracker {
+    progress: RunProgress,
+    last_write: Option<Instant>,
+}
+
+impl ProgressTracker {
+    pub fn start(conn: &Connection, session: &str, command: &str) -> Self {
+        let now = chrono::Utc::now().naive_utc();
+        let mut tracker = ProgressTracker {
+            progress: RunProgress {
+                session: session.to_owned(),
+                pid: std::process::id(),
+                command: command.to_owned(),
+                stage: "starting".to_owned(),
+                done: 0,
+                total: None,
+                bytes: 0,
+                current_file: None,
+                started_at: now,
+                stage_started_at: now,
+                updated_at: now,
+                finished_at: None,
+            },
+            last_write: None,
+        };
+        tracker.write(conn);
+        tracker
+    }
+
+    pub fn session(&self) -> &str {
+        &self.progress.session
+    }
+
+    /// Begin a new stage of the run, with `total` items if known
+    pub fn stage(&mut self, conn: &Connection, stage: &str, total: Option<u64>) {
+        self.progress.stage = stage.to_owned();
+        self.progress.done = 0;
+        self.progress.total = total;
+        self.progress.bytes = 0;
+        self.progress.current_file = None;
+        self.progress.stage_started_at = chrono::Utc::now().naive_utc();
+        self.write(conn);
+    }
+
+    /// Count an item of the current stage as done
+    pub fn advance(&mut self, conn: &Connection, current_file: &str, bytes: u64) {
+        self.progress.done += 1;
+        self.progress.bytes += bytes;
+        self.progress.current_file = Some(current_file.to_owned());
+        if self
+            .last_write
+            .is_none_or(|last| last.elapsed() >= WRITE_INTERVAL)
+        {
+            self.write(conn);
+        }
+    }
+
+    pub fn finish(mut self, conn: &Connection) {
+        self.progress.finished_at = Some(chrono::Utc::now().naive_utc());
+        self.write(conn);
+    }
+
+    fn write(&mut self, conn: &Connection) {
+        self.progress.updated_at = chrono::Utc::now().naive_utc();
+        self.last_write = Some(Instant::now());
+        // Progress is only informative, so never fail the run over it
+        if let Err(err) = db::update_progress(conn, &self.progress) {
+            debug!("Failed to record progress: {}", err);
+        }
+    }
+}
+
+/// Bytes per second processed in the current stage
+pub fn throughput(progress: &RunProgress) -> Option<f64> {
+    let elapsed =
+        (progress.updated_at - progress.stage_started_at).num_milliseconds() as f64 / 1000.0;
+    (elapsed > 0.0 && progress.bytes > 0).then(|| progress.bytes as f64 / elapsed)
+}
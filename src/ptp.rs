@@ -0,0 +1,217 @@
+//! Cameras and phones that don't mount as mass storage, read over PTP or MTP
+//! with the `gphoto2` tool of libgphoto2 in builds with the `ptp` feature.
+//! Their files are downloaded into a staging folder, which is then archived
+//! like a card.
+
+use std::path::{Path, PathBuf};
+#[cfg(feature = "ptp")]
+use std::{collections::BTreeMap, ffi::OsStr, fs, process::Command};
+
+use anyhow::bail;
+#[cfg(feature = "ptp")]
+use anyhow::Context;
+#[cfg(feature = "ptp")]
+use log::info;
+
+/// Sources starting with this are phones read over MTP, like
+/// `mtp://Pixel 7/DCIM/Camera`
+const MTP_SCHEME: &str = "mtp://";
+
+/// Folder of a phone downloaded unless the source names another one
+const MTP_FOLDER: &str = "DCIM";
+
+/// The device and folder of an `mtp://[device][/folder]` source, `None` for
+/// other sources. The device is a model or a port, the only one connected if
+/// not given, and the folder defaults to `DCIM`.
+pub fn parse_mtp(source: &Path) -> Option<(Option<&str>, &str)> {
+    let rest = source.to_str()?.strip_prefix(MTP_SCHEME)?;
+    let (device, folder) = rest.split_once('/').unwrap_or((rest, ""));
+    let folder = folder.trim_matches('/');
+    Some((
+        (!device.is_empty()).then_some(device),
+        if folder.is_empty() {
+            MTP_FOLDER
+        } else {
+            folder
+        },
+    ))
+}
+
+/// A camera or phone gphoto2 found
+#[derive(Debug)]
+pub struct Camera {
+    pub model: String,
+    /// Where it is connected, such as `usb:001,004`
+    pub port: String,
+}
+
+/// Run gphoto2 with `args` and return what it printed
+#[cfg(feature = "ptp")]
+fn gphoto2<S: AsRef<OsStr>>(args: &[S]) -> anyhow::Result<String> {
+    let output = Command::new("gphoto2")
+        .args(args)
+        .output()
+        .context("Failed to run gphoto2, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "gphoto2 failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The cameras connected over PTP
+#[cfg(feature = "ptp")]
+pub fn detect() -> anyhow::Result<Vec<Camera>> {
+    let output = gphoto2(&["--auto-detect"])?;
+    // A header and a line of dashes, then the model and port of each camera
+    Ok(output
+        .lines()
+        .skip_while(|line| !line.starts_with("---"))
+        .skip(1)
+        .filter_map(|line| {
+            let (model, port) = line.trim_end().rsplit_once(char::is_whitespace)?;
+            let model = model.trim();
+            (!model.is_empty()).then(|| Camera {
+                model: model.to_owned(),
+                port: port.to_owned(),
+            })
+        })
+        .collect())
+}
+
+/// The device at the port or with the model `device`, or the only one
+/// connected if not given
+#[cfg(feature = "ptp")]
+fn find_camera(device: Option<&str>) -> anyhow::Result<Camera> {
+    let mut cameras = detect()?;
+    if let Some(device) = device {
+        let found = cameras
+            .iter()
+            .position(|camera| camera.port == device || camera.model.eq_ignore_ascii_case(device));
+        return match found {
+            Some(idx) => Ok(cameras.swap_remove(idx)),
+            // Ports like usb:001,004 are used even if not detected
+            None if device.contains(':') => Ok(Camera {
+                model: "camera".to_owned(),
+                port: device.to_owned(),
+            }),
+            None => bail!("No camera or phone {} found", device),
+        };
+    }
+    match cameras.len() {
+        0 => bail!("No camera or phone found over PTP or MTP"),
+        1 => Ok(cameras.remove(0)),
+        _ => bail!(
+            "Several cameras found, pick one with --camera-port: {}",
+            cameras
+                .iter()
+                .map(|camera| format!("{} at {}", camera.model, camera.port))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// The folders of the camera holding files, with how many each holds
+#[cfg(feature = "ptp")]
+fn list_folders(camera: &Camera) -> anyhow::Result<BTreeMap<String, usize>> {
+    let output = gphoto2(&["--port", &camera.port, "--list-files"])?;
+    // Such as `There are 12 files in folder '/store_00010001/DCIM/100CANON'.`
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            let line = line.strip_prefix("There ")?;
+            let (count, folder) = line.split_once(" in folder '")?;
+            let count = count.split_whitespace().nth(1)?.parse().ok()?;
+            Some((folder.strip_suffix("'.")?.to_owned(), count))
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect())
+}
+
+/// Download the files of `device`, a port or a model, or of the only one
+/// connected, into a folder of `staging` named after it. Only the files
+/// below `only` are downloaded if given, such as the `DCIM` folder of a phone.
+/// Files downloaded before are kept, so only new ones are read from the
+/// device. Returns the folder, to archive like a card.
+#[cfg(feature = "ptp")]
+pub fn download(
+    device: Option<&str>,
+    only: Option<&str>,
+    staging: &Path,
+) -> anyhow::Result<PathBuf> {
+    let camera = find_camera(device)?;
+    let name = camera
+        .model
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let camera_dir = staging.join(name);
+
+    let mut folders = list_folders(&camera)?;
+    if let Some(only) = only {
+        folders.retain(|folder, _| {
+            let below = folder.trim_start_matches('/').split_once('/');
+            below.is_some_and(|(_, below)| Path::new(below).starts_with(only))
+        });
+    }
+    // Folders start with the storage they are on, which is left out unless
+    // the camera has several, like one per card slot
+    let store_of = |folder: &str| {
+        folder
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .map(str::to_owned)
+    };
+    let mut stores = folders
+        .keys()
+        .filter_map(|folder| store_of(folder))
+        .collect::<Vec<_>>();
+    stores.dedup();
+    info!(
+        "Downloading {} files of {} at {} into {}",
+        folders.values().sum::<usize>(),
+        camera.model,
+        camera.port,
+        camera_dir.display()
+    );
+    for folder in folders.keys() {
+        let relative = folder.trim_start_matches('/');
+        let relative = match relative.split_once('/') {
+            Some((_, below)) if stores.len() == 1 => below,
+            _ => relative,
+        };
+        let dest = camera_dir.join(relative);
+        fs::create_dir_all(&dest)
+            .with_context(|| format!("Failed to create {}", dest.display()))?;
+        gphoto2(&[
+            OsStr::new("--port"),
+            OsStr::new(&camera.port),
+            OsStr::new("--folder"),
+            OsStr::new(folder),
+            OsStr::new("--get-all-files"),
+            OsStr::new("--no-recurse"),
+            OsStr::new("--skip-existing"),
+            OsStr::new("--filename"),
+            dest.join("%f.%C").as_os_str(),
+        ])
+        .with_context(|| format!("Failed to download {} from {}", folder, camera.model))?;
+    }
+
+    Ok(camera_dir)
+}
+
+/// Builds without the `ptp` feature can't read cameras or phones
+#[cfg(not(feature = "ptp"))]
+pub fn download(
+    _device: Option<&str>,
+    _only: Option<&str>,
+    _staging: &Path,
+) -> anyhow::Result<PathBuf> {
+    bail!(
+        "Cameras and phones can't be read over PTP or MTP, rawdb was built without the ptp feature"
+    )
+}
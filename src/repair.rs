@@ -0,0 +1,126 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use rusqlite::Connection;
+
+use crate::{
+    db::{get_chunks, log_operation},
+    error,
+    failures::FailureKind,
+    hash::{hash_file_chunked, HashAlgorithm},
+    safety::Safety,
+    verify::{CorruptFile, Problem},
+};
+
+/// Restore a corrupt archived file from an intact copy in a mirror of the
+/// target directory. Returns a description of what was repaired.
+pub fn repair_file(
+    conn: &Connection,
+    file: &CorruptFile,
+    target_dir: &Path,
+    mirror_dir: &Path,
+    algorithm: HashAlgorithm,
+    safety: Safety,
+) -> anyhow::Result<String> {
+    let target_path = target_dir.join(&file.path);
+    let mirror_path = mirror_dir.join(&file.path);
+    let Some(expected) = &file.checksum else {
+        bail!("No checksum was recorded to check the mirror copy against");
+    };
+
+    let chunks = get_chunks(conn, &file.path)?;
+    let chunk_size = chunks.first().map(|chunk| chunk.length);
+
+    // Never spread corruption, the mirror has to match the recorded checksums
+    let mirror_len = fs::metadata(&mirror_path)
+        .with_context(|| format!("No copy found at {}", mirror_path.display()))?
+        .len();
+    if mirror_len != file.size {
+        bail!(
+            "Mirror copy {} has the wrong size ({} != {} bytes)",
+            mirror_path.display(),
+            mirror_len,
+            file.size
+        );
+    }
+    let (mirror_checksum, mirror_chunks) =
+        hash_file_chunked(&mirror_path, algorithm, chunk_size)
+            .with_context(|| format!("Failed to read {}", mirror_path.display()))?;
+    let chunks_match = chunks
+        .iter()
+        .zip(&mirror_chunks)
+        .all(|(chunk, mirror)| chunk.checksum == *mirror);
+    if mirror_checksum != *expected || !chunks_match {
+        return Err(error::mismatch(
+            FailureKind::Other,
+            format!("Mirror copy {} is corrupt as well", mirror_path.display()),
+        ));
+    }
+
+    let detail = match &file.problem {
+        Problem::ChecksumMismatch { ranges } if !chunks.is_empty() => {
+            safety.check_overwrite(&target_path)?;
+            // Only rewrite the damaged chunks in place
+            let mut mirror = File::open(&mirror_path)?;
+            let mut target = OpenOptions::new()
+                .write(true)
+                .open(&target_path)
+                .with_context(|| format!("Failed to open {}", target_path.display()))?;
+            let mut bytes = 0;
+            for range in ranges {
+                mirror.seek(SeekFrom::Start(range.start))?;
+                target.seek(SeekFrom::Start(range.start))?;
+                bytes += io::copy(
+                    &mut (&mut mirror).take(range.end - range.start),
+                    &mut target,
+                )?;
+            }
+            target.sync_all()?;
+
+            format!(
+                "Restored {} corrupt chunks ({} bytes) from {}",
+                ranges.len(),
+                bytes,
+                mirror_path.display()
+            )
+        }
+        _ => {
+            // Copy next to the target first, so a failed copy leaves the
+            // original untouched
+            let parent = target_path
+                .parent()
+                .expect("Archived images are always inside the target directory");
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+            let file_name = target_path
+                .file_name()
+                .expect("Archived images always have a file name");
+            let temp_path = parent.join(format!(".{}.repair", file_name.to_string_lossy()));
+            safety.check_overwrite(&temp_path)?;
+            safety.check_overwrite(&target_path)?;
+            fs::copy(&mirror_path, &temp_path)
+                .with_context(|| format!("Failed to copy {}", mirror_path.display()))?;
+            fs::rename(&temp_path, &target_path)
+                .with_context(|| format!("Failed to replace {}", target_path.display()))?;
+
+            format!("Restored whole file from {}", mirror_path.display())
+        }
+    };
+
+    let (checksum, _) = hash_file_chunked(&target_path, algorithm, None)
+        .with_context(|| format!("Failed to read back {}", target_path.display()))?;
+    if checksum != *expected {
+        return Err(error::mismatch(
+            FailureKind::IoError,
+            format!("{} is still corrupt after repair", target_path.display()),
+        ));
+    }
+
+    log_operation(conn, "repair", Some(&file.path), &detail)?;
+
+    Ok(detail)
+}
@@ -0,0 +1,161 @@
+use std::fmt::Write;
+
+use chrono::{Months, NaiveDate};
+use rusqlite::Connection;
+
+use crate::db::get_period_summary;
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Parse a month in the form `2024-07` into its first day
+pub fn parse_month(month: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("Invalid month {:?} (Expected YYYY-MM)", month))
+}
+
+/// Render an HTML summary of everything archived during the month starting at `month`
+pub fn monthly_report(conn: &Connection, month: NaiveDate) -> anyhow::Result<String> {
+    let start = month.and_time(Default::default());
+    let end = (month + Months::new(1)).and_time(Default::default());
+    let summary = get_period_summary(conn, start, end)?;
+    let title = format!("Archive report for {}", month.format("%B %Y"));
+
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>")?;
+    writeln!(html, "<html>")?;
+    writeln!(html, "<head>")?;
+    writeln!(html, "<meta charset=\"utf-8\">")?;
+    writeln!(html, "<title>{}</title>", title)?;
+    writeln!(html, "</head>")?;
+    writeln!(html, "<body>")?;
+    writeln!(html, "<h1>{}</h1>", title)?;
+
+    writeln!(html, "<h2>Summary</h2>")?;
+    writeln!(html, "<ul>")?;
+    writeln!(
+        html,
+        "<li>{} files archived ({})</li>",
+        summary.archived_files,
+        format_size(summary.archived_bytes)
+    )?;
+    writeln!(
+        html,
+        "<li>Archive grew from {} to {}</li>",
+        format_size(summary.previous_bytes),
+        format_size(summary.previous_bytes + summary.archived_bytes)
+    )?;
+    writeln!(html, "<li>{} files repaired</li>", summary.repairs)?;
+    writeln!(html, "</ul>")?;
+
+    writeln!(html, "<h2>Cameras</h2>")?;
+    writeln!(html, "<table>")?;
+    writeln!(
+        html,
+        "<tr><th>Camera</th><th>Card</th><th>Files</th><th>Size</th></tr>"
+    )?;
+    for camera in &summary.cameras {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape_html(camera.camera.as_deref().unwrap_or("unknown")),
+            escape_html(camera.card_id.as_deref().unwrap_or("unknown")),
+            camera.files,
+            format_size(camera.bytes)
+        )?;
+    }
+    writeln!(html, "</table>")?;
+
+    writeln!(html, "<h2>Runs</h2>")?;
+    writeln!(html, "<table>")?;
+    writeln!(
+        html,
+        "<tr><th>Started</th><th>Command</th><th>Files</th><th>Size</th><th>Failures</th><th>Duration</th></tr>"
+    )?;
+    for run in &summary.runs {
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}s</td></tr>",
+            run.started_at.format("%Y-%m-%d %H:%M"),
+            escape_html(&run.command),
+            run.files,
+            format_size(run.bytes),
+            run.failures,
+            (run.finished_at - run.started_at).num_seconds()
+        )?;
+    }
+    writeln!(html, "</table>")?;
+
+    writeln!(html, "<h2>Verification</h2>")?;
+    match summary.runs.iter().rfind(|run| run.command == "verify") {
+        Some(run) if run.failures == 0 => writeln!(
+            html,
+            "<p>Last verified {}: all {} files intact</p>",
+            run.finished_at.format("%Y-%m-%d"),
+            run.files
+        )?,
+        Some(run) => writeln!(
+            html,
+            "<p>Last verified {}: {} of {} files failed verification</p>",
+            run.finished_at.format("%Y-%m-%d"),
+            run.failures,
+            run.files
+        )?,
+        None => writeln!(html, "<p>The archive was not verified this month</p>")?,
+    }
+
+    writeln!(html, "<h2>Errors</h2>")?;
+    if summary.failures.is_empty() {
+        writeln!(html, "<p>No files are pending due to errors</p>")?;
+    } else {
+        writeln!(html, "<ul>")?;
+        for failure in &summary.failures {
+            writeln!(
+                html,
+                "<li>{} - {} files pending due to {}",
+                escape_html(&failure.source),
+                failure.count,
+                failure.kind.description()
+            )?;
+            writeln!(html, "<details><summary>Files</summary><ul>")?;
+            for file in summary
+                .failed_files
+                .iter()
+                .filter(|file| file.source == failure.source && file.kind == failure.kind)
+            {
+                writeln!(
+                    html,
+                    "<li>{} - {}</li>",
+                    escape_html(&file.path),
+                    escape_html(&file.message)
+                )?;
+            }
+            writeln!(html, "</ul></details></li>")?;
+        }
+        writeln!(html, "</ul>")?;
+    }
+
+    writeln!(html, "</body>")?;
+    writeln!(html, "</html>")?;
+
+    Ok(html)
+}
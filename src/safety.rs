@@ -0,0 +1,111 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use log::warn;
+use rusqlite::{
+    hooks::{AuthAction, AuthContext, Authorization},
+    Connection,
+};
+
+/// Folder inside the target that `--paranoid` moves files into instead of
+/// deleting them
+pub const QUARANTINE_DIR: &str = ".rawdb-quarantine";
+
+/// What a run may delete or overwrite
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Safety<'a> {
+    #[default]
+    Normal,
+    /// `--paranoid`: nothing is deleted or overwritten. Files that would be
+    /// removed are moved into the quarantine folder of the target, if the
+    /// command has one.
+    Paranoid { target_dir: Option<&'a Path> },
+}
+
+impl<'a> Safety<'a> {
+    /// Paranoid if `paranoid` is set, quarantining into `target_dir`
+    pub fn new(paranoid: bool, target_dir: Option<&'a Path>) -> Self {
+        if paranoid {
+            Safety::Paranoid { target_dir }
+        } else {
+            Safety::Normal
+        }
+    }
+
+    pub fn is_paranoid(self) -> bool {
+        matches!(self, Safety::Paranoid { .. })
+    }
+
+    /// Delete a file, or move it into quarantine in paranoid mode
+    pub fn remove_file(self, path: &Path) -> anyhow::Result<()> {
+        let Safety::Paranoid { target_dir } = self else {
+            return fs::remove_file(path)
+                .with_context(|| format!("Failed to remove {}", path.display()));
+        };
+        let Some(target_dir) = target_dir else {
+            bail!("Refusing to remove {} in paranoid mode", path.display());
+        };
+
+        let quarantine = target_dir.join(QUARANTINE_DIR);
+        fs::create_dir_all(&quarantine)
+            .with_context(|| format!("Failed to create directory {}", quarantine.display()))?;
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("{} has no file name", path.display()))?;
+        let moved = quarantine.join(format!(
+            "{}-{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.f"),
+            file_name.to_string_lossy()
+        ));
+        fs::rename(path, &moved)
+            .with_context(|| format!("Failed to move {} into quarantine", path.display()))?;
+        warn!("Moved {} to {}", path.display(), moved.display());
+
+        Ok(())
+    }
+
+    /// Fail if `path` exists and may not be overwritten
+    pub fn check_overwrite(self, path: &Path) -> anyhow::Result<()> {
+        if self.is_paranoid() && path.exists() {
+            bail!("Refusing to overwrite {} in paranoid mode", path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Temporary table marking a connection guarded by [`guard_connection`]
+const GUARD_TABLE: &str = "paranoid_guard";
+
+/// Make the database reject deleting rows or dropping anything outside of
+/// temporary and scratch tables. Schema rows are left to the drop checks.
+pub fn guard_connection(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TEMP TABLE IF NOT EXISTS {GUARD_TABLE} (id INTEGER)"
+    ))?;
+    conn.authorizer(Some(|ctx: AuthContext<'_>| {
+        let table = match ctx.action {
+            AuthAction::Delete { table_name }
+            | AuthAction::DropTable { table_name }
+            | AuthAction::DropIndex { table_name, .. } => table_name,
+            _ => return Authorization::Allow,
+        };
+        let scratch = table.starts_with("new_on_") || table.starts_with("sqlite_");
+        if ctx.database_name == Some("main") && !scratch {
+            Authorization::Deny
+        } else {
+            Authorization::Allow
+        }
+    }));
+    Ok(())
+}
+
+/// Whether `conn` was guarded by [`guard_connection`], so functions keep
+/// the rows they would otherwise delete
+pub fn is_guarded(conn: &Connection) -> bool {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM temp.sqlite_schema WHERE name = ?1)",
+        [GUARD_TABLE],
+        |row| row.get(0),
+    )
+    .unwrap_or(false)
+}
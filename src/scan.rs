@@ -0,0 +1,535 @@
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+use indicatif::ProgressBar;
+use log::{debug, error, info, warn};
+use rusqlite::{Connection, TransactionBehavior};
+
+use crate::{
+    clock::ClockOffset,
+    dates::{self, DateFallback, DateMapping},
+    db::{
+        self, add_to_table, clear_failures, get_directory_mtimes, get_exhausted_failures,
+        get_hash_algorithm, populate_new_table, prune_failures, record_checksums,
+        record_directory_mtimes, record_failure, update_table_get_new,
+        TableType::{self, *},
+    },
+    duplicates::{self, DuplicatePolicy},
+    error,
+    failures::{self, FailureKind},
+    hash::{self, HashAlgorithm},
+    images::{
+        self, load_card_images, load_images, load_listed_images, scan_changed_folders, ExifDetails,
+        FileFilter, ImageAdv, ImageBasic, ReadOptions,
+    },
+    layout::TimeZonePolicy,
+    output,
+};
+
+/// Files that failed this many times are only retried with `retry_failed`
+pub const MAX_FAILED_ATTEMPTS: u64 = 3;
+
+/// Incremental scans of the target miss files changed in place, so every
+/// folder is read again after this many days
+const FULL_SCAN_DAYS: u64 = 30;
+
+/// How [`Scanner::scan`] discovers the files in a directory
+pub enum Scan<'a> {
+    /// Walk the whole directory
+    Walk,
+    /// Only look at the listed files
+    Listed(&'a [PathBuf]),
+    /// Walk the directory, but reuse the index for folders unchanged since
+    /// the last scan unless `full` is set
+    Incremental { full: bool },
+}
+
+impl Scan<'_> {
+    /// Scan the target incrementally, unless a full scan is requested or due
+    pub fn target(conn: &Connection, full_scan: bool) -> error::Result<Self> {
+        let due = chrono::Utc::now().naive_utc() - chrono::Days::new(FULL_SCAN_DAYS);
+        let full = full_scan || db::get_last_full_scan(conn)?.is_none_or(|last| last < due);
+        Ok(Scan::Incremental { full })
+    }
+}
+
+/// What [`Scanner::scan`] found in a directory
+#[derive(Default)]
+pub struct IndexSummary {
+    pub found: usize,
+    pub indexed: usize,
+    pub bytes: u64,
+    pub failed: usize,
+    /// Entries skipped because they couldn't be read
+    pub denied: usize,
+    /// Names found more than once with the same date
+    pub duplicates: Vec<output::Duplicate>,
+    /// Paths of the files a [`Scan::Listed`] read, relative to the directory
+    pub listed: Option<Vec<String>>,
+}
+
+/// Rows for files new to a table, read from the files themselves
+pub struct NewRows {
+    pub images: Vec<ImageAdv>,
+    pub checksums: Vec<(String, Vec<u8>)>,
+    /// Archived files recognized at a new path, with their old entry
+    pub moved: Vec<(String, db::RemovedFile)>,
+    pub failures: Vec<(ImageBasic, anyhow::Error)>,
+}
+
+impl NewRows {
+    /// Add the rows to the table, along with their checksums and failures
+    pub fn record(&self, trans: &Connection, table: TableType, card: &str) -> error::Result<()> {
+        add_to_table(trans, table, card, &self.images)?;
+        record_checksums(
+            trans,
+            table,
+            card,
+            self.checksums
+                .iter()
+                .map(|(path, checksum)| (path.as_str(), checksum.as_slice())),
+        )?;
+        for (path, removed) in &self.moved {
+            debug!("{} was moved to {}", removed.path, path);
+            record_checksums(
+                trans,
+                Disk,
+                "",
+                [(path.as_str(), removed.checksum.as_slice())],
+            )?;
+            db::carry_over_removed(trans, removed, path)?;
+        }
+        for (image, err) in &self.failures {
+            record_failure(trans, table, image, err)?;
+        }
+        clear_failures(trans, table, self.images.iter().map(|i| &i.basic))?;
+
+        Ok(())
+    }
+}
+
+/// Indexes the files of a directory into a table of the catalog
+#[derive(Default)]
+pub struct Scanner<'a> {
+    /// Threads hashing and reading the metadata of new files, one per core
+    /// if unset
+    pub jobs: Option<usize>,
+    /// Dates of files without usable metadata
+    pub dates: Option<&'a DateMapping>,
+    /// How files without usable metadata or a mapped date are dated
+    pub date_fallback: DateFallback,
+    /// Zone of camera clocks, the creation times of videos and the
+    /// modification times used by the date fallback are shown in
+    pub timezone: TimeZonePolicy,
+    /// Corrections of cameras whose clock was off
+    pub clock_offsets: &'a [ClockOffset],
+    /// Binary probing videos other than MP4 and QuickTime, see
+    /// [`find_ffprobe`](crate::video::find_ffprobe)
+    pub ffprobe: Option<&'a Path>,
+    /// Which files and folders of the directory are indexed
+    pub filter: FileFilter,
+    /// Which copies of a name found more than once are indexed
+    pub duplicates: DuplicatePolicy,
+    /// Keep the temporary tables of the scan
+    pub leave: bool,
+    /// Retry files that failed [`MAX_FAILED_ATTEMPTS`] times
+    pub retry_failed: bool,
+    /// Fail when entries of the directory can't be read, instead of
+    /// skipping them
+    pub fail_on_access_errors: bool,
+    /// Fail at the first file that can't be indexed, before anything is
+    /// recorded, instead of recording it as a failure
+    pub strict: bool,
+    /// Id of the card a camera scan reads, empty for sources without one
+    pub card: &'a str,
+}
+
+impl Scanner<'_> {
+    /// Find the files of `dir` new to `table` and index them. `label` names
+    /// the directory in messages.
+    pub fn scan(
+        &self,
+        conn: &mut Connection,
+        table: TableType,
+        dir: &Path,
+        label: &'static str,
+        pb: ProgressBar,
+        scan: Scan,
+    ) -> error::Result<IndexSummary> {
+        // Read file structure on disk, find rows that don't exist in in on_disk
+        // An unknown file in the target is an error
+        let mut mtimes = None;
+        let mut patched = None;
+        let mut listed = None;
+        let mut denied = Vec::new();
+        let target_images = match scan {
+            Scan::Listed(files) => {
+                info!("Reading {} listed {} files", files.len(), label);
+                let images = load_listed_images(dir, files, &self.filter)?;
+                // Only the listed files are brought up to date
+                listed = Some(images.iter().map(|i| i.path.clone()).collect::<Vec<_>>());
+                images
+            }
+            Scan::Walk => {
+                info!("Scanning {} at {}", label, dir.display());
+                let found = if matches!(table, Camera) {
+                    let (media_dirs, found) = load_card_images::<ImageBasic>(dir, &self.filter);
+                    if !media_dirs.is_empty() {
+                        let media_dirs = media_dirs
+                            .iter()
+                            .filter_map(|media| media.strip_prefix(dir).ok())
+                            .map(|media| media.display().to_string())
+                            .collect::<Vec<_>>();
+                        info!(
+                            "  Only reading the camera folders {}",
+                            media_dirs.join(", ")
+                        );
+                    }
+                    Box::new(found) as Box<dyn Iterator<Item = _>>
+                } else {
+                    Box::new(load_images::<ImageBasic>(dir, &self.filter))
+                };
+                let mut images = Vec::new();
+                for res in found {
+                    match res {
+                        Ok(image) => images.push(image),
+                        Err(err) => match images::access_denied(&err) {
+                            Some(path) => denied.push(path),
+                            None => return Err(err.into()),
+                        },
+                    }
+                }
+                images
+            }
+            Scan::Incremental { full } => {
+                if full {
+                    info!("Scanning all of {} at {}", label, dir.display());
+                } else {
+                    info!("Scanning changed folders of {} at {}", label, dir.display());
+                }
+                let scan =
+                    scan_changed_folders(dir, &self.filter, &get_directory_mtimes(conn)?, full)?;
+                if !full {
+                    info!("  Skipped {} unchanged folders", scan.unchanged);
+                    // Only the changed folders are brought up to date
+                    patched = Some(scan.changed);
+                }
+                mtimes = Some((scan.mtimes, scan.removed, full));
+                denied = scan.denied;
+                scan.images
+            }
+        };
+        for path in &denied {
+            warn!("Permission denied reading {}", path.display());
+            failures::record_left_behind("permission denied", path.display());
+        }
+        if !denied.is_empty() && self.fail_on_access_errors {
+            return Err(
+                anyhow::anyhow!("{} entries in {} could not be read", denied.len(), label).into(),
+            );
+        }
+        if denied.is_empty() {
+            info!("  Found {} {} images", target_images.len(), label);
+        } else {
+            info!(
+                "  Found {} {} images, skipped {} unreadable entries",
+                target_images.len(),
+                label,
+                denied.len()
+            );
+        }
+
+        // Only hold the write lock while touching the database, so concurrent
+        // runs can make progress while this one reads metadata
+        let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let duplicates = populate_new_table(&trans, table, &target_images, self.leave)?;
+        trans.commit()?;
+
+        // Duplicates are hashed and maybe asked about without holding the lock
+        let algorithm = get_hash_algorithm(conn)?;
+        let (duplicate_reports, left_out) =
+            resolve_duplicates(dir, label, duplicates, algorithm, self.duplicates)?;
+
+        let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        db::remove_from_new_table(&trans, table, &left_out)?;
+        prune_failures(&trans, table)?;
+        let mut new_on = match (&patched, &listed) {
+            (Some(folders), _) => db::update_folders_get_new(&trans, folders)?,
+            (None, Some(paths)) => db::update_paths_get_new(&trans, table, self.card, paths)?,
+            (None, None) => update_table_get_new(&trans, table, self.card)?,
+        };
+
+        if !self.retry_failed {
+            let exhausted = get_exhausted_failures(&trans, table, MAX_FAILED_ATTEMPTS)?;
+            if !exhausted.is_empty() {
+                for path in &exhausted {
+                    failures::record_left_behind(
+                        "failed too often before, see --retry-failed",
+                        path,
+                    );
+                }
+                new_on.retain(|i| !exhausted.contains(&i.path));
+                info!(
+                    "  Skipping {} {} images that failed {} times, use --retry-failed to retry them",
+                    exhausted.len(),
+                    label,
+                    MAX_FAILED_ATTEMPTS
+                );
+            }
+        }
+
+        trans.commit()?;
+
+        let rows = self.inspect(conn, table, dir, label, new_on, &pb)?;
+
+        // With that new metadata, add the rows to the database
+        let trans = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        rows.record(&trans, table, self.card)?;
+        if let Some((mtimes, removed, full)) = &mtimes {
+            record_directory_mtimes(&trans, mtimes, removed)?;
+            if *full {
+                db::set_last_full_scan(&trans, chrono::Utc::now().naive_utc())?;
+            }
+        }
+        trans.commit()?;
+
+        Ok(IndexSummary {
+            found: target_images.len(),
+            indexed: rows.images.len(),
+            bytes: rows.images.iter().map(|i| i.basic.size).sum(),
+            failed: rows.failures.len(),
+            denied: denied.len(),
+            duplicates: duplicate_reports,
+            listed,
+        })
+    }
+
+    /// Hash the files of `new_on` and read their metadata, without recording
+    /// anything yet
+    pub fn inspect(
+        &self,
+        conn: &Connection,
+        table: TableType,
+        dir: &Path,
+        label: &'static str,
+        new_on: Vec<ImageBasic>,
+        pb: &ProgressBar,
+    ) -> error::Result<NewRows> {
+        // For those new rows, hash them and read their metadata by actually
+        // opening the files, unless they are archived files that were only moved
+        let algorithm = get_hash_algorithm(conn)?;
+        pb.set_length(new_on.len() as u64);
+        pb.set_message(format!("Indexing new {} images", table.label()));
+        let jobs = self
+            .jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |jobs| jobs.get()));
+        let inspected = images::inspect_images(
+            dir,
+            new_on,
+            algorithm,
+            ReadOptions {
+                timezone: self.timezone,
+                clock_offsets: self.clock_offsets,
+                ffprobe: self.ffprobe,
+            },
+            jobs,
+            pb,
+        );
+        pb.finish();
+
+        let mut failures = Vec::new();
+        let mut moved = Vec::new();
+        let mut checksums = Vec::new();
+        let mut new_on_adv = Vec::new();
+        let mut mtime_dated = 0;
+        for (i, res) in inspected {
+            let (checksum, image) = match res {
+                Ok(inspected) => (inspected.checksum, inspected.image),
+                Err(err) if self.strict => return Err(err.into()),
+                Err(err) => {
+                    warn!(target: FailureKind::classify(&err).label(), "{:#}", err);
+                    failures.push((i, err));
+                    continue;
+                }
+            };
+            if let Disk = table {
+                if let Some(removed) = find_moved(conn, &i, &checksum)? {
+                    new_on_adv.push(ImageAdv {
+                        basic: i.clone(),
+                        date: removed.date,
+                        rating: removed.rating,
+                        date_source: removed.date_source.clone(),
+                        camera: removed.camera.clone(),
+                        utc_offset: removed.utc_offset,
+                        exif: removed.exif.clone(),
+                    });
+                    moved.push((i.path, removed));
+                    continue;
+                }
+            }
+            match image {
+                Ok(image) => {
+                    checksums.push((i.path, checksum));
+                    new_on_adv.push(image);
+                }
+                Err(err) => match self.fallback_date(dir, &i, &err) {
+                    Some((date, utc_offset, date_source)) => {
+                        debug!("Dating {} from the {}: {}", i.path, date_source, err);
+                        if date_source == dates::MTIME_DATE_SOURCE {
+                            mtime_dated += 1;
+                        }
+                        checksums.push((i.path.clone(), checksum));
+                        new_on_adv.push(ImageAdv {
+                            basic: i,
+                            date,
+                            rating: None,
+                            date_source: Some(date_source.to_owned()),
+                            camera: None,
+                            utc_offset,
+                            exif: ExifDetails::default(),
+                        });
+                    }
+                    None if self.strict => return Err(err.into()),
+                    None => {
+                        warn!(target: FailureKind::classify(&err).label(), "{}", err);
+                        failures.push((i, err));
+                    }
+                },
+            }
+        }
+        if mtime_dated > 0 {
+            warn!(
+                "  Dated {} {} files without usable metadata by their modification time",
+                mtime_dated, label
+            );
+        }
+        if !moved.is_empty() {
+            info!(
+                "  Recognized {} moved {} images, keeping their metadata",
+                moved.len(),
+                label
+            );
+        }
+
+        Ok(NewRows {
+            images: new_on_adv,
+            checksums,
+            moved,
+            failures,
+        })
+    }
+
+    /// Date of a file whose metadata couldn't be used, from the date mapping
+    /// or else the date fallback, with its offset from UTC and the date
+    /// source to record
+    fn fallback_date(
+        &self,
+        dir: &Path,
+        image: &ImageBasic,
+        err: &anyhow::Error,
+    ) -> Option<(NaiveDateTime, Option<i32>, &'static str)> {
+        if let Some(date) = self.dates.and_then(|dates| dates.lookup(&image.path)) {
+            return Some((date, None, dates::DATE_SOURCE));
+        }
+        // Files that aren't images at all are still left alone
+        match (self.date_fallback, FailureKind::classify(err)) {
+            (DateFallback::Mtime, FailureKind::NoExif | FailureKind::UnparseableDate) => {
+                let modified = dates::mtime_date(&dir.join(&image.path), self.timezone).ok()?;
+                Some((
+                    modified.naive_local(),
+                    Some(modified.offset().local_minus_utc()),
+                    dates::MTIME_DATE_SOURCE,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Report the files of a scan found under the same name and size, and pick
+/// the copies left out of the index by `policy`
+fn resolve_duplicates(
+    dir: &Path,
+    label: &'static str,
+    duplicates: Vec<db::DuplicateImage>,
+    algorithm: HashAlgorithm,
+    policy: DuplicatePolicy,
+) -> anyhow::Result<(Vec<output::Duplicate>, Vec<String>)> {
+    let mut reports = Vec::new();
+    let mut left_out = Vec::new();
+    for dup in duplicates {
+        // Files sharing a name and size are only duplicates if their
+        // contents match as well
+        let checksums = dup
+            .paths
+            .iter()
+            .map(|path| hash::hash_file_chunked(&dir.join(path), algorithm, None).ok())
+            .collect::<Vec<_>>();
+        let contents = if checksums.iter().any(Option::is_none) {
+            error!("Possible duplicate file detected: {}", dup.name);
+            "unreadable"
+        } else if checksums.windows(2).all(|pair| pair[0] == pair[1]) {
+            error!("Duplicate file detected: {}", dup.name);
+            "identical"
+        } else {
+            error!("Different images share the name {} and size", dup.name);
+            "different"
+        };
+        for path in &dup.paths {
+            error!("  {}", path);
+        }
+        failures::record_left_behind(
+            "names found more than once",
+            format_args!("{} in the {}: {}", dup.name, label, dup.paths.join(", ")),
+        );
+
+        let kept = match policy {
+            DuplicatePolicy::KeepFirst => Some(0),
+            DuplicatePolicy::KeepAll | DuplicatePolicy::Abort => None,
+            DuplicatePolicy::Ask => duplicates::ask_which(&dup.name, &dup.paths)?,
+        };
+        let indexed = match kept {
+            Some(kept) => {
+                info!("  Indexing only {}", dup.paths[kept]);
+                left_out.extend(
+                    dup.paths
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != kept)
+                        .map(|(_, path)| path.clone()),
+                );
+                vec![dup.paths[kept].clone()]
+            }
+            None => dup.paths.clone(),
+        };
+        reports.push(output::Duplicate {
+            found_in: label,
+            name: dup.name,
+            contents,
+            paths: dup.paths,
+            indexed,
+        });
+    }
+
+    if policy == DuplicatePolicy::Abort && !reports.is_empty() {
+        anyhow::bail!(
+            "{} names were found more than once in {}, choose which to index with --duplicates",
+            reports.len(),
+            label
+        );
+    }
+
+    Ok((reports, left_out))
+}
+
+/// Find the recently removed archived file `image` is a moved copy of, by
+/// its size and checksum
+fn find_moved(
+    conn: &Connection,
+    image: &ImageBasic,
+    checksum: &[u8],
+) -> anyhow::Result<Option<db::RemovedFile>> {
+    Ok(db::get_removed_files(conn, image.size)?
+        .into_iter()
+        .find(|removed| removed.checksum == checksum))
+}
@@ -1,5 +1,3 @@
-BEGIN;
-
 CREATE TABLE on_disk(
   name TEXT NOT NULL,
   path TEXT NOT NULL,
@@ -33,5 +31,3 @@ ON on_camera(name, date);
 
 CREATE INDEX on_camera_join
 ON on_camera(name, date, size);
-
-COMMIT;
@@ -0,0 +1,11 @@
+CREATE TABLE runs(
+  started_at   TEXT NOT NULL,
+  finished_at  TEXT NOT NULL,
+  command      TEXT NOT NULL,
+  files         INT NOT NULL,
+  bytes         INT NOT NULL,
+  failures      INT NOT NULL
+) STRICT;
+
+CREATE INDEX runs_started_at
+ON runs(started_at);
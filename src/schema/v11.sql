@@ -0,0 +1,2 @@
+ALTER TABLE provenance ADD COLUMN utc_offset INT;
+ALTER TABLE provenance ADD COLUMN dst_policy TEXT;
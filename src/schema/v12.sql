@@ -0,0 +1,4 @@
+CREATE TABLE directories(
+  path   TEXT NOT NULL PRIMARY KEY,
+  mtime   INT NOT NULL
+) STRICT;
@@ -0,0 +1,2 @@
+ALTER TABLE on_disk ADD COLUMN rating INT;
+ALTER TABLE on_camera ADD COLUMN rating INT;
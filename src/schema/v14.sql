@@ -0,0 +1,15 @@
+CREATE TABLE discs(
+  label       TEXT NOT NULL PRIMARY KEY,
+  created_at  TEXT NOT NULL,
+  capacity     INT NOT NULL
+) STRICT;
+
+CREATE TABLE disc_files(
+  disc      TEXT NOT NULL REFERENCES discs(label),
+  path      TEXT NOT NULL,
+  size       INT NOT NULL,
+  checksum  BLOB NOT NULL
+) STRICT;
+
+CREATE INDEX disc_files_path
+ON disc_files(path);
@@ -0,0 +1,3 @@
+ALTER TABLE on_disk ADD COLUMN date_source TEXT;
+ALTER TABLE on_camera ADD COLUMN date_source TEXT;
+ALTER TABLE provenance ADD COLUMN date_source TEXT;
@@ -0,0 +1,12 @@
+CREATE TABLE removed_files(
+  path         TEXT NOT NULL,
+  size          INT NOT NULL,
+  date         TEXT NOT NULL,
+  rating        INT,
+  date_source  TEXT,
+  checksum     BLOB NOT NULL,
+  removed_at   TEXT NOT NULL
+) STRICT;
+
+CREATE INDEX removed_files_size
+ON removed_files(size);
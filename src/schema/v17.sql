@@ -0,0 +1,10 @@
+CREATE TABLE brackets(
+  raw_path     TEXT NOT NULL,
+  member_path  TEXT NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX brackets_member
+ON brackets(member_path);
+
+CREATE INDEX brackets_raw
+ON brackets(raw_path);
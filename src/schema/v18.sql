@@ -0,0 +1,14 @@
+CREATE TABLE progress(
+  session           TEXT PRIMARY KEY,
+  pid                INT NOT NULL,
+  command           TEXT NOT NULL,
+  stage             TEXT NOT NULL,
+  done               INT NOT NULL,
+  total              INT,
+  bytes              INT NOT NULL,
+  current_file      TEXT,
+  started_at        TEXT NOT NULL,
+  stage_started_at  TEXT NOT NULL,
+  updated_at        TEXT NOT NULL,
+  finished_at       TEXT
+) STRICT;
@@ -0,0 +1,8 @@
+CREATE TABLE verifications(
+  copy         TEXT NOT NULL,
+  path         TEXT NOT NULL,
+  verified_at  TEXT NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX verifications_copy_path
+ON verifications(copy, path);
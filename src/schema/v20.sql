@@ -0,0 +1,7 @@
+ALTER TABLE on_camera ADD COLUMN checksum BLOB;
+
+CREATE INDEX on_disk_checksum
+ON on_disk(checksum);
+
+CREATE INDEX on_camera_checksum
+ON on_camera(checksum);
@@ -0,0 +1,3 @@
+ALTER TABLE on_disk ADD COLUMN camera TEXT;
+ALTER TABLE on_camera ADD COLUMN camera TEXT;
+ALTER TABLE removed_files ADD COLUMN camera TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE on_disk ADD COLUMN utc_offset INT;
+ALTER TABLE on_camera ADD COLUMN utc_offset INT;
+ALTER TABLE removed_files ADD COLUMN utc_offset INT;
@@ -0,0 +1,3 @@
+-- Two images of one card may share a name and date, such as after the
+-- folder counter rolls over
+DROP INDEX on_camera_uniq;
@@ -0,0 +1 @@
+DROP INDEX on_disk_uniq;
@@ -0,0 +1,17 @@
+ALTER TABLE on_disk ADD COLUMN make TEXT;
+ALTER TABLE on_disk ADD COLUMN lens TEXT;
+ALTER TABLE on_disk ADD COLUMN iso INT;
+ALTER TABLE on_disk ADD COLUMN shutter TEXT;
+ALTER TABLE on_disk ADD COLUMN aperture REAL;
+
+ALTER TABLE on_camera ADD COLUMN make TEXT;
+ALTER TABLE on_camera ADD COLUMN lens TEXT;
+ALTER TABLE on_camera ADD COLUMN iso INT;
+ALTER TABLE on_camera ADD COLUMN shutter TEXT;
+ALTER TABLE on_camera ADD COLUMN aperture REAL;
+
+ALTER TABLE removed_files ADD COLUMN make TEXT;
+ALTER TABLE removed_files ADD COLUMN lens TEXT;
+ALTER TABLE removed_files ADD COLUMN iso INT;
+ALTER TABLE removed_files ADD COLUMN shutter TEXT;
+ALTER TABLE removed_files ADD COLUMN aperture REAL;
@@ -0,0 +1,11 @@
+ALTER TABLE on_disk ADD COLUMN latitude REAL;
+ALTER TABLE on_disk ADD COLUMN longitude REAL;
+ALTER TABLE on_disk ADD COLUMN altitude REAL;
+
+ALTER TABLE on_camera ADD COLUMN latitude REAL;
+ALTER TABLE on_camera ADD COLUMN longitude REAL;
+ALTER TABLE on_camera ADD COLUMN altitude REAL;
+
+ALTER TABLE removed_files ADD COLUMN latitude REAL;
+ALTER TABLE removed_files ADD COLUMN longitude REAL;
+ALTER TABLE removed_files ADD COLUMN altitude REAL;
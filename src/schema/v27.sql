@@ -0,0 +1,10 @@
+CREATE TABLE bursts(
+  burst  INT NOT NULL,
+  path   TEXT NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX bursts_path
+ON bursts(path);
+
+CREATE INDEX bursts_burst
+ON bursts(burst);
@@ -0,0 +1,14 @@
+ALTER TABLE on_disk ADD COLUMN content_id TEXT;
+ALTER TABLE on_camera ADD COLUMN content_id TEXT;
+ALTER TABLE removed_files ADD COLUMN content_id TEXT;
+
+CREATE TABLE live_photos(
+  image_path  TEXT NOT NULL,
+  video_path  TEXT NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX live_photos_video
+ON live_photos(video_path);
+
+CREATE INDEX live_photos_image
+ON live_photos(image_path);
@@ -0,0 +1,10 @@
+-- Camera images are kept per card, so runs archiving different cards at the
+-- same time don't replace each other's images. Images of cards without an id
+-- share the empty one.
+ALTER TABLE on_camera ADD COLUMN card TEXT NOT NULL DEFAULT '';
+ALTER TABLE on_camera ADD COLUMN scanned_at TEXT;
+
+DROP INDEX on_camera_path;
+
+CREATE UNIQUE INDEX on_camera_path
+ON on_camera(card, path);
@@ -0,0 +1,2 @@
+ALTER TABLE on_camera
+ADD COLUMN archived_at TEXT;
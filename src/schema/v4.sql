@@ -0,0 +1,12 @@
+CREATE TABLE failures(
+  source    TEXT NOT NULL,
+  path      TEXT NOT NULL,
+  size       INT NOT NULL,
+  kind      TEXT NOT NULL,
+  message   TEXT NOT NULL,
+  attempts   INT NOT NULL DEFAULT 1,
+  last_seen TEXT NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX failures_path
+ON failures(source, path);
@@ -0,0 +1,5 @@
+ALTER TABLE on_camera
+ADD COLUMN claimed_by TEXT;
+
+ALTER TABLE on_camera
+ADD COLUMN claimed_at TEXT;
@@ -0,0 +1,7 @@
+CREATE TABLE settings(
+  key   TEXT NOT NULL PRIMARY KEY,
+  value TEXT NOT NULL
+) STRICT;
+
+ALTER TABLE on_disk
+ADD COLUMN checksum BLOB;
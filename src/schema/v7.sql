@@ -0,0 +1,9 @@
+CREATE TABLE chunks(
+  path      TEXT NOT NULL,
+  offset     INT NOT NULL,
+  length     INT NOT NULL,
+  checksum  BLOB NOT NULL
+) STRICT;
+
+CREATE UNIQUE INDEX chunks_path
+ON chunks(path, offset);
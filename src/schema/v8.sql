@@ -0,0 +1,9 @@
+CREATE TABLE operations(
+  time    TEXT NOT NULL,
+  kind    TEXT NOT NULL,
+  path    TEXT,
+  detail  TEXT NOT NULL
+) STRICT;
+
+CREATE INDEX operations_time
+ON operations(time);
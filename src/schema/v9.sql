@@ -0,0 +1,13 @@
+CREATE TABLE provenance(
+  name         TEXT NOT NULL,
+  date         TEXT NOT NULL,
+  size          INT NOT NULL,
+  checksum     BLOB,
+  card_id      TEXT,
+  source_path  TEXT NOT NULL,
+  disk_path    TEXT NOT NULL,
+  archived_at  TEXT NOT NULL
+) STRICT;
+
+CREATE INDEX provenance_name
+ON provenance(name, date);
@@ -0,0 +1,102 @@
+//! The udev rule and systemd template unit written by `rawdb
+//! install-service`. udev starts an instance of the unit for each registered
+//! card that is inserted, which mounts the card with a mount unit of its own,
+//! archives it and unmounts it again.
+
+use std::{fmt::Write as _, fs, process::Command};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+
+pub const UDEV_RULE_PATH: &str = "/etc/udev/rules.d/99-rawdb.rules";
+pub const UNIT_PATH: &str = "/etc/systemd/system/rawdb-archive@.service";
+
+/// Cards are mounted below this by the unit, in a folder named after their
+/// device such as `sdb1`
+const MOUNT_DIR: &str = "/run/rawdb";
+
+/// A udev rule starting the unit for every volume whose label or UUID is one
+/// of `cards`
+pub fn udev_rule(cards: &[String]) -> anyhow::Result<String> {
+    let mut rule = String::from("# Written by rawdb install-service\n");
+    for card in cards {
+        if card.contains(['"', '\\']) {
+            bail!("Card {:?} can't be matched by a udev rule", card);
+        }
+        for key in ["ID_FS_LABEL", "ID_FS_UUID"] {
+            writeln!(
+                rule,
+                "ACTION==\"add\", SUBSYSTEM==\"block\", ENV{{{key}}}==\"{card}\", \
+                 TAG+=\"systemd\", ENV{{SYSTEMD_WANTS}}+=\"rawdb-archive@%k.service\""
+            )?;
+        }
+    }
+    Ok(rule)
+}
+
+/// Quote `arg` for a command line of a unit, where `%` starts a specifier
+/// and `$` a variable
+fn quote(arg: &str) -> String {
+    let arg = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", arg)
+}
+
+/// A template unit archiving the card on the device it is started for, as
+/// `user`. `command` is rawdb with the options to archive with, the folder
+/// the card is mounted at is added to it. The card is mounted before and
+/// unmounted after the run, and the run is stopped if the card is removed.
+pub fn unit(command: &[String], user: &str) -> anyhow::Result<String> {
+    if !user
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        bail!("User {:?} can't be named in a unit", user);
+    }
+    let mount = format!("{}/%i", MOUNT_DIR);
+    let command = command.iter().map(|arg| quote(arg)).collect::<Vec<_>>();
+    Ok(format!(
+        "\
+# Written by rawdb install-service
+[Unit]
+Description=Archive the card on /dev/%i with rawdb
+BindsTo=dev-%i.device
+After=dev-%i.device
+
+[Service]
+Type=oneshot
+User={user}
+ExecStartPre=+/usr/bin/systemd-mount --no-ask-password --collect --owner={user} /dev/%i {mount}
+ExecStart={command} {mount}
+ExecStopPost=+/usr/bin/systemd-umount {mount}
+",
+        command = command.join(" "),
+    ))
+}
+
+/// Write the rule and the unit and have udev and systemd load them
+pub fn install(rule: &str, unit: &str) -> anyhow::Result<()> {
+    for (path, contents) in [(UDEV_RULE_PATH, rule), (UNIT_PATH, unit)] {
+        fs::write(path, contents).with_context(|| {
+            format!(
+                "Failed to write {}, install-service needs to run as root",
+                path
+            )
+        })?;
+        info!("Wrote {}", path);
+    }
+    for (program, args) in [
+        ("udevadm", &["control", "--reload-rules"][..]),
+        ("systemctl", &["daemon-reload"][..]),
+    ] {
+        match Command::new(program).args(args).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("{} {} failed with {}", program, args.join(" "), status),
+            Err(err) => warn!("Failed to run {}: {}", program, err),
+        }
+    }
+    Ok(())
+}
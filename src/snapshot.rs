@@ -0,0 +1,28 @@
+use std::{path::Path, process::Command};
+
+use anyhow::Context;
+use log::debug;
+
+/// Run the configured snapshot command for the target and return the name
+/// of the new snapshot. `{target}` and `{name}` in the command's arguments
+/// are replaced with the target directory and the snapshot name.
+pub fn take_snapshot(command: &str, target_dir: &Path) -> anyhow::Result<String> {
+    let name = format!("rawdb-{}", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+    let target = target_dir.to_string_lossy();
+    let mut args = command
+        .split_whitespace()
+        .map(|arg| arg.replace("{target}", &target).replace("{name}", &name));
+    let program = args.next().context("The snapshot command is empty")?;
+    let args = args.collect::<Vec<_>>();
+
+    debug!("Running {} {:?}", program, args);
+    let status = Command::new(&program)
+        .args(&args)
+        .status()
+        .with_context(|| format!("Failed to run snapshot command {}", program))?;
+    if !status.success() {
+        anyhow::bail!("Snapshot command {} failed with {}", program, status);
+    }
+
+    Ok(name)
+}
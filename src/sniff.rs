@@ -0,0 +1,204 @@
+//! Telling images and videos apart by their first bytes, as cards also hold
+//! files with wrong or missing extensions, like renamed `.tmp` files or
+//! `MVI_1234` without one.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use log::debug;
+
+/// Bytes read from the start of a file to recognize it
+const HEADER_LEN: usize = 32;
+
+/// Brands of ISO base media files holding HEIF or AVIF images rather than
+/// videos
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+/// What a file holds
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    /// An image or RAW file exiv2 reads directly
+    Image,
+    /// A HEIF image, see [`crate::heif`]
+    Heif,
+    Video,
+    /// Neither an image nor a video, such as an empty file or an XMP packet
+    Other,
+}
+
+/// Recognize a file by its first bytes, `None` if they don't tell
+pub fn sniff(header: &[u8]) -> Option<ContentType> {
+    let starts_with = |magic: &[u8]| header.starts_with(magic);
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if header.is_empty()
+        || [&b"<?xpacket"[..], b"<x:xmpmeta", b"<?xml"]
+            .into_iter()
+            .any(starts_with)
+    {
+        return Some(ContentType::Other);
+    }
+    // TIFF based RAWs, the variants of Olympus and Panasonic, Fujifilm RAF
+    // and the formats of camera JPEGs
+    let image = [
+        &b"II*\0"[..],
+        b"MM\0*",
+        b"IIRO",
+        b"IIRS",
+        b"MMOR",
+        b"IIU\0",
+        b"FUJIFILMCCD-RAW",
+        b"\xff\xd8\xff",
+        b"\x89PNG",
+    ];
+    if image.into_iter().any(starts_with) || (at(0, b"RIFF") && at(8, b"WEBP")) {
+        return Some(ContentType::Image);
+    }
+    if at(4, b"ftyp") {
+        let brand = header.get(8..12)?;
+        return Some(if HEIF_BRANDS.iter().any(|heif| brand == &heif[..]) {
+            ContentType::Heif
+        } else if brand == b"crx " {
+            // Canon CR3, which exiv2 reads like other RAWs
+            ContentType::Image
+        } else {
+            ContentType::Video
+        });
+    }
+    // QuickTime files from before MP4, AVI and Matroska or WebM
+    let video = [&b"moov"[..], b"mdat", b"wide", b"free", b"skip", b"pnot"];
+    if video.into_iter().any(|kind| at(4, kind))
+        || (at(0, b"RIFF") && at(8, b"AVI "))
+        || starts_with(b"\x1a\x45\xdf\xa3")
+    {
+        return Some(ContentType::Video);
+    }
+    None
+}
+
+/// Recognize the file at `path` by its first bytes, `None` if they don't
+/// tell
+pub fn detect(path: &Path) -> io::Result<Option<ContentType>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(sniff(&header))
+}
+
+/// Recognize the file at `path` by its first bytes, or take `by_extension`,
+/// what its extension says, if they don't tell
+pub fn detect_or(path: &Path, by_extension: ContentType) -> io::Result<ContentType> {
+    let Some(sniffed) = detect(path)? else {
+        return Ok(by_extension);
+    };
+    if sniffed != by_extension {
+        debug!(
+            "Reading {} as {:?}, not by its extension",
+            path.display(),
+            sniffed
+        );
+    }
+    Ok(sniffed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    /// A box header of type `kind`, followed by `rest`
+    fn boxed(kind: &[u8; 4], rest: &[u8]) -> Vec<u8> {
+        let mut header = vec![0, 0, 0, 0x18];
+        header.extend_from_slice(kind);
+        header.extend_from_slice(rest);
+        header
+    }
+
+    #[test]
+    fn test_sniff() {
+        use ContentType::*;
+        let cases: &[(&str, Vec<u8>, Option<ContentType>)] = &[
+            ("empty", vec![], Some(Other)),
+            ("xpacket", b"<?xpacket begin=".to_vec(), Some(Other)),
+            ("xmpmeta", b"<x:xmpmeta xmlns:x=".to_vec(), Some(Other)),
+            ("xml", b"<?xml version=".to_vec(), Some(Other)),
+            ("tiff le", b"II*\0\x08\0\0\0".to_vec(), Some(Image)),
+            ("tiff be", b"MM\0*\0\0\0\x08".to_vec(), Some(Image)),
+            ("orf", b"IIRO\x08\0\0\0".to_vec(), Some(Image)),
+            ("orf sp", b"IIRS\x08\0\0\0".to_vec(), Some(Image)),
+            ("orf be", b"MMOR\0\0\0\x08".to_vec(), Some(Image)),
+            ("rw2", b"IIU\0\x08\0\0\0".to_vec(), Some(Image)),
+            ("raf", b"FUJIFILMCCD-RAW 0201".to_vec(), Some(Image)),
+            ("jpeg", b"\xff\xd8\xff\xe1\0\x10Exif".to_vec(), Some(Image)),
+            ("png", b"\x89PNG\r\n\x1a\n".to_vec(), Some(Image)),
+            ("webp", b"RIFF\0\0\0\0WEBPVP8 ".to_vec(), Some(Image)),
+            ("heic", boxed(b"ftyp", b"heic\0\0\0\0"), Some(Heif)),
+            ("heix", boxed(b"ftyp", b"heix"), Some(Heif)),
+            ("heim", boxed(b"ftyp", b"heim"), Some(Heif)),
+            ("heis", boxed(b"ftyp", b"heis"), Some(Heif)),
+            ("hevc", boxed(b"ftyp", b"hevc"), Some(Heif)),
+            ("hevx", boxed(b"ftyp", b"hevx"), Some(Heif)),
+            ("mif1", boxed(b"ftyp", b"mif1"), Some(Heif)),
+            ("msf1", boxed(b"ftyp", b"msf1"), Some(Heif)),
+            ("avif", boxed(b"ftyp", b"avif"), Some(Heif)),
+            ("avis", boxed(b"ftyp", b"avis"), Some(Heif)),
+            ("cr3", boxed(b"ftyp", b"crx \0\0\0\x01"), Some(Image)),
+            ("mp4", boxed(b"ftyp", b"isom\0\0\x02\0"), Some(Video)),
+            ("mov", boxed(b"ftyp", b"qt  \0\0\x02\0"), Some(Video)),
+            ("ftyp without brand", boxed(b"ftyp", b"he"), None),
+            ("moov", boxed(b"moov", b""), Some(Video)),
+            ("mdat", boxed(b"mdat", b""), Some(Video)),
+            ("wide", boxed(b"wide", b""), Some(Video)),
+            ("free", boxed(b"free", b""), Some(Video)),
+            ("skip", boxed(b"skip", b""), Some(Video)),
+            ("pnot", boxed(b"pnot", b""), Some(Video)),
+            ("avi", b"RIFF\0\0\0\0AVI LIST".to_vec(), Some(Video)),
+            ("riff other", b"RIFF\0\0\0\0WAVEfmt ".to_vec(), None),
+            (
+                "matroska",
+                b"\x1a\x45\xdf\xa3\x01\0\0\0".to_vec(),
+                Some(Video),
+            ),
+            ("text", b"hello world".to_vec(), None),
+            ("short", b"I".to_vec(), None),
+        ];
+        for (name, header, expected) in cases {
+            assert_eq!(sniff(header), *expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_detect_or() {
+        use ContentType::*;
+        // Header, what the extension says, what the file is taken for
+        let cases: &[(&[u8], ContentType, ContentType)] = &[
+            (b"not a known header", Image, Image),
+            (b"not a known header", Heif, Heif),
+            (b"not a known header", Video, Video),
+            (b"\xff\xd8\xff\xe0", Video, Image),
+            (b"\0\0\0\x18ftypheic", Image, Heif),
+            (b"\0\0\0\x18ftypisom", Image, Video),
+            (b"", Image, Other),
+        ];
+        let path = env::temp_dir().join(format!("rawdb-sniff-{}", uuid::Uuid::new_v4()));
+        for (header, by_extension, expected) in cases {
+            fs::write(&path, header).unwrap();
+            assert_eq!(
+                detect_or(&path, *by_extension).unwrap(),
+                *expected,
+                "{:?} as {:?}",
+                header,
+                by_extension
+            );
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(detect_or(&path, Image).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rawdb::images::{load_images, FileFilter, ImageBasic};
+
+/// How often the session folder is read while tethered
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a file has to stay unchanged before it counts as fully written
+const STABLE_FOR: Duration = Duration::from_millis(500);
+
+/// A file that was still changing at the last poll
+struct Pending {
+    size: u64,
+    modified: Option<SystemTime>,
+    since: Instant,
+}
+
+/// Watches a folder capture software writes into, handing out each file
+/// once it stopped changing
+pub struct HotFolder {
+    dir: PathBuf,
+    filter: FileFilter,
+    pending: HashMap<String, Pending>,
+    seen: HashSet<String>,
+}
+
+impl HotFolder {
+    pub fn new(dir: &Path, filter: FileFilter) -> Self {
+        HotFolder {
+            dir: dir.to_owned(),
+            filter,
+            pending: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Read the folder and return the files whose size and modification time
+    /// haven't changed for a while. Each file is only returned once.
+    pub fn poll(&mut self) -> anyhow::Result<Vec<ImageBasic>> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        let mut present = HashSet::new();
+        for image in load_images::<ImageBasic>(&self.dir, &self.filter) {
+            let image = image?;
+            present.insert(image.path.clone());
+            if self.seen.contains(&image.path) {
+                continue;
+            }
+            // The capture software may still be renaming or replacing it
+            let Ok(meta) = fs::metadata(self.dir.join(&image.path)) else {
+                continue;
+            };
+            let modified = meta.modified().ok();
+
+            match self.pending.get_mut(&image.path) {
+                Some(pending) if pending.size == image.size && pending.modified == modified => {
+                    if now - pending.since >= STABLE_FOR {
+                        self.pending.remove(&image.path);
+                        self.seen.insert(image.path.clone());
+                        ready.push(image);
+                    }
+                }
+                _ => {
+                    self.pending.insert(
+                        image.path.clone(),
+                        Pending {
+                            size: image.size,
+                            modified,
+                            since: now,
+                        },
+                    );
+                }
+            }
+        }
+        self.pending.retain(|path, _| present.contains(path));
+
+        Ok(ready)
+    }
+}
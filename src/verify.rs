@@ -0,0 +1,146 @@
+use std::{collections::HashSet, fs, io, ops::Range, path::Path};
+
+use indicatif::{ProgressBar, ProgressIterator};
+use log::error;
+use rusqlite::Connection;
+
+use crate::{
+    db::{get_chunks, get_hash_algorithm, get_recorded_files},
+    hash::{hash_file_chunked, to_hex},
+    images::{load_images, FileFilter, ImageBasic},
+};
+
+pub enum Problem {
+    Missing(io::Error),
+    /// Reading the file failed partway, like on a bad sector
+    Unreadable(io::Error),
+    SizeChanged {
+        actual: u64,
+    },
+    /// The byte ranges whose chunk checksums differ, or the whole file if
+    /// no chunk checksums were recorded
+    ChecksumMismatch {
+        ranges: Vec<Range<u64>>,
+    },
+}
+
+pub struct CorruptFile {
+    /// Path relative to the target directory
+    pub path: String,
+    pub size: u64,
+    pub checksum: Option<Vec<u8>>,
+    pub problem: Problem,
+}
+
+pub struct VerifyReport {
+    pub checked: u64,
+    pub bytes: u64,
+    /// Paths of the files that matched their checksums
+    pub verified: Vec<String>,
+    /// Files without a recorded checksum, only their size was checked
+    pub size_only: u64,
+    pub corrupt: Vec<CorruptFile>,
+    /// Files in the directory that aren't in the database
+    pub untracked: Vec<String>,
+}
+
+/// Check every archived file in `target_dir`, the target or a mirror of it,
+/// against the database: it has to exist with the recorded size and, if a
+/// checksum was recorded, the same contents. Files the database doesn't know
+/// are reported as well, as far as `filter` indexes them.
+pub fn verify_archive(
+    conn: &Connection,
+    target_dir: &Path,
+    filter: &FileFilter,
+    pb: ProgressBar,
+) -> anyhow::Result<VerifyReport> {
+    let algorithm = get_hash_algorithm(conn)?;
+    let files = get_recorded_files(conn)?;
+
+    let known = files
+        .iter()
+        .map(|file| file.path.as_str())
+        .collect::<HashSet<_>>();
+    let mut untracked = Vec::new();
+    for image in load_images::<ImageBasic>(target_dir, filter) {
+        let image = image?;
+        if !known.contains(image.path.as_str()) {
+            untracked.push(image.path);
+        }
+    }
+    untracked.sort();
+
+    pb.set_length(files.len() as u64);
+    let checked = files.len() as u64;
+    let bytes = files.iter().map(|file| file.size).sum();
+    let mut verified = Vec::new();
+    let mut size_only = 0;
+    let mut corrupt = Vec::new();
+    for file in files
+        .into_iter()
+        .progress_with(pb)
+        .with_message("Verifying archived images")
+    {
+        let abs_path = target_dir.join(&file.path);
+        let problem = match fs::metadata(&abs_path) {
+            Err(err) => Some(Problem::Missing(err)),
+            Ok(meta) if meta.len() != file.size => {
+                Some(Problem::SizeChanged { actual: meta.len() })
+            }
+            Ok(_) if file.checksum.is_none() => {
+                size_only += 1;
+                None
+            }
+            Ok(_) => {
+                let expected = file.checksum.as_deref().unwrap_or_default();
+                let chunks = get_chunks(conn, &file.path)?;
+                let chunk_size = chunks.first().map(|chunk| chunk.length);
+                match hash_file_chunked(&abs_path, algorithm, chunk_size) {
+                    Err(err) => Some(Problem::Unreadable(err)),
+                    Ok((checksum, _)) if checksum == expected => {
+                        verified.push(file.path.clone());
+                        None
+                    }
+                    Ok((checksum, chunk_checksums)) => {
+                        error!(
+                            "{} - expected {} {}, found {}",
+                            file.path,
+                            algorithm,
+                            to_hex(expected),
+                            to_hex(&checksum)
+                        );
+                        let ranges = if chunks.is_empty() {
+                            std::iter::once(0..file.size).collect()
+                        } else {
+                            chunks
+                                .iter()
+                                .zip(&chunk_checksums)
+                                .filter(|(chunk, actual)| chunk.checksum != **actual)
+                                .map(|(chunk, _)| chunk.offset..chunk.offset + chunk.length)
+                                .collect()
+                        };
+                        Some(Problem::ChecksumMismatch { ranges })
+                    }
+                }
+            }
+        };
+
+        if let Some(problem) = problem {
+            corrupt.push(CorruptFile {
+                path: file.path,
+                size: file.size,
+                checksum: file.checksum,
+                problem,
+            });
+        }
+    }
+
+    Ok(VerifyReport {
+        checked,
+        bytes,
+        verified,
+        size_only,
+        corrupt,
+        untracked,
+    })
+}
@@ -0,0 +1,304 @@
+//! The metadata of videos. MP4 and QuickTime files are read directly, other
+//! containers are probed with ffmpeg's `ffprobe` in builds with the `ffprobe`
+//! feature.
+
+#[cfg(feature = "ffprobe")]
+use std::process::{Command, Stdio};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, SecondsFormat};
+
+use crate::bmff::{read_header, Cursor};
+
+/// Largest `moov` box read into memory. It holds the sample tables, which
+/// take a few megabytes for hours of video.
+const MAX_MOOV_SIZE: u64 = 64 << 20;
+
+/// Seconds from 1904, the epoch of the container, to 1970
+const EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Boxes MP4 and QuickTime files start with. QuickTime files from before
+/// MP4 have no `ftyp`.
+const FIRST_BOXES: &[&[u8; 4]] = &[
+    b"ftyp", b"moov", b"mdat", b"wide", b"free", b"skip", b"pnot",
+];
+
+/// The ffprobe binary to probe videos with: `bin`, or the one on the
+/// `PATH`. Fails if `bin` doesn't run, and warns if there is no ffprobe on
+/// the `PATH`, returning `None`.
+#[cfg(feature = "ffprobe")]
+pub fn find_ffprobe(bin: Option<PathBuf>) -> anyhow::Result<Option<PathBuf>> {
+    let explicit = bin.is_some();
+    let bin = bin.unwrap_or_else(|| PathBuf::from("ffprobe"));
+    let runs = Command::new(&bin)
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !runs {
+        if explicit {
+            bail!("ffprobe at {} doesn't run", bin.display());
+        }
+        log::warn!(
+            "ffprobe was not found on the PATH, only MP4 and QuickTime videos can be read. \
+             Set its location with --ffprobe, RAWDB_FFPROBE or ffprobe in the config."
+        );
+    }
+    Ok(runs.then_some(bin))
+}
+
+/// Builds without the `ffprobe` feature never probe videos
+#[cfg(not(feature = "ffprobe"))]
+pub fn find_ffprobe(bin: Option<PathBuf>) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(bin) = bin {
+        bail!(
+            "ffprobe at {} can't be used, rawdb was built without the ffprobe feature",
+            bin.display()
+        );
+    }
+    Ok(None)
+}
+
+/// The metadata of a video
+#[derive(Debug, Default)]
+pub struct VideoMetadata {
+    /// Text tags of the container, such as the QuickTime keys Apple devices
+    /// write
+    pub tags: HashMap<String, String>,
+    /// Creation times of the container and then of each stream, with where
+    /// each was read from
+    pub creation_times: Vec<(&'static str, String)>,
+    /// Names of the handlers of the streams, which name the camera for some
+    pub handlers: Vec<String>,
+    pub streams: usize,
+}
+
+/// Read the metadata of the video at `path`, probing containers other than
+/// MP4 and QuickTime with the `ffprobe` binary, see [`find_ffprobe`]
+#[cfg_attr(not(feature = "ffprobe"), allow(unused_variables))]
+pub fn read_metadata(path: &Path, ffprobe: Option<&Path>) -> anyhow::Result<VideoMetadata> {
+    match read_bmff(path) {
+        Ok(metadata) => Ok(metadata),
+        #[cfg(feature = "ffprobe")]
+        Err(err) => {
+            log::debug!("Probing {} with ffprobe: {:#}", path.display(), err);
+            let Some(bin) = ffprobe else {
+                bail!("ffprobe is not available to read {}", path.display());
+            };
+            probe(path, bin)
+        }
+        #[cfg(not(feature = "ffprobe"))]
+        Err(err) => Err(err),
+    }
+}
+
+fn read_bmff(path: &Path) -> anyhow::Result<VideoMetadata> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let file_len = file.metadata()?.len();
+
+    let mut first = [0; 8];
+    let known = file.read_exact(&mut first).is_ok()
+        && FIRST_BOXES.iter().any(|kind| first[4..] == kind[..]);
+    if !known {
+        bail!("{} is not an MP4 or QuickTime file", path.display());
+    }
+    file.rewind()?;
+
+    let mut moov = None;
+    while let Some(header) = read_header(&mut file, file_len)? {
+        if &header.kind == b"moov" {
+            if header.end - header.start > MAX_MOOV_SIZE {
+                bail!("moov box of {} is too large", path.display());
+            }
+            let mut contents = vec![0; (header.end - header.start) as usize];
+            file.read_exact(&mut contents)?;
+            moov = Some(contents);
+            break;
+        }
+        file.seek(SeekFrom::Start(header.end))?;
+    }
+    let Some(moov) = moov else {
+        bail!("No moov box found in {}", path.display());
+    };
+
+    let mut metadata = VideoMetadata::default();
+    let mut stream_times = Vec::new();
+    let mut boxes = Cursor::new(&moov);
+    while let Some(header) = boxes.header()? {
+        match &header.kind {
+            b"mvhd" => {
+                if let Some(time) = read_creation_time(boxes.full_box(&header))? {
+                    metadata.creation_times.push(("mvhd creation_time", time));
+                }
+            }
+            b"trak" => {
+                metadata.streams += 1;
+                let (time, handler) = read_track(boxes.contents(&header))?;
+                stream_times.extend(time.map(|time| ("mdhd creation_time", time)));
+                metadata.handlers.extend(handler);
+            }
+            b"meta" => read_keys(boxes.contents(&header), &mut metadata.tags)?,
+            _ => {}
+        }
+        boxes.skip_to(&header);
+    }
+    metadata.creation_times.extend(stream_times);
+
+    Ok(metadata)
+}
+
+/// The creation time of an `mvhd` or `mdhd` box, unless it is not set
+fn read_creation_time((version, mut contents): (u8, Cursor)) -> anyhow::Result<Option<String>> {
+    let seconds = contents.uint(if version == 1 { 8 } else { 4 })?;
+    if seconds == 0 {
+        return Ok(None);
+    }
+    let time = i64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds - EPOCH_OFFSET, 0))
+        .context("Creation time out of range")?;
+    Ok(Some(time.to_rfc3339_opts(SecondsFormat::Secs, true)))
+}
+
+/// The creation time and handler name of a `trak` box, from its media box
+fn read_track(mut trak: Cursor) -> anyhow::Result<(Option<String>, Option<String>)> {
+    let (mut time, mut handler) = (None, None);
+    while let Some(header) = trak.header()? {
+        if &header.kind == b"mdia" {
+            let mut mdia = trak.contents(&header);
+            while let Some(header) = mdia.header()? {
+                match &header.kind {
+                    b"mdhd" => time = read_creation_time(mdia.full_box(&header))?,
+                    b"hdlr" => {
+                        let (_, mut hdlr) = mdia.full_box(&header);
+                        // Predefined, handler type and reserved fields
+                        hdlr.bytes(20)?;
+                        handler = handler_name(hdlr.rest());
+                    }
+                    _ => {}
+                }
+                mdia.skip_to(&header);
+            }
+        }
+        trak.skip_to(&header);
+    }
+    Ok((time, handler))
+}
+
+/// QuickTime writes handler names as counted strings, MP4 as null terminated
+/// ones
+fn handler_name(name: &[u8]) -> Option<String> {
+    let name = match name.split_first() {
+        Some((len, rest)) if usize::from(*len) == rest.len() => rest,
+        _ => name,
+    };
+    let name = name.split(|b| *b == 0).next().unwrap_or_default();
+    let name = String::from_utf8_lossy(name).trim().to_owned();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Read the text values of the QuickTime keys in a `meta` box
+fn read_keys(mut meta: Cursor, tags: &mut HashMap<String, String>) -> anyhow::Result<()> {
+    // The meta box of QuickTime is a plain box, the one of MP4 a full box
+    let contents = meta.rest();
+    let contents = match contents.get(4..8) {
+        Some(b"hdlr") => contents,
+        _ => contents.get(4..).unwrap_or_default(),
+    };
+
+    let mut boxes = Cursor::new(contents);
+    let mut keys = Vec::new();
+    let mut ilst = None;
+    while let Some(header) = boxes.header()? {
+        match &header.kind {
+            b"keys" => {
+                let (_, mut entries) = boxes.full_box(&header);
+                let count = entries.uint(4)?;
+                for _ in 0..count {
+                    let size = entries.uint(4)?;
+                    // The namespace, `mdta` for the keys of Apple devices
+                    entries.bytes(4)?;
+                    let name = entries.bytes(size.saturating_sub(8) as usize)?;
+                    keys.push(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            b"ilst" => ilst = Some(boxes.contents(&header)),
+            _ => {}
+        }
+        boxes.skip_to(&header);
+    }
+
+    // Items are named after the index of their key, counting from 1
+    let Some(mut ilst) = ilst else {
+        return Ok(());
+    };
+    while let Some(header) = ilst.header()? {
+        let index = u32::from_be_bytes(header.kind) as usize;
+        if let Some(key) = index.checked_sub(1).and_then(|index| keys.get(index)) {
+            let mut item = ilst.contents(&header);
+            while let Some(header) = item.header()? {
+                if &header.kind == b"data" {
+                    let mut data = item.contents(&header);
+                    let kind = data.uint(4)?;
+                    // The locale
+                    data.uint(4)?;
+                    // Type 1 is UTF-8 text
+                    if kind == 1 {
+                        let value = String::from_utf8_lossy(data.rest()).into_owned();
+                        tags.insert(key.clone(), value);
+                    }
+                }
+                item.skip_to(&header);
+            }
+        }
+        ilst.skip_to(&header);
+    }
+
+    Ok(())
+}
+
+/// Read the metadata of a video with ffprobe
+#[cfg(feature = "ffprobe")]
+fn probe(path: &Path, bin: &Path) -> anyhow::Result<VideoMetadata> {
+    let probed =
+        ffprobe::ffprobe_config(ffprobe::Config::builder().ffprobe_bin(bin).build(), path)?;
+
+    let format_tags = probed.format.tags.as_ref();
+    let tags = format_tags
+        .map(|tags| {
+            tags.extra
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_owned())))
+                .collect()
+        })
+        .unwrap_or_default();
+    let creation_times = format_tags
+        .and_then(|tags| tags.creation_time.clone())
+        .map(|time| ("ffprobe format creation_time", time))
+        .into_iter()
+        .chain(probed.streams.iter().filter_map(|stream| {
+            let time = stream.tags.as_ref()?.creation_time.clone()?;
+            Some(("ffprobe stream creation_time", time))
+        }))
+        .collect();
+    let handlers = probed
+        .streams
+        .iter()
+        .filter_map(|stream| stream.tags.as_ref()?.handler_name.clone())
+        .collect();
+
+    Ok(VideoMetadata {
+        tags,
+        creation_times,
+        handlers,
+        streams: probed.streams.len(),
+    })
+}
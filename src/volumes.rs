@@ -0,0 +1,127 @@
+//! Finding mounted cards by their volume label or UUID, read from
+//! `/proc/mounts` and the links of `/dev/disk` on Linux.
+
+use std::path::PathBuf;
+#[cfg(target_os = "linux")]
+use std::{collections::HashMap, fs, io, path::Path};
+
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+
+/// A mounted volume
+#[derive(Debug)]
+pub struct Volume {
+    pub mount_point: PathBuf,
+    /// The block device, such as `/dev/sdb1`
+    pub device: PathBuf,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+}
+
+/// Undo the octal escapes of `/proc/mounts` and `/dev/disk/by-label`, such
+/// as `\040` and `\x20` for a space
+#[cfg(target_os = "linux")]
+fn unescape(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let escape = match after {
+            [b'x', code @ ..] if byte == b'\\' => code.get(..2).map(|code| (code, 16, 3)),
+            code if byte == b'\\' => code.get(..3).map(|code| (code, 8, 3)),
+            _ => None,
+        };
+        let code = escape.and_then(|(code, radix, len)| {
+            let code = std::str::from_utf8(code).ok()?;
+            Some((u8::from_str_radix(code, radix).ok()?, len))
+        });
+        match code {
+            Some((code, len)) => {
+                bytes.push(code);
+                rest = &after[len..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The devices the links in `dir` point to, with the names of the links
+#[cfg(target_os = "linux")]
+fn read_disk_links(dir: &Path) -> anyhow::Result<HashMap<PathBuf, String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // There is none if no volume has a label
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", dir.display()));
+        }
+    };
+    let mut links = HashMap::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Ok(device) = entry.path().canonicalize() {
+            links.insert(device, unescape(&entry.file_name().to_string_lossy()));
+        }
+    }
+    Ok(links)
+}
+
+/// The mounted volumes backed by a device, with its label and UUID
+#[cfg(target_os = "linux")]
+pub fn mounted_volumes() -> anyhow::Result<Vec<Volume>> {
+    let mounts = fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let labels = read_disk_links(Path::new("/dev/disk/by-label"))?;
+    let uuids = read_disk_links(Path::new("/dev/disk/by-uuid"))?;
+
+    let mut volumes = Vec::new();
+    for line in mounts.lines() {
+        let mut fields = line.split(' ');
+        let (Some(source), Some(mount_point)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if !source.starts_with("/dev/") {
+            continue;
+        }
+        let Ok(device) = Path::new(&unescape(source)).canonicalize() else {
+            continue;
+        };
+        volumes.push(Volume {
+            mount_point: PathBuf::from(unescape(mount_point)),
+            label: labels.get(&device).cloned(),
+            uuid: uuids.get(&device).cloned(),
+            device,
+        });
+    }
+    Ok(volumes)
+}
+
+/// Only Linux tells the labels of mounted volumes
+#[cfg(not(target_os = "linux"))]
+pub fn mounted_volumes() -> anyhow::Result<Vec<Volume>> {
+    anyhow::bail!("Finding cards by label or UUID is only supported on Linux")
+}
+
+/// Mount points of the volumes whose label or UUID is one of `ids`, in the
+/// order of `ids`. Both are compared ignoring case, as FAT UUIDs like
+/// `1A2B-3C4D` are shown in either.
+pub fn find_cards(ids: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+    let volumes = mounted_volumes()?;
+    let mut cards = Vec::new();
+    for id in ids {
+        let matches = volumes.iter().filter(|volume| {
+            [&volume.label, &volume.uuid]
+                .into_iter()
+                .flatten()
+                .any(|name| name.eq_ignore_ascii_case(id))
+        });
+        for volume in matches {
+            if !cards.contains(&volume.mount_point) {
+                cards.push(volume.mount_point.clone());
+            }
+        }
+    }
+    Ok(cards)
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+#[cfg(target_os = "linux")]
+use std::{
+    ffi::CString,
+    fs::File,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+};
+
+use log::{debug, warn};
+
+/// How often the mount point is read while watching for cards, where the
+/// system doesn't tell when it changes. Reading one small folder every few
+/// seconds costs next to nothing.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the mount point is read anyway while the system tells when it
+/// changes, for a `DCIM` folder created on a card that was already mounted,
+/// which neither notification covers
+#[cfg(target_os = "linux")]
+const RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watches a folder removable media gets mounted under, like `/media/$USER`,
+/// handing out each card once when it appears
+pub struct CardWatcher {
+    mount_dir: PathBuf,
+    present: HashSet<PathBuf>,
+    /// The last error reading the mount point, only logged when it changes
+    last_error: Option<String>,
+    /// Tells when the mount point or the mounts change, `None` to poll
+    #[cfg(target_os = "linux")]
+    notifier: Option<Notifier>,
+}
+
+impl CardWatcher {
+    pub fn new(mount_dir: &Path) -> Self {
+        CardWatcher {
+            mount_dir: mount_dir.to_owned(),
+            present: HashSet::new(),
+            last_error: None,
+            #[cfg(target_os = "linux")]
+            notifier: Notifier::new()
+                .inspect_err(|err| {
+                    warn!(
+                        "Failed to watch for mounted cards, polling instead: {}",
+                        err
+                    )
+                })
+                .ok(),
+        }
+    }
+
+    /// Block until cards may have been mounted or removed since the last
+    /// [`poll`](Self::poll). On Linux that is when an entry of the mount
+    /// point is created or removed or anything is mounted or unmounted,
+    /// elsewhere or if watching fails it is after [`POLL_INTERVAL`].
+    pub fn wait(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Some(notifier) = &self.notifier {
+            let res = notifier
+                .watch(&self.mount_dir)
+                .and_then(|()| notifier.wait(RESCAN_INTERVAL));
+            match res {
+                Ok(()) => return,
+                Err(err) => {
+                    warn!(
+                        "Failed to watch {}, polling instead: {}",
+                        self.mount_dir.display(),
+                        err
+                    );
+                    self.notifier = None;
+                }
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    /// Return the mounted cards that appeared since the last poll. A card is
+    /// any folder under the mount point holding a `DCIM` folder, and is
+    /// returned again after it was removed and inserted again. A missing
+    /// mount point holds no cards, other errors reading it are logged and
+    /// leave the cards as they were, so watching carries on.
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let cards = match self.read_cards() {
+            Ok(cards) => cards,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => {
+                let message = format!("Failed to read {}: {}", self.mount_dir.display(), err);
+                if self.last_error.as_ref() != Some(&message) {
+                    warn!("{}, trying again", message);
+                    self.last_error = Some(message);
+                }
+                return Vec::new();
+            }
+        };
+        self.last_error = None;
+
+        let mut appeared = cards
+            .iter()
+            .filter(|card| !self.present.contains(*card))
+            .cloned()
+            .collect::<Vec<_>>();
+        appeared.sort();
+        self.present = cards;
+
+        appeared
+    }
+
+    fn read_cards(&self) -> io::Result<HashSet<PathBuf>> {
+        let mut cards = HashSet::new();
+        for entry in fs::read_dir(&self.mount_dir)? {
+            let card = entry?.path();
+            match fs::metadata(card.join("DCIM")) {
+                Ok(meta) if meta.is_dir() => {
+                    cards.insert(card);
+                }
+                Ok(_) => {}
+                // Mount points of other media may not be readable, and a card
+                // may be unmounted while it is read
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(cards)
+    }
+}
+
+/// Changes of the mount point through inotify, and of the mounts through
+/// `/proc/self/mounts`, which is marked with a priority event whenever
+/// anything is mounted or unmounted. Watching the mounts too covers cards
+/// mounted on a folder that already existed, and udisks removing the mount
+/// point after the last card is unmounted, which ends the inotify watch.
+#[cfg(target_os = "linux")]
+struct Notifier {
+    inotify: OwnedFd,
+    mounts: File,
+}
+
+#[cfg(target_os = "linux")]
+impl Notifier {
+    fn new() -> io::Result<Self> {
+        // SAFETY: inotify_init1 only takes flags
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and isn't owned by anything else
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mounts = File::open("/proc/self/mounts")?;
+
+        Ok(Notifier { inotify, mounts })
+    }
+
+    /// Watch `dir` for entries created, removed or renamed. Watching it again
+    /// keeps the same watch, so this is done before every wait to pick the
+    /// mount point up again after it was removed and created again. A missing
+    /// mount point is left to the mounts to tell about.
+    fn watch(&self, dir: &Path) -> io::Result<()> {
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_ONLYDIR;
+        // SAFETY: `path` is a valid C string for the duration of the call
+        let res = unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), path.as_ptr(), mask) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block until the watched folder or the mounts change, or for at most
+    /// `timeout`
+    fn wait(&self, timeout: Duration) -> io::Result<()> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.mounts.as_raw_fd(),
+                events: libc::POLLPRI,
+                revents: 0,
+            },
+        ];
+        let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        // SAFETY: `fds` holds `fds.len()` valid pollfd structs
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            // A signal like SIGWINCH just means reading the mount point early
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        if fds[1].revents & libc::POLLPRI != 0 {
+            debug!("Mounts changed");
+        }
+
+        // Only that something changed matters, not what, so the queued
+        // events are dropped
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+            let read =
+                unsafe { libc::read(self.inotify.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if read <= 0 {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}